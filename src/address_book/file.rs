@@ -0,0 +1,50 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::sleep;
+
+use super::{snapshot::load_snapshot, AddressBookStorage};
+
+/// Static address book provider, which reloads a local yaml/json file when it is changed
+pub struct AddressBookFile {
+    path: PathBuf,
+    storage: AddressBookStorage,
+    interval: Duration,
+    last_modified: Option<SystemTime>,
+}
+
+impl AddressBookFile {
+    pub fn new(path: &Path, interval: Duration, storage: AddressBookStorage) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            storage,
+            interval,
+            last_modified: None,
+        }
+    }
+
+    async fn reload_if_changed(&mut self) -> io::Result<()> {
+        let modified = tokio::fs::metadata(&self.path).await?.modified()?;
+        if self.last_modified == Some(modified) {
+            return Ok(());
+        }
+        let snapshot = load_snapshot(&self.path).await?;
+        log::info!("[AddressBookFile] loaded {} apps, {} numbers from {:?}", snapshot.apps.len(), snapshot.numbers.len(), self.path);
+        self.storage.apply_snapshot(snapshot);
+        self.storage.set_synced();
+        self.last_modified = Some(modified);
+        Ok(())
+    }
+
+    pub async fn run_loop(&mut self) {
+        loop {
+            if let Err(e) = self.reload_if_changed().await {
+                log::error!("[AddressBookFile] reload {:?} error {e:?}", self.path);
+            }
+            sleep(self.interval).await;
+        }
+    }
+}
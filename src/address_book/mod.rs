@@ -1,5 +1,11 @@
+mod file;
+mod replicate;
+mod snapshot;
 mod storage;
 mod sync;
 
-pub use storage::AddressBookStorage;
-pub use sync::AddressBookSync;
+pub use file::AddressBookFile;
+pub use replicate::{AddressBookReplicator, AddressBookUpdater};
+pub use snapshot::load_snapshot;
+pub use storage::{AddressBookStorage, AddressBookUpdate};
+pub use sync::{AddressBookSync, AddressBookSyncConfig};
@@ -0,0 +1,129 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use atm0s_small_p2p::pubsub_service::{PubsubChannelId, PubsubServiceRequester, SubscriberEventOb};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::interval,
+};
+
+use crate::{
+    protocol::{AddressBookChange, AdminChange},
+    utils::{select2, select3},
+};
+
+use super::AddressBookStorage;
+
+const ADDRESS_BOOK_CHANNEL: u64 = 0x6164_6472_626f_6f6b;
+/// All admin changes are republished with this interval, so nodes which joined later also receive them
+const ADDRESS_BOOK_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReplicateMessage {
+    origin: u64,
+    changes: Vec<AdminChange>,
+}
+
+/// Handle for pushing address book changes, changes are applied locally then replicated to all nodes
+#[derive(Clone)]
+pub struct AddressBookUpdater {
+    storage: AddressBookStorage,
+    tx: UnboundedSender<AdminChange>,
+}
+
+impl AddressBookUpdater {
+    pub fn storage(&self) -> &AddressBookStorage {
+        &self.storage
+    }
+
+    pub fn apply(&self, change: AddressBookChange) {
+        let version = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let admin = AdminChange { version, change };
+        self.storage.apply_admin_change(admin.clone());
+        if self.tx.send(admin).is_err() {
+            log::error!("[AddressBookUpdater] replicate worker stopped");
+        }
+    }
+}
+
+/// Replicate address book changes over a cluster-wide pubsub channel,
+/// each node publishes its local changes and applies changes from other nodes.
+/// All known admin changes are also republished periodically, newer changes win so republishing is idempotent.
+pub struct AddressBookReplicator {
+    origin: u64,
+    storage: AddressBookStorage,
+    pubsub: PubsubServiceRequester,
+    rx: UnboundedReceiver<AdminChange>,
+}
+
+impl AddressBookReplicator {
+    pub fn new(storage: AddressBookStorage, pubsub: PubsubServiceRequester) -> (Self, AddressBookUpdater) {
+        let (tx, rx) = unbounded_channel();
+        (
+            Self {
+                origin: rand::random(),
+                storage: storage.clone(),
+                pubsub,
+                rx,
+            },
+            AddressBookUpdater { storage, tx },
+        )
+    }
+
+    pub async fn run_loop(&mut self) {
+        let channel: PubsubChannelId = ADDRESS_BOOK_CHANNEL.into();
+        let mut publisher = self.pubsub.publisher(channel).await;
+        let mut subscriber = self.pubsub.subscriber(channel).await;
+        let mut ticker = interval(ADDRESS_BOOK_SYNC_INTERVAL);
+        loop {
+            // publisher events are polled only for keeping its queue drained, feedback is not used here
+            let out = select3::or(
+                select2::or(self.rx.recv(), ticker.tick()),
+                subscriber.recv_ob::<ReplicateMessage>(),
+                publisher.recv_ob::<ReplicateMessage>(),
+            )
+            .await;
+            let changes = match out {
+                select3::OrOutput::Left(select2::OrOutput::Left(Some(change))) => {
+                    log::info!("[AddressBookReplicator] replicate local change");
+                    vec![change]
+                }
+                select3::OrOutput::Left(select2::OrOutput::Left(None)) => {
+                    log::warn!("[AddressBookReplicator] all updaters dropped => stop");
+                    break;
+                }
+                select3::OrOutput::Left(select2::OrOutput::Right(_)) => {
+                    let changes = self.storage.admin_changes();
+                    if changes.is_empty() {
+                        continue;
+                    }
+                    changes
+                }
+                select3::OrOutput::Middle(Ok(SubscriberEventOb::Publish(msg))) => {
+                    if msg.origin != self.origin {
+                        for change in msg.changes {
+                            if self.storage.apply_admin_change(change) {
+                                log::info!("[AddressBookReplicator] applied remote change");
+                            }
+                        }
+                    }
+                    continue;
+                }
+                select3::OrOutput::Middle(Ok(_)) => continue,
+                select3::OrOutput::Middle(Err(e)) => {
+                    log::error!("[AddressBookReplicator] subscriber error {e:?}");
+                    break;
+                }
+                select3::OrOutput::Right(Ok(_)) => continue,
+                select3::OrOutput::Right(Err(e)) => {
+                    log::error!("[AddressBookReplicator] publisher error {e:?}");
+                    break;
+                }
+            };
+            let msg = ReplicateMessage { origin: self.origin, changes };
+            if let Err(e) = publisher.requester().publish_ob(&msg).await {
+                log::error!("[AddressBookReplicator] publish changes error {e:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::protocol::AddressBookSnapshot;
+
+/// Parse address book content, format is detected by file extension: yaml/yml or json (default)
+pub fn parse_snapshot(path: &Path, data: &[u8]) -> io::Result<AddressBookSnapshot> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        _ => serde_json::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+pub async fn load_snapshot(path: &Path) -> io::Result<AddressBookSnapshot> {
+    let data = tokio::fs::read(path).await?;
+    parse_snapshot(path, &data)
+}
+
+/// Write snapshot to a temp file then rename it, so a crash in the middle never leaves a broken snapshot.
+/// The snapshot has SIP credentials and media secrets, so the file is only readable by the owner
+pub async fn save_snapshot(path: &Path, snapshot: &AddressBookSnapshot) -> io::Result<()> {
+    let data = serde_json::to_vec(snapshot).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut tmp = PathBuf::from(path);
+    tmp.as_mut_os_string().push(".tmp");
+    write_private(&tmp, &data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Create the file with mode 0600 before any data is written, a stale file is removed first because its mode is kept when reopened
+async fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{AppInfo, PhoneNumber, SecretHash};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshot_roundtrip() {
+        let path = std::env::temp_dir().join(format!("address_book_{}.json", rand::random::<u64>()));
+        let snapshot = AddressBookSnapshot {
+            apps: vec![AppInfo {
+                app_id: "app1".to_owned(),
+                secret_hash: SecretHash::of("secret1"),
+                limits: Default::default(),
+                token_ttl_secs: None,
+                media_backend: None,
+                media_secret: None,
+            }],
+            numbers: vec![PhoneNumber {
+                number: "1000".to_owned(),
+                subnets: vec!["10.0.0.0/8".parse().expect("should parse subnet")],
+                auth: None,
+                app_id: "app1".to_owned(),
+                hook: "http://localhost/hook".to_owned(),
+                limits: Default::default(),
+                codecs: vec![],
+            }],
+            admin: vec![],
+        };
+        save_snapshot(&path, &snapshot).await.expect("should save");
+        let loaded = load_snapshot(&path).await.expect("should load");
+        assert_eq!(loaded.apps.len(), 1);
+        assert_eq!(loaded.apps[0].app_id, "app1");
+        assert_eq!(loaded.apps[0].secret_hash, SecretHash::of("secret1"));
+        assert_eq!(loaded.numbers.len(), 1);
+        assert_eq!(loaded.numbers[0].number, "1000");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).expect("should have metadata").permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "snapshot should only be readable by the owner");
+        }
+        std::fs::remove_file(path).expect("should remove");
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let yaml = "apps:\n  - app_id: app1\n    app_secret: secret1\nnumbers:\n  - number: \"1000\"\n    subnets: [\"10.0.0.0/8\"]\n    app_id: app1\n    hook: http://localhost/hook\n";
+        let snapshot = parse_snapshot(Path::new("address_book.yaml"), yaml.as_bytes()).expect("should parse");
+        assert_eq!(snapshot.apps.len(), 1);
+        assert!(snapshot.apps[0].secret_hash.verify("secret1"));
+        assert_eq!(snapshot.numbers[0].subnets.len(), 1);
+        assert!(snapshot.numbers[0].auth.is_none());
+    }
+}
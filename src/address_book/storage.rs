@@ -1,12 +1,27 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use spin::RwLock;
 
-use crate::protocol::{AppInfo, PhoneNumber};
+use crate::protocol::{AddressBookChange, AddressBookSnapshot, AdminChange, AppInfo, CallLimits, Codec, MediaSecret, PhoneNumber, SecretHash};
+
+/// Change of a list in address book, delta deletes are identified by app_id or number
+pub enum AddressBookUpdate<T> {
+    Unchanged,
+    Full(Vec<T>),
+    Delta { upserts: Vec<T>, deletes: Vec<String> },
+}
 
 #[derive(Clone)]
 pub struct AddressBookStorage {
     internal: Arc<RwLock<AddressBookStorageInternal>>,
+    synced: Arc<AtomicBool>,
+    admin_changed: Arc<AtomicBool>,
 }
 
 impl AddressBookStorage {
@@ -15,15 +30,33 @@ impl AddressBookStorage {
             internal: Arc::new(RwLock::new(AddressBookStorageInternal {
                 root_app: AppInfo {
                     app_id: "".to_owned(),
-                    app_secret: root_secret.to_owned(),
+                    secret_hash: SecretHash::of(root_secret),
+                    limits: Default::default(),
+                    token_ttl_secs: None,
+                    media_backend: None,
+                    // the root app is the gateway itself, it uses the gateway secret on the media server
+                    media_secret: Some(MediaSecret(root_secret.to_owned())),
                 },
                 app_ids: Default::default(),
                 app_secrets: Default::default(),
                 numbers: Default::default(),
+                admin_apps: Default::default(),
+                admin_numbers: Default::default(),
             })),
+            synced: Default::default(),
+            admin_changed: Default::default(),
         }
     }
 
+    /// Mark that the address book source has been loaded successfully at least once
+    pub fn set_synced(&self) {
+        self.synced.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::Relaxed)
+    }
+
     pub fn validate_app(&self, app_secret: &str) -> Option<AppInfo> {
         self.internal.read().validate_app(app_secret)
     }
@@ -33,27 +66,178 @@ impl AddressBookStorage {
     }
 
     pub fn sync_apps(&self, new_apps: Vec<AppInfo>) {
-        self.internal.write().sync_apps(new_apps);
+        self.apply_update(AddressBookUpdate::Full(new_apps), AddressBookUpdate::Unchanged);
     }
 
     pub fn sync_numbers(&self, new_numbers: Vec<PhoneNumber>) {
-        self.internal.write().sync_numbers(new_numbers);
+        self.apply_update(AddressBookUpdate::Unchanged, AddressBookUpdate::Full(new_numbers));
+    }
+
+    /// Apply apps and numbers changes together, readers never see a state with only one of them applied
+    pub fn apply_update(&self, apps: AddressBookUpdate<AppInfo>, numbers: AddressBookUpdate<PhoneNumber>) {
+        // full lists are indexed before taking the lock for keeping the write section short
+        let apps = apps.map_full(|apps| apps.into_iter().map(|a| (a.app_id.clone(), a)).collect::<HashMap<_, _>>());
+        let numbers = numbers.map_full(|numbers| numbers.into_iter().map(|n| (n.number.clone(), n)).collect::<HashMap<_, _>>());
+        let full_apps = matches!(apps, PreparedUpdate::Full(_));
+        let full_numbers = matches!(numbers, PreparedUpdate::Full(_));
+        let mut internal = self.internal.write();
+        internal.apply_apps(apps);
+        internal.apply_numbers(numbers);
+        internal.reapply_admin(full_apps, full_numbers);
+        internal.check_orphan_numbers();
+    }
+
+    /// Apply a change from admin api, it is kept on top of the synced lists so the next full sync doesn't revert it.
+    /// Return false if a newer change of the same app or number is already applied.
+    pub fn apply_admin_change(&self, admin: AdminChange) -> bool {
+        let mut internal = self.internal.write();
+        if !internal.record_admin(&admin) {
+            return false;
+        }
+        internal.apply_admin(&admin.change);
+        internal.check_orphan_numbers();
+        self.admin_changed.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// All admin changes which are kept on top of the synced lists
+    pub fn admin_changes(&self) -> Vec<AdminChange> {
+        self.internal.read().admin_changes()
+    }
+
+    /// Return true once after admin changes are applied, used for persisting them with the snapshot
+    pub fn take_admin_changed(&self) -> bool {
+        self.admin_changed.swap(false, Ordering::Relaxed)
+    }
+
+    /// App entry by id, including the root app
+    pub fn app(&self, app_id: &str) -> Option<AppInfo> {
+        let internal = self.internal.read();
+        if internal.root_app.app_id == app_id {
+            return Some(internal.root_app.clone());
+        }
+        internal.app_ids.get(app_id).cloned()
+    }
+
+    pub fn app_limits(&self, app_id: &str) -> Option<CallLimits> {
+        let internal = self.internal.read();
+        if internal.root_app.app_id == app_id {
+            return Some(internal.root_app.limits.clone());
+        }
+        internal.app_ids.get(app_id).map(|a| a.limits.clone())
+    }
+
+    pub fn app_token_ttl(&self, app_id: &str) -> Option<u64> {
+        let internal = self.internal.read();
+        if internal.root_app.app_id == app_id {
+            return internal.root_app.token_ttl_secs;
+        }
+        internal.app_ids.get(app_id).and_then(|a| a.token_ttl_secs)
+    }
+
+    pub fn app_media_backend(&self, app_id: &str) -> Option<String> {
+        let internal = self.internal.read();
+        if internal.root_app.app_id == app_id {
+            return internal.root_app.media_backend.clone();
+        }
+        internal.app_ids.get(app_id).and_then(|a| a.media_backend.clone())
+    }
+
+    pub fn app_media_secret(&self, app_id: &str) -> Option<MediaSecret> {
+        let internal = self.internal.read();
+        if internal.root_app.app_id == app_id {
+            return internal.root_app.media_secret.clone();
+        }
+        internal.app_ids.get(app_id).and_then(|a| a.media_secret.clone())
+    }
+
+    pub fn number_limits(&self, number: &str) -> Option<CallLimits> {
+        self.internal.read().numbers.get(number).map(|n| n.limits.clone())
+    }
+
+    /// Codec policy of a number, empty if the number is unknown or allows all codecs
+    pub fn number_codecs(&self, number: &str) -> Vec<Codec> {
+        self.internal.read().numbers.get(number).map(|n| n.codecs.clone()).unwrap_or_default()
+    }
+
+    pub fn has_app(&self, app_id: &str) -> bool {
+        let internal = self.internal.read();
+        internal.root_app.app_id == app_id || internal.app_ids.contains_key(app_id)
+    }
+
+    /// Check the ip is in the subnets of any number, used for exempting carriers from flood protection
+    pub fn is_known_source(&self, ip: std::net::IpAddr) -> bool {
+        self.internal.read().numbers.values().any(|n| n.subnets.iter().any(|subnet| subnet.contains(&ip)))
+    }
+
+    pub fn has_number(&self, number: &str) -> bool {
+        self.internal.read().numbers.contains_key(number)
+    }
+
+    /// Check the secret is the gateway root secret, which is used for admin apis
+    pub fn validate_root(&self, secret: &str) -> bool {
+        self.internal.read().root_app.secret_hash.verify(secret)
+    }
+
+    pub fn snapshot(&self) -> AddressBookSnapshot {
+        let internal = self.internal.read();
+        AddressBookSnapshot {
+            apps: internal.app_ids.values().cloned().collect(),
+            numbers: internal.numbers.values().cloned().collect(),
+            admin: internal.admin_changes(),
+        }
+    }
+
+    pub fn apply_snapshot(&self, snapshot: AddressBookSnapshot) {
+        self.apply_update(AddressBookUpdate::Full(snapshot.apps), AddressBookUpdate::Full(snapshot.numbers));
+        for admin in snapshot.admin {
+            self.apply_admin_change(admin);
+        }
+    }
+}
+
+enum PreparedUpdate<T> {
+    Unchanged,
+    Full(HashMap<String, T>),
+    Delta { upserts: Vec<T>, deletes: Vec<String> },
+}
+
+impl<T> AddressBookUpdate<T> {
+    fn map_full<F: FnOnce(Vec<T>) -> HashMap<String, T>>(self, f: F) -> PreparedUpdate<T> {
+        match self {
+            AddressBookUpdate::Unchanged => PreparedUpdate::Unchanged,
+            AddressBookUpdate::Full(list) => PreparedUpdate::Full(f(list)),
+            AddressBookUpdate::Delta { upserts, deletes } => PreparedUpdate::Delta { upserts, deletes },
+        }
     }
 }
 
 struct AddressBookStorageInternal {
     root_app: AppInfo,
     app_ids: HashMap<String, AppInfo>,
-    app_secrets: HashMap<String, AppInfo>,
+    /// Secret hash => app id, lookup time only depends on the hash so it doesn't leak the secret
+    app_secrets: HashMap<SecretHash, String>,
     numbers: HashMap<String, PhoneNumber>,
+    /// Latest admin change by app id and by number, re-applied after each full sync
+    admin_apps: HashMap<String, AdminChange>,
+    admin_numbers: HashMap<String, AdminChange>,
 }
 
 impl AddressBookStorageInternal {
     pub fn validate_app(&self, app_secret: &str) -> Option<AppInfo> {
-        if app_secret.eq(&self.root_app.app_secret) {
+        if self.root_app.secret_hash.verify(app_secret) {
             return Some(self.root_app.clone());
         }
-        self.app_secrets.get(app_secret).cloned()
+        let app_id = self.app_secrets.get(&SecretHash::of(app_secret))?;
+        self.app_ids.get(app_id).cloned()
+    }
+
+    fn index_secret(&mut self, app: &AppInfo) {
+        self.app_secrets.insert(app.secret_hash, app.app_id.clone());
+    }
+
+    fn unindex_secret(&mut self, app: &AppInfo) {
+        self.app_secrets.remove(&app.secret_hash);
     }
 
     pub fn validate_phone(&self, remote: std::net::SocketAddr, _from: &str, to: &str) -> Option<(AppInfo, PhoneNumber)> {
@@ -71,27 +255,229 @@ impl AddressBookStorageInternal {
         None
     }
 
-    pub fn sync_apps(&mut self, new_apps: Vec<AppInfo>) {
+    fn apply_apps(&mut self, update: PreparedUpdate<AppInfo>) {
         let pre_len = self.app_ids.len();
-        self.app_ids.clear();
-        self.app_secrets.clear();
-        for app in new_apps {
-            self.app_secrets.insert(app.app_secret.clone(), app.clone());
-            self.app_ids.insert(app.app_id.clone(), app);
+        match update {
+            PreparedUpdate::Unchanged => return,
+            PreparedUpdate::Full(new_apps) => {
+                self.app_secrets.clear();
+                for app in new_apps.values() {
+                    self.index_secret(app);
+                }
+                self.app_ids = new_apps;
+            }
+            PreparedUpdate::Delta { upserts, deletes } => {
+                for app_id in deletes {
+                    if let Some(app) = self.app_ids.remove(&app_id) {
+                        self.unindex_secret(&app);
+                    }
+                }
+                for app in upserts {
+                    if let Some(old) = self.app_ids.insert(app.app_id.clone(), app.clone()) {
+                        self.unindex_secret(&old);
+                    }
+                    self.index_secret(&app);
+                }
+            }
         }
         if self.app_ids.len() != pre_len {
             log::info!("[AddressBookStorage] apps len changed from {} to {}", pre_len, self.app_ids.len());
         }
     }
 
-    pub fn sync_numbers(&mut self, new_numbers: Vec<PhoneNumber>) {
+    fn apply_numbers(&mut self, update: PreparedUpdate<PhoneNumber>) {
         let pre_len = self.numbers.len();
-        self.numbers.clear();
-        for number in new_numbers {
-            self.numbers.insert(number.number.clone(), number);
+        match update {
+            PreparedUpdate::Unchanged => return,
+            PreparedUpdate::Full(new_numbers) => {
+                self.numbers = new_numbers;
+            }
+            PreparedUpdate::Delta { upserts, deletes } => {
+                for number in deletes {
+                    self.numbers.remove(&number);
+                }
+                for number in upserts {
+                    self.numbers.insert(number.number.clone(), number);
+                }
+            }
         }
         if self.numbers.len() != pre_len {
-            log::info!("[AddressBookStorage] numbers len changed from {} to {:?}", pre_len, self.numbers);
+            log::info!("[AddressBookStorage] numbers len changed from {} to {}", pre_len, self.numbers.len());
+        }
+    }
+
+    fn record_admin(&mut self, admin: &AdminChange) -> bool {
+        let (overlay, key) = match &admin.change {
+            AddressBookChange::UpsertApp(app) => (&mut self.admin_apps, &app.app_id),
+            AddressBookChange::DeleteApp(app_id) => (&mut self.admin_apps, app_id),
+            AddressBookChange::UpsertNumber(number) => (&mut self.admin_numbers, &number.number),
+            AddressBookChange::DeleteNumber(number) => (&mut self.admin_numbers, number),
+        };
+        if overlay.get(key).is_some_and(|old| old.version >= admin.version) {
+            return false;
+        }
+        overlay.insert(key.clone(), admin.clone());
+        true
+    }
+
+    fn apply_admin(&mut self, change: &AddressBookChange) {
+        match change {
+            AddressBookChange::UpsertApp(app) => self.apply_apps(PreparedUpdate::Delta {
+                upserts: vec![app.clone()],
+                deletes: vec![],
+            }),
+            AddressBookChange::DeleteApp(app_id) => self.apply_apps(PreparedUpdate::Delta {
+                upserts: vec![],
+                deletes: vec![app_id.clone()],
+            }),
+            AddressBookChange::UpsertNumber(number) => self.apply_numbers(PreparedUpdate::Delta {
+                upserts: vec![number.clone()],
+                deletes: vec![],
+            }),
+            AddressBookChange::DeleteNumber(number) => self.apply_numbers(PreparedUpdate::Delta {
+                upserts: vec![],
+                deletes: vec![number.clone()],
+            }),
+        }
+    }
+
+    fn reapply_admin(&mut self, apps: bool, numbers: bool) {
+        let mut changes = vec![];
+        if apps {
+            changes.extend(self.admin_apps.values().map(|a| a.change.clone()));
+        }
+        if numbers {
+            changes.extend(self.admin_numbers.values().map(|a| a.change.clone()));
+        }
+        for change in changes {
+            self.apply_admin(&change);
+        }
+    }
+
+    fn admin_changes(&self) -> Vec<AdminChange> {
+        self.admin_apps.values().chain(self.admin_numbers.values()).cloned().collect()
+    }
+
+    fn check_orphan_numbers(&self) {
+        let orphans = self.numbers.values().filter(|n| n.app_id != self.root_app.app_id && !self.app_ids.contains_key(&n.app_id)).count();
+        if orphans > 0 {
+            log::warn!("[AddressBookStorage] {orphans} numbers are linked to unknown apps");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{AddressBookChange, AdminChange, AppInfo, PhoneNumber, SecretHash};
+
+    use super::{AddressBookStorage, AddressBookUpdate};
+
+    fn app(id: &str, secret: &str) -> AppInfo {
+        AppInfo {
+            app_id: id.to_owned(),
+            secret_hash: SecretHash::of(secret),
+            limits: Default::default(),
+            token_ttl_secs: None,
+            media_backend: None,
+            media_secret: None,
+        }
+    }
+
+    fn number(number: &str, app_id: &str) -> PhoneNumber {
+        PhoneNumber {
+            number: number.to_owned(),
+            subnets: vec!["127.0.0.0/8".parse().expect("should parse subnet")],
+            auth: None,
+            app_id: app_id.to_owned(),
+            hook: "http://localhost/hook".to_owned(),
+            limits: Default::default(),
+            codecs: vec![],
         }
     }
+
+    #[test]
+    fn test_full_then_delta_update() {
+        let storage = AddressBookStorage::new("root");
+        let remote = "127.0.0.1:5060".parse().expect("should parse addr");
+        storage.apply_update(AddressBookUpdate::Full(vec![app("app1", "secret1")]), AddressBookUpdate::Full(vec![number("1000", "app1")]));
+        assert_eq!(storage.validate_phone(remote, "", "1000").map(|(a, _)| a.app_id), Some("app1".to_owned()));
+
+        storage.apply_update(
+            AddressBookUpdate::Delta {
+                upserts: vec![app("app1", "secret2"), app("app2", "secret3")],
+                deletes: vec![],
+            },
+            AddressBookUpdate::Delta {
+                upserts: vec![number("2000", "app2")],
+                deletes: vec!["1000".to_owned()],
+            },
+        );
+        assert!(storage.validate_app("secret1").is_none());
+        assert_eq!(storage.validate_app("secret2").map(|a| a.app_id), Some("app1".to_owned()));
+        assert!(storage.validate_phone(remote, "", "1000").is_none());
+        assert_eq!(storage.validate_phone(remote, "", "2000").map(|(a, _)| a.app_id), Some("app2".to_owned()));
+
+        storage.apply_update(AddressBookUpdate::Unchanged, AddressBookUpdate::Unchanged);
+        assert_eq!(storage.snapshot().numbers.len(), 1);
+        assert_eq!(storage.snapshot().apps.len(), 2);
+    }
+
+    #[test]
+    fn test_hashed_secret() {
+        let storage = AddressBookStorage::new("root");
+        let hash = SecretHash::of("secret1").to_hex().to_uppercase();
+        let apps: Vec<AppInfo> =
+            serde_json::from_str(&format!(r#"[{{ "app_id": "app1", "app_secret_hash": "{hash}" }}, {{ "app_id": "app3", "app_secret": "secret3" }}]"#)).expect("should parse apps");
+        assert!(serde_json::from_str::<AppInfo>(r#"{ "app_id": "app2", "app_secret_hash": "not-a-hash" }"#).is_err());
+        assert!(serde_json::from_str::<AppInfo>(r#"{ "app_id": "app2", "app_secret": "" }"#).is_err());
+        storage.sync_apps(apps);
+
+        assert_eq!(storage.validate_app("secret1").map(|a| a.app_id), Some("app1".to_owned()));
+        assert_eq!(storage.validate_app("secret3").map(|a| a.app_id), Some("app3".to_owned()));
+        assert!(storage.validate_app("").is_none());
+        assert!(storage.validate_app("not-a-hash").is_none());
+        assert!(storage.validate_root("root"));
+        assert!(!storage.validate_root("roo"));
+        // raw secrets are never kept, so they can't be printed or persisted
+        assert!(!format!("{:?}", storage.validate_app("secret3")).contains("secret3"));
+        let snapshot = serde_json::to_string(&storage.snapshot()).expect("should serialize");
+        assert!(!snapshot.contains("secret3"));
+        assert!(snapshot.contains(&SecretHash::of("secret3").to_hex()));
+    }
+
+    #[test]
+    fn test_admin_changes_survive_full_sync() {
+        let storage = AddressBookStorage::new("root");
+        let remote = "127.0.0.1:5060".parse().expect("should parse addr");
+        storage.sync_apps(vec![app("app1", "secret1")]);
+        storage.sync_numbers(vec![number("1000", "app1")]);
+
+        assert!(storage.apply_admin_change(AdminChange {
+            version: 2,
+            change: AddressBookChange::UpsertApp(app("app2", "secret2")),
+        }));
+        assert!(storage.apply_admin_change(AdminChange {
+            version: 2,
+            change: AddressBookChange::DeleteNumber("1000".to_owned()),
+        }));
+        // an older change from another node doesn't override the newer one
+        assert!(!storage.apply_admin_change(AdminChange {
+            version: 1,
+            change: AddressBookChange::DeleteApp("app2".to_owned()),
+        }));
+        assert!(storage.take_admin_changed());
+        assert!(!storage.take_admin_changed());
+
+        storage.apply_update(AddressBookUpdate::Full(vec![app("app1", "secret1")]), AddressBookUpdate::Full(vec![number("1000", "app1")]));
+        assert_eq!(storage.validate_app("secret2").map(|a| a.app_id), Some("app2".to_owned()));
+        assert!(storage.validate_phone(remote, "", "1000").is_none());
+
+        let snapshot = storage.snapshot();
+        assert_eq!(snapshot.admin.len(), 2);
+        let restored = AddressBookStorage::new("root");
+        restored.apply_snapshot(snapshot);
+        assert_eq!(restored.validate_app("secret2").map(|a| a.app_id), Some("app2".to_owned()));
+        assert!(restored.validate_phone(remote, "", "1000").is_none());
+        assert_eq!(restored.admin_changes().len(), 2);
+    }
 }
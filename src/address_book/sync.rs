@@ -1,58 +1,203 @@
-use std::time::Duration;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use tokio::time::sleep;
+use reqwest::{
+    header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Client, StatusCode,
+};
+use serde::de::DeserializeOwned;
+use tokio::{sync::watch, time::sleep};
 
-use crate::protocol::{AppsSyncResponse, PhoneNumbersSyncResponse};
+use crate::{
+    protocol::{AppInfo, AppsSyncResponse, PhoneNumber, PhoneNumbersSyncResponse},
+    utils::select2,
+};
 
-use super::AddressBookStorage;
+use super::{snapshot::save_snapshot, AddressBookStorage, AddressBookUpdate};
+
+/// Conditional request validators from the last applied response
+#[derive(Default, Clone)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Sync endpoint with its validators and delta cursor
+struct SyncSource {
+    url: String,
+    validators: Validators,
+    cursor: Option<String>,
+}
+
+impl SyncSource {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            validators: Validators::default(),
+            cursor: None,
+        }
+    }
+
+    /// Fetch the list, return None body if server responds 304 Not Modified.
+    /// Validators are returned instead of stored, they are only committed after the body is applied.
+    async fn fetch<T: DeserializeOwned>(&self, client: &Client) -> reqwest::Result<(Option<T>, Validators)> {
+        let mut builder = client.get(&self.url);
+        if let Some(cursor) = &self.cursor {
+            builder = builder.query(&[("since", cursor)]);
+        }
+        if let Some(etag) = &self.validators.etag {
+            builder = builder.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.validators.last_modified {
+            builder = builder.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let res = builder.send().await?.error_for_status()?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok((None, self.validators.clone()));
+        }
+        let header = |name: HeaderName| res.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        Ok((Some(res.json().await?), validators))
+    }
+
+    /// Commit validators and cursor after fetched data is applied, cursor is only changed when data is modified
+    fn commit(&mut self, validators: Validators, cursor: Option<Option<String>>) {
+        self.validators = validators;
+        if let Some(cursor) = cursor {
+            self.cursor = cursor;
+        }
+    }
+}
+
+/// Full list is used when the server returns it, otherwise the response is a delta from the `since` cursor
+fn numbers_update(res: PhoneNumbersSyncResponse) -> (AddressBookUpdate<PhoneNumber>, Option<String>) {
+    match res.numbers {
+        Some(numbers) => (AddressBookUpdate::Full(numbers), res.cursor),
+        None => (
+            AddressBookUpdate::Delta {
+                upserts: res.upserts,
+                deletes: res.deletes,
+            },
+            res.cursor,
+        ),
+    }
+}
+
+fn apps_update(res: AppsSyncResponse) -> (AddressBookUpdate<AppInfo>, Option<String>) {
+    match res.apps {
+        Some(apps) => (AddressBookUpdate::Full(apps), res.cursor),
+        None => (
+            AddressBookUpdate::Delta {
+                upserts: res.upserts,
+                deletes: res.deletes,
+            },
+            res.cursor,
+        ),
+    }
+}
+
+/// Sync endpoints and interval, which can be changed while running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressBookSyncConfig {
+    pub numbers_url: String,
+    pub apps_url: String,
+    pub interval: Duration,
+}
 
 pub struct AddressBookSync {
-    numbers_url: String,
-    apps_url: String,
+    numbers: SyncSource,
+    apps: SyncSource,
     storage: AddressBookStorage,
     interval: Duration,
+    snapshot: Option<PathBuf>,
+    cfg_rx: watch::Receiver<AddressBookSyncConfig>,
 }
 
 impl AddressBookSync {
-    pub fn new(numbers_url: &str, apps_url: &str, interval: Duration, storage: AddressBookStorage) -> Self {
+    /// If snapshot is provided, the address book is persisted to that path after each successful sync.
+    /// Config changes from the watch channel are applied immediately and trigger a sync.
+    pub fn new(mut cfg_rx: watch::Receiver<AddressBookSyncConfig>, storage: AddressBookStorage, snapshot: Option<&Path>) -> Self {
+        let cfg = cfg_rx.borrow_and_update().clone();
         Self {
-            numbers_url: numbers_url.to_string(),
-            apps_url: apps_url.to_string(),
-            interval,
+            numbers: SyncSource::new(&cfg.numbers_url),
+            apps: SyncSource::new(&cfg.apps_url),
+            interval: cfg.interval,
             storage,
+            snapshot: snapshot.map(|p| p.to_path_buf()),
+            cfg_rx,
         }
     }
 
-    async fn sync(&mut self) -> reqwest::Result<()> {
-        let res: PhoneNumbersSyncResponse = reqwest::ClientBuilder::default()
-            .timeout(self.interval / 2)
-            .build()
-            .expect("Should build client")
-            .get(&self.numbers_url)
-            .send()
-            .await?
-            .json()
-            .await?;
-        self.storage.sync_numbers(res.numbers);
-        let res: AppsSyncResponse = reqwest::ClientBuilder::default()
-            .timeout(self.interval / 2)
-            .build()
-            .expect("Should build client")
-            .get(&self.apps_url)
-            .send()
-            .await?
-            .json()
-            .await?;
-        self.storage.sync_apps(res.apps);
-        Ok(())
+    /// Changed endpoints start from a full sync because validators and cursor belong to the old endpoint
+    fn apply_config(&mut self, cfg: AddressBookSyncConfig) {
+        log::info!("[AddressBookSync] config changed to {cfg:?}");
+        if self.numbers.url != cfg.numbers_url {
+            self.numbers = SyncSource::new(&cfg.numbers_url);
+        }
+        if self.apps.url != cfg.apps_url {
+            self.apps = SyncSource::new(&cfg.apps_url);
+        }
+        self.interval = cfg.interval;
+    }
+
+    /// Fetch both lists then apply them together, if any request failed nothing is applied.
+    /// Return true if the address book is changed.
+    async fn sync(&mut self) -> reqwest::Result<bool> {
+        let client = reqwest::ClientBuilder::default().timeout(self.interval / 2).build().expect("Should build client");
+        let (numbers, numbers_validators) = self.numbers.fetch::<PhoneNumbersSyncResponse>(&client).await?;
+        let (apps, apps_validators) = self.apps.fetch::<AppsSyncResponse>(&client).await?;
+        if numbers.is_none() && apps.is_none() {
+            log::debug!("[AddressBookSync] address book not modified");
+            return Ok(false);
+        }
+
+        let (numbers, numbers_cursor) = numbers.map(numbers_update).map(|(u, c)| (u, Some(c))).unwrap_or((AddressBookUpdate::Unchanged, None));
+        let (apps, apps_cursor) = apps.map(apps_update).map(|(u, c)| (u, Some(c))).unwrap_or((AddressBookUpdate::Unchanged, None));
+        self.storage.apply_update(apps, numbers);
+
+        self.numbers.commit(numbers_validators, numbers_cursor);
+        self.apps.commit(apps_validators, apps_cursor);
+        Ok(true)
     }
 
     pub async fn run_loop(&mut self) {
         loop {
-            if let Err(e) = self.sync().await {
-                log::error!("[AddressBookSync] sync error {e:?}");
+            let res = self.sync().await;
+            if res.is_ok() {
+                self.storage.set_synced();
+            }
+            let changed = match res {
+                Ok(changed) => changed,
+                Err(e) => {
+                    log::error!("[AddressBookSync] sync error {e:?}");
+                    false
+                }
+            };
+            // admin changes are persisted together with the synced lists
+            if changed | self.storage.take_admin_changed() {
+                if let Some(path) = &self.snapshot {
+                    if let Err(e) = save_snapshot(path, &self.storage.snapshot()).await {
+                        log::error!("[AddressBookSync] save snapshot to {path:?} error {e:?}");
+                    }
+                }
+            }
+            let out = select2::or(sleep(self.interval), self.cfg_rx.changed()).await;
+            match out {
+                select2::OrOutput::Left(_) => {}
+                select2::OrOutput::Right(Ok(())) => {
+                    let cfg = self.cfg_rx.borrow_and_update().clone();
+                    self.apply_config(cfg);
+                }
+                select2::OrOutput::Right(Err(_)) => {
+                    // config sender is dropped, keep syncing with the current config
+                    sleep(self.interval).await;
+                }
             }
-            sleep(self.interval).await;
         }
     }
 }
@@ -1,21 +1,75 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use atm0s_small_p2p::pubsub_service::PubsubServiceRequester;
-use incoming_call::IncomingCall;
+use incoming_call::{build_call_notify_rejected, IncomingCall};
+use limiter::{CallRateLimiter, CallUsage};
 use outgoing_call::OutgoingCall;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{interval_at, Interval},
+};
+use tracker::CallTracker;
 
 use crate::{
     address_book::AddressBookStorage,
-    hook::HttpHook,
-    protocol::{CallApiError, CallDirection, CreateCallRequest, CreateCallResponse, InternalCallId},
+    cluster::{CallReplica, CallReplicator, PlacementConstraint},
+    config::{CallLimitsConfig, SipTrunk},
+    drain::DrainState,
+    error::PrintErrorSimple,
+    hook::{sip_hook_headers, HttpHook},
+    protocol::{
+        protobuf::sip_gateway::{
+            call_event,
+            incoming_call_data::{incoming_call_event, incoming_call_request, incoming_call_response, IncomingCallEvent},
+            outgoing_call_data::{outgoing_call_event, outgoing_call_request, outgoing_call_response, OutgoingCallEvent},
+            recording_state, CallEvent, RecordingState,
+        },
+        AdminCallInfo, AppId, CallApiError, CallDirection, CallTokenScope, CreateCallRequest, CreateCallResponse, InternalCallId, SipAuth,
+    },
     secure::{CallToken, SecureContext},
-    sip::{MediaApi, SipServer},
+    sip::{caller_id_headers, validate_custom_headers, CodecPolicy, MediaApi, MediaBackends, RecordingAction, SipOutgoingCallParams, SipServer},
     utils::select2,
 };
 
+/// Response of a created outgoing call with a control token, which is signed by the given context.
+/// For calls placed on another node it is signed by the node which got the request, so the token works there
+pub fn outgoing_call_response(secure_ctx: &SecureContext, call_id: InternalCallId, app_id: &AppId) -> CreateCallResponse {
+    let call_token = secure_ctx.encode_call_token(
+        CallToken {
+            direction: CallDirection::Outgoing,
+            call_id: call_id.clone(),
+            app_id: app_id.to_string(),
+            scope: CallTokenScope::Control,
+        },
+        secure_ctx.call_token_ttl(app_id),
+    );
+    CreateCallResponse {
+        call_ws: format!("/call/outgoing/{call_id}?token={call_token}"),
+        call_id: call_id.into(),
+        call_token,
+    }
+}
+
 pub mod incoming_call;
+mod limiter;
 pub mod outgoing_call;
+mod tracker;
+
+/// Retry-After which is returned when a concurrency limit is reached, calls don't have a known end time
+const CONCURRENCY_RETRY_AFTER_SECS: u64 = 5;
+/// Retry-After which is returned to incoming calls while the node is draining, upstream should try other nodes
+const DRAIN_RETRY_AFTER_SECS: u64 = 60;
+/// Status code which is reported to the hook when an incoming call offer has no allowed codec
+const MEDIA_REJECTED_CODE: u32 = 488;
+const END_CALL_TIMEOUT_SECONDS: u64 = 2;
+/// Interval of media stats events of talking calls
+const MEDIA_STATS_INTERVAL: Duration = Duration::from_secs(10);
+/// Ended reason of calls which are cleaned up because their node failed
+const NODE_FAILURE_REASON: &str = "node_failure";
 
 pub enum CallManagerOut {
     Continue,
@@ -32,12 +86,24 @@ pub struct CallManager {
     destroy_rx: UnboundedReceiver<InternalCallId>,
     secure_ctx: Arc<SecureContext>,
     address_book: AddressBookStorage,
-    media_gateway: String,
+    media_backends: MediaBackends,
+    rate_limiter: CallRateLimiter,
+    limits: CallLimitsConfig,
+    trunks: HashMap<String, SipTrunk>,
+    replicator: Option<CallReplicator>,
+    drain: DrainState,
 }
 
 impl CallManager {
-    pub async fn new(call_pubsub: PubsubServiceRequester, sip_addr: SocketAddr, address_book: AddressBookStorage, secure_ctx: Arc<SecureContext>, http_hook: HttpHook, media_gateway: &str) -> Self {
-        let sip = SipServer::new(sip_addr).await.expect("should create sip-server");
+    pub fn new(
+        call_pubsub: PubsubServiceRequester,
+        sip: SipServer,
+        address_book: AddressBookStorage,
+        secure_ctx: Arc<SecureContext>,
+        http_hook: HttpHook,
+        media_backends: MediaBackends,
+        drain: DrainState,
+    ) -> Self {
         let (destroy_tx, destroy_rx) = unbounded_channel();
         Self {
             call_pubsub,
@@ -49,36 +115,186 @@ impl CallManager {
             destroy_rx,
             secure_ctx,
             address_book,
-            media_gateway: media_gateway.to_owned(),
+            media_backends,
+            rate_limiter: CallRateLimiter::default(),
+            limits: CallLimitsConfig::default(),
+            trunks: HashMap::new(),
+            replicator: None,
+            drain,
         }
     }
 
-    pub fn create_call(&mut self, req: CreateCallRequest, media_api: MediaApi) -> Result<CreateCallResponse, CallApiError> {
+    /// Limits from config, which are merged with address book limits for new calls
+    pub fn set_limits(&mut self, limits: CallLimitsConfig) {
+        self.limits = limits;
+    }
+
+    pub fn set_trunks(&mut self, trunks: Vec<SipTrunk>) {
+        self.trunks = trunks.into_iter().map(|t| (t.name.clone(), t)).collect();
+    }
+
+    /// Established calls are replicated to other nodes for cleaning up if this node fails
+    pub fn set_replicator(&mut self, replicator: CallReplicator) {
+        self.replicator = Some(replicator);
+    }
+
+    /// Placement constraint of an outgoing call from the request, or from its trunk if the request has none
+    pub fn placement_constraint(&self, req: &CreateCallRequest) -> Result<PlacementConstraint, CallApiError> {
+        let trunk = req.trunk.as_ref().and_then(|name| self.trunks.get(name));
+        let placement = req.placement.clone().unwrap_or_default();
+        let source_ip = match placement.source_ip {
+            Some(ip) => Some(ip.parse().map_err(|_| CallApiError::BadRequest(format!("invalid placement source_ip {ip}")))?),
+            None => trunk.and_then(|t| t.source_ip),
+        };
+        Ok(PlacementConstraint {
+            region: placement.region.or_else(|| trunk.and_then(|t| t.region.clone())),
+            source_ip,
+        })
+    }
+
+    /// Resolve sip server and auth of an outgoing call from the trunk name or the request fields
+    fn resolve_destination(&self, req: &CreateCallRequest) -> Result<(String, Option<SipAuth>), CallApiError> {
+        match (&req.trunk, &req.sip_server) {
+            (Some(_), Some(_)) => Err(CallApiError::BadRequest("only one of trunk and sip_server is allowed".to_owned())),
+            (Some(name), None) => {
+                let trunk = self.trunks.get(name).ok_or_else(|| CallApiError::BadRequest(format!("unknown trunk {name}")))?;
+                Ok((trunk.server.clone(), req.sip_auth.clone().or_else(|| trunk.auth.clone())))
+            }
+            (None, Some(server)) => Ok((server.clone(), req.sip_auth.clone())),
+            (None, None) => Err(CallApiError::BadRequest("trunk or sip_server is required".to_owned())),
+        }
+    }
+
+    pub fn create_call(&mut self, req: CreateCallRequest, media_api: MediaApi, app_id: AppId) -> Result<CreateCallResponse, CallApiError> {
+        if self.drain.is_draining() {
+            return Err(CallApiError::Draining);
+        }
+        let custom_headers = req.headers.unwrap_or_default();
+        validate_custom_headers(&custom_headers).map_err(|e| CallApiError::BadRequest(e.to_owned()))?;
+        let (sip_server, sip_auth) = self.resolve_destination(&req)?;
+        self.check_limits(&app_id, &req.from_number, CallDirection::Outgoing).map_err(CallApiError::RateLimited)?;
         let hook_sender = self.http_hook.new_sender(&req.hook, HashMap::new());
-        let from = format!("sip:{}@{}", req.from_number, req.sip_server);
-        let to = format!("sip:{}@{}", req.to_number, req.sip_server);
-        match self.sip.make_call(media_api, &from, &to, req.sip_auth, req.streaming) {
+        let from = format!("sip:{}@{}", req.from_number, sip_server);
+        let to = format!("sip:{}@{}", req.to_number, sip_server);
+        let mut headers = caller_id_headers(req.asserted_identity.as_deref(), req.privacy.unwrap_or(false), &sip_server);
+        headers.extend(custom_headers);
+        let params = SipOutgoingCallParams {
+            from_display: req.from_display_name,
+            headers,
+            codecs: self.address_book.number_codecs(&req.from_number),
+        };
+        match self.sip.make_call(media_api, &from, &to, sip_auth, req.streaming, params) {
             Ok(call) => {
                 let call_id = call.call_id();
-                let call_token = self.secure_ctx.encode_call_token(
-                    CallToken {
-                        direction: CallDirection::Outgoing,
-                        call_id: call_id.clone(),
-                    },
-                    3600,
-                );
+                let tracker = CallTracker::new(call_id.clone(), CallDirection::Outgoing, &req.from_number, &req.to_number, &app_id, self.replicator.clone());
                 self.out_calls
-                    .insert(call_id.clone(), OutgoingCall::new(call, self.destroy_tx.clone(), hook_sender, self.call_pubsub.clone()));
-                Ok(CreateCallResponse {
-                    call_ws: format!("/call/outgoing/{call_id}?token={call_token}"),
-                    call_id: call_id.clone().into(),
-                    call_token,
-                })
+                    .insert(call_id.clone(), OutgoingCall::new(call, self.destroy_tx.clone(), hook_sender, self.call_pubsub.clone(), tracker));
+                Ok(outgoing_call_response(&self.secure_ctx, call_id, &app_id))
             }
             Err(err) => Err(CallApiError::SipError(err.to_string())),
         }
     }
 
+    /// Check app and number limits for a new call, return retry after seconds if any of them is reached.
+    /// Numbers which are neither in the address book nor in the config limits are not limited.
+    fn check_limits(&mut self, app_id: &str, number: &str, direction: CallDirection) -> Result<(), u64> {
+        let outgoing = direction == CallDirection::Outgoing;
+        let mut rate_keys = vec![];
+        if let Some(limits) = self.limits.app(app_id, self.address_book.app_limits(app_id)) {
+            if !self.usage(|t| t.app_id() == app_id).allows(&limits, outgoing) {
+                return Err(CONCURRENCY_RETRY_AFTER_SECS);
+            }
+            if let Some(cps) = limits.calls_per_second {
+                rate_keys.push((format!("app:{app_id}"), cps));
+            }
+        }
+        if let Some(limits) = self.limits.number(number, self.address_book.number_limits(number)) {
+            if !self.usage(|t| t.number() == number).allows(&limits, outgoing) {
+                return Err(CONCURRENCY_RETRY_AFTER_SECS);
+            }
+            if let Some(cps) = limits.calls_per_second {
+                rate_keys.push((format!("number:{number}"), cps));
+            }
+        }
+        self.rate_limiter.try_acquire(&rate_keys, Instant::now())
+    }
+
+    fn usage<F: Fn(&CallTracker) -> bool>(&self, filter: F) -> CallUsage {
+        let mut usage = CallUsage::default();
+        let trackers = self.out_calls.values().map(|c| c.tracker()).chain(self.in_calls.values().map(|c| c.tracker()));
+        for tracker in trackers {
+            if !filter(tracker) {
+                continue;
+            }
+            usage.concurrent += 1;
+            if tracker.direction() == CallDirection::Outgoing {
+                usage.concurrent_outgoing += 1;
+            }
+        }
+        usage
+    }
+
+    pub fn calls_count(&self) -> usize {
+        self.out_calls.len() + self.in_calls.len()
+    }
+
+    /// Request all running calls to end, each call sends BYE or rejects the INVITE by itself then is destroyed
+    pub fn end_all_calls(&self) {
+        let timeout = Duration::from_secs(END_CALL_TIMEOUT_SECONDS);
+        for call_id in self.out_calls.keys() {
+            let channel = call_id.to_pubsub_channel();
+            let call_pubsub = self.call_pubsub.clone();
+            tokio::spawn(async move {
+                let req = outgoing_call_request::Action::End(Default::default());
+                call_pubsub
+                    .feedback_rpc_as_guest_ob::<_, outgoing_call_response::Response>(channel, "destroy", &req, timeout)
+                    .await
+                    .print_error("[CallManager] end outgoing call");
+            });
+        }
+        for call_id in self.in_calls.keys() {
+            let channel = call_id.to_pubsub_channel();
+            let call_pubsub = self.call_pubsub.clone();
+            tokio::spawn(async move {
+                let req = incoming_call_request::Action::End(Default::default());
+                call_pubsub
+                    .feedback_rpc_as_guest_ob::<_, incoming_call_response::Response>(channel, "destroy", &req, timeout)
+                    .await
+                    .print_error("[CallManager] end incoming call");
+            });
+        }
+    }
+
+    /// Clean up calls of a failed node from their replicas: send BYE to the remote side,
+    /// delete the rtpengine session and send Ended with node_failure reason to the hook
+    pub fn takeover_calls(&self, replicas: Vec<CallReplica>) {
+        for replica in replicas {
+            log::warn!("[CallManager] take over {:?} call {} from failed node", replica.direction, replica.call_id);
+            if let Some(url) = replica.sip.media_url.clone() {
+                match self.media_backends.get(replica.sip.media_backend.as_deref()) {
+                    Ok(backend) => {
+                        tokio::spawn(async move {
+                            if let Err(e) = backend.destroy(&url).await {
+                                log::warn!("[CallManager] delete media session {url} error {e:?}");
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("[CallManager] cannot delete media session {url}: {e}"),
+                }
+            }
+            self.sip.terminate_snapshot(replica.sip);
+            let hook = self.http_hook.new_sender(&replica.hook, replica.hook_headers);
+            hook.send(&build_node_failure_event(replica.direction));
+        }
+    }
+
+    /// Info of all calls which are running in this node
+    pub fn calls_info(&self, node: &str) -> Vec<AdminCallInfo> {
+        let outgoing = self.out_calls.values().map(|c| c.tracker().info(node));
+        let incoming = self.in_calls.values().map(|c| c.tracker().info(node));
+        outgoing.chain(incoming).collect()
+    }
+
     pub async fn recv(&mut self) -> Option<CallManagerOut> {
         let out = select2::or(self.destroy_rx.recv(), self.sip.recv()).await;
         match out {
@@ -87,22 +303,63 @@ impl CallManager {
                 if self.out_calls.remove(&call_id).is_none() && self.in_calls.remove(&call_id).is_none() {
                     log::warn!("[CallManager] got Destroyed event for {call_id} but not found");
                 }
+                if let Some(replicator) = &self.replicator {
+                    replicator.remove(call_id);
+                }
                 Some(CallManagerOut::Continue)
             }
             select2::OrOutput::Right(event) => match event? {
                 crate::sip::SipServerOut::Incoming(call) => {
+                    if self.drain.is_draining() {
+                        log::warn!("[CallManager] rejected call from {} to {} because node is draining", call.from(), call.to());
+                        call.kill_because_overloaded(DRAIN_RETRY_AFTER_SECS);
+                        return Some(CallManagerOut::Continue);
+                    }
                     if let Some((app, number)) = self.address_book.validate_phone(call.remote(), call.from(), call.to()) {
-                        let hook_sender = self.http_hook.new_sender(&number.hook, HashMap::new());
+                        let api = match self.media_backends.api(&app.app_id, None) {
+                            Ok(api) => api,
+                            Err(e) => {
+                                log::warn!(
+                                    "[CallManager] rejected call from {} to {} because app {} media is misconfigured: {e}",
+                                    call.from(),
+                                    call.to(),
+                                    app.app_id
+                                );
+                                call.kill_because_validate_failed();
+                                return Some(CallManagerOut::Continue);
+                            }
+                        };
+                        if let Err(e) = call.apply_codec_policy(&CodecPolicy::new(number.codecs.clone())) {
+                            log::warn!("[CallManager] rejected call from {} to {} because of media policy: {e}", call.from(), call.to());
+                            let hook_sender = self.http_hook.new_sender(&number.hook, sip_hook_headers(call.headers()));
+                            hook_sender.send(&build_call_notify_rejected(&call.call_id(), call.from(), call.to(), MEDIA_REJECTED_CODE, e.to_string()));
+                            call.kill_because_media_rejected();
+                            return Some(CallManagerOut::Continue);
+                        }
+                        if let Err(retry_after) = self.check_limits(&app.app_id, &number.number, CallDirection::Incoming) {
+                            log::warn!(
+                                "[CallManager] rejected call from {} to {} because of app {} limits, retry after {retry_after}s",
+                                call.from(),
+                                call.to(),
+                                app.app_id
+                            );
+                            call.kill_because_overloaded(retry_after);
+                            return Some(CallManagerOut::Continue);
+                        }
+                        // forwarded sip headers are also attached to hook requests as prefixed http headers
+                        let hook_sender = self.http_hook.new_sender(&number.hook, sip_hook_headers(call.headers()));
                         let call_id = call.call_id();
                         let call_token = self.secure_ctx.encode_call_token(
                             CallToken {
-                                direction: CallDirection::Outgoing,
+                                direction: CallDirection::Incoming,
                                 call_id: call_id.clone(),
+                                app_id: app.app_id.clone(),
+                                scope: CallTokenScope::Control,
                             },
-                            3600,
+                            self.secure_ctx.call_token_ttl(&app.app_id),
                         );
-                        let api: MediaApi = MediaApi::new(&self.media_gateway, &app.app_secret);
-                        let call = IncomingCall::new(api, call, call_token, self.destroy_tx.clone(), hook_sender, self.call_pubsub.clone());
+                        let tracker = CallTracker::new(call_id.clone(), CallDirection::Incoming, call.from(), call.to(), &app.app_id, self.replicator.clone());
+                        let call = IncomingCall::new(api, call, call_token, self.destroy_tx.clone(), hook_sender, self.call_pubsub.clone(), tracker);
                         self.in_calls.insert(call_id, call);
                         Some(CallManagerOut::IncomingCall())
                     } else {
@@ -115,3 +372,41 @@ impl CallManager {
         }
     }
 }
+
+fn build_node_failure_event(direction: CallDirection) -> CallEvent {
+    let reason = NODE_FAILURE_REASON.to_owned();
+    let event = match direction {
+        CallDirection::Incoming => call_event::Event::Incoming(IncomingCallEvent {
+            event: Some(incoming_call_event::Event::Ended(incoming_call_event::Ended { reason, media_stats: None })),
+        }),
+        CallDirection::Outgoing => call_event::Event::Outgoing(OutgoingCallEvent {
+            event: Some(outgoing_call_event::Event::Ended(outgoing_call_event::Ended { reason, media_stats: None })),
+        }),
+    };
+    CallEvent { event: Some(event) }
+}
+
+/// Timer of media stats events, None when the media backend has no stats, like atm0s
+fn media_stats_timer(has_stats: bool) -> Option<Interval> {
+    has_stats.then(|| interval_at(tokio::time::Instant::now() + MEDIA_STATS_INTERVAL, MEDIA_STATS_INTERVAL))
+}
+
+/// Wait for the next media stats tick, calls without a stats timer never tick
+async fn media_stats_tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Recording state after the media server applied a recording action
+fn build_recording_state(action: RecordingAction, recording_id: String) -> RecordingState {
+    let state = match action {
+        RecordingAction::Start => recording_state::State::Recording,
+        RecordingAction::Pause => recording_state::State::Paused,
+        RecordingAction::Stop => recording_state::State::Stopped,
+    };
+    RecordingState { state: state as i32, recording_id }
+}
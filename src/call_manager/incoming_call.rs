@@ -12,34 +12,54 @@ use crate::{
         protobuf::sip_gateway::{
             call_event,
             incoming_call_data::{incoming_call_event, incoming_call_request, incoming_call_response, IncomingCallEvent},
-            incoming_call_notify::{self, CallArrived, CallCancelled},
+            incoming_call_notify::{self, CallArrived, CallCancelled, CallRejected},
             CallEvent, IncomingCallNotify,
         },
-        IncomingCallAction, IncomingCallActionRequest, InternalCallId, StreamingInfo,
+        AdminCallState, IncomingCallAction, IncomingCallActionRequest, InternalCallId, StreamingInfo,
     },
-    sip::{MediaApi, SipIncomingCall, SipIncomingCallOut},
-    utils::select2,
+    sip::{MediaApi, MediaRecorder, RecordingAction, SipIncomingCall, SipIncomingCallError, SipIncomingCallOut},
+    utils::select3,
 };
-pub struct IncomingCall {}
+
+use super::{build_recording_state, media_stats_tick, media_stats_timer, tracker::CallTracker};
+
+pub struct IncomingCall {
+    tracker: CallTracker,
+}
 
 impl IncomingCall {
-    pub fn new(api: MediaApi, sip: SipIncomingCall, call_token: String, destroy_tx: UnboundedSender<InternalCallId>, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester) -> Self {
+    pub fn new(
+        api: MediaApi,
+        sip: SipIncomingCall,
+        call_token: String,
+        destroy_tx: UnboundedSender<InternalCallId>,
+        hook: HttpHookSender<CallEvent>,
+        call_pubsub: PubsubServiceRequester,
+        tracker: CallTracker,
+    ) -> Self {
+        let task_tracker = tracker.clone();
         tokio::spawn(async move {
             let call_id = sip.call_id();
-            if let Err(e) = run_call_loop(api, sip, call_token, hook, call_pubsub).await {
+            if let Err(e) = run_call_loop(api, sip, call_token, hook, call_pubsub, task_tracker).await {
                 log::error!("[IncomingCall] call {call_id} error {e:?}");
             }
             destroy_tx.send(call_id).expect("should send destroy request to main loop");
         });
 
-        Self {}
+        Self { tracker }
+    }
+
+    pub fn tracker(&self) -> &CallTracker {
+        &self.tracker
     }
 }
 
-async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: String, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester) -> anyhow::Result<()> {
+async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: String, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester, tracker: CallTracker) -> anyhow::Result<()> {
     let call_id = call.call_id();
     let from = call.from().to_owned();
     let to = call.to().to_owned();
+    let caller = call.caller().clone();
+    let headers = call.headers().iter().cloned().collect();
 
     let channel_id = call_id.to_pubsub_channel();
     let mut subscribers = HashSet::new();
@@ -59,6 +79,13 @@ async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: Str
                 call_ws,
                 call_from: from.clone(),
                 call_to: to.clone(),
+                call_from_display: caller.from_display,
+                call_to_display: caller.to_display,
+                asserted_identity: caller.asserted_identity,
+                remote_party_id: caller.remote_party_id,
+                diversions: caller.diversions,
+                privacy: caller.privacy,
+                headers,
             }),
         ))
         .await
@@ -72,38 +99,54 @@ async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: Str
 
     log::info!("[IncomingCall] call {call_id} got hook action {:?}", action);
 
+    let action_headers = action.headers.unwrap_or_default().into_iter().collect();
     match action.action {
-        IncomingCallAction::Ring => call.send_ringing().await?,
+        IncomingCallAction::Ring => {
+            call.send_ringing().await?;
+            tracker.set_state(AdminCallState::Ringing);
+        }
         IncomingCallAction::Accept => {
             let stream = action.stream.ok_or(anyhow!("missing stream in accept action"))?;
-            call.accept(api.clone(), stream).await?;
+            call.accept(api.clone(), stream, action_headers).await?;
+            tracker.set_state(AdminCallState::Talking);
+            if let Some(snapshot) = call.snapshot() {
+                tracker.replicate(snapshot, &hook);
+            }
         }
         IncomingCallAction::End => {
-            call.end().await.print_error("[IncomingCall] end call from hook response");
+            call.end(action_headers).await.print_error("[IncomingCall] end call from hook response");
             return Ok(());
         }
     };
 
     log::info!("[IncomingCall] call {call_id} started loop");
 
+    let mut stats_timer = media_stats_timer(api.has_stats());
     loop {
-        let out = select2::or(call.recv(), publisher.recv_ob::<incoming_call_request::Action>()).await;
+        let out = select3::or(call.recv(), publisher.recv_ob::<incoming_call_request::Action>(), media_stats_tick(&mut stats_timer)).await;
         match out {
-            select2::OrOutput::Left(Ok(Some(out))) => match out {
+            select3::OrOutput::Left(Ok(Some(out))) => match out {
                 SipIncomingCallOut::Event(event) => {
                     if is_sip_incoming_cancelled(&event.event).is_some() {
                         hook.send(&build_call_notify_cancel(&call_id, &from, &to));
                     }
+                    tracker.on_incoming_event(&event);
                     publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
                     hook.send(&build_call_event(event));
                 }
+                SipIncomingCallOut::DialogUpdated => {
+                    // CSeq and remote target of the BYE sent by a takeover node must follow the dialog
+                    if let Some(snapshot) = call.snapshot() {
+                        tracker.replicate(snapshot, &hook);
+                    }
+                }
                 SipIncomingCallOut::Continue => {}
             },
-            select2::OrOutput::Left(Ok(None)) => {
+            select3::OrOutput::Left(Ok(None)) => {
                 log::info!("[IncomingCall] call {call_id} end");
                 break;
             }
-            select2::OrOutput::Left(Err(e)) => {
+            select3::OrOutput::Left(Err(e)) => {
                 log::error!("[IncomingCall] call {call_id} error {e:?}");
                 let event = IncomingCallEvent {
                     event: Some(incoming_call_event::Event::Err(incoming_call_event::Error { message: e.to_string() })),
@@ -112,14 +155,17 @@ async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: Str
                 hook.send(&build_call_event(event));
                 break;
             }
-            select2::OrOutput::Right(Ok(control)) => match control {
+            select3::OrOutput::Middle(Ok(control)) => match control {
                 PublisherEventOb::PeerJoined(peer_src) => {
                     subscribers.insert(peer_src);
+                    tracker.set_subscribers(subscribers.len());
                 }
                 PublisherEventOb::PeerLeaved(peer_src) => {
-                    if subscribers.remove(&peer_src) && subscribers.is_empty() {
+                    let removed = subscribers.remove(&peer_src);
+                    tracker.set_subscribers(subscribers.len());
+                    if removed && subscribers.is_empty() {
                         log::info!("[IncomingCall] call {call_id} all subs disconnected => end call");
-                        if let Err(e) = call.end().await {
+                        if let Err(e) = call.end(vec![]).await {
                             log::error!("[IncomingCall] call {call_id} end error {e:?}");
                         }
                         break;
@@ -127,6 +173,22 @@ async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: Str
                 }
                 PublisherEventOb::FeedbackRpc(action, rpc_id, method, peer_src) | PublisherEventOb::GuestFeedbackRpc(action, rpc_id, method, peer_src) => {
                     log::info!("[IncomingCall] on rpc {method} from {peer_src:?} with payload: {action:?}");
+                    if let Some(recording) = recording_action(&action) {
+                        // media server requests can be slow, they run beside the call loop so SIP events are not delayed
+                        let recorder = call.recorder();
+                        let requester = publisher.requester().clone();
+                        let hook = hook.clone();
+                        let call_id = call_id.clone();
+                        tokio::spawn(async move {
+                            let (res, event) = control_recording(&call_id, recorder, recording).await;
+                            requester.answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[IncomingCall] answer rpc");
+                            if let Some(event) = event {
+                                requester.publish_ob(&event).await.print_error("[IncomingCall] publish event");
+                                hook.send(&build_call_event(event));
+                            }
+                        });
+                        continue;
+                    }
                     let res = match action {
                         incoming_call_request::Action::Ring(_ring) => {
                             if let Err(e) = call.send_trying().await {
@@ -142,10 +204,14 @@ async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: Str
                                 peer: accept.peer,
                                 record: accept.record,
                             };
-                            if let Err(e) = call.accept(api.clone(), stream).await {
+                            if let Err(e) = call.accept(api.clone(), stream, accept.headers.into_iter().collect()).await {
                                 log::error!("[IncomingCall] call {call_id} accept error {e:?}");
                                 incoming_call_response::Response::Error(incoming_call_response::Error { message: e.to_string() })
                             } else {
+                                tracker.set_state(AdminCallState::Talking);
+                                if let Some(snapshot) = call.snapshot() {
+                                    tracker.replicate(snapshot, &hook);
+                                }
                                 hook.send(&build_call_notify_accept(&call_id, &from, &to));
                                 incoming_call_response::Response::Accept(Default::default())
                             }
@@ -159,15 +225,18 @@ async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: Str
                                 Err(e) => incoming_call_response::Response::Error(incoming_call_response::Error { message: e.to_string() }),
                             }
                         }
-                        incoming_call_request::Action::End(_end) => {
+                        incoming_call_request::Action::End(end) => {
                             log::info!("[IncomingCall] call {call_id} received end request");
-                            if let Err(e) = call.end().await {
+                            if let Err(e) = call.end(end.headers.into_iter().collect()).await {
                                 log::error!("[IncomingCall] call {call_id} end error {e:?}");
                                 incoming_call_response::Response::Error(incoming_call_response::Error { message: e.to_string() })
                             } else {
                                 incoming_call_response::Response::End(Default::default())
                             }
                         }
+                        incoming_call_request::Action::StartRecording(_) | incoming_call_request::Action::StopRecording(_) | incoming_call_request::Action::PauseRecording(_) => {
+                            unreachable!("recording actions are handled above")
+                        }
                     };
                     publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[IncomingCall] answer rpc");
                 }
@@ -175,21 +244,62 @@ async fn run_call_loop(api: MediaApi, mut call: SipIncomingCall, call_token: Str
                     log::warn!("IncomingCall] invalid pubsub event {control:?}");
                 }
             },
-            select2::OrOutput::Right(Err(_e)) => {
+            select3::OrOutput::Middle(Err(_e)) => {
                 break;
             }
+            select3::OrOutput::Right(_) => {
+                if let Some(stats) = call.media_stats().await {
+                    let event = IncomingCallEvent {
+                        event: Some(incoming_call_event::Event::MediaStats(stats)),
+                    };
+                    publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
+                    hook.send(&build_call_event(event));
+                }
+            }
         }
     }
 
     log::info!("[IncomingCall] call {call_id} destroyed");
     let event = IncomingCallEvent {
-        event: Some(incoming_call_event::Event::Ended(Default::default())),
+        event: Some(incoming_call_event::Event::Ended(incoming_call_event::Ended {
+            reason: String::new(),
+            media_stats: call.media_stats().await,
+        })),
     };
     publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
     hook.send(&build_call_event(event));
     Ok(())
 }
 
+fn recording_action(action: &incoming_call_request::Action) -> Option<RecordingAction> {
+    match action {
+        incoming_call_request::Action::StartRecording(_) => Some(RecordingAction::Start),
+        incoming_call_request::Action::StopRecording(_) => Some(RecordingAction::Stop),
+        incoming_call_request::Action::PauseRecording(_) => Some(RecordingAction::Pause),
+        _ => None,
+    }
+}
+
+/// Apply a recording action to the call media, the new recording state event is returned on success
+async fn control_recording(call_id: &InternalCallId, recorder: Result<MediaRecorder, SipIncomingCallError>, action: RecordingAction) -> (incoming_call_response::Response, Option<IncomingCallEvent>) {
+    let res = match recorder {
+        Ok(recorder) => recorder.apply(action).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match res {
+        Ok(recording_id) => {
+            let event = IncomingCallEvent {
+                event: Some(incoming_call_event::Event::Recording(build_recording_state(action, recording_id.clone()))),
+            };
+            (incoming_call_response::Response::Recording(incoming_call_response::Recording { recording_id }), Some(event))
+        }
+        Err(message) => {
+            log::error!("[IncomingCall] call {call_id} recording {} error {message}", action.as_str());
+            (incoming_call_response::Response::Error(incoming_call_response::Error { message }), None)
+        }
+    }
+}
+
 fn build_call_notify_cancel(call_id: &InternalCallId, from: &str, to: &str) -> CallEvent {
     build_call_notify(
         call_id,
@@ -210,6 +320,19 @@ fn build_call_notify_accept(call_id: &InternalCallId, from: &str, to: &str) -> C
     )
 }
 
+/// Notify for calls which are rejected before a hook request, like 488 by the number codec policy
+pub fn build_call_notify_rejected(call_id: &InternalCallId, from: &str, to: &str, code: u32, reason: String) -> CallEvent {
+    build_call_notify(
+        call_id,
+        incoming_call_notify::Event::Rejected(CallRejected {
+            call_from: from.to_owned(),
+            call_to: to.to_owned(),
+            code,
+            reason,
+        }),
+    )
+}
+
 fn build_call_notify(call_id: &InternalCallId, event: incoming_call_notify::Event) -> CallEvent {
     CallEvent {
         event: Some(call_event::Event::Notify(IncomingCallNotify {
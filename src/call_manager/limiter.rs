@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::protocol::CallLimits;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Current usage of a limited key, counted from running calls
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CallUsage {
+    pub concurrent: u32,
+    pub concurrent_outgoing: u32,
+}
+
+impl CallUsage {
+    /// Check if one more call is allowed by the concurrency limits
+    pub fn allows(&self, limits: &CallLimits, outgoing: bool) -> bool {
+        if limits.max_concurrent_calls.is_some_and(|max| self.concurrent >= max) {
+            return false;
+        }
+        !(outgoing && limits.max_concurrent_outgoing_calls.is_some_and(|max| self.concurrent_outgoing >= max))
+    }
+}
+
+struct RateWindow {
+    started_at: Instant,
+    count: u32,
+}
+
+/// Fixed window calls per second counter, keys are like `app:{app_id}` or `number:{number}`
+#[derive(Default)]
+pub struct CallRateLimiter {
+    windows: HashMap<String, RateWindow>,
+}
+
+impl CallRateLimiter {
+    /// Check if one more call is allowed for all keys, return retry after seconds if not.
+    /// The call is only counted when all keys allow it.
+    pub fn try_acquire(&mut self, keys: &[(String, u32)], now: Instant) -> Result<(), u64> {
+        self.windows.retain(|_, w| now.duration_since(w.started_at) < RATE_WINDOW);
+        for (key, calls_per_second) in keys {
+            if self.windows.get(key).is_some_and(|w| w.count >= *calls_per_second) {
+                return Err(RATE_WINDOW.as_secs());
+            }
+        }
+        for (key, _) in keys {
+            self.windows.entry(key.clone()).or_insert(RateWindow { started_at: now, count: 0 }).count += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::protocol::CallLimits;
+
+    use super::{CallRateLimiter, CallUsage};
+
+    #[test]
+    fn test_rate_window() {
+        let mut limiter = CallRateLimiter::default();
+        let now = Instant::now();
+        let keys = vec![("app:app1".to_owned(), 3), ("number:1000".to_owned(), 1)];
+        assert_eq!(limiter.try_acquire(&keys[..1], now), Ok(()));
+        assert_eq!(limiter.try_acquire(&keys, now), Ok(()));
+        assert_eq!(limiter.try_acquire(&keys, now), Err(1));
+        // rejected call is not counted to app key
+        assert_eq!(limiter.try_acquire(&keys[..1], now), Ok(()));
+        assert_eq!(limiter.try_acquire(&keys[..1], now), Err(1));
+        assert_eq!(limiter.try_acquire(&keys, now + Duration::from_millis(1000)), Ok(()));
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let limits = CallLimits {
+            calls_per_second: None,
+            max_concurrent_calls: Some(2),
+            max_concurrent_outgoing_calls: Some(1),
+        };
+        let usage = CallUsage {
+            concurrent: 1,
+            concurrent_outgoing: 1,
+        };
+        assert!(usage.allows(&limits, false));
+        assert!(!usage.allows(&limits, true));
+        let usage = CallUsage {
+            concurrent: 2,
+            concurrent_outgoing: 0,
+        };
+        assert!(!usage.allows(&limits, false));
+        assert!(usage.allows(&CallLimits::default(), true));
+    }
+}
@@ -14,21 +14,30 @@ use crate::{
         },
         InternalCallId,
     },
-    sip::{SipOutgoingCall, SipOutgoingCallOut},
-    utils::select2,
+    sip::{MediaRecorder, RecordingAction, SipOutgoingCall, SipOutgoingCallError, SipOutgoingCallOut},
+    utils::select3,
 };
 
-pub struct OutgoingCall {}
+use super::{build_recording_state, media_stats_tick, media_stats_timer, tracker::CallTracker};
+
+pub struct OutgoingCall {
+    tracker: CallTracker,
+}
 
 impl OutgoingCall {
-    pub fn new(sip: SipOutgoingCall, destroy_tx: UnboundedSender<InternalCallId>, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester) -> Self {
-        tokio::spawn(async move { run_call_loop(sip, destroy_tx, hook, call_pubsub).await });
+    pub fn new(sip: SipOutgoingCall, destroy_tx: UnboundedSender<InternalCallId>, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester, tracker: CallTracker) -> Self {
+        let task_tracker = tracker.clone();
+        tokio::spawn(async move { run_call_loop(sip, destroy_tx, hook, call_pubsub, task_tracker).await });
+
+        Self { tracker }
+    }
 
-        Self {}
+    pub fn tracker(&self) -> &CallTracker {
+        &self.tracker
     }
 }
 
-async fn run_call_loop(mut call: SipOutgoingCall, destroy_tx: UnboundedSender<InternalCallId>, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester) {
+async fn run_call_loop(mut call: SipOutgoingCall, destroy_tx: UnboundedSender<InternalCallId>, hook: HttpHookSender<CallEvent>, call_pubsub: PubsubServiceRequester, tracker: CallTracker) {
     let call_id = call.call_id();
     let channel_id = call_id.to_pubsub_channel();
     let mut subscribers = HashSet::new();
@@ -38,27 +47,46 @@ async fn run_call_loop(mut call: SipOutgoingCall, destroy_tx: UnboundedSender<In
 
     if let Err(e) = call.start().await {
         log::error!("[OutgoingCall] call start error {e:?}");
+        // like no common codec with the number policy, the INVITE is not sent
+        let event = OutgoingCallEvent {
+            event: Some(outgoing_call_event::Event::Err(outgoing_call_event::Error { message: e.to_string() })),
+        };
+        publisher.requester().publish_ob(&event).await.print_error("[OutgoingCall] send event");
+        hook.send(&build_call_event(event));
         destroy_tx.send(call_id).expect("should send destroy request to main loop");
         return;
     }
 
     log::info!("[OutgoingCall] call started");
 
+    let mut stats_timer = media_stats_timer(call.has_media_stats());
     loop {
-        let out = select2::or(call.recv(), publisher.recv_ob::<outgoing_call_request::Action>()).await;
+        let out = select3::or(call.recv(), publisher.recv_ob::<outgoing_call_request::Action>(), media_stats_tick(&mut stats_timer)).await;
         match out {
-            select2::OrOutput::Left(Ok(Some(out))) => match out {
+            select3::OrOutput::Left(Ok(Some(out))) => match out {
                 SipOutgoingCallOut::Event(event) => {
+                    tracker.on_outgoing_event(&event);
+                    if is_accepted(&event) {
+                        if let Some(snapshot) = call.snapshot() {
+                            tracker.replicate(snapshot, &hook);
+                        }
+                    }
                     publisher.requester().publish_ob(&event).await.print_error("[OutgoingCall] send event");
                     hook.send(&build_call_event(event));
                 }
+                SipOutgoingCallOut::DialogUpdated => {
+                    // CSeq and remote target of the BYE sent by a takeover node must follow the dialog
+                    if let Some(snapshot) = call.snapshot() {
+                        tracker.replicate(snapshot, &hook);
+                    }
+                }
                 SipOutgoingCallOut::Continue => {}
             },
-            select2::OrOutput::Left(Ok(None)) => {
+            select3::OrOutput::Left(Ok(None)) => {
                 log::info!("[OutgoingCall] call end");
                 break;
             }
-            select2::OrOutput::Left(Err(e)) => {
+            select3::OrOutput::Left(Err(e)) => {
                 log::error!("[OutgoingCall] call error {e:?}");
                 let event = OutgoingCallEvent {
                     event: Some(outgoing_call_event::Event::Err(outgoing_call_event::Error { message: e.to_string() })),
@@ -67,47 +95,108 @@ async fn run_call_loop(mut call: SipOutgoingCall, destroy_tx: UnboundedSender<In
                 hook.send(&build_call_event(event));
                 break;
             }
-            select2::OrOutput::Right(Ok(control)) => match control {
+            select3::OrOutput::Middle(Ok(control)) => match control {
                 PublisherEventOb::PeerJoined(peer_src) => {
                     subscribers.insert(peer_src);
+                    tracker.set_subscribers(subscribers.len());
                 }
                 PublisherEventOb::PeerLeaved(peer_src) => {
-                    if subscribers.remove(&peer_src) && subscribers.is_empty() {
+                    let removed = subscribers.remove(&peer_src);
+                    tracker.set_subscribers(subscribers.len());
+                    if removed && subscribers.is_empty() {
                         log::info!("[OutgoingCall] all sub disconnected => end call");
                         if let Err(e) = call.end().await {
                             log::error!("[OutgoingCall] end call error {e:?}");
                         }
                     }
                 }
-                PublisherEventOb::FeedbackRpc(action, rpc_id, _method, peer_src) | PublisherEventOb::GuestFeedbackRpc(action, rpc_id, _method, peer_src) => match action {
-                    outgoing_call_request::Action::End(_end) => {
-                        log::info!("[OutgoingCall] call {call_id} received end request");
-                        let res = if let Err(e) = call.end().await {
-                            log::error!("[OutgoingCall] call {call_id} end error {e:?}");
-                            outgoing_call_response::Response::Error(outgoing_call_response::Error { message: e.to_string() })
-                        } else {
-                            outgoing_call_response::Response::End(Default::default())
-                        };
-                        publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[IncomingCall] answer rpc");
-                    }
-                },
+                PublisherEventOb::FeedbackRpc(action, rpc_id, _method, peer_src) | PublisherEventOb::GuestFeedbackRpc(action, rpc_id, _method, peer_src) => {
+                    let recording = match action {
+                        outgoing_call_request::Action::End(_end) => {
+                            log::info!("[OutgoingCall] call {call_id} received end request");
+                            let res = if let Err(e) = call.end().await {
+                                log::error!("[OutgoingCall] call {call_id} end error {e:?}");
+                                outgoing_call_response::Response::Error(outgoing_call_response::Error { message: e.to_string() })
+                            } else {
+                                outgoing_call_response::Response::End(Default::default())
+                            };
+                            publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[OutgoingCall] answer rpc");
+                            continue;
+                        }
+                        outgoing_call_request::Action::StartRecording(_) => RecordingAction::Start,
+                        outgoing_call_request::Action::StopRecording(_) => RecordingAction::Stop,
+                        outgoing_call_request::Action::PauseRecording(_) => RecordingAction::Pause,
+                    };
+                    // media server requests can be slow, they run beside the call loop so SIP events are not delayed
+                    let recorder = call.recorder();
+                    let requester = publisher.requester().clone();
+                    let hook = hook.clone();
+                    let call_id = call_id.clone();
+                    tokio::spawn(async move {
+                        let (res, event) = control_recording(&call_id, recorder, recording).await;
+                        requester.answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[OutgoingCall] answer rpc");
+                        if let Some(event) = event {
+                            requester.publish_ob(&event).await.print_error("[OutgoingCall] send event");
+                            hook.send(&build_call_event(event));
+                        }
+                    });
+                }
                 _ => {}
             },
-            select2::OrOutput::Right(Err(_e)) => {
+            select3::OrOutput::Middle(Err(_e)) => {
                 break;
             }
+            select3::OrOutput::Right(_) => {
+                if let Some(stats) = call.media_stats().await {
+                    let event = OutgoingCallEvent {
+                        event: Some(outgoing_call_event::Event::MediaStats(stats)),
+                    };
+                    publisher.requester().publish_ob(&event).await.print_error("[OutgoingCall] send event");
+                    hook.send(&build_call_event(event));
+                }
+            }
         }
     }
 
     log::info!("[OutgoingCall] call destroyed");
     let event = OutgoingCallEvent {
-        event: Some(outgoing_call_event::Event::Ended(Default::default())),
+        event: Some(outgoing_call_event::Event::Ended(outgoing_call_event::Ended {
+            reason: String::new(),
+            media_stats: call.media_stats().await,
+        })),
     };
     publisher.requester().publish_ob(&event).await.print_error("[IncomingCall] publish event");
     hook.send(&build_call_event(event));
     destroy_tx.send(call_id).expect("should send destroy request to main loop");
 }
 
+/// Apply a recording action to the call media, the new recording state event is returned on success
+async fn control_recording(call_id: &InternalCallId, recorder: Result<MediaRecorder, SipOutgoingCallError>, action: RecordingAction) -> (outgoing_call_response::Response, Option<OutgoingCallEvent>) {
+    let res = match recorder {
+        Ok(recorder) => recorder.apply(action).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match res {
+        Ok(recording_id) => {
+            let event = OutgoingCallEvent {
+                event: Some(outgoing_call_event::Event::Recording(build_recording_state(action, recording_id.clone()))),
+            };
+            (outgoing_call_response::Response::Recording(outgoing_call_response::Recording { recording_id }), Some(event))
+        }
+        Err(message) => {
+            log::error!("[OutgoingCall] call {call_id} recording {} error {message}", action.as_str());
+            (outgoing_call_response::Response::Error(outgoing_call_response::Error { message }), None)
+        }
+    }
+}
+
+fn is_accepted(event: &OutgoingCallEvent) -> bool {
+    match event.event.as_ref() {
+        Some(outgoing_call_event::Event::Sip(sip)) => matches!(sip.event, Some(outgoing_call_event::sip_event::Event::Accepted(_))),
+        _ => false,
+    }
+}
+
 fn build_call_event(event: OutgoingCallEvent) -> CallEvent {
     CallEvent {
         event: Some(call_event::Event::Outgoing(event)),
@@ -0,0 +1,130 @@
+use std::{sync::Arc, time::Instant};
+
+use spin::RwLock;
+
+use crate::{
+    cluster::{CallReplica, CallReplicator},
+    hook::HttpHookSender,
+    protocol::{
+        protobuf::sip_gateway::{
+            incoming_call_data::{incoming_call_event, IncomingCallEvent},
+            outgoing_call_data::{outgoing_call_event, OutgoingCallEvent},
+            CallEvent,
+        },
+        AdminCallInfo, AdminCallState, CallDirection, InternalCallId,
+    },
+    sip::SipCallSnapshot,
+};
+
+struct CallTrackerInternal {
+    state: AdminCallState,
+    subscribers: usize,
+}
+
+/// Info of a running call, state and subscribers are updated by the call task and read by admin apis
+#[derive(Clone)]
+pub struct CallTracker {
+    call_id: InternalCallId,
+    direction: CallDirection,
+    from: String,
+    to: String,
+    app_id: String,
+    started_at: Instant,
+    replicator: Option<CallReplicator>,
+    internal: Arc<RwLock<CallTrackerInternal>>,
+}
+
+impl CallTracker {
+    pub fn new(call_id: InternalCallId, direction: CallDirection, from: &str, to: &str, app_id: &str, replicator: Option<CallReplicator>) -> Self {
+        Self {
+            call_id,
+            direction,
+            from: from.to_owned(),
+            to: to.to_owned(),
+            app_id: app_id.to_owned(),
+            started_at: Instant::now(),
+            replicator,
+            internal: Arc::new(RwLock::new(CallTrackerInternal {
+                state: AdminCallState::Trying,
+                subscribers: 0,
+            })),
+        }
+    }
+
+    pub fn app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    pub fn direction(&self) -> CallDirection {
+        self.direction
+    }
+
+    /// Number of the gateway side, which is the caller of outgoing calls and the callee of incoming calls
+    pub fn number(&self) -> &str {
+        match self.direction {
+            CallDirection::Outgoing => &self.from,
+            CallDirection::Incoming => &self.to,
+        }
+    }
+
+    pub fn set_state(&self, state: AdminCallState) {
+        self.internal.write().state = state;
+    }
+
+    pub fn set_subscribers(&self, subscribers: usize) {
+        self.internal.write().subscribers = subscribers;
+    }
+
+    /// Replicate the call to other nodes, so it can be cleaned up if this node fails.
+    /// The replica is removed by the call manager when the call is destroyed.
+    pub fn replicate(&self, sip: SipCallSnapshot, hook: &HttpHookSender<CallEvent>) {
+        if let Some(replicator) = &self.replicator {
+            replicator.upsert(CallReplica {
+                call_id: self.call_id.clone(),
+                direction: self.direction,
+                sip,
+                hook: hook.endpoint.clone(),
+                hook_headers: hook.headers.clone(),
+            });
+        }
+    }
+
+    pub fn on_incoming_event(&self, event: &IncomingCallEvent) {
+        let state = match event.event.as_ref() {
+            Some(incoming_call_event::Event::Accepted(_)) => AdminCallState::Talking,
+            Some(incoming_call_event::Event::Sip(_)) | Some(incoming_call_event::Event::Ended(_)) | Some(incoming_call_event::Event::Err(_)) => AdminCallState::Ending,
+            Some(incoming_call_event::Event::MediaStats(_)) | Some(incoming_call_event::Event::Recording(_)) | None => return,
+        };
+        self.set_state(state);
+    }
+
+    pub fn on_outgoing_event(&self, event: &OutgoingCallEvent) {
+        let state = match event.event.as_ref() {
+            Some(outgoing_call_event::Event::Sip(sip)) => match sip.event.as_ref() {
+                Some(outgoing_call_event::sip_event::Event::Provisional(_)) => AdminCallState::Ringing,
+                Some(outgoing_call_event::sip_event::Event::Early(_)) => AdminCallState::Early,
+                Some(outgoing_call_event::sip_event::Event::Accepted(_)) => AdminCallState::Talking,
+                Some(outgoing_call_event::sip_event::Event::Failure(_)) | Some(outgoing_call_event::sip_event::Event::Bye(_)) => AdminCallState::Ending,
+                None => return,
+            },
+            Some(outgoing_call_event::Event::Ended(_)) | Some(outgoing_call_event::Event::Err(_)) => AdminCallState::Ending,
+            Some(outgoing_call_event::Event::MediaStats(_)) | Some(outgoing_call_event::Event::Recording(_)) | None => return,
+        };
+        self.set_state(state);
+    }
+
+    pub fn info(&self, node: &str) -> AdminCallInfo {
+        let internal = self.internal.read();
+        AdminCallInfo {
+            call_id: self.call_id.to_string(),
+            node: node.to_owned(),
+            direction: self.direction,
+            state: internal.state,
+            from: self.from.clone(),
+            to: self.to.clone(),
+            app_id: self.app_id.clone(),
+            age_secs: self.started_at.elapsed().as_secs(),
+            subscribers: internal.subscribers as u32,
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
+
+use atm0s_small_p2p::{pubsub_service::PubsubChannelId, PeerId};
+use spin::RwLock;
+
+mod admin;
+mod placement;
+mod replica;
+mod revocation;
+mod tls;
+
+pub use admin::{AdminRpcClient, AdminRpcServer};
+pub use placement::{NodePlacement, PlacementConstraint, PlacementSync, PlacementTable};
+pub use replica::{CallReplica, CallReplicator, ReplicaSync, ReplicaTable, Takeover, TAKEOVER_GRACE, TAKEOVER_RETRY};
+pub use revocation::RevocationSync;
+pub use tls::{ClusterHandshake, ClusterIdentity, ClusterTlsConfig, ClusterTlsError};
+
+/// Nodes which are known by this node, updated from P2pNetwork events
+#[derive(Clone)]
+pub struct ClusterNodes {
+    local: PeerId,
+    peers: Arc<RwLock<HashSet<PeerId>>>,
+}
+
+impl ClusterNodes {
+    pub fn new(local: PeerId) -> Self {
+        Self { local, peers: Default::default() }
+    }
+
+    pub fn local(&self) -> PeerId {
+        self.local
+    }
+
+    pub fn on_connected(&self, peer: PeerId) {
+        self.peers.write().insert(peer);
+    }
+
+    pub fn on_disconnected(&self, peer: PeerId) {
+        self.peers.write().remove(&peer);
+    }
+
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.peers.read().iter().cloned().collect()
+    }
+
+    /// Local node first, then connected peers
+    pub fn all(&self) -> Vec<PeerId> {
+        let mut nodes = vec![self.local];
+        nodes.extend(self.peers());
+        nodes
+    }
+}
+
+/// Pubsub channel which is owned by a single node, used for node-to-node rpc
+pub fn node_channel(node: PeerId, service: &str) -> PubsubChannelId {
+    let mut hasher = DefaultHasher::default();
+    service.hash(&mut hasher);
+    node.to_string().hash(&mut hasher);
+    hasher.finish().into()
+}
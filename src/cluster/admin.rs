@@ -0,0 +1,289 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use atm0s_small_p2p::{
+    pubsub_service::{PublisherEventOb, PubsubServiceRequester},
+    PeerId,
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{
+    error::PrintErrorSimple,
+    http::HttpCommand,
+    protocol::{AdminCallInfo, AdminRelaySession, AdminRelayStream, AdminSipBan, AppId, CallApiError, CreateCallRequest, CreateCallResponse},
+    secure::SecureContext,
+    sip::{MediaBackends, RelaySessionInfo, RelayStreamStats, RtpRelay, SipFloodFilter, SipTraceEntry, SipTracer},
+};
+
+use super::{node_channel, ClusterNodes};
+
+const ADMIN_SERVICE: &str = "admin";
+const RPC_TIMEOUT_SECONDS: u64 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NodeRequest {
+    ListCalls,
+    ListBans,
+    Unban(IpAddr),
+    /// Outgoing call which is placed on the target node, with the app id of the original request.
+    /// The target node uses its own address book entry of the app, secrets are not sent
+    CreateCall(CreateCallRequest, AppId),
+    /// Revoke a call token by its id until the expire time
+    RevokeToken(String, u64),
+    /// Captured SIP messages of a call by its internal call id
+    SipTrace(String),
+    /// Sessions of the built-in RTP relay with packet counters
+    RelaySessions,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NodeResponse {
+    Calls(Vec<AdminCallInfo>),
+    Bans(Vec<AdminSipBan>),
+    Unbanned(bool),
+    Call(Result<CreateCallResponse, CallApiError>),
+    Revoked,
+    SipTrace(Option<Vec<SipTraceEntry>>),
+    RelaySessions(Vec<AdminRelaySession>),
+    Error(String),
+}
+
+/// Answer admin queries and forwarded calls from other nodes, each node owns a dedicated channel
+pub struct AdminRpcServer {
+    local: PeerId,
+    pubsub: PubsubServiceRequester,
+    tx: Sender<HttpCommand>,
+    sip_filter: SipFloodFilter,
+    secure_ctx: Arc<SecureContext>,
+    media_backends: MediaBackends,
+    media_relay: Option<RtpRelay>,
+    tracer: SipTracer,
+}
+
+impl AdminRpcServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        local: PeerId,
+        pubsub: PubsubServiceRequester,
+        tx: Sender<HttpCommand>,
+        sip_filter: SipFloodFilter,
+        secure_ctx: Arc<SecureContext>,
+        media_backends: MediaBackends,
+        media_relay: Option<RtpRelay>,
+        tracer: SipTracer,
+    ) -> Self {
+        Self {
+            local,
+            pubsub,
+            tx,
+            sip_filter,
+            secure_ctx,
+            media_backends,
+            media_relay,
+            tracer,
+        }
+    }
+
+    async fn create_call(&self, req: CreateCallRequest, app_id: AppId) -> Result<CreateCallResponse, CallApiError> {
+        let media_api = self
+            .media_backends
+            .app_api(&app_id)
+            .ok_or_else(|| CallApiError::BadRequest(format!("app {app_id} is unknown on node {}", self.local)))?
+            .map_err(|e| CallApiError::BadRequest(format!("{e} on node {}", self.local)))?;
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(HttpCommand::CreatePlacedCall(req, media_api, app_id, tx))
+            .await
+            .map_err(|e| CallApiError::InternalChannel(e.to_string()))?;
+        rx.await.map_err(|e| CallApiError::InternalChannel(e.to_string()))?
+    }
+
+    fn relay_session(&self, session: RelaySessionInfo) -> AdminRelaySession {
+        let stream = |stats: RelayStreamStats| AdminRelayStream {
+            rtp_packets: stats.rtp_packets,
+            rtp_bytes: stats.rtp_bytes,
+            rtcp_packets: stats.rtcp_packets,
+            dropped_packets: stats.dropped_packets,
+        };
+        AdminRelaySession {
+            node: self.local.to_string(),
+            session: session.session,
+            room: session.room,
+            peer: session.peer,
+            sip_local: session.sip_local.to_string(),
+            sip_remote: session.sip_remote.map(|a| a.to_string()),
+            peer_local: session.peer_local.to_string(),
+            peer_remote: session.peer_remote.map(|a| a.to_string()),
+            age_secs: session.age_secs,
+            to_peer: stream(session.to_peer),
+            to_sip: stream(session.to_sip),
+        }
+    }
+
+    async fn process(&self, req: NodeRequest) -> NodeResponse {
+        match req {
+            NodeRequest::ListCalls => {
+                let (tx, rx) = oneshot::channel();
+                if let Err(e) = self.tx.send(HttpCommand::ListCalls(tx)).await {
+                    return NodeResponse::Error(e.to_string());
+                }
+                match rx.await {
+                    Ok(calls) => NodeResponse::Calls(calls),
+                    Err(e) => NodeResponse::Error(e.to_string()),
+                }
+            }
+            NodeRequest::ListBans => NodeResponse::Bans(
+                self.sip_filter
+                    .bans()
+                    .into_iter()
+                    .map(|(ip, remain, reason)| AdminSipBan {
+                        node: self.local.to_string(),
+                        ip: ip.to_string(),
+                        expires_in_secs: remain.as_secs(),
+                        reason: reason.to_owned(),
+                    })
+                    .collect(),
+            ),
+            NodeRequest::Unban(ip) => NodeResponse::Unbanned(self.sip_filter.unban(ip)),
+            NodeRequest::CreateCall(req, app_id) => NodeResponse::Call(self.create_call(req, app_id).await),
+            NodeRequest::RevokeToken(token_id, expires_at) => {
+                self.secure_ctx.revoke_token(&token_id, expires_at);
+                NodeResponse::Revoked
+            }
+            NodeRequest::SipTrace(call_id) => NodeResponse::SipTrace(self.tracer.get(&call_id.into())),
+            NodeRequest::RelaySessions => {
+                let sessions = self.media_relay.as_ref().map(|relay| relay.sessions()).unwrap_or_default();
+                NodeResponse::RelaySessions(sessions.into_iter().map(|session| self.relay_session(session)).collect())
+            }
+        }
+    }
+
+    pub async fn run_loop(&mut self) {
+        let mut publisher = self.pubsub.publisher(node_channel(self.local, ADMIN_SERVICE)).await;
+        loop {
+            match publisher.recv_ob::<NodeRequest>().await {
+                Ok(PublisherEventOb::FeedbackRpc(req, rpc_id, method, peer_src)) | Ok(PublisherEventOb::GuestFeedbackRpc(req, rpc_id, method, peer_src)) => {
+                    log::debug!("[AdminRpcServer] on rpc {method} from {peer_src:?}");
+                    let res = self.process(req).await;
+                    publisher.requester().answer_feedback_rpc_ob(rpc_id, peer_src, &res).await.print_error("[AdminRpcServer] answer rpc");
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("[AdminRpcServer] publisher error {e:?}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Send admin queries to all known nodes and aggregate the results
+#[derive(Clone)]
+pub struct AdminRpcClient {
+    nodes: ClusterNodes,
+    pubsub: PubsubServiceRequester,
+}
+
+impl AdminRpcClient {
+    pub fn new(nodes: ClusterNodes, pubsub: PubsubServiceRequester) -> Self {
+        Self { nodes, pubsub }
+    }
+
+    async fn request(&self, node: PeerId, method: &str, req: &NodeRequest) -> Result<NodeResponse, String> {
+        self.pubsub
+            .feedback_rpc_as_guest_ob::<_, NodeResponse>(node_channel(node, ADMIN_SERVICE), method, req, Duration::from_secs(RPC_TIMEOUT_SECONDS))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Send the request to all nodes in parallel, unreachable nodes are reported as errors instead of failing the whole query
+    pub async fn request_all(&self, method: &str, req: NodeRequest) -> Vec<(PeerId, Result<NodeResponse, String>)> {
+        let nodes = self.nodes.all();
+        let results = join_all(nodes.iter().map(|node| self.request(*node, method, &req))).await;
+        nodes.into_iter().zip(results).collect()
+    }
+
+    pub async fn list_calls(&self) -> Vec<AdminCallInfo> {
+        let mut calls = vec![];
+        for (node, res) in self.request_all("list_calls", NodeRequest::ListCalls).await {
+            match res {
+                Ok(NodeResponse::Calls(node_calls)) => calls.extend(node_calls),
+                Ok(NodeResponse::Error(e)) | Err(e) => log::warn!("[AdminRpcClient] list calls from node {node} error {e}"),
+                Ok(res) => log::warn!("[AdminRpcClient] list calls from node {node} got unexpected {res:?}"),
+            }
+        }
+        calls
+    }
+
+    pub async fn list_bans(&self) -> Vec<AdminSipBan> {
+        let mut bans = vec![];
+        for (node, res) in self.request_all("list_bans", NodeRequest::ListBans).await {
+            match res {
+                Ok(NodeResponse::Bans(node_bans)) => bans.extend(node_bans),
+                Ok(NodeResponse::Error(e)) | Err(e) => log::warn!("[AdminRpcClient] list bans from node {node} error {e}"),
+                Ok(res) => log::warn!("[AdminRpcClient] list bans from node {node} got unexpected {res:?}"),
+            }
+        }
+        bans
+    }
+
+    /// Place an outgoing call on the given node
+    pub async fn create_call(&self, node: PeerId, req: CreateCallRequest, app_id: AppId) -> Result<CreateCallResponse, CallApiError> {
+        match self.request(node, "create_call", &NodeRequest::CreateCall(req, app_id)).await {
+            Ok(NodeResponse::Call(res)) => res,
+            Ok(NodeResponse::Error(e)) | Err(e) => Err(CallApiError::InternalChannel(e)),
+            Ok(res) => Err(CallApiError::InternalChannel(format!("unexpected response {res:?}"))),
+        }
+    }
+
+    /// Remove the ban on all nodes, return true if any node had banned the ip
+    pub async fn unban(&self, ip: IpAddr) -> bool {
+        let mut found = false;
+        for (node, res) in self.request_all("unban", NodeRequest::Unban(ip)).await {
+            match res {
+                Ok(NodeResponse::Unbanned(unbanned)) => found |= unbanned,
+                Ok(NodeResponse::Error(e)) | Err(e) => log::warn!("[AdminRpcClient] unban on node {node} error {e}"),
+                Ok(res) => log::warn!("[AdminRpcClient] unban on node {node} got unexpected {res:?}"),
+            }
+        }
+        found
+    }
+
+    /// Captured SIP messages of a call from the node which handles it, None if no node has the call
+    pub async fn sip_trace(&self, call_id: &str) -> Option<Vec<SipTraceEntry>> {
+        let mut found = None;
+        for (node, res) in self.request_all("sip_trace", NodeRequest::SipTrace(call_id.to_owned())).await {
+            match res {
+                Ok(NodeResponse::SipTrace(Some(entries))) => found = Some(entries),
+                Ok(NodeResponse::SipTrace(None)) => {}
+                Ok(NodeResponse::Error(e)) | Err(e) => log::warn!("[AdminRpcClient] sip trace from node {node} error {e}"),
+                Ok(res) => log::warn!("[AdminRpcClient] sip trace from node {node} got unexpected {res:?}"),
+            }
+        }
+        found
+    }
+
+    pub async fn relay_sessions(&self) -> Vec<AdminRelaySession> {
+        let mut sessions = vec![];
+        for (node, res) in self.request_all("relay_sessions", NodeRequest::RelaySessions).await {
+            match res {
+                Ok(NodeResponse::RelaySessions(node_sessions)) => sessions.extend(node_sessions),
+                Ok(NodeResponse::Error(e)) | Err(e) => log::warn!("[AdminRpcClient] relay sessions from node {node} error {e}"),
+                Ok(res) => log::warn!("[AdminRpcClient] relay sessions from node {node} got unexpected {res:?}"),
+            }
+        }
+        sessions
+    }
+
+    /// Revoke the call token on all nodes, nodes which are unreachable now don't know the revocation
+    pub async fn revoke_call_token(&self, token_id: &str, expires_at: u64) {
+        for (node, res) in self.request_all("revoke_token", NodeRequest::RevokeToken(token_id.to_owned(), expires_at)).await {
+            match res {
+                Ok(NodeResponse::Revoked) => {}
+                Ok(NodeResponse::Error(e)) | Err(e) => log::warn!("[AdminRpcClient] revoke token on node {node} error {e}"),
+                Ok(res) => log::warn!("[AdminRpcClient] revoke token on node {node} got unexpected {res:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use atm0s_small_p2p::{
+    pubsub_service::{PubsubChannelId, PubsubServiceRequester, SubscriberEventOb},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use spin::RwLock;
+use tokio::time::interval;
+
+use crate::{drain::DrainState, utils::select3};
+
+use super::ClusterNodes;
+
+const PLACEMENT_CHANNEL: u64 = 0x706c_6163_656d_656e;
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+/// Status of a node is ignored if it is not refreshed within this time
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+/// Calls stay on the local node unless another node has at least this number of calls less,
+/// so small load differences don't cause forwarding
+const LOAD_MARGIN: usize = 2;
+
+/// Load and placement attributes which are broadcasted by each node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NodeStatus {
+    node: String,
+    calls: usize,
+    draining: bool,
+    region: Option<String>,
+    sip_ips: Vec<IpAddr>,
+}
+
+/// Placement attributes of the local node
+#[derive(Debug, Clone, Default)]
+pub struct NodePlacement {
+    pub region: Option<String>,
+    /// Public ips which are used as SIP source
+    pub sip_ips: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlacementConstraint {
+    pub region: Option<String>,
+    pub source_ip: Option<IpAddr>,
+}
+
+impl NodeStatus {
+    fn matches(&self, constraint: &PlacementConstraint) -> bool {
+        let region_ok = constraint.region.is_none() || self.region == constraint.region;
+        let source_ok = match constraint.source_ip {
+            Some(ip) => self.sip_ips.contains(&ip),
+            None => true,
+        };
+        !self.draining && region_ok && source_ok
+    }
+}
+
+/// Select the least loaded matching node, the local node is preferred within the load margin
+fn select_node<'a>(local: &'a NodeStatus, remotes: impl Iterator<Item = &'a NodeStatus>, constraint: &PlacementConstraint) -> Option<&'a NodeStatus> {
+    let best_remote = remotes.filter(|s| s.matches(constraint)).min_by_key(|s| s.calls);
+    match (local.matches(constraint), best_remote) {
+        (true, Some(remote)) if remote.calls + LOAD_MARGIN <= local.calls => Some(remote),
+        (true, _) => Some(local),
+        (false, remote) => remote,
+    }
+}
+
+/// Known status of all nodes for choosing where outgoing calls are placed
+#[derive(Clone)]
+pub struct PlacementTable {
+    nodes: ClusterNodes,
+    local: NodePlacement,
+    drain: DrainState,
+    local_calls: Arc<AtomicUsize>,
+    remotes: Arc<RwLock<HashMap<String, (NodeStatus, Instant)>>>,
+}
+
+impl PlacementTable {
+    pub fn new(nodes: ClusterNodes, local: NodePlacement, drain: DrainState) -> Self {
+        Self {
+            nodes,
+            local,
+            drain,
+            local_calls: Default::default(),
+            remotes: Default::default(),
+        }
+    }
+
+    pub fn set_local_calls(&self, calls: usize) {
+        self.local_calls.store(calls, Ordering::Relaxed);
+    }
+
+    fn local_status(&self) -> NodeStatus {
+        NodeStatus {
+            node: self.nodes.local().to_string(),
+            calls: self.local_calls.load(Ordering::Relaxed),
+            draining: self.drain.is_draining(),
+            region: self.local.region.clone(),
+            sip_ips: self.local.sip_ips.clone(),
+        }
+    }
+
+    fn on_status(&self, status: NodeStatus, now: Instant) {
+        let mut remotes = self.remotes.write();
+        remotes.retain(|_, (_, updated_at)| now.duration_since(*updated_at) < STATUS_TIMEOUT);
+        if status.node != self.nodes.local().to_string() {
+            remotes.insert(status.node.clone(), (status, now));
+        }
+    }
+
+    /// Pick the node for a new outgoing call, None if no connected node matches the constraint.
+    /// A picked remote node is counted with one more call until its next status, so a burst of calls is spread
+    pub fn pick(&self, constraint: &PlacementConstraint) -> Option<PeerId> {
+        let now = Instant::now();
+        let local = self.local_status();
+        let peers = self.nodes.peers().into_iter().map(|p| (p.to_string(), p)).collect::<HashMap<_, _>>();
+        let mut remotes = self.remotes.write();
+        let candidates = remotes
+            .values()
+            .filter(|(status, updated_at)| peers.contains_key(&status.node) && now.duration_since(*updated_at) < STATUS_TIMEOUT)
+            .map(|(status, _)| status);
+        let selected = select_node(&local, candidates, constraint)?.node.clone();
+        if selected == local.node {
+            // local calls are counted by the call manager as soon as they are created
+            return Some(self.nodes.local());
+        }
+        if let Some((status, _)) = remotes.get_mut(&selected) {
+            status.calls += 1;
+        }
+        peers.get(&selected).copied()
+    }
+}
+
+/// Broadcast the local status and collect status of other nodes over a cluster-wide channel
+pub struct PlacementSync {
+    table: PlacementTable,
+    pubsub: PubsubServiceRequester,
+}
+
+impl PlacementSync {
+    pub fn new(table: PlacementTable, pubsub: PubsubServiceRequester) -> Self {
+        Self { table, pubsub }
+    }
+
+    pub async fn run_loop(&mut self) {
+        let channel: PubsubChannelId = PLACEMENT_CHANNEL.into();
+        let mut publisher = self.pubsub.publisher(channel).await;
+        let mut subscriber = self.pubsub.subscriber(channel).await;
+        let mut ticker = interval(STATUS_INTERVAL);
+        loop {
+            let out = select3::or(ticker.tick(), subscriber.recv_ob::<NodeStatus>(), publisher.recv_ob::<NodeStatus>()).await;
+            match out {
+                select3::OrOutput::Left(_) => {
+                    if let Err(e) = publisher.requester().publish_ob(&self.table.local_status()).await {
+                        log::error!("[PlacementSync] publish status error {e:?}");
+                    }
+                }
+                select3::OrOutput::Middle(Ok(SubscriberEventOb::Publish(status))) => self.table.on_status(status, Instant::now()),
+                select3::OrOutput::Middle(Ok(_)) => {}
+                select3::OrOutput::Middle(Err(e)) => {
+                    log::error!("[PlacementSync] subscriber error {e:?}");
+                    break;
+                }
+                select3::OrOutput::Right(Ok(_)) => {}
+                select3::OrOutput::Right(Err(e)) => {
+                    log::error!("[PlacementSync] publisher error {e:?}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use atm0s_small_p2p::PeerId;
+
+    use crate::{cluster::ClusterNodes, drain::DrainState};
+
+    use super::{select_node, NodePlacement, NodeStatus, PlacementConstraint, PlacementTable};
+
+    fn status(node: &str, calls: usize, region: Option<&str>, sip_ip: &str) -> NodeStatus {
+        NodeStatus {
+            node: node.to_owned(),
+            calls,
+            draining: false,
+            region: region.map(|r| r.to_owned()),
+            sip_ips: vec![sip_ip.parse().expect("should parse ip")],
+        }
+    }
+
+    #[test]
+    fn test_select_by_load() {
+        let local = status("local", 10, None, "1.1.1.1");
+        let remotes = [status("n1", 9, None, "2.2.2.2"), status("n2", 3, None, "3.3.3.3")];
+        let no_constraint = PlacementConstraint::default();
+        assert_eq!(select_node(&local, remotes.iter(), &no_constraint).map(|s| s.node.as_str()), Some("n2"));
+        assert_eq!(select_node(&local, remotes[..1].iter(), &no_constraint).map(|s| s.node.as_str()), Some("local"));
+
+        let mut draining = status("n3", 0, None, "4.4.4.4");
+        draining.draining = true;
+        assert_eq!(select_node(&local, [draining].iter(), &no_constraint).map(|s| s.node.as_str()), Some("local"));
+    }
+
+    #[test]
+    fn test_select_by_region_and_source_ip() {
+        let local = status("local", 0, Some("eu"), "1.1.1.1");
+        let remotes = [status("n1", 5, Some("us"), "2.2.2.2"), status("n2", 8, Some("us"), "3.3.3.3")];
+        let region = PlacementConstraint {
+            region: Some("us".to_owned()),
+            source_ip: None,
+        };
+        assert_eq!(select_node(&local, remotes.iter(), &region).map(|s| s.node.as_str()), Some("n1"));
+        let source_ip = PlacementConstraint {
+            region: None,
+            source_ip: Some("3.3.3.3".parse().expect("should parse ip")),
+        };
+        assert_eq!(select_node(&local, remotes.iter(), &source_ip).map(|s| s.node.as_str()), Some("n2"));
+        let unknown = PlacementConstraint {
+            region: Some("asia".to_owned()),
+            source_ip: None,
+        };
+        assert!(select_node(&local, remotes.iter(), &unknown).is_none());
+    }
+
+    #[test]
+    fn test_pick_counts_placed_calls() {
+        let (local, node2, node3) = (PeerId::from(1), PeerId::from(2), PeerId::from(3));
+        let nodes = ClusterNodes::new(local);
+        nodes.on_connected(node2);
+        nodes.on_connected(node3);
+        let table = PlacementTable::new(nodes, NodePlacement::default(), DrainState::default());
+        table.set_local_calls(10);
+        let now = Instant::now();
+        table.on_status(status(&node2.to_string(), 0, None, "2.2.2.2"), now);
+        table.on_status(status(&node3.to_string(), 0, None, "3.3.3.3"), now);
+
+        // a burst before the next status broadcast is spread over both nodes until they are within the margin of the local node
+        let picked = (0..18).map(|_| table.pick(&PlacementConstraint::default()).expect("should pick")).collect::<Vec<_>>();
+        assert_eq!(picked.iter().filter(|n| **n == node2).count(), 9);
+        assert_eq!(picked.iter().filter(|n| **n == node3).count(), 9);
+        assert_eq!(table.pick(&PlacementConstraint::default()), Some(local));
+    }
+}
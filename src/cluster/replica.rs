@@ -0,0 +1,410 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use atm0s_small_p2p::{
+    pubsub_service::{PubsubChannelId, PubsubServiceRequester, SubscriberEventOb},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use spin::RwLock;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::interval,
+};
+
+use crate::{
+    protocol::{CallDirection, InternalCallId},
+    sip::SipCallSnapshot,
+    utils::{select2, select3},
+};
+
+use super::ClusterNodes;
+
+const REPLICA_CHANNEL: u64 = 0x7265_706c_6963_6173;
+/// Full call list of each node is republished with this interval, so nodes which joined later
+/// or missed some changes are synced
+const FULL_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// Calls of a disconnected node are only taken over if it doesn't reconnect within this time,
+/// a short p2p disconnect must not end calls which the node still owns
+pub const TAKEOVER_GRACE: Duration = Duration::from_secs(30);
+/// Interval of checking again a failed node whose takeover is not allowed yet
+pub const TAKEOVER_RETRY: Duration = FULL_SYNC_INTERVAL;
+
+/// Minimal state of an established call, which is used for cleaning up when its node fails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallReplica {
+    pub call_id: InternalCallId,
+    pub direction: CallDirection,
+    pub sip: SipCallSnapshot,
+    pub hook: String,
+    pub hook_headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ReplicaMessage {
+    Upsert(String, CallReplica),
+    Remove(String, InternalCallId),
+    /// All calls and the SIP source ips of a node, replaces the known calls of that node.
+    /// It is also the heartbeat of the node
+    Full(String, Vec<IpAddr>, Vec<CallReplica>),
+}
+
+/// Result of checking a disconnected node
+#[derive(Debug)]
+pub enum Takeover {
+    /// The node may still be alive or this node is not in the majority, check again after `TAKEOVER_RETRY`
+    Wait,
+    /// The node is handled, the returned calls must be ended by this node
+    Done(Vec<CallReplica>),
+}
+
+enum ReplicaChange {
+    Upsert(CallReplica),
+    Remove(InternalCallId),
+}
+
+/// Handle for pushing state of local calls to other nodes
+#[derive(Clone)]
+pub struct CallReplicator {
+    tx: UnboundedSender<ReplicaChange>,
+}
+
+impl CallReplicator {
+    pub fn upsert(&self, replica: CallReplica) {
+        if self.tx.send(ReplicaChange::Upsert(replica)).is_err() {
+            log::error!("[CallReplicator] replicate worker stopped");
+        }
+    }
+
+    pub fn remove(&self, call_id: InternalCallId) {
+        if self.tx.send(ReplicaChange::Remove(call_id)).is_err() {
+            log::error!("[CallReplicator] replicate worker stopped");
+        }
+    }
+}
+
+/// The node which takes over calls of a failed node, it is the next alive node after the failed one in sorted order,
+/// so all nodes agree on it without coordination.
+/// Nodes which share a SIP source ip with the failed node are preferred, because carriers with ip allowlist
+/// reject the BYE from other ips
+fn takeover_node<'a>(failed: &str, failed_ips: &[IpAddr], alive: &'a [(String, Vec<IpAddr>)]) -> Option<&'a str> {
+    let same_ip = |ips: &[IpAddr]| ips.iter().any(|ip| failed_ips.contains(ip));
+    let prefer_same_ip = alive.iter().any(|(n, ips)| n != failed && same_ip(ips));
+    let mut sorted = alive
+        .iter()
+        .filter(|(n, ips)| n != failed && (!prefer_same_ip || same_ip(ips)))
+        .map(|(n, _)| n.as_str())
+        .collect::<Vec<_>>();
+    sorted.sort();
+    sorted.iter().find(|n| **n > failed).or(sorted.first()).copied()
+}
+
+/// Replicated calls of other nodes
+#[derive(Clone)]
+pub struct ReplicaTable {
+    nodes: ClusterNodes,
+    /// SIP source ips of the local node
+    sip_ips: Vec<IpAddr>,
+    remotes: Arc<RwLock<HashMap<String, HashMap<InternalCallId, CallReplica>>>>,
+    /// Last full sync of each node with its SIP source ips, it arrives over other nodes when the direct connection is lost
+    last_seen: Arc<RwLock<HashMap<String, (Vec<IpAddr>, Instant)>>>,
+    /// Disconnected nodes with the disconnect time, they are failed after `TAKEOVER_GRACE`
+    disconnected: Arc<RwLock<HashMap<String, Instant>>>,
+}
+
+impl ReplicaTable {
+    pub fn new(nodes: ClusterNodes, sip_ips: Vec<IpAddr>) -> Self {
+        Self {
+            nodes,
+            sip_ips,
+            remotes: Default::default(),
+            last_seen: Default::default(),
+            disconnected: Default::default(),
+        }
+    }
+
+    /// Start the grace period of a disconnected node
+    pub fn on_disconnected(&self, node: PeerId, now: Instant) {
+        self.disconnected.write().insert(node.to_string(), now);
+    }
+
+    /// Cancel the pending takeover of a node which is back
+    pub fn on_connected(&self, node: PeerId) {
+        if self.disconnected.write().remove(&node.to_string()).is_some() {
+            log::info!("[ReplicaTable] node {node} reconnected within the grace period => takeover cancelled");
+        }
+    }
+
+    fn on_message(&self, msg: ReplicaMessage, now: Instant) {
+        let local = self.nodes.local().to_string();
+        let mut remotes = self.remotes.write();
+        match msg {
+            ReplicaMessage::Upsert(node, replica) if node != local => {
+                remotes.entry(node).or_default().insert(replica.call_id.clone(), replica);
+            }
+            ReplicaMessage::Remove(node, call_id) if node != local => {
+                if let Some(calls) = remotes.get_mut(&node) {
+                    calls.remove(&call_id);
+                }
+            }
+            ReplicaMessage::Full(node, sip_ips, calls) if node != local => {
+                self.last_seen.write().insert(node.clone(), (sip_ips, now));
+                remotes.insert(node, calls.into_iter().map(|c| (c.call_id.clone(), c)).collect());
+            }
+            _ => {}
+        }
+    }
+
+    /// Forget calls of a node which stayed disconnected for the grace period, they are returned if this node is responsible
+    /// for cleaning them up. The node is only failed when nothing is heard from it over other nodes either and this node
+    /// sees the majority of the cluster, so both sides of a network partition don't end each other's calls.
+    pub fn take_failed(&self, failed: PeerId, now: Instant) -> Takeover {
+        let failed = failed.to_string();
+        let alive = self.nodes.all().iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        {
+            let mut disconnected = self.disconnected.write();
+            match disconnected.get(&failed) {
+                Some(at) if now.duration_since(*at) >= TAKEOVER_GRACE => {}
+                Some(_) => return Takeover::Wait,
+                None => return Takeover::Done(vec![]),
+            }
+            if let Some((_, seen_at)) = self.last_seen.read().get(&failed) {
+                if now.duration_since(*seen_at) < TAKEOVER_GRACE {
+                    log::warn!("[ReplicaTable] node {failed} is disconnected but still syncs over other nodes => wait");
+                    return Takeover::Wait;
+                }
+            }
+            // the local node and connected peers must be the majority of the known nodes
+            let members = alive.len() + disconnected.keys().filter(|n| !alive.contains(n)).count();
+            if alive.len() * 2 <= members {
+                log::warn!("[ReplicaTable] node {failed} is disconnected but only {}/{members} nodes are reachable => wait", alive.len());
+                return Takeover::Wait;
+            }
+            disconnected.remove(&failed);
+        }
+        let failed_ips = self.last_seen.write().remove(&failed).map(|(ips, _)| ips).unwrap_or_default();
+        let calls = match self.remotes.write().remove(&failed) {
+            Some(calls) if !calls.is_empty() => calls,
+            _ => return Takeover::Done(vec![]),
+        };
+        let last_seen = self.last_seen.read();
+        let local = self.nodes.local().to_string();
+        let alive = alive
+            .into_iter()
+            .map(|n| {
+                let ips = if n == local {
+                    self.sip_ips.clone()
+                } else {
+                    last_seen.get(&n).map(|(ips, _)| ips.clone()).unwrap_or_default()
+                };
+                (n, ips)
+            })
+            .collect::<Vec<_>>();
+        if !alive.iter().any(|(_, ips)| ips.iter().any(|ip| failed_ips.contains(ip))) {
+            log::warn!("[ReplicaTable] no alive node has the SIP source ips {failed_ips:?} of node {failed} => BYE is sent from other ips, carriers with ip allowlist may reject it");
+        }
+        if takeover_node(&failed, &failed_ips, &alive) == Some(local.as_str()) {
+            Takeover::Done(calls.into_values().collect())
+        } else {
+            Takeover::Done(vec![])
+        }
+    }
+}
+
+/// Publish local call changes and collect calls of other nodes over a cluster-wide channel
+pub struct ReplicaSync {
+    table: ReplicaTable,
+    pubsub: PubsubServiceRequester,
+    rx: UnboundedReceiver<ReplicaChange>,
+    local: HashMap<InternalCallId, CallReplica>,
+}
+
+impl ReplicaSync {
+    pub fn new(table: ReplicaTable, pubsub: PubsubServiceRequester) -> (Self, CallReplicator) {
+        let (tx, rx) = unbounded_channel();
+        (
+            Self {
+                table,
+                pubsub,
+                rx,
+                local: HashMap::new(),
+            },
+            CallReplicator { tx },
+        )
+    }
+
+    fn on_change(&mut self, change: ReplicaChange) -> Option<ReplicaMessage> {
+        let node = self.table.nodes.local().to_string();
+        match change {
+            ReplicaChange::Upsert(replica) => {
+                self.local.insert(replica.call_id.clone(), replica.clone());
+                Some(ReplicaMessage::Upsert(node, replica))
+            }
+            // calls which are ended before being replicated don't need to be published
+            ReplicaChange::Remove(call_id) => self.local.remove(&call_id).map(|_| ReplicaMessage::Remove(node, call_id)),
+        }
+    }
+
+    pub async fn run_loop(&mut self) {
+        let channel: PubsubChannelId = REPLICA_CHANNEL.into();
+        let mut publisher = self.pubsub.publisher(channel).await;
+        let mut subscriber = self.pubsub.subscriber(channel).await;
+        let mut ticker = interval(FULL_SYNC_INTERVAL);
+        loop {
+            let out = select3::or(
+                select2::or(ticker.tick(), self.rx.recv()),
+                subscriber.recv_ob::<ReplicaMessage>(),
+                publisher.recv_ob::<ReplicaMessage>(),
+            )
+            .await;
+            let msg = match out {
+                select3::OrOutput::Left(select2::OrOutput::Left(_)) => Some(ReplicaMessage::Full(
+                    self.table.nodes.local().to_string(),
+                    self.table.sip_ips.clone(),
+                    self.local.values().cloned().collect(),
+                )),
+                select3::OrOutput::Left(select2::OrOutput::Right(Some(change))) => self.on_change(change),
+                select3::OrOutput::Left(select2::OrOutput::Right(None)) => {
+                    log::warn!("[ReplicaSync] all replicators dropped => stop");
+                    break;
+                }
+                select3::OrOutput::Middle(Ok(SubscriberEventOb::Publish(msg))) => {
+                    self.table.on_message(msg, Instant::now());
+                    None
+                }
+                select3::OrOutput::Middle(Ok(_)) => None,
+                select3::OrOutput::Middle(Err(e)) => {
+                    log::error!("[ReplicaSync] subscriber error {e:?}");
+                    break;
+                }
+                select3::OrOutput::Right(Ok(_)) => None,
+                select3::OrOutput::Right(Err(e)) => {
+                    log::error!("[ReplicaSync] publisher error {e:?}");
+                    break;
+                }
+            };
+            if let Some(msg) = msg {
+                if let Err(e) = publisher.requester().publish_ob(&msg).await {
+                    log::error!("[ReplicaSync] publish error {e:?}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use atm0s_small_p2p::PeerId;
+
+    use crate::{cluster::ClusterNodes, protocol::CallDirection, sip::SipCallSnapshot};
+
+    use super::{takeover_node, CallReplica, ReplicaMessage, ReplicaTable, Takeover, TAKEOVER_GRACE};
+
+    fn replica(call_id: &str) -> CallReplica {
+        CallReplica {
+            call_id: call_id.to_owned().into(),
+            direction: CallDirection::Outgoing,
+            sip: SipCallSnapshot {
+                target: "sip:2000@127.0.0.1".to_owned(),
+                headers: vec![],
+                media_url: None,
+                media_backend: None,
+            },
+            hook: "http://localhost/hook".to_owned(),
+            hook_headers: Default::default(),
+        }
+    }
+
+    fn taken(takeover: Takeover) -> Option<Vec<String>> {
+        match takeover {
+            Takeover::Wait => None,
+            Takeover::Done(calls) => Some(calls.iter().map(|c| c.call_id.to_string()).collect()),
+        }
+    }
+
+    /// Local node 3 with connected node 1, node 2 is the failed one and node 3 is next after it
+    fn three_nodes() -> (ReplicaTable, PeerId) {
+        let (local, other, failed) = (PeerId::from(3), PeerId::from(1), PeerId::from(2));
+        let nodes = ClusterNodes::new(local);
+        nodes.on_connected(other);
+        let table = ReplicaTable::new(nodes, vec![]);
+        (table, failed)
+    }
+
+    #[test]
+    fn test_takeover_after_grace_period() {
+        let (table, failed) = three_nodes();
+        let t0 = Instant::now();
+        table.on_message(ReplicaMessage::Full(failed.to_string(), vec![], vec![replica("call1")]), t0);
+
+        // a short disconnect is cancelled by the reconnect
+        table.on_disconnected(failed, t0);
+        assert_eq!(taken(table.take_failed(failed, t0 + Duration::from_secs(1))), None);
+        table.on_connected(failed);
+        assert_eq!(taken(table.take_failed(failed, t0 + TAKEOVER_GRACE)), Some(vec![]), "reconnected node should keep its calls");
+
+        let t1 = t0 + Duration::from_secs(60);
+        table.on_disconnected(failed, t1);
+        assert_eq!(taken(table.take_failed(failed, t1 + TAKEOVER_GRACE)), Some(vec!["call1".to_owned()]));
+        assert_eq!(taken(table.take_failed(failed, t1 + TAKEOVER_GRACE * 2)), Some(vec![]), "calls are taken over once");
+    }
+
+    #[test]
+    fn test_takeover_waits_while_node_syncs_over_other_nodes() {
+        let (table, failed) = three_nodes();
+        let t0 = Instant::now();
+        table.on_disconnected(failed, t0);
+        // the node lost only the direct connection, its full sync still arrives
+        table.on_message(ReplicaMessage::Full(failed.to_string(), vec![], vec![replica("call1")]), t0 + Duration::from_secs(25));
+        assert_eq!(taken(table.take_failed(failed, t0 + TAKEOVER_GRACE)), None);
+        assert_eq!(taken(table.take_failed(failed, t0 + Duration::from_secs(25) + TAKEOVER_GRACE)), Some(vec!["call1".to_owned()]));
+    }
+
+    #[test]
+    fn test_takeover_needs_majority() {
+        // two nodes can't tell a failed peer from a network partition
+        let (local, failed) = (PeerId::from(1), PeerId::from(2));
+        let table = ReplicaTable::new(ClusterNodes::new(local), vec![]);
+        let t0 = Instant::now();
+        table.on_message(ReplicaMessage::Full(failed.to_string(), vec![], vec![replica("call1")]), t0);
+        table.on_disconnected(failed, t0);
+        assert_eq!(taken(table.take_failed(failed, t0 + TAKEOVER_GRACE * 10)), None);
+
+        // minority side of a partition in a three nodes cluster
+        let (table, failed) = three_nodes();
+        table.nodes.on_disconnected(PeerId::from(1));
+        table.on_disconnected(PeerId::from(1), t0);
+        table.on_disconnected(failed, t0);
+        assert_eq!(taken(table.take_failed(failed, t0 + TAKEOVER_GRACE)), None);
+    }
+
+    #[test]
+    fn test_takeover_node() {
+        let alive = [("n1".to_owned(), vec![]), ("n3".to_owned(), vec![]), ("n5".to_owned(), vec![])];
+        assert_eq!(takeover_node("n2", &[], &alive), Some("n3"));
+        assert_eq!(takeover_node("n4", &[], &alive), Some("n5"));
+        // wrap around to the first node
+        assert_eq!(takeover_node("n6", &[], &alive), Some("n1"));
+        assert_eq!(takeover_node("n1", &[], &alive), Some("n3"));
+        assert_eq!(takeover_node("n1", &[], &alive[..1]), None);
+    }
+
+    #[test]
+    fn test_takeover_node_prefers_same_sip_ip() {
+        let shared: IpAddr = "203.0.113.10".parse().expect("should parse ip");
+        let other: IpAddr = "203.0.113.20".parse().expect("should parse ip");
+        let alive = [("n1".to_owned(), vec![shared]), ("n3".to_owned(), vec![other]), ("n5".to_owned(), vec![])];
+        assert_eq!(takeover_node("n2", &[shared], &alive), Some("n1"));
+        // no alive node has the ip => the next node sends the BYE
+        assert_eq!(takeover_node("n2", &["203.0.113.30".parse().expect("should parse ip")], &alive), Some("n3"));
+    }
+}
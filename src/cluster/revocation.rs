@@ -0,0 +1,66 @@
+use std::{sync::Arc, time::Duration};
+
+use atm0s_small_p2p::pubsub_service::{PubsubChannelId, PubsubServiceRequester, SubscriberEventOb};
+use serde::{Deserialize, Serialize};
+use tokio::time::interval;
+
+use crate::{secure::SecureContext, utils::select3};
+
+const REVOCATION_CHANNEL: u64 = 0x7265_766f_6b65_6473;
+/// Revoked tokens of each node are republished with this interval, so nodes which were offline at revoke time
+/// or restarted later still reject them
+const REVOCATION_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Revoked token ids with their expire time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RevocationList {
+    tokens: Vec<(String, u64)>,
+}
+
+/// Replicate revoked call tokens over a cluster-wide channel, entries are dropped by each node after the token is expired
+pub struct RevocationSync {
+    secure_ctx: Arc<SecureContext>,
+    pubsub: PubsubServiceRequester,
+}
+
+impl RevocationSync {
+    pub fn new(secure_ctx: Arc<SecureContext>, pubsub: PubsubServiceRequester) -> Self {
+        Self { secure_ctx, pubsub }
+    }
+
+    pub async fn run_loop(&mut self) {
+        let channel: PubsubChannelId = REVOCATION_CHANNEL.into();
+        let mut publisher = self.pubsub.publisher(channel).await;
+        let mut subscriber = self.pubsub.subscriber(channel).await;
+        let mut ticker = interval(REVOCATION_SYNC_INTERVAL);
+        loop {
+            let out = select3::or(ticker.tick(), subscriber.recv_ob::<RevocationList>(), publisher.recv_ob::<RevocationList>()).await;
+            match out {
+                select3::OrOutput::Left(_) => {
+                    let tokens = self.secure_ctx.revoked_tokens();
+                    if tokens.is_empty() {
+                        continue;
+                    }
+                    if let Err(e) = publisher.requester().publish_ob(&RevocationList { tokens }).await {
+                        log::error!("[RevocationSync] publish error {e:?}");
+                    }
+                }
+                select3::OrOutput::Middle(Ok(SubscriberEventOb::Publish(list))) => {
+                    for (token_id, expires_at) in list.tokens {
+                        self.secure_ctx.revoke_token(&token_id, expires_at);
+                    }
+                }
+                select3::OrOutput::Middle(Ok(_)) => {}
+                select3::OrOutput::Middle(Err(e)) => {
+                    log::error!("[RevocationSync] subscriber error {e:?}");
+                    break;
+                }
+                select3::OrOutput::Right(Ok(_)) => {}
+                select3::OrOutput::Right(Err(e)) => {
+                    log::error!("[RevocationSync] publisher error {e:?}");
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use atm0s_small_p2p::{HandshakeProtocol, PeerId, SharedKeyHandshake};
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, TrustAnchor, UnixTime},
+    sign::Signer,
+    SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{DEFAULT_CLUSTER_CERT, DEFAULT_CLUSTER_KEY};
+
+const SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+#[derive(Debug, Error)]
+pub enum ClusterTlsError {
+    #[error("IoError {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid config: {0}")]
+    Config(&'static str),
+    #[error("Invalid certificate or key: {0}")]
+    Invalid(String),
+}
+
+/// Where the cluster TLS identity comes from, the embedded dev certificate is used when nothing is configured
+#[derive(Debug, Clone, Default)]
+pub struct ClusterTlsConfig {
+    /// Certificate chain file in PEM or DER, the first certificate is the node certificate
+    pub cert: Option<PathBuf>,
+    /// PKCS#8 private key file in PEM or DER
+    pub key: Option<PathBuf>,
+    /// Generate a self-signed identity at startup
+    pub self_signed: bool,
+    /// CA certificates in PEM or DER, peers must present a certificate issued by them in the handshake
+    pub ca: Option<PathBuf>,
+}
+
+/// TLS identity of this node in the cluster network
+pub struct ClusterIdentity {
+    pub chain: Vec<CertificateDer<'static>>,
+    pub key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl ClusterIdentity {
+    pub async fn load(cfg: &ClusterTlsConfig, local: PeerId) -> Result<Self, ClusterTlsError> {
+        match (&cfg.cert, &cfg.key, cfg.self_signed) {
+            (None, None, _) if cfg.ca.is_some() => Err(ClusterTlsError::Config("ca requires a cert and key issued by it")),
+            (Some(cert), Some(key), false) => {
+                let chain = load_certs(cert).await?;
+                let key = load_key(key).await?;
+                log::info!("[ClusterTls] loaded identity from {cert:?} with {} certificates", chain.len());
+                Ok(Self { chain, key })
+            }
+            (None, None, true) => {
+                let generated = rcgen::generate_simple_self_signed(vec![peer_cert_name(local)]).map_err(|e| ClusterTlsError::Invalid(e.to_string()))?;
+                log::info!("[ClusterTls] generated self-signed identity");
+                Ok(Self {
+                    chain: vec![generated.cert.der().clone()],
+                    key: PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()),
+                })
+            }
+            (None, None, false) => {
+                log::warn!("[ClusterTls] !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+                log::warn!("[ClusterTls] !!! USING THE EMBEDDED DEV CLUSTER CERTIFICATE, ITS PRIVATE KEY IS PUBLIC !!!");
+                log::warn!("[ClusterTls] !!! configure a cert/key or a self-signed identity for production    !!!");
+                log::warn!("[ClusterTls] !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+                Ok(Self {
+                    chain: vec![CertificateDer::from(DEFAULT_CLUSTER_CERT.to_vec())],
+                    key: PrivatePkcs8KeyDer::from(DEFAULT_CLUSTER_KEY.to_vec()),
+                })
+            }
+            (_, _, true) => Err(ClusterTlsError::Config("self-signed identity cannot be used with cert or key")),
+            _ => Err(ClusterTlsError::Config("both cert and key are required")),
+        }
+    }
+
+    pub fn cert(&self) -> CertificateDer<'static> {
+        self.chain[0].clone()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CertifiedHandshake {
+    shared: Vec<u8>,
+    chain: Vec<Vec<u8>>,
+    signature: Vec<u8>,
+}
+
+/// Name which a node certificate must contain as DNS subject alt name, it binds the certificate to the node id
+pub fn peer_cert_name(peer: PeerId) -> String {
+    format!("node-{peer}")
+}
+
+/// Verify that peers own a certificate issued by the cluster CA for their node id.
+/// The node presents its chain and signs the shared key handshake with its private key.
+struct PeerVerifier {
+    anchors: Vec<TrustAnchor<'static>>,
+    chain: Vec<Vec<u8>>,
+    signer: Box<dyn Signer>,
+}
+
+impl PeerVerifier {
+    fn sign(&self, shared: Vec<u8>) -> Result<Vec<u8>, String> {
+        let signature = self.signer.sign(&shared).map_err(|e| e.to_string())?;
+        serde_json::to_vec(&CertifiedHandshake {
+            shared,
+            chain: self.chain.clone(),
+            signature,
+        })
+        .map_err(|e| e.to_string())
+    }
+
+    /// Return the shared key handshake payload if the peer certificate is valid for the peer id and the signature is valid
+    fn verify(&self, data: &[u8], peer: PeerId) -> Result<Vec<u8>, String> {
+        let handshake: CertifiedHandshake = serde_json::from_slice(data).map_err(|e| format!("invalid certified handshake: {e}"))?;
+        let (cert, intermediates) = handshake.chain.split_first().ok_or("missing peer certificate")?;
+        let cert = CertificateDer::from(cert.as_slice());
+        let intermediates = intermediates.iter().map(|c| CertificateDer::from(c.as_slice())).collect::<Vec<_>>();
+        let cert = webpki::EndEntityCert::try_from(&cert).map_err(|e| format!("invalid peer certificate: {e:?}"))?;
+        cert.verify_for_usage(
+            webpki::ALL_VERIFICATION_ALGS,
+            &self.anchors,
+            &intermediates,
+            UnixTime::now(),
+            webpki::KeyUsage::server_auth(),
+            None,
+            None,
+        )
+        .map_err(|e| format!("untrusted peer certificate: {e:?}"))?;
+        let name = ServerName::try_from(peer_cert_name(peer)).map_err(|e| format!("invalid peer name: {e}"))?;
+        cert.verify_is_valid_for_subject_name(&name).map_err(|_| format!("peer certificate is not issued for node {peer}"))?;
+        if !webpki::ALL_VERIFICATION_ALGS
+            .iter()
+            .any(|alg| cert.verify_signature(*alg, &handshake.shared, &handshake.signature).is_ok())
+        {
+            return Err("invalid peer signature".to_owned());
+        }
+        Ok(handshake.shared)
+    }
+}
+
+/// Cluster handshake, the shared secret is always checked and peer certificates are checked when a CA is configured.
+/// All nodes of a cluster must use the same CA setting.
+pub struct ClusterHandshake {
+    shared: SharedKeyHandshake,
+    verifier: Option<PeerVerifier>,
+}
+
+impl ClusterHandshake {
+    /// With a CA, the node certificate must be issued for `local`, see [`peer_cert_name`]
+    pub async fn new(secret: &str, identity: &ClusterIdentity, ca: Option<&Path>, local: PeerId) -> Result<Self, ClusterTlsError> {
+        let verifier = match ca {
+            Some(ca) => {
+                let ca_certs = load_certs(ca).await?;
+                let anchors = ca_certs
+                    .iter()
+                    .map(|c| webpki::anchor_from_trusted_cert(c).map(|a| a.to_owned()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| ClusterTlsError::Invalid(format!("{e:?}")))?;
+                let verifier = Self::verifier(anchors, identity)?;
+                // peers would refuse this node, fail at startup instead
+                verifier.verify(&verifier.sign(vec![]).map_err(ClusterTlsError::Invalid)?, local).map_err(ClusterTlsError::Invalid)?;
+                Some(verifier)
+            }
+            None => None,
+        };
+        Ok(Self {
+            shared: SharedKeyHandshake::from(secret),
+            verifier,
+        })
+    }
+
+    fn verifier(anchors: Vec<TrustAnchor<'static>>, identity: &ClusterIdentity) -> Result<PeerVerifier, ClusterTlsError> {
+        let signing_key = any_supported_type(&PrivateKeyDer::Pkcs8(identity.key.clone_key())).map_err(|e| ClusterTlsError::Invalid(e.to_string()))?;
+        let signer = signing_key.choose_scheme(SIGNATURE_SCHEMES).ok_or(ClusterTlsError::Config("unsupported cluster key type"))?;
+        Ok(PeerVerifier {
+            anchors,
+            chain: identity.chain.iter().map(|c| c.to_vec()).collect(),
+            signer,
+        })
+    }
+
+    fn wrap(&self, shared: Vec<u8>) -> Result<Vec<u8>, String> {
+        match &self.verifier {
+            Some(verifier) => verifier.sign(shared),
+            None => Ok(shared),
+        }
+    }
+
+    fn unwrap(&self, data: Vec<u8>, peer: PeerId) -> Result<Vec<u8>, String> {
+        match &self.verifier {
+            Some(verifier) => verifier.verify(&data, peer),
+            None => Ok(data),
+        }
+    }
+
+    /// The handshake protocol has no error for created messages, on sign error nothing is sent:
+    /// an empty message is never a valid handshake so the peer aborts it
+    fn abort_on_error(res: Result<Vec<u8>, String>, from: PeerId, to: PeerId) -> Vec<u8> {
+        res.unwrap_or_else(|e| {
+            log::error!("[ClusterTls] sign handshake {from} => {to} error {e} => abort");
+            vec![]
+        })
+    }
+}
+
+impl HandshakeProtocol for ClusterHandshake {
+    fn create_request(&self, from: PeerId, to: PeerId, now_ms: u64) -> Vec<u8> {
+        Self::abort_on_error(self.wrap(self.shared.create_request(from, to, now_ms)), from, to)
+    }
+
+    fn verify_request(&self, data: Vec<u8>, expected_from: PeerId, expected_to: PeerId, now_ms: u64) -> Result<(), String> {
+        if data.is_empty() {
+            return Err("empty handshake".to_owned());
+        }
+        let shared = self.unwrap(data, expected_from)?;
+        self.shared.verify_request(shared, expected_from, expected_to, now_ms)
+    }
+
+    fn create_response(&self, from: PeerId, to: PeerId, now_ms: u64) -> Vec<u8> {
+        Self::abort_on_error(self.wrap(self.shared.create_response(from, to, now_ms)), from, to)
+    }
+
+    fn verify_response(&self, data: Vec<u8>, expected_from: PeerId, expected_to: PeerId, now_ms: u64) -> Result<(), String> {
+        if data.is_empty() {
+            return Err("empty handshake".to_owned());
+        }
+        let shared = self.unwrap(data, expected_from)?;
+        self.shared.verify_response(shared, expected_from, expected_to, now_ms)
+    }
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.starts_with(b"-----BEGIN")
+}
+
+async fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, ClusterTlsError> {
+    let data = tokio::fs::read(path).await?;
+    let certs = if is_pem(&data) {
+        rustls_pemfile::certs(&mut data.as_slice()).collect::<Result<Vec<_>, _>>()?
+    } else {
+        vec![CertificateDer::from(data)]
+    };
+    if certs.is_empty() {
+        return Err(ClusterTlsError::Invalid(format!("no certificate in {path:?}")));
+    }
+    Ok(certs)
+}
+
+async fn load_key(path: &Path) -> Result<PrivatePkcs8KeyDer<'static>, ClusterTlsError> {
+    let data = tokio::fs::read(path).await?;
+    if is_pem(&data) {
+        rustls_pemfile::pkcs8_private_keys(&mut data.as_slice())
+            .next()
+            .ok_or_else(|| ClusterTlsError::Invalid(format!("no PKCS#8 private key in {path:?}")))?
+            .map_err(ClusterTlsError::Io)
+    } else {
+        Ok(PrivatePkcs8KeyDer::from(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_small_p2p::HandshakeProtocol;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, TrustAnchor};
+
+    use super::{peer_cert_name, ClusterHandshake, ClusterIdentity};
+
+    fn ca() -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::generate().expect("should generate key");
+        let mut params = CertificateParams::new(vec![]).expect("should create params");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        (params.self_signed(&key).expect("should sign ca"), key)
+    }
+
+    fn node(name: &str, issuer: Option<&(rcgen::Certificate, KeyPair)>) -> ClusterIdentity {
+        let key = KeyPair::generate().expect("should generate key");
+        let params = CertificateParams::new(vec![name.to_owned()]).expect("should create params");
+        let cert = match issuer {
+            Some((ca, ca_key)) => params.signed_by(&key, ca, ca_key),
+            None => params.self_signed(&key),
+        }
+        .expect("should sign cert");
+        ClusterIdentity {
+            chain: vec![cert.der().clone()],
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()),
+        }
+    }
+
+    fn handshake(identity: &ClusterIdentity, ca: &rcgen::Certificate) -> ClusterHandshake {
+        let anchor: TrustAnchor<'static> = webpki::anchor_from_trusted_cert(&CertificateDer::from(ca.der().to_vec())).expect("should create anchor").to_owned();
+        ClusterHandshake {
+            shared: "secret".into(),
+            verifier: Some(ClusterHandshake::verifier(vec![anchor], identity).expect("should create verifier")),
+        }
+    }
+
+    #[test]
+    fn test_handshake_with_ca() {
+        let ca = ca();
+        let node1 = handshake(&node(&peer_cert_name(1.into()), Some(&ca)), &ca.0);
+        let node2 = handshake(&node(&peer_cert_name(2.into()), Some(&ca)), &ca.0);
+        let req = node1.create_request(1.into(), 2.into(), 1000);
+        assert_eq!(node2.verify_request(req, 1.into(), 2.into(), 1000), Ok(()));
+        let res = node2.create_response(2.into(), 1.into(), 1000);
+        assert_eq!(node1.verify_response(res, 2.into(), 1.into(), 1000), Ok(()));
+    }
+
+    #[test]
+    fn test_handshake_reject_untrusted_peer() {
+        let ca = ca();
+        let other_ca = ca();
+        let node1 = handshake(&node(&peer_cert_name(1.into()), Some(&ca)), &ca.0);
+        let attacker = handshake(&node(&peer_cert_name(3.into()), Some(&other_ca)), &other_ca.0);
+        let self_signed = handshake(&node(&peer_cert_name(3.into()), None), &ca.0);
+        assert!(node1.verify_request(attacker.create_request(3.into(), 1.into(), 1000), 3.into(), 1.into(), 1000).is_err());
+        assert!(node1.verify_request(self_signed.create_request(3.into(), 1.into(), 1000), 3.into(), 1.into(), 1000).is_err());
+        assert!(node1.verify_request(vec![], 3.into(), 1.into(), 1000).is_err(), "aborted handshake should be refused");
+    }
+
+    #[test]
+    fn test_handshake_reject_other_node_id() {
+        let ca = ca();
+        let node1 = handshake(&node(&peer_cert_name(1.into()), Some(&ca)), &ca.0);
+        // a valid certificate of node 2 cannot be used to join as node 3
+        let node2 = handshake(&node(&peer_cert_name(2.into()), Some(&ca)), &ca.0);
+        let err = node1
+            .verify_request(node2.create_request(3.into(), 1.into(), 1000), 3.into(), 1.into(), 1000)
+            .expect_err("should reject");
+        assert!(err.contains("not issued for node 3"), "{err}");
+    }
+}
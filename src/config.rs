@@ -0,0 +1,494 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    address_book::AddressBookSyncConfig,
+    cluster::ClusterTlsConfig,
+    protocol::{CallLimits, SipAuth},
+    secure::CallTokenKeyConfig,
+    sip::{RtpRelayConfig, SipFloodConfig, SipTraceConfig},
+};
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("IoError {0}")]
+    Io(#[from] std::io::Error),
+    #[error("ParseError {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
+
+/// Gateway config file in TOML, missing fields use the same defaults as the command line flags.
+/// Only log level, address book sync, limits and sip blocklist are applied on reload, other fields need a restart.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String,
+    pub secret: String,
+    pub http: HttpSection,
+    pub sip: SipSection,
+    /// Named sip trunks, outgoing calls can use a trunk name instead of sip server and auth
+    pub trunks: Vec<SipTrunk>,
+    pub address_book: AddressBookSection,
+    pub limits: CallLimitsConfig,
+    pub hook: HookSection,
+    pub media: MediaSection,
+    pub sdn: SdnSection,
+    pub drain_timeout_secs: u64,
+    /// Default TTL of call tokens, apps can override it in the address book
+    pub call_token_ttl_secs: u64,
+    /// ES256 or Ed25519 keys for signing call tokens, the first one signs and the others only verify.
+    /// Call tokens are signed with the gateway secret when it is empty
+    pub call_token_keys: Vec<CallTokenKeyConfig>,
+    /// Reject HS256 call tokens when `call_token_keys` are set, enable it after the HS256 tokens issued before the switch are expired
+    pub call_token_reject_hs256: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSection {
+    pub addr: SocketAddr,
+    pub public: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SipSection {
+    /// UDP listeners, the first one is used as contact address
+    pub listeners: Vec<SocketAddr>,
+    /// Public SIP source ips of this node for call placement, default to listener ips
+    pub source_ips: Vec<IpAddr>,
+    pub forward_headers: Vec<String>,
+    pub flood: FloodSection,
+    pub trace: TraceSection,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloodSection {
+    pub invites_per_second: u32,
+    pub ban_threshold: u32,
+    pub ban_secs: u64,
+    /// INVITEs from these subnets are always dropped
+    pub blocklist: Vec<IpNet>,
+    /// INVITEs from these subnets are never rate limited or banned, trunk servers and address book subnets are always trusted
+    pub trusted: Vec<IpNet>,
+}
+
+/// Per call SIP message capture for the admin trace apis
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceSection {
+    /// Number of recent calls whose SIP messages are kept in memory, 0 disables it
+    pub max_calls: usize,
+    /// Homer compatible collector which receives every captured message as HEP3 over UDP
+    pub hep_collector: Option<SocketAddr>,
+    pub hep_capture_id: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SipTrunk {
+    pub name: String,
+    pub server: String,
+    pub auth: Option<SipAuth>,
+    /// Place calls of this trunk on nodes with this region tag
+    pub region: Option<String>,
+    /// Place calls of this trunk on nodes with this SIP source ip, for carriers which allowlist gateway ips
+    pub source_ip: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AddressBookSection {
+    pub numbers_sync: Option<String>,
+    pub apps_sync: Option<String>,
+    pub sync_interval_ms: u64,
+    pub snapshot: Option<PathBuf>,
+    pub file: Option<PathBuf>,
+}
+
+/// Call limits from config, they are merged with the address book limits field by field:
+/// per app or number override first, then the address book entry, then the default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CallLimitsConfig {
+    /// Default limits of apps in the address book
+    pub default_app: CallLimits,
+    /// Default limits of numbers in the address book
+    pub default_number: CallLimits,
+    pub apps: HashMap<String, CallLimits>,
+    pub numbers: HashMap<String, CallLimits>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSection {
+    pub queues: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaSection {
+    pub gateway: String,
+    pub app_sync: Option<String>,
+    /// Built-in RTP relay, enabled when the section is present
+    pub relay: Option<RelaySection>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelaySection {
+    pub bind_ip: IpAddr,
+    /// Ip in sdp connection lines, default to bind_ip
+    pub public_ip: Option<IpAddr>,
+    pub port_min: u16,
+    pub port_max: u16,
+    /// Latch the SIP side address from its first packet, for carriers or phones behind NAT
+    pub sip_latching: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SdnSection {
+    pub peer_id: Option<u64>,
+    pub listener: SocketAddr,
+    pub seeds: Vec<String>,
+    pub advertise_address: Option<SocketAddr>,
+    pub secure_code: String,
+    /// Region tag of this node for call placement
+    pub region: Option<String>,
+    pub tls: TlsSection,
+}
+
+/// Cluster TLS identity, the embedded dev certificate is used if neither cert/key nor self_signed is provided
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub self_signed: bool,
+    pub ca: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_owned(),
+            secret: "insecure".to_owned(),
+            http: Default::default(),
+            sip: Default::default(),
+            trunks: vec![],
+            address_book: Default::default(),
+            limits: Default::default(),
+            hook: Default::default(),
+            media: Default::default(),
+            sdn: Default::default(),
+            drain_timeout_secs: 300,
+            call_token_ttl_secs: 3600,
+            call_token_keys: vec![],
+            call_token_reject_hs256: false,
+        }
+    }
+}
+
+impl Default for HttpSection {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0:8008".parse().expect("should parse addr"),
+            public: "http://127.0.0.1:8008".to_owned(),
+        }
+    }
+}
+
+impl Default for SipSection {
+    fn default() -> Self {
+        Self {
+            listeners: vec!["0.0.0.0:5060".parse().expect("should parse addr")],
+            source_ips: vec![],
+            forward_headers: vec![],
+            flood: Default::default(),
+            trace: Default::default(),
+        }
+    }
+}
+
+impl Default for FloodSection {
+    fn default() -> Self {
+        Self {
+            invites_per_second: 20,
+            ban_threshold: 50,
+            ban_secs: 600,
+            blocklist: vec![],
+            trusted: vec![],
+        }
+    }
+}
+
+impl Default for TraceSection {
+    fn default() -> Self {
+        let default = SipTraceConfig::default();
+        Self {
+            max_calls: default.max_calls,
+            hep_collector: default.hep_collector,
+            hep_capture_id: default.hep_capture_id,
+        }
+    }
+}
+
+impl Default for RelaySection {
+    fn default() -> Self {
+        let default = RtpRelayConfig::default();
+        Self {
+            bind_ip: default.bind_ip,
+            public_ip: default.public_ip,
+            port_min: default.port_min,
+            port_max: default.port_max,
+            sip_latching: default.sip_latching,
+        }
+    }
+}
+
+impl Default for AddressBookSection {
+    fn default() -> Self {
+        Self {
+            numbers_sync: None,
+            apps_sync: None,
+            sync_interval_ms: 30_000,
+            snapshot: None,
+            file: None,
+        }
+    }
+}
+
+impl Default for HookSection {
+    fn default() -> Self {
+        Self { queues: 20 }
+    }
+}
+
+impl Default for SdnSection {
+    fn default() -> Self {
+        Self {
+            peer_id: None,
+            listener: "0.0.0.0:0".parse().expect("should parse addr"),
+            seeds: vec![],
+            advertise_address: None,
+            secure_code: "insecure".to_owned(),
+            region: None,
+            tls: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the file and apply the overrides before it is validated, so required fields can also come from the overrides
+    pub async fn load(path: &Path, overrides: impl FnOnce(&mut Self)) -> Result<Self, ConfigError> {
+        let data = tokio::fs::read_to_string(path).await?;
+        Self::parse_with(&data, overrides)
+    }
+
+    pub fn parse(data: &str) -> Result<Self, ConfigError> {
+        Self::parse_with(data, |_| {})
+    }
+
+    pub fn parse_with(data: &str, overrides: impl FnOnce(&mut Self)) -> Result<Self, ConfigError> {
+        let mut cfg: Self = toml::from_str(data)?;
+        overrides(&mut cfg);
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.media.gateway.is_empty() {
+            return Err(ConfigError::Invalid("media.gateway is required".to_owned()));
+        }
+        if self.sip.listeners.is_empty() {
+            return Err(ConfigError::Invalid("sip.listeners must not be empty".to_owned()));
+        }
+        if let Some(relay) = &self.media.relay {
+            if relay.port_max < relay.port_min.saturating_add(3) {
+                return Err(ConfigError::Invalid("media.relay port range needs at least 4 ports".to_owned()));
+            }
+        }
+        for (index, trunk) in self.trunks.iter().enumerate() {
+            if self.trunks[..index].iter().any(|t| t.name == trunk.name) {
+                return Err(ConfigError::Invalid(format!("duplicated trunk {}", trunk.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Http sync config, only when both sync endpoints are provided
+    pub fn address_book_sync(&self) -> Option<AddressBookSyncConfig> {
+        let book = &self.address_book;
+        Some(AddressBookSyncConfig {
+            numbers_url: book.numbers_sync.clone()?,
+            apps_url: book.apps_sync.clone()?,
+            interval: Duration::from_millis(book.sync_interval_ms),
+        })
+    }
+
+    pub fn sdn_tls(&self) -> ClusterTlsConfig {
+        let tls = &self.sdn.tls;
+        ClusterTlsConfig {
+            cert: tls.cert.clone(),
+            key: tls.key.clone(),
+            self_signed: tls.self_signed,
+            ca: tls.ca.clone(),
+        }
+    }
+
+    pub fn sip_flood(&self) -> SipFloodConfig {
+        let flood = &self.sip.flood;
+        SipFloodConfig {
+            invites_per_second: flood.invites_per_second,
+            ban_threshold: flood.ban_threshold,
+            ban_duration: Duration::from_secs(flood.ban_secs),
+            blocklist: flood.blocklist.clone(),
+            trusted: flood.trusted.clone(),
+        }
+    }
+
+    pub fn media_relay(&self) -> Option<RtpRelayConfig> {
+        let relay = self.media.relay.as_ref()?;
+        Some(RtpRelayConfig {
+            bind_ip: relay.bind_ip,
+            public_ip: relay.public_ip,
+            port_min: relay.port_min,
+            port_max: relay.port_max,
+            sip_latching: relay.sip_latching,
+        })
+    }
+
+    pub fn sip_trace(&self) -> SipTraceConfig {
+        let trace = &self.sip.trace;
+        SipTraceConfig {
+            max_calls: trace.max_calls,
+            hep_collector: trace.hep_collector,
+            hep_capture_id: trace.hep_capture_id,
+        }
+    }
+}
+
+impl CallLimitsConfig {
+    /// Effective limits of an app, None if the app is neither in the address book nor in the config
+    pub fn app(&self, app_id: &str, address_book: Option<CallLimits>) -> Option<CallLimits> {
+        Self::merge(self.apps.get(app_id), address_book, &self.default_app)
+    }
+
+    /// Effective limits of a number, None if the number is neither in the address book nor in the config
+    pub fn number(&self, number: &str, address_book: Option<CallLimits>) -> Option<CallLimits> {
+        Self::merge(self.numbers.get(number), address_book, &self.default_number)
+    }
+
+    fn merge(config: Option<&CallLimits>, address_book: Option<CallLimits>, default: &CallLimits) -> Option<CallLimits> {
+        let limits = match (config, address_book) {
+            (None, None) => return None,
+            (Some(config), None) => config.clone(),
+            (config, Some(address_book)) => {
+                let limits = address_book.or(default);
+                config.map(|c| c.or(&limits)).unwrap_or(limits)
+            }
+        };
+        Some(limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::CallLimits;
+
+    use super::{CallLimitsConfig, Config};
+
+    #[test]
+    fn test_parse_config() {
+        let cfg = Config::parse(
+            r#"
+            log_level = "debug"
+
+            [[call_token_keys]]
+            kid = "2024-10"
+            file = "/etc/gateway/token-2024-10.pem"
+
+            [sip]
+            listeners = ["0.0.0.0:5060", "0.0.0.0:5070"]
+
+            [sip.flood]
+            blocklist = ["10.0.0.0/8"]
+
+            [[trunks]]
+            name = "carrier1"
+            server = "sip.carrier1.com:5060"
+            auth = { username = "user", password = "pass" }
+
+            [address_book]
+            numbers_sync = "http://localhost/numbers"
+            apps_sync = "http://localhost/apps"
+
+            [limits.default_app]
+            max_concurrent_calls = 100
+
+            [media]
+            gateway = "http://localhost:3000"
+
+            [media.relay]
+            public_ip = "203.0.113.5"
+            sip_latching = true
+            "#,
+        )
+        .expect("should parse config");
+        assert_eq!(cfg.log_level, "debug");
+        assert_eq!(cfg.call_token_keys[0].kid, "2024-10");
+        assert_eq!(cfg.sip.listeners.len(), 2);
+        assert_eq!(cfg.sip.flood.invites_per_second, 20);
+        assert_eq!(cfg.sip_flood().blocklist.len(), 1);
+        assert_eq!(cfg.trunks[0].name, "carrier1");
+        assert!(cfg.trunks[0].auth.is_some());
+        assert_eq!(cfg.limits.default_app.max_concurrent_calls, Some(100));
+        assert_eq!(cfg.http.addr.port(), 8008);
+        let sync = cfg.address_book_sync().expect("should have sync config");
+        assert_eq!(sync.numbers_url, "http://localhost/numbers");
+        assert_eq!(sync.interval.as_millis(), 30_000);
+        let relay = cfg.media_relay().expect("should have relay config");
+        assert_eq!(relay.public_ip, Some("203.0.113.5".parse().expect("should parse ip")));
+        assert_eq!((relay.port_min, relay.port_max), (40000, 40999));
+        assert!(relay.sip_latching);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Config::parse("").is_err(), "media gateway is required");
+        assert!(Config::parse("unknown = 1\n[media]\ngateway = \"http://localhost\"").is_err());
+        assert!(Config::parse("[media]\ngateway = \"http://localhost\"\n[[trunks]]\nname = \"a\"\nserver = \"s1\"\n[[trunks]]\nname = \"a\"\nserver = \"s2\"").is_err());
+        assert!(Config::parse("[media]\ngateway = \"http://localhost\"\n[media.relay]\nport_min = 40000\nport_max = 40001").is_err());
+    }
+
+    #[test]
+    fn test_merge_limits() {
+        let limits = |cps: Option<u32>, concurrent: Option<u32>| CallLimits {
+            calls_per_second: cps,
+            max_concurrent_calls: concurrent,
+            max_concurrent_outgoing_calls: None,
+        };
+        let mut cfg = CallLimitsConfig {
+            default_app: limits(Some(10), Some(100)),
+            ..Default::default()
+        };
+        cfg.apps.insert("app2".to_owned(), limits(None, Some(5)));
+
+        assert_eq!(cfg.app("unknown", None), None);
+        assert_eq!(cfg.app("app1", Some(limits(Some(1), None))), Some(limits(Some(1), Some(100))));
+        assert_eq!(cfg.app("app2", Some(CallLimits::default())), Some(limits(Some(10), Some(5))));
+        assert_eq!(cfg.app("app2", None), Some(limits(None, Some(5))));
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use spin::RwLock;
+
+/// Time after the drain deadline for remaining calls to be ended before the node exits anyway
+const FORCE_EXIT_GRACE: Duration = Duration::from_secs(10);
+
+struct DrainInternal {
+    started_at: Instant,
+    deadline: Instant,
+    calls_ended: bool,
+}
+
+/// Drain mode of this node, new calls are refused while active calls are waited until the deadline
+#[derive(Clone, Default)]
+pub struct DrainState {
+    internal: Arc<RwLock<Option<DrainInternal>>>,
+}
+
+impl DrainState {
+    /// Start draining, return false if the node is already draining
+    pub fn start(&self, timeout: Duration) -> bool {
+        let mut internal = self.internal.write();
+        if internal.is_some() {
+            return false;
+        }
+        let now = Instant::now();
+        *internal = Some(DrainInternal {
+            started_at: now,
+            deadline: now + timeout,
+            calls_ended: false,
+        });
+        true
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.internal.read().is_some()
+    }
+
+    /// Return true only once after the deadline is passed, remaining calls should be ended at that time
+    pub fn take_deadline_passed(&self) -> bool {
+        let mut internal = self.internal.write();
+        match internal.as_mut() {
+            Some(drain) if !drain.calls_ended && Instant::now() >= drain.deadline => {
+                drain.calls_ended = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drain is finished when all calls are ended or remaining calls cannot be ended after the grace time
+    pub fn is_finished(&self, active_calls: usize) -> bool {
+        match self.internal.read().as_ref() {
+            Some(drain) => active_calls == 0 || Instant::now() >= drain.deadline + FORCE_EXIT_GRACE,
+            None => false,
+        }
+    }
+
+    /// Elapsed and remaining time to the deadline, None if not draining
+    pub fn progress(&self) -> Option<(Duration, Duration)> {
+        let now = Instant::now();
+        self.internal
+            .read()
+            .as_ref()
+            .map(|drain| (now.duration_since(drain.started_at), drain.deadline.saturating_duration_since(now)))
+    }
+}
@@ -0,0 +1,64 @@
+use std::{sync::Arc, time::Duration};
+
+use spin::RwLock;
+use tokio::time::sleep;
+
+#[derive(Default)]
+struct HealthInternal {
+    sip_bound: bool,
+    media_gateway: Option<Result<(), String>>,
+}
+
+/// Dependency states which are updated by the gateway and background probes, then read by readiness checks
+#[derive(Clone, Default)]
+pub struct HealthState {
+    internal: Arc<RwLock<HealthInternal>>,
+}
+
+impl HealthState {
+    pub fn set_sip_bound(&self) {
+        self.internal.write().sip_bound = true;
+    }
+
+    pub fn sip_bound(&self) -> bool {
+        self.internal.read().sip_bound
+    }
+
+    pub fn set_media_gateway(&self, res: Result<(), String>) {
+        self.internal.write().media_gateway = Some(res);
+    }
+
+    /// Last media gateway probe result, None if it is not probed yet
+    pub fn media_gateway(&self) -> Option<Result<(), String>> {
+        self.internal.read().media_gateway.clone()
+    }
+}
+
+/// Periodically request the media gateway, any http response means it is reachable
+pub struct MediaGatewayProbe {
+    url: String,
+    interval: Duration,
+    health: HealthState,
+}
+
+impl MediaGatewayProbe {
+    pub fn new(url: &str, interval: Duration, health: HealthState) -> Self {
+        Self {
+            url: url.to_owned(),
+            interval,
+            health,
+        }
+    }
+
+    pub async fn run_loop(&mut self) {
+        let client = reqwest::ClientBuilder::default().timeout(self.interval / 2).build().expect("Should build client");
+        loop {
+            let res = client.get(&self.url).send().await.map(|_| ()).map_err(|e| e.to_string());
+            if let Err(e) = &res {
+                log::warn!("[MediaGatewayProbe] media gateway {} unreachable {e}", self.url);
+            }
+            self.health.set_media_gateway(res);
+            sleep(self.interval).await;
+        }
+    }
+}
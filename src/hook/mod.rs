@@ -1,6 +1,7 @@
 use std::{collections::HashMap, marker::PhantomData};
 
 use queue::{new_queue, HttpHookRequest};
+use reqwest::header::{HeaderName, HeaderValue};
 use tokio::sync::mpsc::UnboundedSender;
 
 mod queue;
@@ -8,6 +9,24 @@ mod sender;
 
 pub use sender::HttpHookSender;
 
+/// Prefix of forwarded SIP headers in hook requests, so a header from the carrier can't override Content-Type, Authorization or Host
+pub const SIP_HEADER_PREFIX: &str = "X-Sip-";
+
+/// Http headers of hook requests from forwarded SIP headers, names are prefixed and pairs which are not valid http headers are skipped
+pub fn sip_hook_headers<'a>(headers: impl IntoIterator<Item = &'a (String, String)>) -> HashMap<String, String> {
+    headers
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let name = format!("{SIP_HEADER_PREFIX}{name}");
+            if HeaderName::from_bytes(name.as_bytes()).is_err() || HeaderValue::from_str(value).is_err() {
+                log::warn!("[HttpHook] skip forwarded header {name} which is not a valid http header");
+                return None;
+            }
+            Some((name, value.clone()))
+        })
+        .collect()
+}
+
 pub struct HttpHook {
     queues: Vec<UnboundedSender<HttpHookRequest>>,
 }
@@ -32,3 +51,22 @@ impl HttpHook {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::sip_hook_headers;
+
+    #[test]
+    fn test_sip_hook_headers() {
+        let headers = vec![
+            ("X-Correlation-Id".to_owned(), "abc".to_owned()),
+            ("Content-Type".to_owned(), "text/plain".to_owned()),
+            ("Bad Name".to_owned(), "value".to_owned()),
+            ("User-to-User".to_owned(), "a\r\nHost: evil".to_owned()),
+        ];
+        let hook_headers = sip_hook_headers(&headers);
+        assert_eq!(hook_headers.len(), 2);
+        assert_eq!(hook_headers.get("X-Sip-X-Correlation-Id").map(|v| v.as_str()), Some("abc"));
+        assert_eq!(hook_headers.get("X-Sip-Content-Type").map(|v| v.as_str()), Some("text/plain"));
+    }
+}
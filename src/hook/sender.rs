@@ -12,6 +12,17 @@ pub struct HttpHookSender<Event> {
     pub _tmp: PhantomData<Event>,
 }
 
+impl<Event> Clone for HttpHookSender<Event> {
+    fn clone(&self) -> Self {
+        Self {
+            endpoint: self.endpoint.clone(),
+            headers: self.headers.clone(),
+            tx: self.tx.clone(),
+            _tmp: PhantomData,
+        }
+    }
+}
+
 impl<Event: Serialize> HttpHookSender<Event> {
     pub fn send(&self, body: &Event) {
         self.tx
@@ -0,0 +1,224 @@
+use std::{sync::Arc, time::Duration};
+
+use atm0s_small_p2p::pubsub_service::PubsubServiceRequester;
+use poem_openapi::{
+    param::Path,
+    payload::{Attachment, AttachmentType, Json, PlainText},
+    OpenApi,
+};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{
+    address_book::AddressBookUpdater,
+    cluster::AdminRpcClient,
+    protocol::{
+        protobuf::sip_gateway::{
+            incoming_call_data::{incoming_call_request, incoming_call_response},
+            outgoing_call_data::{outgoing_call_request, outgoing_call_response},
+        },
+        AddressBookChange, AdminApiError, AdminAppInfo, AdminCallInfo, AdminDrainRequest, AdminPhoneNumber, AdminRelaySession, AdminSipBan, AdminSipLadderEntry, AppInfo, CallDirection,
+        IncomingCallActionResponse, InternalCallId, OutgoingCallActionResponse,
+    },
+    secure::SecureContext,
+    sip::{to_pcap, to_text, MediaBackends, SipTraceDirection, SipTraceEntry},
+};
+
+use super::{
+    header_secret::TokenAuthorization,
+    response_result::{ApiRes, ApiResError},
+    HttpCommand,
+};
+
+const RPC_TIMEOUT_SECONDS: u64 = 2;
+
+pub struct AdminApis {
+    pub secure_ctx: Arc<SecureContext>,
+    pub address_book: AddressBookUpdater,
+    pub admin_rpc: AdminRpcClient,
+    pub call_pubsub: PubsubServiceRequester,
+    pub tx: Sender<HttpCommand>,
+    pub media_backends: MediaBackends,
+}
+
+impl AdminApis {
+    fn check_root(&self, secret: &TokenAuthorization) -> Result<(), AdminApiError> {
+        if self.secure_ctx.check_root_secret(&secret.0.token) {
+            Ok(())
+        } else {
+            Err(AdminApiError::WrongSecret)
+        }
+    }
+
+    async fn find_call(&self, call_id: &str) -> Result<AdminCallInfo, AdminApiError> {
+        self.admin_rpc.list_calls().await.into_iter().find(|c| c.call_id == call_id).ok_or(AdminApiError::NotFound)
+    }
+
+    async fn sip_trace(&self, call_id: &str) -> Result<Vec<SipTraceEntry>, AdminApiError> {
+        self.admin_rpc.sip_trace(call_id).await.ok_or(AdminApiError::NotFound)
+    }
+}
+
+#[OpenApi]
+impl AdminApis {
+    /// Create or update an app, the change is replicated to all gateway nodes. The media backend must be registered on this node
+    #[oai(path = "/address_book/apps", method = "put")]
+    async fn upsert_app(&self, secret: TokenAuthorization, data: Json<AdminAppInfo>) -> ApiRes<String, AdminApiError> {
+        self.check_root(&secret)?;
+        let app: AppInfo = data.0.try_into()?;
+        if let Some(name) = app.media_backend.as_deref().filter(|name| !self.media_backends.has(name)) {
+            return Err(AdminApiError::BadRequest(format!("unknown media_backend {name}")).into());
+        }
+        self.address_book.apply(AddressBookChange::UpsertApp(app));
+        Ok("OK".to_owned().into())
+    }
+
+    #[oai(path = "/address_book/apps/:app_id", method = "delete")]
+    async fn delete_app(&self, secret: TokenAuthorization, Path(app_id): Path<String>) -> ApiRes<String, AdminApiError> {
+        self.check_root(&secret)?;
+        if !self.address_book.storage().has_app(&app_id) {
+            return Err(AdminApiError::NotFound.into());
+        }
+        self.address_book.apply(AddressBookChange::DeleteApp(app_id));
+        Ok("OK".to_owned().into())
+    }
+
+    /// Create or update a phone number, the change is replicated to all gateway nodes
+    #[oai(path = "/address_book/numbers", method = "put")]
+    async fn upsert_number(&self, secret: TokenAuthorization, data: Json<AdminPhoneNumber>) -> ApiRes<String, AdminApiError> {
+        self.check_root(&secret)?;
+        let number = data.0.try_into()?;
+        self.address_book.apply(AddressBookChange::UpsertNumber(number));
+        Ok("OK".to_owned().into())
+    }
+
+    #[oai(path = "/address_book/numbers/:number", method = "delete")]
+    async fn delete_number(&self, secret: TokenAuthorization, Path(number): Path<String>) -> ApiRes<String, AdminApiError> {
+        self.check_root(&secret)?;
+        if !self.address_book.storage().has_number(&number) {
+            return Err(AdminApiError::NotFound.into());
+        }
+        self.address_book.apply(AddressBookChange::DeleteNumber(number));
+        Ok("OK".to_owned().into())
+    }
+
+    /// List calls of all gateway nodes
+    #[oai(path = "/calls", method = "get")]
+    async fn list_calls(&self, secret: TokenAuthorization) -> ApiRes<Vec<AdminCallInfo>, AdminApiError> {
+        self.check_root(&secret)?;
+        Ok(self.admin_rpc.list_calls().await.into())
+    }
+
+    #[oai(path = "/calls/:call_id", method = "get")]
+    async fn get_call(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> ApiRes<AdminCallInfo, AdminApiError> {
+        self.check_root(&secret)?;
+        Ok(self.find_call(&call_id).await?.into())
+    }
+
+    /// Force terminate a call, the call is ended by the node which is handling it
+    #[oai(path = "/calls/:call_id", method = "delete")]
+    async fn kill_call(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> ApiRes<String, AdminApiError> {
+        self.check_root(&secret)?;
+        let call = self.find_call(&call_id).await?;
+        log::warn!("[AdminApis] force terminate {:?} call {call_id} on node {}", call.direction, call.node);
+        let channel = InternalCallId::from(call_id).to_pubsub_channel();
+        let timeout = Duration::from_secs(RPC_TIMEOUT_SECONDS);
+        match call.direction {
+            CallDirection::Outgoing => {
+                let req = outgoing_call_request::Action::End(Default::default());
+                let res = self
+                    .call_pubsub
+                    .feedback_rpc_as_guest_ob::<_, outgoing_call_response::Response>(channel, "destroy", &req, timeout)
+                    .await
+                    .map_err(|e| AdminApiError::InternalChannel(e.to_string()))?;
+                let _: OutgoingCallActionResponse = res.try_into().map_err(AdminApiError::CallError)?;
+            }
+            CallDirection::Incoming => {
+                let req = incoming_call_request::Action::End(Default::default());
+                let res = self
+                    .call_pubsub
+                    .feedback_rpc_as_guest_ob::<_, incoming_call_response::Response>(channel, "destroy", &req, timeout)
+                    .await
+                    .map_err(|e| AdminApiError::InternalChannel(e.to_string()))?;
+                let _: IncomingCallActionResponse = res.try_into().map_err(AdminApiError::CallError)?;
+            }
+        }
+        Ok("OK".to_owned().into())
+    }
+
+    /// Captured SIP messages of a call as raw text, the call can be ended but must still be in the trace store of its node
+    #[oai(path = "/calls/:call_id/sip_trace", method = "get")]
+    async fn get_sip_trace(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> Result<PlainText<String>, ApiResError<AdminApiError>> {
+        self.check_root(&secret)?;
+        Ok(PlainText(to_text(&self.sip_trace(&call_id).await?)))
+    }
+
+    /// Captured SIP messages of a call as a ladder, without message bodies
+    #[oai(path = "/calls/:call_id/sip_ladder", method = "get")]
+    async fn get_sip_ladder(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> ApiRes<Vec<AdminSipLadderEntry>, AdminApiError> {
+        self.check_root(&secret)?;
+        let ladder = self
+            .sip_trace(&call_id)
+            .await?
+            .into_iter()
+            .map(|entry| AdminSipLadderEntry {
+                ts_us: entry.ts_us,
+                direction: match entry.direction {
+                    SipTraceDirection::In => "in".to_owned(),
+                    SipTraceDirection::Out => "out".to_owned(),
+                },
+                src: entry.src().to_string(),
+                dst: entry.dst().to_string(),
+                sip_call_id: entry.sip_call_id,
+                summary: entry.first_line,
+            })
+            .collect::<Vec<_>>();
+        Ok(ladder.into())
+    }
+
+    /// Captured SIP messages of a call as a pcap file with synthetic IP/UDP headers
+    #[oai(path = "/calls/:call_id/sip_trace.pcap", method = "get")]
+    async fn get_sip_pcap(&self, secret: TokenAuthorization, Path(call_id): Path<String>) -> Result<Attachment<Vec<u8>>, ApiResError<AdminApiError>> {
+        self.check_root(&secret)?;
+        let pcap = to_pcap(&self.sip_trace(&call_id).await?);
+        Ok(Attachment::new(pcap).attachment_type(AttachmentType::Attachment).filename(format!("{call_id}.pcap")))
+    }
+
+    /// List source ips which are banned by the sip flood filter of all nodes
+    #[oai(path = "/sip/bans", method = "get")]
+    async fn list_bans(&self, secret: TokenAuthorization) -> ApiRes<Vec<AdminSipBan>, AdminApiError> {
+        self.check_root(&secret)?;
+        Ok(self.admin_rpc.list_bans().await.into())
+    }
+
+    #[oai(path = "/sip/bans/:ip", method = "delete")]
+    async fn unban(&self, secret: TokenAuthorization, Path(ip): Path<String>) -> ApiRes<String, AdminApiError> {
+        self.check_root(&secret)?;
+        let ip = ip.parse().map_err(|_| AdminApiError::BadRequest(format!("invalid ip {ip}")))?;
+        if !self.admin_rpc.unban(ip).await {
+            return Err(AdminApiError::NotFound.into());
+        }
+        Ok("OK".to_owned().into())
+    }
+
+    /// List sessions of the built-in RTP relay of all nodes with their packet counters
+    #[oai(path = "/media/relay/sessions", method = "get")]
+    async fn list_relay_sessions(&self, secret: TokenAuthorization) -> ApiRes<Vec<AdminRelaySession>, AdminApiError> {
+        self.check_root(&secret)?;
+        Ok(self.admin_rpc.relay_sessions().await.into())
+    }
+
+    /// Drain this node: new calls are refused, active calls are ended after the timeout then the node exits
+    #[oai(path = "/drain", method = "post")]
+    async fn drain(&self, secret: TokenAuthorization, data: Json<AdminDrainRequest>) -> ApiRes<String, AdminApiError> {
+        self.check_root(&secret)?;
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(HttpCommand::Drain(data.0.timeout_secs.map(Duration::from_secs), tx))
+            .await
+            .map_err(|e| AdminApiError::InternalChannel(e.to_string()))?;
+        if !rx.await.map_err(|e| AdminApiError::InternalChannel(e.to_string()))? {
+            return Err(AdminApiError::BadRequest("already draining".to_owned()).into());
+        }
+        Ok("OK".to_owned().into())
+    }
+}
@@ -6,15 +6,17 @@ use poem_openapi::{param::Path, payload::Json, OpenApi};
 use tokio::sync::{mpsc::Sender, oneshot};
 
 use crate::{
+    cluster::AdminRpcClient,
     protocol::{
         protobuf::sip_gateway::{
             incoming_call_data::{incoming_call_request, incoming_call_response},
             outgoing_call_data::{outgoing_call_request, outgoing_call_response},
         },
-        CallApiError, CreateCallRequest, CreateCallResponse, IncomingCallActionRequest, IncomingCallActionResponse, OutgoingCallActionRequest, OutgoingCallActionResponse,
+        CallApiError, CallDirection, CreateCallRequest, CreateCallResponse, CreateCallTokenRequest, CreateCallTokenResponse, IncomingCallActionResponse, IncomingCallControlRequest,
+        OutgoingCallActionRequest, OutgoingCallActionResponse, RevokeCallTokenRequest,
     },
-    secure::SecureContext,
-    sip::MediaApi,
+    secure::{CallToken, SecureContext},
+    sip::MediaBackends,
 };
 
 use super::{header_secret::TokenAuthorization, response_result::ApiRes, HttpCommand};
@@ -22,22 +24,37 @@ use super::{header_secret::TokenAuthorization, response_result::ApiRes, HttpComm
 const RPC_TIMEOUT_SECONDS: u64 = 2;
 
 pub struct CallApis {
-    pub media_gateway: String,
+    pub media_backends: MediaBackends,
     pub secure_ctx: Arc<SecureContext>,
     pub tx: Sender<HttpCommand>,
     pub call_pubsub: PubsubServiceRequester,
+    pub admin_rpc: AdminRpcClient,
+}
+
+impl CallApis {
+    /// Decode the token and check it can control the call
+    fn check_call_token(&self, token: &str, call_id: &str) -> Result<CallToken, CallApiError> {
+        let token = self.secure_ctx.decode_call_token(token).ok_or(CallApiError::WrongToken)?;
+        if *token.call_id != call_id {
+            return Err(CallApiError::WrongToken);
+        }
+        if !token.can_control() {
+            return Err(CallApiError::WrongTokenScope);
+        }
+        Ok(token)
+    }
 }
 
 #[OpenApi]
 impl CallApis {
     #[oai(path = "/outgoing", method = "post")]
     async fn create_call(&self, secret: TokenAuthorization, data: Json<CreateCallRequest>) -> ApiRes<CreateCallResponse, CallApiError> {
-        let _app_id: crate::protocol::AppId = self.secure_ctx.check_secret(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret.into())?;
-        let media_api = MediaApi::new(&self.media_gateway, &secret.0.token);
+        let app_id: crate::protocol::AppId = self.secure_ctx.check_secret(&secret.0.token).ok_or::<CallApiError>(CallApiError::WrongSecret.into())?;
+        let media_api = self.media_backends.api(&app_id, Some(&secret.0.token)).map_err(|e| CallApiError::BadRequest(e.to_string()))?;
 
         let (tx, rx) = oneshot::channel();
         self.tx
-            .send(HttpCommand::CreateCall(data.0, media_api, tx))
+            .send(HttpCommand::CreateCall(data.0, media_api, app_id, tx))
             .await
             .map_err(|e| CallApiError::InternalChannel(e.to_string()))?;
 
@@ -45,19 +62,53 @@ impl CallApis {
         Ok(res.into())
     }
 
-    #[oai(path = "/outgoing/:call_id/action", method = "post")]
-    async fn action_outcall(&self, Path(call_id): Path<String>, Query(token): Query<String>, data: Json<OutgoingCallActionRequest>) -> ApiRes<OutgoingCallActionResponse, CallApiError> {
-        let token = if let Some(token) = self.secure_ctx.decode_call_token(&token) {
-            if *token.call_id != call_id {
-                return Err(CallApiError::WrongToken.into());
-            }
-            token
-        } else {
+    /// Mint an extra token for a call, for example an observe-only token for a supervisor
+    #[oai(path = "/tokens", method = "post")]
+    async fn create_call_token(&self, secret: TokenAuthorization, data: Json<CreateCallTokenRequest>) -> ApiRes<CreateCallTokenResponse, CallApiError> {
+        let app_id = self.secure_ctx.check_secret(&secret.0.token).ok_or(CallApiError::WrongSecret)?;
+        let parent = self.secure_ctx.inspect_call_token(&data.0.call_token).ok_or(CallApiError::WrongToken)?;
+        let parent_expires_at = parent.expires_at;
+        let parent = parent.token;
+        if parent.app_id != *app_id {
             return Err(CallApiError::WrongToken.into());
+        }
+        if !parent.can_control() {
+            return Err(CallApiError::WrongTokenScope.into());
+        }
+
+        let ttl = self.secure_ctx.child_token_ttl(&app_id, parent_expires_at, data.0.ttl_secs);
+        let direction = match parent.direction {
+            CallDirection::Outgoing => "outgoing",
+            CallDirection::Incoming => "incoming",
         };
+        let call_id = parent.call_id.clone();
+        let call_token = self.secure_ctx.encode_call_token(CallToken { scope: data.0.scope, ..parent }, ttl);
+        Ok(CreateCallTokenResponse {
+            call_ws: format!("/call/{direction}/{call_id}?token={call_token}"),
+            call_token,
+            expires_in_secs: ttl,
+        }
+        .into())
+    }
+
+    /// Revoke a call token on all nodes, other tokens of the call are still valid
+    #[oai(path = "/tokens/revoke", method = "post")]
+    async fn revoke_call_token(&self, secret: TokenAuthorization, data: Json<RevokeCallTokenRequest>) -> ApiRes<String, CallApiError> {
+        let app_id = self.secure_ctx.check_secret(&secret.0.token).ok_or(CallApiError::WrongSecret)?;
+        let info = self.secure_ctx.inspect_call_token(&data.0.call_token).ok_or(CallApiError::WrongToken)?;
+        if info.token.app_id != *app_id {
+            return Err(CallApiError::WrongToken.into());
+        }
+        self.admin_rpc.revoke_call_token(&info.token_id, info.expires_at).await;
+        Ok("OK".to_owned().into())
+    }
+
+    #[oai(path = "/outgoing/:call_id/action", method = "post")]
+    async fn action_outcall(&self, Path(call_id): Path<String>, Query(token): Query<String>, data: Json<OutgoingCallActionRequest>) -> ApiRes<OutgoingCallActionResponse, CallApiError> {
+        let token = self.check_call_token(&token, &call_id)?;
 
         let channel = token.call_id.to_pubsub_channel();
-        let req: outgoing_call_request::Action = data.0.try_into().map_err(|e| CallApiError::BadRequest(e))?;
+        let req: outgoing_call_request::Action = data.0.try_into().map_err(|e| CallApiError::BadRequest(e.to_owned()))?;
         let res = self
             .call_pubsub
             .feedback_rpc_as_guest_ob::<_, outgoing_call_response::Response>(channel, "action", &req, Duration::from_secs(RPC_TIMEOUT_SECONDS))
@@ -68,18 +119,11 @@ impl CallApis {
     }
 
     #[oai(path = "/incoming/:call_id/action", method = "post")]
-    async fn action_incall(&self, Path(call_id): Path<String>, Query(token): Query<String>, data: Json<IncomingCallActionRequest>) -> ApiRes<IncomingCallActionResponse, CallApiError> {
-        let token = if let Some(token) = self.secure_ctx.decode_call_token(&token) {
-            if *token.call_id != call_id {
-                return Err(CallApiError::WrongToken.into());
-            }
-            token
-        } else {
-            return Err(CallApiError::WrongToken.into());
-        };
+    async fn action_incall(&self, Path(call_id): Path<String>, Query(token): Query<String>, data: Json<IncomingCallControlRequest>) -> ApiRes<IncomingCallActionResponse, CallApiError> {
+        let token = self.check_call_token(&token, &call_id)?;
 
         let channel = token.call_id.to_pubsub_channel();
-        let req: incoming_call_request::Action = data.0.try_into().map_err(|e| CallApiError::BadRequest(e))?;
+        let req: incoming_call_request::Action = data.0.try_into().map_err(|e| CallApiError::BadRequest(e.to_owned()))?;
         let res = self
             .call_pubsub
             .feedback_rpc_as_guest_ob::<_, incoming_call_response::Response>(channel, "action", &req, Duration::from_secs(RPC_TIMEOUT_SECONDS))
@@ -91,14 +135,7 @@ impl CallApis {
 
     #[oai(path = "/outgoing/:call_id", method = "delete")]
     async fn end_outgoing_call(&self, Query(token): Query<String>, Path(call_id): Path<String>) -> ApiRes<String, CallApiError> {
-        let token = if let Some(token) = self.secure_ctx.decode_call_token(&token) {
-            if *token.call_id != call_id {
-                return Err(CallApiError::WrongToken.into());
-            }
-            token
-        } else {
-            return Err(CallApiError::WrongToken.into());
-        };
+        let token = self.check_call_token(&token, &call_id)?;
 
         let channel = token.call_id.to_pubsub_channel();
         let req = outgoing_call_request::Action::End(Default::default());
@@ -113,14 +150,7 @@ impl CallApis {
 
     #[oai(path = "/incoming/:call_id", method = "delete")]
     async fn end_incoming_call(&self, Query(token): Query<String>, Path(call_id): Path<String>) -> ApiRes<String, CallApiError> {
-        let token = if let Some(token) = self.secure_ctx.decode_call_token(&token) {
-            if *token.call_id != call_id {
-                return Err(CallApiError::WrongToken.into());
-            }
-            token
-        } else {
-            return Err(CallApiError::WrongToken.into());
-        };
+        let token = self.check_call_token(&token, &call_id)?;
 
         let channel = token.call_id.to_pubsub_channel();
         let req = incoming_call_request::Action::End(Default::default());
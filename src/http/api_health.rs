@@ -0,0 +1,71 @@
+use poem_openapi::{payload::Json, ApiResponse, OpenApi};
+
+use crate::{
+    address_book::AddressBookStorage,
+    cluster::ClusterNodes,
+    drain::DrainState,
+    health::HealthState,
+    protocol::{DrainStatus, HealthCheck, HealthReport},
+};
+
+#[derive(ApiResponse)]
+enum ReadyResponse {
+    #[oai(status = 200)]
+    Ready(Json<HealthReport>),
+    #[oai(status = 503)]
+    NotReady(Json<HealthReport>),
+}
+
+#[derive(Clone)]
+pub struct HealthApis {
+    pub drain: DrainState,
+    pub health: HealthState,
+    pub address_book: AddressBookStorage,
+    pub nodes: ClusterNodes,
+    /// Seeds are configured, so at least one peer is required for readiness
+    pub require_peer: bool,
+}
+
+#[OpenApi]
+impl HealthApis {
+    /// Liveness, the http server is responding
+    #[oai(path = "/live", method = "get")]
+    async fn live(&self) -> Json<HealthReport> {
+        Json(HealthReport::new(vec![]))
+    }
+
+    /// Readiness with the state of each dependency, 503 if any check failed
+    #[oai(path = "/ready", method = "get")]
+    async fn ready(&self) -> ReadyResponse {
+        let media_gateway = match self.health.media_gateway() {
+            Some(Ok(())) => HealthCheck::new("media_gateway", true, None),
+            Some(Err(e)) => HealthCheck::new("media_gateway", false, Some(e)),
+            None => HealthCheck::new("media_gateway", false, Some("not probed yet".to_owned())),
+        };
+        let peers = self.nodes.peers().len();
+        let checks = vec![
+            HealthCheck::new("sip_transport", self.health.sip_bound(), None),
+            HealthCheck::new("address_book", self.address_book.is_synced(), (!self.address_book.is_synced()).then(|| "not synced yet".to_owned())),
+            media_gateway,
+            HealthCheck::new("p2p", !self.require_peer || peers > 0, Some(format!("{peers} peers connected"))),
+            HealthCheck::new("not_draining", !self.drain.is_draining(), None),
+        ];
+        let report = HealthReport::new(checks);
+        if report.ok {
+            ReadyResponse::Ready(Json(report))
+        } else {
+            ReadyResponse::NotReady(Json(report))
+        }
+    }
+
+    /// Drain state of this node
+    #[oai(path = "/drain", method = "get")]
+    async fn drain(&self) -> Json<DrainStatus> {
+        let progress = self.drain.progress();
+        Json(DrainStatus {
+            draining: progress.is_some(),
+            elapsed_secs: progress.map(|(elapsed, _)| elapsed.as_secs()),
+            remaining_secs: progress.map(|(_, remaining)| remaining.as_secs()),
+        })
+    }
+}
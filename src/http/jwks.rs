@@ -0,0 +1,14 @@
+use std::sync::Arc;
+
+use poem::{
+    handler,
+    web::{Data, Json},
+};
+
+use crate::secure::{Jwks, SecureContext};
+
+/// Public keys for verifying call tokens, empty when tokens are signed with the gateway secret
+#[handler]
+pub async fn jwks(secure_ctx: Data<&Arc<SecureContext>>) -> Json<Jwks> {
+    Json(secure_ctx.jwks())
+}
@@ -1,11 +1,15 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use crate::{
-    protocol::{CallApiError, CreateCallRequest, CreateCallResponse},
+    address_book::AddressBookUpdater,
+    cluster::AdminRpcClient,
+    config::CallLimitsConfig,
+    protocol::{AdminCallInfo, AppId, CallApiError, CreateCallRequest, CreateCallResponse},
     secure::SecureContext,
-    sip::MediaApi,
+    sip::{MediaApi, MediaBackends},
 };
-use atm0s_small_p2p::pubsub_service::PubsubServiceRequester;
+use atm0s_small_p2p::{pubsub_service::PubsubServiceRequester, PeerId};
+use ipnet::IpNet;
 use poem::{get, listener::TcpListener, middleware::Tracing, EndpointExt, Route, Server};
 use poem_openapi::OpenApiService;
 use tokio::sync::{
@@ -13,54 +17,104 @@ use tokio::sync::{
     oneshot,
 };
 
+mod api_admin;
 mod api_call;
+mod api_health;
 mod header_secret;
+mod jwks;
 mod response_result;
 mod ws_in_call;
 mod ws_out_call;
 
+pub use api_health::HealthApis;
+
 pub enum HttpCommand {
-    CreateCall(CreateCallRequest, MediaApi, oneshot::Sender<Result<CreateCallResponse, CallApiError>>),
+    CreateCall(CreateCallRequest, MediaApi, AppId, oneshot::Sender<Result<CreateCallResponse, CallApiError>>),
+    /// Call which is already placed on this node by another node, it is never forwarded again
+    CreatePlacedCall(CreateCallRequest, MediaApi, AppId, oneshot::Sender<Result<CreateCallResponse, CallApiError>>),
+    ListCalls(oneshot::Sender<Vec<AdminCallInfo>>),
+    /// Start draining with optional timeout, reply false if the node is already draining
+    Drain(Option<Duration>, oneshot::Sender<bool>),
+    DrainTick,
+    /// Grace period of a disconnected node is over, its calls are taken over if it is still disconnected
+    TakeoverCheck(PeerId),
+    /// Reloaded call limits and sip blocklist from the config file
+    Reload(CallLimitsConfig, Vec<IpNet>),
 }
 
 pub struct HttpServer {
     addr: SocketAddr,
-    media_gateway: String,
+    media_backends: MediaBackends,
     secure_ctx: Arc<SecureContext>,
     tx: Sender<HttpCommand>,
     call_pubsub: PubsubServiceRequester,
+    address_book: AddressBookUpdater,
+    admin_rpc: AdminRpcClient,
+    health: HealthApis,
 }
 
 impl HttpServer {
-    pub fn new(addr: SocketAddr, media_gateway: &str, secure_ctx: Arc<SecureContext>, call_pubsub: PubsubServiceRequester) -> (Self, Receiver<HttpCommand>) {
+    pub fn new(
+        addr: SocketAddr,
+        media_backends: MediaBackends,
+        secure_ctx: Arc<SecureContext>,
+        call_pubsub: PubsubServiceRequester,
+        address_book: AddressBookUpdater,
+        admin_rpc: AdminRpcClient,
+        health: HealthApis,
+    ) -> (Self, Sender<HttpCommand>, Receiver<HttpCommand>) {
         let (tx, rx) = channel(10);
         (
             Self {
                 addr,
-                media_gateway: media_gateway.to_owned(),
-                tx,
+                media_backends,
+                tx: tx.clone(),
                 secure_ctx,
                 call_pubsub,
+                address_book,
+                admin_rpc,
+                health,
             },
+            tx,
             rx,
         )
     }
 
     pub async fn run_loop(&mut self) -> io::Result<()> {
         let call_api = api_call::CallApis {
-            media_gateway: self.media_gateway.clone(),
+            media_backends: self.media_backends.clone(),
             tx: self.tx.clone(),
             secure_ctx: self.secure_ctx.clone(),
             call_pubsub: self.call_pubsub.clone(),
+            admin_rpc: self.admin_rpc.clone(),
         };
         let call_service: OpenApiService<_, ()> = OpenApiService::new(call_api, "Console call APIs", env!("CARGO_PKG_VERSION")).server("/").url_prefix("/call");
         let call_ui = call_service.swagger_ui();
         let call_spec = call_service.spec();
 
+        let admin_api = api_admin::AdminApis {
+            secure_ctx: self.secure_ctx.clone(),
+            address_book: self.address_book.clone(),
+            admin_rpc: self.admin_rpc.clone(),
+            call_pubsub: self.call_pubsub.clone(),
+            tx: self.tx.clone(),
+            media_backends: self.media_backends.clone(),
+        };
+        let admin_service: OpenApiService<_, ()> = OpenApiService::new(admin_api, "Admin APIs", env!("CARGO_PKG_VERSION")).server("/").url_prefix("/admin");
+        let admin_ui = admin_service.swagger_ui();
+        let admin_spec = admin_service.spec();
+
+        let health_service: OpenApiService<_, ()> = OpenApiService::new(self.health.clone(), "Health APIs", env!("CARGO_PKG_VERSION")).server("/").url_prefix("/health");
+
         let app = Route::new()
             .nest("/call/", call_service)
             .nest("/docs/call/", call_ui)
             .at("/docs/call/spec", poem::endpoint::make_sync(move |_| call_spec.clone()))
+            .nest("/admin/", admin_service)
+            .nest("/docs/admin/", admin_ui)
+            .at("/docs/admin/spec", poem::endpoint::make_sync(move |_| admin_spec.clone()))
+            .nest("/health/", health_service)
+            .at("/.well-known/jwks.json", get(jwks::jwks).data(self.secure_ctx.clone()))
             .at(
                 "/call/outgoing/:call_id",
                 get(ws_out_call::ws_single_call).data(ws_out_call::WebsocketCallCtx {
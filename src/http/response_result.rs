@@ -1,6 +1,10 @@
 use std::fmt::{Debug, Display};
 
-use poem::{error::ResponseError, IntoResponse};
+use poem::{
+    error::ResponseError,
+    http::{header::RETRY_AFTER, StatusCode},
+    IntoResponse,
+};
 use poem_openapi::{
     payload::Json,
     registry::{MetaResponses, Registry},
@@ -8,6 +12,8 @@ use poem_openapi::{
     ApiResponse, Object,
 };
 
+use crate::protocol::{AdminApiError, CallApiError};
+
 #[derive(Debug, Object)]
 struct ApiSuccessJson<T: Type + ToJSON + ParseFromJSON> {
     pub status: bool,
@@ -60,18 +66,53 @@ impl<E: Display + Debug> Debug for ApiResError<E> {
     }
 }
 
-impl<E> ResponseError for ApiResError<E> {
+/// Http status of api errors, errors are 400 Bad Request by default
+pub trait ApiErrorStatus {
+    fn status(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    /// Retry-After header value in seconds
+    fn retry_after(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl ApiErrorStatus for AdminApiError {}
+
+impl ApiErrorStatus for CallApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            CallApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            CallApiError::Draining | CallApiError::NoNodeAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            CallApiError::WrongTokenScope => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            CallApiError::RateLimited(secs) => Some(*secs),
+            _ => None,
+        }
+    }
+}
+
+impl<E: ApiErrorStatus> ResponseError for ApiResError<E> {
     fn as_response(&self) -> poem::Response
     where
         Self: std::error::Error + Send + Sync + 'static,
     {
         let mut resp = self.to_string().into_response();
         resp.set_status(self.status());
+        if let Some(secs) = self.0.retry_after() {
+            resp.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
         resp
     }
 
-    fn status(&self) -> poem::http::StatusCode {
-        poem::http::StatusCode::BAD_REQUEST
+    fn status(&self) -> StatusCode {
+        self.0.status()
     }
 }
 
@@ -9,7 +9,10 @@ use crate::{
         InternalCallId,
     },
     secure::SecureContext,
-    utils::select3::{self, OrOutput},
+    utils::{
+        select2,
+        select3::{self, OrOutput},
+    },
 };
 
 use atm0s_small_p2p::pubsub_service::{PubsubServiceRequester, SubscriberEventOb};
@@ -17,7 +20,7 @@ use futures_util::{SinkExt, StreamExt};
 use poem::{
     handler,
     web::{
-        websocket::{Message as WebsocketMessage, WebSocket},
+        websocket::{CloseCode, Message as WebsocketMessage, WebSocket},
         Data, Path, Query,
     },
     IntoResponse, Response,
@@ -43,21 +46,29 @@ struct WsQuery {
 #[handler]
 pub async fn ws_single_call(Path(call_id): Path<String>, Query(query): Query<WsQuery>, ws: WebSocket, data: Data<&WebsocketCallCtx>) -> impl IntoResponse {
     let token = query.token;
-    if let Some(token) = data.secure_ctx.decode_call_token(&token) {
-        if *token.call_id != call_id {
+    // observe-only tokens receive events but cannot send actions
+    let (can_control, token_id) = if let Some(info) = data.secure_ctx.inspect_call_token(&token) {
+        if *info.token.call_id != call_id {
             return Response::builder().status(StatusCode::BAD_REQUEST).finish();
         }
+        (info.token.can_control(), info.token_id)
     } else {
         return Response::builder().status(StatusCode::UNAUTHORIZED).finish();
-    }
+    };
 
     let call_id: InternalCallId = call_id.into();
     let mut subscriber = data.call_pubsub.subscriber(call_id.to_pubsub_channel()).await;
+    let secure_ctx = data.secure_ctx.clone();
     ws.on_upgrade(move |socket| async move {
         let (mut sink, mut stream) = socket.split();
         let (out_tx, mut out_rx) = unbounded_channel();
         loop {
-            let out = select3::or(subscriber.recv_ob::<IncomingCallEvent>(), out_rx.recv(), stream.next()).await;
+            let out = select3::or(
+                subscriber.recv_ob::<IncomingCallEvent>(),
+                select2::or(out_rx.recv(), secure_ctx.token_revoked(&token_id)),
+                stream.next(),
+            )
+            .await;
             match out {
                 OrOutput::Left(Ok(event)) => match event {
                     SubscriberEventOb::PeerJoined(peer_src) => {
@@ -79,7 +90,7 @@ pub async fn ws_single_call(Path(call_id): Path<String>, Query(query): Query<WsQ
                 OrOutput::Left(_) => {
                     break;
                 }
-                OrOutput::Middle(event) => match event {
+                OrOutput::Middle(select2::OrOutput::Left(event)) => match event {
                     Some(msg) => {
                         let data = msg.encode_to_vec();
                         log::info!("[WsCall {call_id}] emit data {msg:?}");
@@ -90,12 +101,28 @@ pub async fn ws_single_call(Path(call_id): Path<String>, Query(query): Query<WsQ
                     }
                     None => break,
                 },
+                OrOutput::Middle(select2::OrOutput::Right(())) => {
+                    log::info!("[WsCall {call_id}] token revoked => close socket");
+                    let _ = sink.send(WebsocketMessage::Close(Some((CloseCode::Policy, "token revoked".to_owned())))).await;
+                    break;
+                }
                 OrOutput::Right(Some(Ok(message))) => {
                     if let WebsocketMessage::Binary(msg) = message {
                         match IncomingCallData::decode(msg.as_slice()) {
                             Ok(data) => match data.data {
                                 Some(incoming_call_data::Data::Request(req)) => {
                                     log::info!("[WsCall {call_id}] on incoming req {} {:?}", req.req_id, req.action);
+                                    if !can_control {
+                                        let _ = out_tx.send(IncomingCallData {
+                                            data: Some(incoming_call_data::Data::Response(IncomingCallResponse {
+                                                req_id: req.req_id,
+                                                response: Some(incoming_call_response::Response::Error(incoming_call_response::Error {
+                                                    message: "token scope does not allow actions".to_owned(),
+                                                })),
+                                            })),
+                                        });
+                                        continue;
+                                    }
                                     let subscriber = subscriber.requester().clone();
                                     let call_id = call_id.clone();
                                     let out_tx = out_tx.clone();
@@ -1,17 +1,43 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use atm0s_small_p2p::{pubsub_service::PubsubService, NetworkAddress, P2pNetwork, P2pNetworkConfig, P2pNetworkEvent, PeerAddress, PeerId, SharedKeyHandshake};
-use call_manager::CallManager;
+use address_book::AddressBookReplicator;
+use atm0s_small_p2p::{pubsub_service::PubsubService, NetworkAddress, P2pNetwork, P2pNetworkConfig, P2pNetworkEvent, PeerAddress, PeerId};
+use call_manager::{outgoing_call_response, CallManager};
+use cluster::{
+    AdminRpcClient, AdminRpcServer, ClusterHandshake, ClusterIdentity, ClusterNodes, NodePlacement, PlacementSync, PlacementTable, ReplicaSync, ReplicaTable, RevocationSync, Takeover, TAKEOVER_GRACE,
+    TAKEOVER_RETRY,
+};
+use drain::DrainState;
+use health::{HealthState, MediaGatewayProbe};
 use hook::HttpHook;
-use http::{HttpCommand, HttpServer};
-use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use http::{HealthApis, HttpCommand, HttpServer};
+use ipnet::IpNet;
+use protocol::{AppId, CallApiError, CreateCallRequest, CreateCallResponse};
+use sip::{trunk_sources, MediaApi, MediaBackends, RtpRelay, SipFloodFilter, SipServer, SipTracer, RELAY_BACKEND};
 use thiserror::Error;
-use tokio::sync::mpsc::Receiver;
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::sleep,
+};
 use utils::select3;
 
 mod address_book;
 mod call_manager;
+mod cluster;
+mod config;
+mod drain;
 mod error;
+mod health;
 mod hook;
 mod http;
 mod protocol;
@@ -19,18 +45,36 @@ mod secure;
 mod sip;
 mod utils;
 
-pub use address_book::{AddressBookStorage, AddressBookSync};
-pub use secure::SecureContext;
+pub use address_book::{load_snapshot, AddressBookFile, AddressBookStorage, AddressBookSync, AddressBookSyncConfig};
+pub use cluster::{ClusterTlsConfig, ClusterTlsError};
+pub use config::{CallLimitsConfig, Config, ConfigError, RelaySection, SipTrunk};
+pub use protocol::{protobuf, PhoneNumber, StreamingInfo};
+pub use secure::{CallTokenKeyConfig, CallTokenKeys, SecureContext, TokenKeyError};
+pub use sip::{Atm0sMediaBackend, HttpRtpEngine, MediaApiError, MediaBackend, MediaEngineError, RecordingAction, RtpEngine, RtpRelayConfig, SipFloodConfig, SipTraceConfig};
+/// Test doubles for the media engine, only for tests of this crate and of apps which embed the gateway
+#[cfg(any(test, feature = "test-utils"))]
+pub use sip::{FakeRtpEngine, RtpEngineCall, FAKE_ANSWER_SDP, FAKE_OFFER_SDP};
 
 pub const DEFAULT_CLUSTER_CERT: &[u8] = include_bytes!("../certs/dev.cluster.cert");
 pub const DEFAULT_CLUSTER_KEY: &[u8] = include_bytes!("../certs/dev.cluster.key");
 
+const CALL_PUBSUB_SERVICE: u16 = 0;
+const ADDRESS_BOOK_PUBSUB_SERVICE: u16 = 1;
+const ADMIN_PUBSUB_SERVICE: u16 = 2;
+const MEDIA_GATEWAY_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Error, Debug)]
 pub enum GatewayError {
     #[error("IoError {0}")]
     Io(#[from] io::Error),
     #[error("SipError {0}")]
     Sip(#[from] sip::SipServerError),
+    #[error("ClusterTlsError {0}")]
+    ClusterTls(#[from] ClusterTlsError),
+    #[error("ConfigError {0}")]
+    Config(#[from] ConfigError),
+    #[error("TokenKeyError {0}")]
+    TokenKey(#[from] TokenKeyError),
     #[error("QueueError")]
     Queue,
     #[error("Anyhow({0})")]
@@ -39,75 +83,340 @@ pub enum GatewayError {
 
 pub struct GatewayConfig {
     pub http_addr: SocketAddr,
-    pub sip_addr: SocketAddr,
+    pub sip_addrs: Vec<SocketAddr>,
+    /// Public SIP source ips for call placement, listener ips are used if empty
+    pub sip_source_ips: Vec<IpAddr>,
+    pub sip_forward_headers: Vec<String>,
+    pub sip_flood: SipFloodConfig,
+    pub sip_trace: SipTraceConfig,
+    pub sip_trunks: Vec<SipTrunk>,
+    pub call_limits: CallLimitsConfig,
     pub address_book: AddressBookStorage,
     pub http_hook_queues: usize,
+    /// Media gateway url, it is probed for readiness
     pub media_gateway: String,
+    /// Rtpengine api for media sessions of calls, usually `HttpRtpEngine` of the media gateway
+    pub media_engine: Arc<dyn RtpEngine>,
+    /// Extra media backends which apps can select by name with `media_backend`, atm0s is used otherwise
+    pub media_backends: HashMap<String, Arc<dyn MediaBackend>>,
+    /// Built-in RTP relay, apps select it with `media_backend = "relay"`
+    pub media_relay: Option<RtpRelayConfig>,
     pub secure_ctx: Arc<SecureContext>,
     pub sdn_peer_id: PeerId,
     pub sdn_listen_addr: SocketAddr,
     pub sdn_advertise: Option<NetworkAddress>,
     pub sdn_seeds: Vec<PeerAddress>,
     pub sdn_secret: String,
+    pub sdn_tls: ClusterTlsConfig,
+    /// Region tag for call placement
+    pub region: Option<String>,
+    /// Default max time to wait for active calls when draining
+    pub drain_timeout: Duration,
 }
 
 pub struct Gateway {
+    http_tx: Sender<HttpCommand>,
     http_rx: Receiver<HttpCommand>,
     call_manager: CallManager,
-    p2p: P2pNetwork<SharedKeyHandshake>,
+    sip_filter: SipFloodFilter,
+    p2p: P2pNetwork<ClusterHandshake>,
+    nodes: ClusterNodes,
+    admin_rpc: AdminRpcClient,
+    placement: PlacementTable,
+    replicas: ReplicaTable,
+    drain: DrainState,
+    drain_timeout: Duration,
+    drain_ticker: Option<JoinHandle<()>>,
+    secure_ctx: Arc<SecureContext>,
+}
+
+/// Handle for controlling the gateway from other tasks, like signal handlers. Commands are applied by the gateway loop,
+/// so `Gateway::recv` never needs to be cancelled for them
+#[derive(Clone)]
+pub struct GatewayControl {
+    tx: Sender<HttpCommand>,
+}
+
+impl GatewayControl {
+    /// Start draining with the default timeout, return false if the node is already draining or the gateway stopped
+    pub async fn drain(&self) -> bool {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(HttpCommand::Drain(None, tx)).await.is_err() {
+            return false;
+        }
+        rx.await.unwrap_or(false)
+    }
+
+    /// Apply reloadable settings, see `Gateway::reload`
+    pub async fn reload(&self, call_limits: CallLimitsConfig, sip_blocklist: Vec<IpNet>) {
+        if self.tx.send(HttpCommand::Reload(call_limits, sip_blocklist)).await.is_err() {
+            log::warn!("[GatewayControl] reload dropped, gateway stopped");
+        }
+    }
 }
 
 impl Gateway {
     pub async fn new(cfg: GatewayConfig) -> Result<Self, GatewayError> {
-        let priv_key = PrivatePkcs8KeyDer::from(DEFAULT_CLUSTER_KEY.to_vec());
-        let cert = CertificateDer::from(DEFAULT_CLUSTER_CERT.to_vec());
+        let require_peer = !cfg.sdn_seeds.is_empty();
+        let media_relay = cfg.media_relay.map(RtpRelay::new);
+        let mut named_backends = cfg.media_backends;
+        if let Some(relay) = &media_relay {
+            named_backends.insert(RELAY_BACKEND.to_owned(), Arc::new(relay.clone()));
+        }
+        let media_backends = MediaBackends::new(cfg.media_engine.clone(), named_backends, cfg.address_book.clone());
+        let identity = ClusterIdentity::load(&cfg.sdn_tls, cfg.sdn_peer_id).await?;
+        let secure = ClusterHandshake::new(&cfg.sdn_secret, &identity, cfg.sdn_tls.ca.as_deref(), cfg.sdn_peer_id).await?;
 
         let mut p2p = P2pNetwork::new(P2pNetworkConfig {
             peer_id: cfg.sdn_peer_id,
             listen_addr: cfg.sdn_listen_addr,
             advertise: cfg.sdn_advertise,
-            priv_key,
-            cert,
+            cert: identity.cert(),
+            priv_key: identity.key,
             tick_ms: 1000,
             seeds: cfg.sdn_seeds,
-            secure: SharedKeyHandshake::from(cfg.sdn_secret.as_str()),
+            secure,
         })
         .await?;
 
-        let mut pubsub_call = PubsubService::new(p2p.create_service(0.into()));
+        let mut pubsub_call = PubsubService::new(p2p.create_service(CALL_PUBSUB_SERVICE.into()));
         let p2p_pubsub_call = pubsub_call.requester();
         let http_hook = HttpHook::new(cfg.http_hook_queues);
 
-        let (mut http, http_rx) = HttpServer::new(cfg.http_addr, &cfg.media_gateway, cfg.secure_ctx.clone(), p2p_pubsub_call.clone());
+        let mut pubsub_address_book = PubsubService::new(p2p.create_service(ADDRESS_BOOK_PUBSUB_SERVICE.into()));
+        let (mut address_book_replicator, address_book_updater) = AddressBookReplicator::new(cfg.address_book.clone(), pubsub_address_book.requester());
+
+        let nodes = ClusterNodes::new(cfg.sdn_peer_id);
+        let mut pubsub_admin = PubsubService::new(p2p.create_service(ADMIN_PUBSUB_SERVICE.into()));
+        let admin_rpc = AdminRpcClient::new(nodes.clone(), pubsub_admin.requester());
+
+        let drain = DrainState::default();
+        let health = HealthState::default();
+        let health_apis = HealthApis {
+            drain: drain.clone(),
+            health: health.clone(),
+            address_book: cfg.address_book.clone(),
+            nodes: nodes.clone(),
+            require_peer,
+        };
+        let (mut http, http_tx, http_rx) = HttpServer::new(
+            cfg.http_addr,
+            media_backends.clone(),
+            cfg.secure_ctx.clone(),
+            p2p_pubsub_call.clone(),
+            address_book_updater,
+            admin_rpc.clone(),
+            health_apis,
+        );
+        let mut sip_flood = cfg.sip_flood;
+        sip_flood.trusted.extend(trunk_sources(&cfg.sip_trunks.iter().map(|t| t.server.clone()).collect::<Vec<_>>()).await);
+        let sip_filter = SipFloodFilter::new(sip_flood, cfg.address_book.clone());
+        let sip_tracer = SipTracer::new(&cfg.sip_trace)?;
+        let sip = SipServer::new(&cfg.sip_addrs, cfg.sip_forward_headers, sip_filter.clone(), sip_tracer.clone()).await?;
+        health.set_sip_bound();
+        let mut media_gateway_probe = MediaGatewayProbe::new(&cfg.media_gateway, MEDIA_GATEWAY_PROBE_INTERVAL, health);
+        let mut admin_rpc_server = AdminRpcServer::new(
+            cfg.sdn_peer_id,
+            pubsub_admin.requester(),
+            http_tx.clone(),
+            sip_filter.clone(),
+            cfg.secure_ctx.clone(),
+            media_backends.clone(),
+            media_relay,
+            sip_tracer,
+        );
+        let sip_ips = if cfg.sip_source_ips.is_empty() {
+            cfg.sip_addrs.iter().map(|a| a.ip()).filter(|ip| !ip.is_unspecified()).collect()
+        } else {
+            cfg.sip_source_ips
+        };
+        let replicas = ReplicaTable::new(nodes.clone(), sip_ips.clone());
+        let placement = PlacementTable::new(nodes.clone(), NodePlacement { region: cfg.region, sip_ips }, drain.clone());
+        let mut placement_sync = PlacementSync::new(placement.clone(), pubsub_admin.requester());
+        let (mut replica_sync, replicator) = ReplicaSync::new(replicas.clone(), pubsub_admin.requester());
+        let mut revocation_sync = RevocationSync::new(cfg.secure_ctx.clone(), pubsub_admin.requester());
         tokio::spawn(async move { http.run_loop().await });
         tokio::spawn(async move { while let Ok(_) = pubsub_call.run_loop().await {} });
+        tokio::spawn(async move { while let Ok(_) = pubsub_address_book.run_loop().await {} });
+        tokio::spawn(async move { while let Ok(_) = pubsub_admin.run_loop().await {} });
+        tokio::spawn(async move { address_book_replicator.run_loop().await });
+        tokio::spawn(async move { admin_rpc_server.run_loop().await });
+        tokio::spawn(async move { placement_sync.run_loop().await });
+        tokio::spawn(async move { replica_sync.run_loop().await });
+        tokio::spawn(async move { revocation_sync.run_loop().await });
+        tokio::spawn(async move { media_gateway_probe.run_loop().await });
+
+        let mut call_manager = CallManager::new(p2p_pubsub_call, sip, cfg.address_book, cfg.secure_ctx.clone(), http_hook, media_backends, drain.clone());
+        call_manager.set_limits(cfg.call_limits);
+        call_manager.set_trunks(cfg.sip_trunks);
+        call_manager.set_replicator(replicator);
 
         Ok(Self {
+            http_tx,
             http_rx,
-            call_manager: CallManager::new(p2p_pubsub_call, cfg.sip_addr, cfg.address_book, cfg.secure_ctx, http_hook, &cfg.media_gateway).await,
+            call_manager,
+            sip_filter,
             p2p,
+            nodes,
+            admin_rpc,
+            placement,
+            replicas,
+            drain,
+            drain_timeout: cfg.drain_timeout,
+            drain_ticker: None,
+            secure_ctx: cfg.secure_ctx,
         })
     }
 
+    pub fn control(&self) -> GatewayControl {
+        GatewayControl { tx: self.http_tx.clone() }
+    }
+
+    /// Apply reloadable settings, they only affect new calls and INVITEs
+    pub fn reload(&mut self, call_limits: CallLimitsConfig, sip_blocklist: Vec<IpNet>) {
+        log::info!("[Gateway] reload call limits and sip blocklist with {} subnets", sip_blocklist.len());
+        self.call_manager.set_limits(call_limits);
+        self.sip_filter.set_blocklist(sip_blocklist);
+    }
+
+    /// Start draining this node, new calls are refused and active calls are ended after the timeout.
+    /// Return false if the node is already draining.
+    pub fn drain(&mut self, timeout: Option<Duration>) -> bool {
+        let timeout = timeout.unwrap_or(self.drain_timeout);
+        if !self.drain.start(timeout) {
+            return false;
+        }
+        log::warn!("[Gateway] start draining with {} active calls, timeout {timeout:?}", self.call_manager.calls_count());
+        // ticks for checking the deadline and finished state while the main loop has no other events
+        // it is stopped when the drain is finished
+        let tx = self.http_tx.clone();
+        self.drain_ticker = Some(tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+                if tx.send(HttpCommand::DrainTick).await.is_err() {
+                    break;
+                }
+            }
+        }));
+        true
+    }
+
+    /// Return true when draining is finished and the process can exit
+    pub fn is_drained(&self) -> bool {
+        self.drain.is_finished(self.call_manager.calls_count())
+    }
+
+    /// Check the calls of a disconnected peer after the delay, the check is scheduled again while the takeover must wait
+    fn schedule_takeover_check(&self, peer_id: PeerId, delay: Duration) {
+        let tx = self.http_tx.clone();
+        tokio::spawn(async move {
+            sleep(delay).await;
+            if tx.send(HttpCommand::TakeoverCheck(peer_id)).await.is_err() {
+                log::warn!("[Gateway] takeover check of peer {peer_id} dropped, gateway stopped");
+            }
+        });
+    }
+
+    /// Place the call on the node chosen by the placement table, forwarded calls are answered from a spawned task
+    fn create_call(&mut self, req: CreateCallRequest, media_api: MediaApi, app_id: AppId, sender: oneshot::Sender<Result<CreateCallResponse, CallApiError>>) {
+        let res = match self.call_manager.placement_constraint(&req).map(|c| self.placement.pick(&c)) {
+            Ok(Some(node)) if node != self.nodes.local() => {
+                log::info!("[Gateway] forward create_call from {} to {} to node {node}", req.from_number, req.to_number);
+                let admin_rpc = self.admin_rpc.clone();
+                let secure_ctx = self.secure_ctx.clone();
+                tokio::spawn(async move {
+                    // the token is signed here, so the returned call_ws works on this node without a shared secret
+                    let res = admin_rpc
+                        .create_call(node, req, app_id.clone())
+                        .await
+                        .map(|res| outgoing_call_response(&secure_ctx, res.call_id.into(), &app_id));
+                    if let Err(e) = sender.send(res) {
+                        log::warn!("[Gateway] sending forwarded create_call response error {e:?}");
+                    }
+                });
+                return;
+            }
+            Ok(Some(_)) => self.call_manager.create_call(req, media_api, app_id),
+            Ok(None) if self.drain.is_draining() => Err(CallApiError::Draining),
+            Ok(None) => Err(CallApiError::NoNodeAvailable),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sender.send(res) {
+            log::warn!("[Gateway] sending create_call response error {e:?}");
+        }
+    }
+
     pub async fn recv(&mut self) -> Result<(), GatewayError> {
+        self.placement.set_local_calls(self.call_manager.calls_count());
         let out = select3::or(self.http_rx.recv(), self.p2p.recv(), self.call_manager.recv()).await;
         match out {
             select3::OrOutput::Left(cmd) => match cmd.expect("internal channel error") {
-                HttpCommand::CreateCall(req, media_api, sender) => {
-                    let res = self.call_manager.create_call(req, media_api);
+                HttpCommand::CreateCall(req, media_api, app_id, sender) => {
+                    self.create_call(req, media_api, app_id, sender);
+                    Ok(())
+                }
+                HttpCommand::CreatePlacedCall(req, media_api, app_id, sender) => {
+                    let res = self.call_manager.create_call(req, media_api, app_id);
                     if let Err(e) = sender.send(res) {
                         log::warn!("[Gateway] sending create_call response error {e:?}");
                     }
                     Ok(())
                 }
+                HttpCommand::ListCalls(sender) => {
+                    let calls = self.call_manager.calls_info(&self.nodes.local().to_string());
+                    if sender.send(calls).is_err() {
+                        log::warn!("[Gateway] sending list_calls response error");
+                    }
+                    Ok(())
+                }
+                HttpCommand::Drain(timeout, sender) => {
+                    let started = self.drain(timeout);
+                    if sender.send(started).is_err() {
+                        log::warn!("[Gateway] sending drain response error");
+                    }
+                    Ok(())
+                }
+                HttpCommand::TakeoverCheck(peer_id) => {
+                    match self.replicas.take_failed(peer_id, Instant::now()) {
+                        Takeover::Done(calls) if !calls.is_empty() => {
+                            log::warn!("[Gateway] peer {peer_id} failed with {} calls => take over", calls.len());
+                            self.call_manager.takeover_calls(calls);
+                        }
+                        Takeover::Done(_) => {}
+                        Takeover::Wait => self.schedule_takeover_check(peer_id, TAKEOVER_RETRY),
+                    }
+                    Ok(())
+                }
+                HttpCommand::DrainTick => {
+                    if self.drain.take_deadline_passed() {
+                        log::warn!("[Gateway] drain deadline passed => end {} remaining calls", self.call_manager.calls_count());
+                        self.call_manager.end_all_calls();
+                    }
+                    if self.is_drained() {
+                        if let Some(ticker) = self.drain_ticker.take() {
+                            log::info!("[Gateway] drain finished => stop drain ticks");
+                            ticker.abort();
+                        }
+                    }
+                    Ok(())
+                }
+                HttpCommand::Reload(call_limits, sip_blocklist) => {
+                    self.reload(call_limits, sip_blocklist);
+                    Ok(())
+                }
             },
             select3::OrOutput::Middle(out) => match out? {
                 P2pNetworkEvent::PeerConnected(_, peer_id) => {
                     log::info!("[Gateway] peer {peer_id} connected");
+                    self.nodes.on_connected(peer_id);
+                    self.replicas.on_connected(peer_id);
                     Ok(())
                 }
                 P2pNetworkEvent::PeerDisconnected(_, peer_id) => {
-                    log::info!("[Gateway] peer {peer_id} disconnected");
+                    log::info!("[Gateway] peer {peer_id} disconnected => take over its calls if it is not back in {TAKEOVER_GRACE:?}");
+                    self.nodes.on_disconnected(peer_id);
+                    self.replicas.on_disconnected(peer_id, Instant::now());
+                    self.schedule_takeover_check(peer_id, TAKEOVER_GRACE);
                     Ok(())
                 }
                 P2pNetworkEvent::Continue => Ok(()),
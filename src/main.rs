@@ -1,12 +1,33 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use atm0s_media_sip_gateway::{AddressBookStorage, AddressBookSync, Gateway, GatewayConfig, GatewayError, SecureContext};
-use clap::Parser;
+use atm0s_media_sip_gateway::{
+    load_snapshot, AddressBookFile, AddressBookStorage, AddressBookSync, AddressBookSyncConfig, CallTokenKeyConfig, CallTokenKeys, Config, Gateway, GatewayConfig, GatewayControl, GatewayError,
+    HttpRtpEngine, RelaySection, SecureContext,
+};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::watch,
+};
+use tracing_subscriber::{filter::LevelFilter, prelude::*, reload, Registry};
 
 /// Sip Gateway for atm0s-media-server
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// TOML config file, it is reloaded on SIGHUP. Flags and env vars which are set override its values
+    #[arg(long, env)]
+    config: Option<PathBuf>,
+
+    /// Log level: error, warn, info, debug or trace
+    #[arg(long, env, default_value = "info")]
+    log_level: String,
+
     /// UDP/TCP port for serving QUIC/TCP connection for SDN network
     #[arg(env, long)]
     sdn_peer_id: Option<u64>,
@@ -29,6 +50,26 @@ struct Args {
     #[arg(env, long, default_value = "insecure")]
     sdn_secure_code: String,
 
+    /// Region tag of this node, used for placing outgoing calls
+    #[arg(env, long)]
+    sdn_region: Option<String>,
+
+    /// Cluster TLS certificate chain file (PEM or DER), requires --sdn-tls-key
+    #[arg(env, long)]
+    sdn_tls_cert: Option<PathBuf>,
+
+    /// Cluster TLS PKCS#8 private key file (PEM or DER)
+    #[arg(env, long)]
+    sdn_tls_key: Option<PathBuf>,
+
+    /// Generate a self-signed cluster TLS identity at startup
+    #[arg(env, long)]
+    sdn_tls_self_signed: bool,
+
+    /// CA certificates file (PEM or DER), peers must present a certificate issued by this CA
+    #[arg(env, long)]
+    sdn_tls_ca: Option<PathBuf>,
+
     /// Listen Address for http server
     #[arg(long, env, default_value = "0.0.0.0:8008")]
     http_addr: SocketAddr,
@@ -41,6 +82,38 @@ struct Args {
     #[arg(long, env, default_value = "0.0.0.0:5060")]
     sip_addr: SocketAddr,
 
+    /// Public SIP source ips of this node, used for placing outgoing calls of carriers with ip allowlist
+    #[arg(long, env, value_delimiter = ',')]
+    sip_source_ips: Vec<IpAddr>,
+
+    /// Headers of incoming INVITE which are forwarded to hook and call notify
+    #[arg(long, env, value_delimiter = ',')]
+    sip_forward_headers: Vec<String>,
+
+    /// Max INVITEs per second from a single source ip, exceeded INVITEs are dropped
+    #[arg(long, env, default_value_t = 20)]
+    sip_flood_invites_per_second: u32,
+
+    /// Number of rejected or malformed INVITEs from a source ip within a minute which bans that ip
+    #[arg(long, env, default_value_t = 50)]
+    sip_flood_ban_threshold: u32,
+
+    /// Ban duration for abusive source ips
+    #[arg(long, env, default_value_t = 600)]
+    sip_flood_ban_secs: u64,
+
+    /// Number of recent calls whose SIP messages are kept for the admin trace apis, 0 disables it
+    #[arg(long, env, default_value_t = 1000)]
+    sip_trace_max_calls: usize,
+
+    /// Homer collector address, captured SIP messages are mirrored to it as HEP3
+    #[arg(long, env)]
+    sip_hep_collector: Option<SocketAddr>,
+
+    /// Capture id in mirrored HEP packets
+    #[arg(long, env, default_value_t = 2001)]
+    sip_hep_capture_id: u32,
+
     /// Secret of this gateway
     #[arg(long, env, default_value = "insecure")]
     secret: String,
@@ -57,56 +130,459 @@ struct Args {
     #[arg(long, env, default_value_t = 30_000)]
     sync_interval_ms: u64,
 
+    /// Persist the last synced address book to this file and load it at startup
+    #[arg(long, env)]
+    address_book_snapshot: Option<PathBuf>,
+
+    /// Static address book file (yaml or json), used instead of http sync
+    #[arg(long, env)]
+    address_book_file: Option<PathBuf>,
+
     /// Http hook queues
     #[arg(long, env, default_value_t = 20)]
     http_hook_queues: usize,
 
     /// MediaServer Gateway
-    #[arg(long, env)]
-    media_gateway: String,
+    #[arg(long, env, required_unless_present = "config")]
+    media_gateway: Option<String>,
 
     /// MediaServer Apps sync endpoint
     #[arg(long, env)]
     media_app_sync: Option<String>,
+
+    /// Enable the built-in RTP relay with this UDP port range, like 40000-40999. Apps select it with media_backend = "relay"
+    #[arg(long, env, value_parser = parse_port_range)]
+    media_relay_ports: Option<(u16, u16)>,
+
+    /// Local ip of the RTP relay sockets
+    #[arg(long, env, default_value = "0.0.0.0")]
+    media_relay_bind_ip: IpAddr,
+
+    /// Ip which is written in sdp of RTP relay sessions, default to the bind ip
+    #[arg(long, env)]
+    media_relay_public_ip: Option<IpAddr>,
+
+    /// Latch the SIP side RTP address of relay sessions from the first received packet (symmetric RTP), for SIP peers behind NAT
+    #[arg(long, env)]
+    media_relay_sip_latching: bool,
+
+    /// Max seconds to wait for active calls when draining on SIGTERM, remaining calls are ended after that
+    #[arg(long, env, default_value_t = 300)]
+    drain_timeout_secs: u64,
+
+    /// Default TTL of call tokens, apps can override it with token_ttl_secs in the address book
+    #[arg(long, env, default_value_t = 3600)]
+    call_token_ttl_secs: u64,
+
+    /// Call token signing keys as kid=path of ES256 or Ed25519 PEM files. The first key signs new tokens,
+    /// the others are only used for verifying tokens after a rotation
+    #[arg(long, env, value_delimiter = ',')]
+    call_token_keys: Vec<CallTokenKeyConfig>,
+
+    /// Reject HS256 call tokens signed with the gateway secret when call token keys are set
+    #[arg(long, env)]
+    call_token_reject_hs256: bool,
+}
+
+impl From<Args> for Config {
+    fn from(args: Args) -> Self {
+        let mut cfg = Config {
+            log_level: args.log_level,
+            secret: args.secret,
+            drain_timeout_secs: args.drain_timeout_secs,
+            call_token_ttl_secs: args.call_token_ttl_secs,
+            call_token_keys: args.call_token_keys,
+            call_token_reject_hs256: args.call_token_reject_hs256,
+            ..Default::default()
+        };
+        cfg.http.addr = args.http_addr;
+        cfg.http.public = args.http_public;
+        cfg.sip.listeners = vec![args.sip_addr];
+        cfg.sip.source_ips = args.sip_source_ips;
+        cfg.sip.forward_headers = args.sip_forward_headers;
+        cfg.sip.flood.invites_per_second = args.sip_flood_invites_per_second;
+        cfg.sip.flood.ban_threshold = args.sip_flood_ban_threshold;
+        cfg.sip.flood.ban_secs = args.sip_flood_ban_secs;
+        cfg.sip.trace.max_calls = args.sip_trace_max_calls;
+        cfg.sip.trace.hep_collector = args.sip_hep_collector;
+        cfg.sip.trace.hep_capture_id = args.sip_hep_capture_id;
+        cfg.address_book.numbers_sync = args.phone_numbers_sync;
+        cfg.address_book.apps_sync = args.apps_sync;
+        cfg.address_book.sync_interval_ms = args.sync_interval_ms;
+        cfg.address_book.snapshot = args.address_book_snapshot;
+        cfg.address_book.file = args.address_book_file;
+        cfg.hook.queues = args.http_hook_queues;
+        cfg.media.gateway = args.media_gateway.unwrap_or_default();
+        cfg.media.app_sync = args.media_app_sync;
+        cfg.media.relay = args.media_relay_ports.map(|(port_min, port_max)| RelaySection {
+            bind_ip: args.media_relay_bind_ip,
+            public_ip: args.media_relay_public_ip,
+            port_min,
+            port_max,
+            sip_latching: args.media_relay_sip_latching,
+        });
+        cfg.sdn.peer_id = args.sdn_peer_id;
+        cfg.sdn.listener = args.sdn_listener;
+        cfg.sdn.seeds = args.sdn_seeds;
+        cfg.sdn.advertise_address = args.sdn_advertise_address;
+        cfg.sdn.secure_code = args.sdn_secure_code;
+        cfg.sdn.region = args.sdn_region;
+        cfg.sdn.tls.cert = args.sdn_tls_cert;
+        cfg.sdn.tls.key = args.sdn_tls_key;
+        cfg.sdn.tls.self_signed = args.sdn_tls_self_signed;
+        cfg.sdn.tls.ca = args.sdn_tls_ca;
+        cfg
+    }
+}
+
+fn parse_port_range(value: &str) -> Result<(u16, u16), String> {
+    let (min, max) = value.split_once('-').ok_or_else(|| format!("invalid port range {value}, expected min-max"))?;
+    let min = min.trim().parse::<u16>().map_err(|e| format!("invalid port {min}: {e}"))?;
+    let max = max.trim().parse::<u16>().map_err(|e| format!("invalid port {max}: {e}"))?;
+    if min > max {
+        return Err(format!("invalid port range {value}"));
+    }
+    Ok((min, max))
+}
+
+/// Apply args which are set on the command line or by env over the config file, defaults of unset args don't override it.
+/// Precedence is: command line, env, config file, defaults
+fn override_config(cfg: &mut Config, args: &Args, matches: &ArgMatches) {
+    let given = |id: &str| matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable));
+    let from_args = Config::from(args.clone());
+    if given("log_level") {
+        cfg.log_level = from_args.log_level;
+    }
+    if given("secret") {
+        cfg.secret = from_args.secret;
+    }
+    if given("drain_timeout_secs") {
+        cfg.drain_timeout_secs = from_args.drain_timeout_secs;
+    }
+    if given("call_token_ttl_secs") {
+        cfg.call_token_ttl_secs = from_args.call_token_ttl_secs;
+    }
+    if given("call_token_keys") {
+        cfg.call_token_keys = from_args.call_token_keys;
+    }
+    if given("call_token_reject_hs256") {
+        cfg.call_token_reject_hs256 = from_args.call_token_reject_hs256;
+    }
+    if given("http_addr") {
+        cfg.http.addr = from_args.http.addr;
+    }
+    if given("http_public") {
+        cfg.http.public = from_args.http.public;
+    }
+    if given("sip_addr") {
+        cfg.sip.listeners = from_args.sip.listeners;
+    }
+    if given("sip_source_ips") {
+        cfg.sip.source_ips = from_args.sip.source_ips;
+    }
+    if given("sip_forward_headers") {
+        cfg.sip.forward_headers = from_args.sip.forward_headers;
+    }
+    if given("sip_flood_invites_per_second") {
+        cfg.sip.flood.invites_per_second = from_args.sip.flood.invites_per_second;
+    }
+    if given("sip_flood_ban_threshold") {
+        cfg.sip.flood.ban_threshold = from_args.sip.flood.ban_threshold;
+    }
+    if given("sip_flood_ban_secs") {
+        cfg.sip.flood.ban_secs = from_args.sip.flood.ban_secs;
+    }
+    if given("sip_trace_max_calls") {
+        cfg.sip.trace.max_calls = from_args.sip.trace.max_calls;
+    }
+    if given("sip_hep_collector") {
+        cfg.sip.trace.hep_collector = from_args.sip.trace.hep_collector;
+    }
+    if given("sip_hep_capture_id") {
+        cfg.sip.trace.hep_capture_id = from_args.sip.trace.hep_capture_id;
+    }
+    if given("phone_numbers_sync") {
+        cfg.address_book.numbers_sync = from_args.address_book.numbers_sync;
+    }
+    if given("apps_sync") {
+        cfg.address_book.apps_sync = from_args.address_book.apps_sync;
+    }
+    if given("sync_interval_ms") {
+        cfg.address_book.sync_interval_ms = from_args.address_book.sync_interval_ms;
+    }
+    if given("address_book_snapshot") {
+        cfg.address_book.snapshot = from_args.address_book.snapshot;
+    }
+    if given("address_book_file") {
+        cfg.address_book.file = from_args.address_book.file;
+    }
+    if given("http_hook_queues") {
+        cfg.hook.queues = from_args.hook.queues;
+    }
+    if given("media_gateway") {
+        cfg.media.gateway = from_args.media.gateway;
+    }
+    if given("media_app_sync") {
+        cfg.media.app_sync = from_args.media.app_sync;
+    }
+    if given("media_relay_ports") {
+        cfg.media.relay = from_args.media.relay;
+    } else if let Some(relay) = cfg.media.relay.as_mut() {
+        if given("media_relay_bind_ip") {
+            relay.bind_ip = args.media_relay_bind_ip;
+        }
+        if given("media_relay_public_ip") {
+            relay.public_ip = args.media_relay_public_ip;
+        }
+        if given("media_relay_sip_latching") {
+            relay.sip_latching = args.media_relay_sip_latching;
+        }
+    }
+    if given("sdn_peer_id") {
+        cfg.sdn.peer_id = from_args.sdn.peer_id;
+    }
+    if given("sdn_listener") {
+        cfg.sdn.listener = from_args.sdn.listener;
+    }
+    if given("sdn_seeds") {
+        cfg.sdn.seeds = from_args.sdn.seeds;
+    }
+    if given("sdn_advertise_address") {
+        cfg.sdn.advertise_address = from_args.sdn.advertise_address;
+    }
+    if given("sdn_secure_code") {
+        cfg.sdn.secure_code = from_args.sdn.secure_code;
+    }
+    if given("sdn_region") {
+        cfg.sdn.region = from_args.sdn.region;
+    }
+    if given("sdn_tls_cert") {
+        cfg.sdn.tls.cert = from_args.sdn.tls.cert;
+    }
+    if given("sdn_tls_key") {
+        cfg.sdn.tls.key = from_args.sdn.tls.key;
+    }
+    if given("sdn_tls_self_signed") {
+        cfg.sdn.tls.self_signed = from_args.sdn.tls.self_signed;
+    }
+    if given("sdn_tls_ca") {
+        cfg.sdn.tls.ca = from_args.sdn.tls.ca;
+    }
+}
+
+fn apply_log_level(handle: &reload::Handle<LevelFilter, Registry>, level: &str) {
+    match (level.parse::<LevelFilter>(), level.parse::<log::LevelFilter>()) {
+        (Ok(filter), Ok(log_filter)) => {
+            if let Err(e) = handle.reload(filter) {
+                log::error!("reload log level error {e:?}");
+            }
+            // log records are bridged to tracing, the log crate filters them before that
+            log::set_max_level(log_filter);
+        }
+        _ => log::warn!("invalid log level {level}"),
+    }
+}
+
+/// Apply the reloadable subset of the config, other changes need a restart
+async fn reload_config(cfg: Config, log_handle: &reload::Handle<LevelFilter, Registry>, sync_tx: Option<&watch::Sender<AddressBookSyncConfig>>, gateway: &GatewayControl) {
+    apply_log_level(log_handle, &cfg.log_level);
+    match (cfg.address_book_sync(), sync_tx) {
+        (Some(sync), Some(tx)) => {
+            tx.send_if_modified(|current| {
+                let changed = *current != sync;
+                *current = sync;
+                changed
+            });
+        }
+        (None, None) => {}
+        _ => log::warn!("enabling or disabling address book sync needs a restart"),
+    }
+    gateway.reload(cfg.limits, cfg.sip.flood.blocklist).await;
+}
+
+/// Drain on the first SIGTERM and exit immediately on the second one, reload the config on SIGHUP.
+/// Signals are handled outside of the gateway loop, so `Gateway::recv` is never cancelled
+async fn handle_signals(
+    mut sigterm: Signal,
+    mut sighup: Signal,
+    gateway: GatewayControl,
+    args: Args,
+    matches: ArgMatches,
+    log_handle: reload::Handle<LevelFilter, Registry>,
+    sync_tx: Option<watch::Sender<AddressBookSyncConfig>>,
+) {
+    let mut draining = false;
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                if draining {
+                    log::warn!("SIGTERM received again => exit without waiting for active calls");
+                    std::process::exit(1);
+                }
+                log::info!("SIGTERM received => draining, send it again for exiting immediately");
+                draining = true;
+                gateway.drain().await;
+            }
+            _ = sighup.recv() => {
+                match &args.config {
+                    Some(path) => match Config::load(path, |cfg| override_config(cfg, &args, &matches)).await {
+                        Ok(cfg) => {
+                            log::info!("SIGHUP received => reloaded config from {path:?}");
+                            reload_config(cfg, &log_handle, sync_tx.as_ref(), &gateway).await;
+                        }
+                        Err(e) => log::error!("SIGHUP received but reload config from {path:?} error {e:?}"),
+                    },
+                    None => log::warn!("SIGHUP received but reload needs --config => ignored"),
+                }
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), GatewayError> {
     rustls::crypto::ring::default_provider().install_default().expect("should install ring as default");
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
-    log::info!("Starting server with addr {}, public endpoint {} and sip port {}", args.http_addr, args.http_public, args.sip_addr);
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let cfg = match &args.config {
+        Some(path) => Config::load(path, |cfg| override_config(cfg, &args, &matches)).await?,
+        None => Config::from(args.clone()),
+    };
 
-    let address_book = AddressBookStorage::new(&args.secret);
-    let secure_ctx = Arc::new(SecureContext::new(&args.secret, address_book.clone()));
+    let (log_filter, log_handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry().with(log_filter).with(tracing_subscriber::fmt::layer()).init();
+    apply_log_level(&log_handle, &cfg.log_level);
+    if args.config.is_none() {
+        log::warn!("Started without config file => SIGHUP reload is disabled");
+    }
+    log::info!(
+        "Starting server with addr {}, public endpoint {} and sip listeners {:?}",
+        cfg.http.addr,
+        cfg.http.public,
+        cfg.sip.listeners
+    );
 
-    if let Some(phone_url) = args.phone_numbers_sync {
-        if let Some(app_url) = args.apps_sync {
-            let mut address_book_sync = AddressBookSync::new(&phone_url, &app_url, Duration::from_millis(args.sync_interval_ms), address_book.clone());
+    let address_book = AddressBookStorage::new(&cfg.secret);
+    let mut secure_ctx = SecureContext::new(&cfg.secret, address_book.clone());
+    secure_ctx.set_call_token_ttl(cfg.call_token_ttl_secs);
+    if !cfg.call_token_keys.is_empty() {
+        secure_ctx.set_token_keys(CallTokenKeys::load(&cfg.call_token_keys).await?);
+        secure_ctx.set_accept_hs256(!cfg.call_token_reject_hs256);
+    } else if cfg.call_token_reject_hs256 {
+        log::warn!("call_token_reject_hs256 is ignored without call_token_keys, tokens are signed with the gateway secret");
+    }
+    let secure_ctx = Arc::new(secure_ctx);
 
-            tokio::spawn(async move {
-                address_book_sync.run_loop().await;
-            });
+    if let Some(path) = &cfg.address_book.snapshot {
+        match load_snapshot(path).await {
+            Ok(snapshot) => {
+                log::info!("Loaded address book snapshot from {path:?} with {} apps, {} numbers", snapshot.apps.len(), snapshot.numbers.len());
+                address_book.apply_snapshot(snapshot);
+            }
+            Err(e) => log::warn!("Cannot load address book snapshot from {path:?}: {e:?}"),
         }
     }
 
-    let cfg = GatewayConfig {
-        http_addr: args.http_addr,
-        sip_addr: args.sip_addr,
+    let mut sync_tx = None;
+    if let Some(path) = &cfg.address_book.file {
+        let mut address_book_file = AddressBookFile::new(path, Duration::from_millis(cfg.address_book.sync_interval_ms), address_book.clone());
+
+        tokio::spawn(async move {
+            address_book_file.run_loop().await;
+        });
+    } else if let Some(sync) = cfg.address_book_sync() {
+        let (tx, rx) = watch::channel(sync);
+        let mut address_book_sync = AddressBookSync::new(rx, address_book.clone(), cfg.address_book.snapshot.as_deref());
+        sync_tx = Some(tx);
+
+        tokio::spawn(async move {
+            address_book_sync.run_loop().await;
+        });
+    } else {
+        // without sync source, the address book is only changed by admin apis
+        address_book.set_synced();
+    }
+
+    let sip_flood = cfg.sip_flood();
+    let sip_trace = cfg.sip_trace();
+    let media_relay = cfg.media_relay();
+    let sdn_tls = cfg.sdn_tls();
+    let gateway_cfg = GatewayConfig {
+        http_addr: cfg.http.addr,
+        sip_addrs: cfg.sip.listeners,
+        sip_source_ips: cfg.sip.source_ips,
+        sip_forward_headers: cfg.sip.forward_headers,
+        sip_flood,
+        sip_trace,
+        sip_trunks: cfg.trunks,
+        call_limits: cfg.limits,
         address_book,
-        http_hook_queues: args.http_hook_queues,
-        media_gateway: args.media_gateway,
+        http_hook_queues: cfg.hook.queues,
+        media_engine: Arc::new(HttpRtpEngine::new(&cfg.media.gateway)),
+        media_backends: Default::default(),
+        media_relay,
+        media_gateway: cfg.media.gateway,
         secure_ctx,
-        sdn_peer_id: args.sdn_peer_id.unwrap_or_else(rand::random).into(),
-        sdn_listen_addr: args.sdn_listener,
-        sdn_advertise: args.sdn_advertise_address.map(|a| a.into()),
-        sdn_seeds: args.sdn_seeds.iter().map(|s| s.parse().expect("should convert to address")).collect::<Vec<_>>(),
-        sdn_secret: args.sdn_secure_code,
+        sdn_peer_id: cfg.sdn.peer_id.unwrap_or_else(rand::random).into(),
+        sdn_listen_addr: cfg.sdn.listener,
+        sdn_advertise: cfg.sdn.advertise_address.map(|a| a.into()),
+        sdn_seeds: cfg.sdn.seeds.iter().map(|s| s.parse().expect("should convert to address")).collect::<Vec<_>>(),
+        sdn_secret: cfg.sdn.secure_code,
+        sdn_tls,
+        region: cfg.sdn.region,
+        drain_timeout: Duration::from_secs(cfg.drain_timeout_secs),
     };
-    let mut gateway = Gateway::new(cfg).await?;
+    let mut gateway = Gateway::new(gateway_cfg).await?;
+    let sigterm = signal(SignalKind::terminate())?;
+    let sighup = signal(SignalKind::hangup())?;
+    tokio::spawn(handle_signals(sigterm, sighup, gateway.control(), args, matches, log_handle, sync_tx));
     loop {
         if let Err(e) = gateway.recv().await {
             log::error!("gateway error {e:?}");
         }
+        if gateway.is_drained() {
+            log::info!("Drain finished => exit");
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_media_sip_gateway::Config;
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::{override_config, Args};
+
+    const FILE: &str = "log_level = \"debug\"\nsecret = \"file-secret\"\n[http]\naddr = \"0.0.0.0:7000\"\n[media]\ngateway = \"http://file-gateway\"";
+
+    fn load(argv: &[&str]) -> Config {
+        let matches = Args::command().try_get_matches_from(argv).expect("should parse args");
+        let args = Args::from_arg_matches(&matches).expect("should build args");
+        Config::parse_with(FILE, |cfg| override_config(cfg, &args, &matches)).expect("should load config")
+    }
+
+    #[test]
+    fn test_config_with_flags() {
+        let cfg = load(&["gateway", "--config", "gateway.toml"]);
+        // defaults of unset flags don't override the file
+        assert_eq!(cfg.log_level, "debug");
+        assert_eq!(cfg.secret, "file-secret");
+        assert_eq!(cfg.http.addr.port(), 7000);
+        assert_eq!(cfg.media.gateway, "http://file-gateway");
+
+        let cfg = load(&["gateway", "--config", "gateway.toml", "--http-addr", "0.0.0.0:9000", "--media-gateway", "http://flag-gateway"]);
+        assert_eq!(cfg.http.addr.port(), 9000);
+        assert_eq!(cfg.media.gateway, "http://flag-gateway");
+        assert_eq!(cfg.log_level, "debug");
+    }
+
+    #[test]
+    fn test_config_with_env() {
+        std::env::set_var("SIP_FLOOD_BAN_SECS", "900");
+        let cfg = load(&["gateway", "--config", "gateway.toml"]);
+        std::env::remove_var("SIP_FLOOD_BAN_SECS");
+        assert_eq!(cfg.sip.flood.ban_secs, 900);
+        assert_eq!(cfg.secret, "file-secret");
     }
 }
@@ -2,18 +2,24 @@ use std::hash::{Hash, Hasher};
 
 use atm0s_small_p2p::pubsub_service::PubsubChannelId;
 use derive_more::derive::{Deref, Display, From, Into};
-use poem_openapi::Object;
+use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 mod address_book;
+mod admin;
+mod health;
 mod incoming;
 mod outgoing;
 pub mod protobuf;
+mod token;
 
 pub use address_book::*;
+pub use admin::*;
+pub use health::*;
 pub use incoming::*;
 pub use outgoing::*;
+pub use token::*;
 
 /// Note that his call_id is from internal state and not a SipCallID
 #[derive(Debug, From, Into, Deref, Clone, Display, Hash, PartialEq, Eq, Serialize, Deserialize)]
@@ -31,12 +37,18 @@ impl InternalCallId {
     }
 }
 
-#[derive(Debug, Clone, Object, Deserialize)]
+#[derive(Clone, Object, Serialize, Deserialize)]
 pub struct SipAuth {
     pub username: String,
     pub password: String,
 }
 
+impl std::fmt::Debug for SipAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SipAuth").field("username", &self.username).field("password", &"<redacted>").finish()
+    }
+}
+
 #[derive(Debug, Object, Serialize, Deserialize)]
 pub struct StreamingInfo {
     pub room: String,
@@ -44,10 +56,10 @@ pub struct StreamingInfo {
     pub record: bool,
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Serialize, Deserialize)]
 pub enum CallApiError {
     #[error("BadRequest {0}")]
-    BadRequest(&'static str),
+    BadRequest(String),
     #[error("InternalChannel {0}")]
     InternalChannel(String),
     #[error("WrongSecret")]
@@ -56,10 +68,27 @@ pub enum CallApiError {
     WrongToken,
     #[error("SipError {0}")]
     SipError(String),
+    #[error("RateLimited retry after {0}s")]
+    RateLimited(u64),
+    #[error("Draining")]
+    Draining,
+    #[error("NoNodeAvailable")]
+    NoNodeAvailable,
+    #[error("WrongTokenScope")]
+    WrongTokenScope,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Enum)]
 pub enum CallDirection {
     Outgoing,
     Incoming,
 }
+
+/// Permission of a call token
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Enum)]
+pub enum CallTokenScope {
+    /// Only receive call events, for example a supervisor view
+    Observe,
+    /// Receive call events and send actions, including ending the call
+    Control,
+}
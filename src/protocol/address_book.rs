@@ -1,33 +1,278 @@
+use std::fmt::Debug;
+
 use derive_more::derive::{Deref, Display, From, Into};
 use ipnet::IpNet;
+use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 
 use super::SipAuth;
 
 #[derive(Debug, Display, Clone, From, Into, Deref, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AppId(String);
 
-#[derive(Debug, Clone, Deserialize)]
+/// SHA-256 of an app secret, apps are looked up by it so raw secrets are never compared.
+/// It is serialized as lowercase hex
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SecretHash([u8; 32]);
+
+impl SecretHash {
+    pub fn of(secret: &str) -> Self {
+        Self(Sha256::digest(secret.as_bytes()).into())
+    }
+
+    /// Parse the lowercase or uppercase hex form
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(Self(hash))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Constant-time check of a raw secret
+    pub fn verify(&self, secret: &str) -> bool {
+        Self::of(secret).0.ct_eq(&self.0).into()
+    }
+}
+
+impl Debug for SecretHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretHash({})", self.to_hex())
+    }
+}
+
+impl Serialize for SecretHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Self::from_hex(&hex).ok_or_else(|| serde::de::Error::custom("invalid secret hash, expected 64 hex chars"))
+    }
+}
+
+/// Credential of an app on the media server. Unlike the app secret it is kept raw, because it is sent to the media server
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MediaSecret(pub String);
+
+impl Debug for MediaSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MediaSecret(<redacted>)")
+    }
+}
+
+/// App entry of the address book. Only the hash of the secret is kept, raw secrets from sync sources,
+/// files and admin apis are hashed when they are loaded, so they never reach snapshots or replication
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "AppInfoPayload")]
 pub struct AppInfo {
     pub app_id: String,
-    pub app_secret: String,
+    #[serde(rename = "app_secret_hash")]
+    pub secret_hash: SecretHash,
+    pub limits: CallLimits,
+    /// TTL of call tokens of this app, the gateway default is used if missing
+    pub token_ttl_secs: Option<u64>,
+    /// Name of the media backend for calls of this app, the atm0s media gateway is used if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_backend: Option<String>,
+    /// Secret of the app on the atm0s media server, media tokens of calls without an api caller secret are requested with it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_secret: Option<MediaSecret>,
+}
+
+/// Serialized form of an app, it can carry the raw secret or its hex SHA-256
+#[derive(Deserialize)]
+struct AppInfoPayload {
+    app_id: String,
+    #[serde(default)]
+    app_secret: Option<String>,
+    #[serde(default)]
+    app_secret_hash: Option<String>,
+    #[serde(default)]
+    limits: CallLimits,
+    #[serde(default)]
+    token_ttl_secs: Option<u64>,
+    #[serde(default)]
+    media_backend: Option<String>,
+    #[serde(default)]
+    media_secret: Option<String>,
+}
+
+impl TryFrom<AppInfoPayload> for AppInfo {
+    type Error = String;
+
+    fn try_from(value: AppInfoPayload) -> Result<Self, Self::Error> {
+        let secret_hash = app_secret_hash(value.app_secret.as_deref(), value.app_secret_hash.as_deref()).ok_or_else(|| format!("app {} needs app_secret or a valid app_secret_hash", value.app_id))?;
+        Ok(Self {
+            app_id: value.app_id,
+            secret_hash,
+            limits: value.limits,
+            token_ttl_secs: value.token_ttl_secs,
+            media_backend: value.media_backend,
+            media_secret: value.media_secret.filter(|s| !s.is_empty()).map(MediaSecret),
+        })
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Hash of an app from its raw secret, or from the hex hash when the secret is missing or empty
+pub fn app_secret_hash(secret: Option<&str>, hash: Option<&str>) -> Option<SecretHash> {
+    match secret.filter(|s| !s.is_empty()) {
+        Some(secret) => Some(SecretHash::of(secret)),
+        None => hash.and_then(SecretHash::from_hex),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PhoneNumber {
     pub number: String,
     pub subnets: Vec<IpNet>,
     pub auth: Option<SipAuth>,
     pub app_id: String,
     pub hook: String,
+    #[serde(default)]
+    pub limits: CallLimits,
+    /// Allowed codecs in preference order, all codecs are allowed if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub codecs: Vec<Codec>,
+}
+
+/// Codecs of the number codec policy, named as their sdp encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, Serialize, Deserialize)]
+pub enum Codec {
+    #[oai(rename = "PCMU")]
+    #[serde(rename = "PCMU")]
+    Pcmu,
+    #[oai(rename = "PCMA")]
+    #[serde(rename = "PCMA")]
+    Pcma,
+    #[oai(rename = "G722")]
+    #[serde(rename = "G722")]
+    G722,
+    #[oai(rename = "opus")]
+    #[serde(rename = "opus")]
+    Opus,
+    #[oai(rename = "telephone-event")]
+    #[serde(rename = "telephone-event")]
+    TelephoneEvent,
+}
+
+impl Codec {
+    /// Codec of a rtpmap encoding name, case insensitive
+    pub fn from_encoding(encoding: &str) -> Option<Self> {
+        [Self::Pcmu, Self::Pcma, Self::G722, Self::Opus, Self::TelephoneEvent]
+            .into_iter()
+            .find(|codec| codec.encoding().eq_ignore_ascii_case(encoding))
+    }
+
+    /// Codec of a static payload type, which can be used without rtpmap
+    pub fn from_static_payload(payload: u8) -> Option<Self> {
+        match payload {
+            0 => Some(Self::Pcmu),
+            8 => Some(Self::Pcma),
+            9 => Some(Self::G722),
+            _ => None,
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Self::Pcmu => "PCMU",
+            Self::Pcma => "PCMA",
+            Self::G722 => "G722",
+            Self::Opus => "opus",
+            Self::TelephoneEvent => "telephone-event",
+        }
+    }
+}
+
+/// Call limits of an app or a number, missing fields are unlimited
+#[derive(Debug, Clone, Default, PartialEq, Eq, Object, Serialize, Deserialize)]
+pub struct CallLimits {
+    /// Max new calls per second, both incoming and outgoing
+    pub calls_per_second: Option<u32>,
+    /// Max concurrent calls, both incoming and outgoing
+    pub max_concurrent_calls: Option<u32>,
+    pub max_concurrent_outgoing_calls: Option<u32>,
 }
 
+impl CallLimits {
+    /// Fill missing fields from other limits
+    pub fn or(&self, other: &CallLimits) -> CallLimits {
+        CallLimits {
+            calls_per_second: self.calls_per_second.or(other.calls_per_second),
+            max_concurrent_calls: self.max_concurrent_calls.or(other.max_concurrent_calls),
+            max_concurrent_outgoing_calls: self.max_concurrent_outgoing_calls.or(other.max_concurrent_outgoing_calls),
+        }
+    }
+}
+
+/// Sync response, if `numbers` is missing it is a delta from the `since` cursor
 #[derive(Debug, Deserialize)]
 pub struct PhoneNumbersSyncResponse {
-    pub numbers: Vec<PhoneNumber>,
+    #[serde(default)]
+    pub numbers: Option<Vec<PhoneNumber>>,
+    #[serde(default)]
+    pub upserts: Vec<PhoneNumber>,
+    /// Deleted numbers
+    #[serde(default)]
+    pub deletes: Vec<String>,
+    /// Cursor for next delta request, the server doesn't support delta sync if it is missing
+    #[serde(default)]
+    pub cursor: Option<String>,
 }
 
+/// Sync response, if `apps` is missing it is a delta from the `since` cursor
 #[derive(Debug, Deserialize)]
 pub struct AppsSyncResponse {
+    #[serde(default)]
+    pub apps: Option<Vec<AppInfo>>,
+    #[serde(default)]
+    pub upserts: Vec<AppInfo>,
+    /// Deleted app ids
+    #[serde(default)]
+    pub deletes: Vec<String>,
+    /// Cursor for next delta request, the server doesn't support delta sync if it is missing
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// Full address book content, used for persisted snapshot and static file provider
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AddressBookSnapshot {
+    #[serde(default)]
     pub apps: Vec<AppInfo>,
+    #[serde(default)]
+    pub numbers: Vec<PhoneNumber>,
+    /// Changes from admin api, they are kept on top of the synced lists
+    #[serde(default)]
+    pub admin: Vec<AdminChange>,
+}
+
+/// Single address book change, which is pushed from admin api and replicated to all nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AddressBookChange {
+    UpsertApp(AppInfo),
+    DeleteApp(String),
+    UpsertNumber(PhoneNumber),
+    DeleteNumber(String),
+}
+
+/// Admin api change with the unix time in milliseconds it was made, a newer change of the same app or number wins
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminChange {
+    pub version: u64,
+    pub change: AddressBookChange,
 }
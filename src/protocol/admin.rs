@@ -0,0 +1,169 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{app_secret_hash, AppInfo, CallDirection, CallLimits, Codec, MediaSecret, PhoneNumber, SipAuth};
+
+#[derive(Error, Debug)]
+pub enum AdminApiError {
+    #[error("BadRequest {0}")]
+    BadRequest(String),
+    #[error("InternalChannel {0}")]
+    InternalChannel(String),
+    #[error("WrongSecret")]
+    WrongSecret,
+    #[error("NotFound")]
+    NotFound,
+    #[error("CallError {0}")]
+    CallError(String),
+}
+
+#[derive(Object)]
+pub struct AdminAppInfo {
+    pub app_id: String,
+    /// Raw secret, only its hash is stored
+    pub app_secret: Option<String>,
+    /// Hex SHA-256 of the secret, used when app_secret is missing
+    pub app_secret_hash: Option<String>,
+    pub limits: Option<CallLimits>,
+    /// TTL of call tokens of this app, the gateway default is used if missing
+    pub token_ttl_secs: Option<u64>,
+    /// Media backend for calls of this app, it must be registered on all gateway nodes
+    pub media_backend: Option<String>,
+    /// Secret of the app on the atm0s media server, needed for incoming calls and calls placed on other nodes
+    pub media_secret: Option<String>,
+}
+
+impl TryFrom<AdminAppInfo> for AppInfo {
+    type Error = AdminApiError;
+
+    fn try_from(value: AdminAppInfo) -> Result<Self, Self::Error> {
+        let secret_hash =
+            app_secret_hash(value.app_secret.as_deref(), value.app_secret_hash.as_deref()).ok_or_else(|| AdminApiError::BadRequest("app_secret or a valid app_secret_hash is required".to_owned()))?;
+        Ok(Self {
+            app_id: value.app_id,
+            secret_hash,
+            limits: value.limits.unwrap_or_default(),
+            token_ttl_secs: value.token_ttl_secs,
+            media_backend: value.media_backend,
+            media_secret: value.media_secret.filter(|s| !s.is_empty()).map(MediaSecret),
+        })
+    }
+}
+
+#[derive(Debug, Object)]
+pub struct AdminPhoneNumber {
+    pub number: String,
+    /// Allowed source subnets for incoming calls, in CIDR format
+    pub subnets: Vec<String>,
+    pub auth: Option<SipAuth>,
+    pub app_id: String,
+    pub hook: String,
+    pub limits: Option<CallLimits>,
+    /// Allowed codecs in preference order, all codecs are allowed if empty
+    pub codecs: Option<Vec<Codec>>,
+}
+
+impl TryFrom<AdminPhoneNumber> for PhoneNumber {
+    type Error = AdminApiError;
+
+    fn try_from(value: AdminPhoneNumber) -> Result<Self, Self::Error> {
+        let subnets = value
+            .subnets
+            .iter()
+            .map(|s| s.parse().map_err(|_| AdminApiError::BadRequest(format!("invalid subnet {s}"))))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            number: value.number,
+            subnets,
+            auth: value.auth,
+            app_id: value.app_id,
+            hook: value.hook,
+            limits: value.limits.unwrap_or_default(),
+            codecs: value.codecs.unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, Serialize, Deserialize)]
+#[oai(rename_all = "snake_case")]
+pub enum AdminCallState {
+    Trying,
+    Ringing,
+    Early,
+    Talking,
+    Ending,
+}
+
+/// Call info which is collected from all gateway nodes
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
+pub struct AdminCallInfo {
+    pub call_id: String,
+    /// Gateway node which is handling this call
+    pub node: String,
+    pub direction: CallDirection,
+    pub state: AdminCallState,
+    pub from: String,
+    pub to: String,
+    pub app_id: String,
+    pub age_secs: u64,
+    /// Number of websocket or rpc clients which are subscribed to the call
+    pub subscribers: u32,
+}
+
+/// Source ip which is banned by the sip flood filter of a gateway node
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
+pub struct AdminSipBan {
+    pub node: String,
+    pub ip: String,
+    pub expires_in_secs: u64,
+    pub reason: String,
+}
+
+/// Packet counters of one direction of a relay session
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
+pub struct AdminRelayStream {
+    pub rtp_packets: u64,
+    pub rtp_bytes: u64,
+    pub rtcp_packets: u64,
+    /// Packets which are received before the other side address is known
+    pub dropped_packets: u64,
+}
+
+/// Media session of the built-in RTP relay of a gateway node
+#[derive(Debug, Clone, Object, Serialize, Deserialize)]
+pub struct AdminRelaySession {
+    pub node: String,
+    pub session: String,
+    pub room: String,
+    pub peer: String,
+    /// Relay address which the SIP side sends to
+    pub sip_local: String,
+    pub sip_remote: Option<String>,
+    /// Relay address which the plain RTP peer sends to
+    pub peer_local: String,
+    pub peer_remote: Option<String>,
+    pub age_secs: u64,
+    pub to_peer: AdminRelayStream,
+    pub to_sip: AdminRelayStream,
+}
+
+/// A captured SIP message as an arrow of the call ladder diagram
+#[derive(Debug, Clone, Object)]
+pub struct AdminSipLadderEntry {
+    /// Unix time in microseconds
+    pub ts_us: u64,
+    /// `in` for messages received by the gateway, `out` for sent ones
+    pub direction: String,
+    pub src: String,
+    pub dst: String,
+    pub sip_call_id: String,
+    /// Request line or status line
+    pub summary: String,
+}
+
+#[derive(Debug, Object)]
+pub struct AdminDrainRequest {
+    /// Max time to wait for active calls before ending them, default is the gateway drain timeout
+    pub timeout_secs: Option<u64>,
+}
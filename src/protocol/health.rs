@@ -0,0 +1,37 @@
+use poem_openapi::Object;
+
+#[derive(Debug, Object)]
+pub struct DrainStatus {
+    pub draining: bool,
+    pub elapsed_secs: Option<u64>,
+    /// Remaining time before active calls are ended
+    pub remaining_secs: Option<u64>,
+}
+
+#[derive(Debug, Object)]
+pub struct HealthCheck {
+    pub name: String,
+    pub ok: bool,
+    pub message: Option<String>,
+}
+
+impl HealthCheck {
+    pub fn new(name: &str, ok: bool, message: Option<String>) -> Self {
+        Self { name: name.to_owned(), ok, message }
+    }
+}
+
+#[derive(Debug, Object)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    pub fn new(checks: Vec<HealthCheck>) -> Self {
+        Self {
+            ok: checks.iter().all(|c| c.ok),
+            checks,
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
 
@@ -6,6 +8,7 @@ use super::{
     StreamingInfo,
 };
 
+/// Action of the hook response to an arrived call
 #[derive(Debug, Enum, Serialize, Deserialize)]
 pub enum IncomingCallAction {
     Ring,
@@ -17,30 +20,61 @@ pub enum IncomingCallAction {
 pub struct IncomingCallActionRequest {
     pub action: IncomingCallAction,
     pub stream: Option<StreamingInfo>,
+    /// Extra headers for the SIP response, only X- and User-to-User headers are allowed
+    pub headers: Option<HashMap<String, String>>,
+}
+
+/// Action of the call action api, recording actions are only for accepted calls
+#[derive(Debug, Enum, Serialize, Deserialize)]
+pub enum IncomingCallControlAction {
+    Ring,
+    Accept,
+    End,
+    /// Start a new recording or resume a paused one
+    StartRecording,
+    StopRecording,
+    PauseRecording,
+}
+
+#[derive(Debug, Object, Serialize, Deserialize)]
+pub struct IncomingCallControlRequest {
+    pub action: IncomingCallControlAction,
+    pub stream: Option<StreamingInfo>,
+    /// Extra headers for the SIP response, only X- and User-to-User headers are allowed
+    pub headers: Option<HashMap<String, String>>,
 }
 
-impl TryFrom<IncomingCallActionRequest> for incoming_call_request::Action {
+impl TryFrom<IncomingCallControlRequest> for incoming_call_request::Action {
     type Error = &'static str;
 
-    fn try_from(mut value: IncomingCallActionRequest) -> Result<Self, Self::Error> {
+    fn try_from(mut value: IncomingCallControlRequest) -> Result<Self, Self::Error> {
         let req = match value.action {
-            IncomingCallAction::Ring => incoming_call_request::Action::Ring(incoming_call_request::Ring {}),
-            IncomingCallAction::Accept => {
+            IncomingCallControlAction::Ring => incoming_call_request::Action::Ring(incoming_call_request::Ring {}),
+            IncomingCallControlAction::Accept => {
                 let stream = value.stream.take().ok_or("missing stream info")?;
                 incoming_call_request::Action::Accept(incoming_call_request::Accept {
                     room: stream.room,
                     peer: stream.peer,
                     record: stream.record,
+                    headers: value.headers.unwrap_or_default(),
                 })
             }
-            IncomingCallAction::End => incoming_call_request::Action::End(incoming_call_request::End {}),
+            IncomingCallControlAction::End => incoming_call_request::Action::End(incoming_call_request::End {
+                headers: value.headers.unwrap_or_default(),
+            }),
+            IncomingCallControlAction::StartRecording => incoming_call_request::Action::StartRecording(Default::default()),
+            IncomingCallControlAction::StopRecording => incoming_call_request::Action::StopRecording(Default::default()),
+            IncomingCallControlAction::PauseRecording => incoming_call_request::Action::PauseRecording(Default::default()),
         };
         Ok(req)
     }
 }
 
 #[derive(Debug, Object, Serialize, Deserialize)]
-pub struct IncomingCallActionResponse {}
+pub struct IncomingCallActionResponse {
+    /// Recording id on the media server, only for recording actions
+    pub recording_id: Option<String>,
+}
 
 impl TryFrom<incoming_call_response::Response> for IncomingCallActionResponse {
     type Error = String;
@@ -48,7 +82,10 @@ impl TryFrom<incoming_call_response::Response> for IncomingCallActionResponse {
     fn try_from(value: incoming_call_response::Response) -> Result<Self, Self::Error> {
         match value {
             incoming_call_response::Response::Error(error) => Err(error.message),
-            _ => Ok(IncomingCallActionResponse {}),
+            incoming_call_response::Response::Recording(recording) => Ok(IncomingCallActionResponse {
+                recording_id: Some(recording.recording_id),
+            }),
+            _ => Ok(IncomingCallActionResponse { recording_id: None }),
         }
     }
 }
@@ -62,5 +99,7 @@ pub fn is_sip_incoming_cancelled(event: &Option<incoming_call_event::Event>) ->
         },
         incoming_call_event::Event::Accepted(..) => None,
         incoming_call_event::Event::Ended(..) => None,
+        incoming_call_event::Event::MediaStats(..) => None,
+        incoming_call_event::Event::Recording(..) => None,
     }
 }
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
 
@@ -6,17 +8,40 @@ use super::{
     SipAuth, StreamingInfo,
 };
 
-#[derive(Debug, Object)]
+#[derive(Debug, Object, Serialize, Deserialize)]
 pub struct CreateCallRequest {
-    pub sip_server: String,
+    /// Sip server of the callee, required if `trunk` is not provided. Optional since 0.2.0
+    pub sip_server: Option<String>,
+    /// Override the trunk auth when `trunk` is provided
     pub sip_auth: Option<SipAuth>,
+    /// Name of a configured trunk, which provides sip server and auth
+    pub trunk: Option<String>,
     pub from_number: String,
+    /// Display name which is shown in From header
+    pub from_display_name: Option<String>,
+    /// P-Asserted-Identity, can be a number or a full sip/tel uri
+    pub asserted_identity: Option<String>,
+    /// Request to hide the caller identity with Privacy: id
+    pub privacy: Option<bool>,
     pub to_number: String,
     pub hook: String,
     pub streaming: StreamingInfo,
+    /// Extra headers for INVITE, only X- and User-to-User headers are allowed
+    pub headers: Option<HashMap<String, String>>,
+    /// Constraints for the node which places the call, default to the trunk region and source ip
+    pub placement: Option<CallPlacement>,
+}
+
+/// The least loaded node matching all constraints places the call
+#[derive(Debug, Clone, Default, Object, Serialize, Deserialize)]
+pub struct CallPlacement {
+    /// Region tag of the node
+    pub region: Option<String>,
+    /// SIP source ip of the node, for carriers which allowlist gateway ips
+    pub source_ip: Option<String>,
 }
 
-#[derive(Debug, Object)]
+#[derive(Debug, Object, Serialize, Deserialize)]
 pub struct CreateCallResponse {
     pub call_id: String,
     pub call_token: String,
@@ -26,6 +51,10 @@ pub struct CreateCallResponse {
 #[derive(Debug, Enum, Serialize, Deserialize)]
 pub enum OutgoingCallAction {
     End,
+    /// Start a new recording or resume a paused one
+    StartRecording,
+    StopRecording,
+    PauseRecording,
 }
 
 #[derive(Debug, Object, Serialize, Deserialize)]
@@ -40,13 +69,19 @@ impl TryFrom<OutgoingCallActionRequest> for outgoing_call_request::Action {
     fn try_from(value: OutgoingCallActionRequest) -> Result<Self, Self::Error> {
         let req = match value.action {
             OutgoingCallAction::End => outgoing_call_request::Action::End(outgoing_call_request::End {}),
+            OutgoingCallAction::StartRecording => outgoing_call_request::Action::StartRecording(Default::default()),
+            OutgoingCallAction::StopRecording => outgoing_call_request::Action::StopRecording(Default::default()),
+            OutgoingCallAction::PauseRecording => outgoing_call_request::Action::PauseRecording(Default::default()),
         };
         Ok(req)
     }
 }
 
 #[derive(Debug, Object, Serialize, Deserialize)]
-pub struct OutgoingCallActionResponse {}
+pub struct OutgoingCallActionResponse {
+    /// Recording id on the media server, only for recording actions
+    pub recording_id: Option<String>,
+}
 
 impl TryFrom<outgoing_call_response::Response> for OutgoingCallActionResponse {
     type Error = String;
@@ -54,7 +89,10 @@ impl TryFrom<outgoing_call_response::Response> for OutgoingCallActionResponse {
     fn try_from(value: outgoing_call_response::Response) -> Result<Self, Self::Error> {
         match value {
             outgoing_call_response::Response::Error(error) => Err(error.message),
-            outgoing_call_response::Response::End(_end) => Ok(OutgoingCallActionResponse {}),
+            outgoing_call_response::Response::End(_end) => Ok(OutgoingCallActionResponse { recording_id: None }),
+            outgoing_call_response::Response::Recording(recording) => Ok(OutgoingCallActionResponse {
+                recording_id: Some(recording.recording_id),
+            }),
         }
     }
 }
@@ -1,4 +1,92 @@
 // This file is @generated by prost-build.
+/// quality of one RTP direction of the SIP leg, measured by the media backend
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct MediaStreamStats {
+    #[prost(uint64, tag = "1")]
+    pub packets: u64,
+    #[prost(uint64, tag = "2")]
+    pub bytes: u64,
+    #[prost(uint64, tag = "3")]
+    pub lost: u64,
+    /// interarrival jitter in milliseconds
+    #[prost(float, tag = "4")]
+    pub jitter_ms: f32,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct MediaStats {
+    /// packets from the SIP side
+    #[prost(message, optional, tag = "1")]
+    pub inbound: ::core::option::Option<MediaStreamStats>,
+    /// packets to the SIP side
+    #[prost(message, optional, tag = "2")]
+    pub outbound: ::core::option::Option<MediaStreamStats>,
+    /// estimated MOS of the worse direction, from 1.0 to 4.5, 0 when no packet is received
+    #[prost(float, tag = "3")]
+    pub mos: f32,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RecordingState {
+    #[prost(enumeration = "recording_state::State", tag = "1")]
+    pub state: i32,
+    /// id of the recording on the media server
+    #[prost(string, tag = "2")]
+    pub recording_id: ::prost::alloc::string::String,
+}
+/// Nested message and enum types in `RecordingState`.
+pub mod recording_state {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum State {
+        Stopped = 0,
+        Recording = 1,
+        Paused = 2,
+    }
+    impl State {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Stopped => "STOPPED",
+                Self::Recording => "RECORDING",
+                Self::Paused => "PAUSED",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "STOPPED" => Some(Self::Stopped),
+                "RECORDING" => Some(Self::Recording),
+                "PAUSED" => Some(Self::Paused),
+                _ => None,
+            }
+        }
+    }
+}
+/// address where the app side peer sends RTP, only for media backends which relay to a plain RTP peer
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MediaEndpoint {
+    #[prost(string, tag = "1")]
+    pub ip: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+}
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct IncomingCallData {
@@ -10,7 +98,7 @@ pub mod incoming_call_data {
     #[derive(serde::Serialize, serde::Deserialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
     pub struct IncomingCallEvent {
-        #[prost(oneof = "incoming_call_event::Event", tags = "1, 2, 3, 4")]
+        #[prost(oneof = "incoming_call_event::Event", tags = "1, 2, 3, 4, 5, 6")]
         pub event: ::core::option::Option<incoming_call_event::Event>,
     }
     /// Nested message and enum types in `IncomingCallEvent`.
@@ -39,11 +127,22 @@ pub mod incoming_call_data {
             }
         }
         #[derive(serde::Serialize, serde::Deserialize)]
-        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
-        pub struct Accepted {}
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct Accepted {
+            /// missing when the media backend has no plain RTP peer
+            #[prost(message, optional, tag = "1")]
+            pub media_endpoint: ::core::option::Option<super::super::MediaEndpoint>,
+        }
         #[derive(serde::Serialize, serde::Deserialize)]
-        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
-        pub struct Ended {}
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct Ended {
+            /// empty for normal end, node_failure when the call is cleaned up by another node
+            #[prost(string, tag = "1")]
+            pub reason: ::prost::alloc::string::String,
+            /// last media stats of the call, missing when the media backend has no stats
+            #[prost(message, optional, tag = "2")]
+            pub media_stats: ::core::option::Option<super::super::MediaStats>,
+        }
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Message)]
         pub struct Error {
@@ -61,6 +160,10 @@ pub mod incoming_call_data {
             Accepted(Accepted),
             #[prost(message, tag = "4")]
             Ended(Ended),
+            #[prost(message, tag = "5")]
+            MediaStats(super::super::MediaStats),
+            #[prost(message, tag = "6")]
+            Recording(super::super::RecordingState),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -68,7 +171,7 @@ pub mod incoming_call_data {
     pub struct IncomingCallRequest {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "incoming_call_request::Action", tags = "10, 11, 12, 13")]
+        #[prost(oneof = "incoming_call_request::Action", tags = "10, 11, 12, 13, 14, 15, 16")]
         pub action: ::core::option::Option<incoming_call_request::Action>,
     }
     /// Nested message and enum types in `IncomingCallRequest`.
@@ -85,13 +188,34 @@ pub mod incoming_call_data {
             pub peer: ::prost::alloc::string::String,
             #[prost(bool, tag = "3")]
             pub record: bool,
+            #[prost(map = "string, string", tag = "4")]
+            pub headers: ::std::collections::HashMap<
+                ::prost::alloc::string::String,
+                ::prost::alloc::string::String,
+            >,
         }
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct Accept2 {}
         #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct End {
+            #[prost(map = "string, string", tag = "1")]
+            pub headers: ::std::collections::HashMap<
+                ::prost::alloc::string::String,
+                ::prost::alloc::string::String,
+            >,
+        }
+        /// start a new recording or resume a paused one
+        #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
-        pub struct End {}
+        pub struct StartRecording {}
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct StopRecording {}
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct PauseRecording {}
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Action {
@@ -103,6 +227,12 @@ pub mod incoming_call_data {
             Accept2(Accept2),
             #[prost(message, tag = "13")]
             End(End),
+            #[prost(message, tag = "14")]
+            StartRecording(StartRecording),
+            #[prost(message, tag = "15")]
+            StopRecording(StopRecording),
+            #[prost(message, tag = "16")]
+            PauseRecording(PauseRecording),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -110,7 +240,7 @@ pub mod incoming_call_data {
     pub struct IncomingCallResponse {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "incoming_call_response::Response", tags = "10, 11, 12, 13, 14")]
+        #[prost(oneof = "incoming_call_response::Response", tags = "10, 11, 12, 13, 14, 15")]
         pub response: ::core::option::Option<incoming_call_response::Response>,
     }
     /// Nested message and enum types in `IncomingCallResponse`.
@@ -141,6 +271,12 @@ pub mod incoming_call_data {
             pub message: ::prost::alloc::string::String,
         }
         #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct Recording {
+            #[prost(string, tag = "1")]
+            pub recording_id: ::prost::alloc::string::String,
+        }
+        #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Response {
             #[prost(message, tag = "10")]
@@ -153,6 +289,8 @@ pub mod incoming_call_data {
             Accept2(Accept2),
             #[prost(message, tag = "14")]
             End(End),
+            #[prost(message, tag = "15")]
+            Recording(Recording),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -177,13 +315,13 @@ pub mod outgoing_call_data {
     #[derive(serde::Serialize, serde::Deserialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
     pub struct OutgoingCallEvent {
-        #[prost(oneof = "outgoing_call_event::Event", tags = "1, 2, 3")]
+        #[prost(oneof = "outgoing_call_event::Event", tags = "1, 2, 3, 4, 5")]
         pub event: ::core::option::Option<outgoing_call_event::Event>,
     }
     /// Nested message and enum types in `OutgoingCallEvent`.
     pub mod outgoing_call_event {
         #[derive(serde::Serialize, serde::Deserialize)]
-        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
         pub struct SipEvent {
             #[prost(oneof = "sip_event::Event", tags = "1, 2, 3, 4, 5")]
             pub event: ::core::option::Option<sip_event::Event>,
@@ -203,10 +341,13 @@ pub mod outgoing_call_data {
                 pub code: u32,
             }
             #[derive(serde::Serialize, serde::Deserialize)]
-            #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+            #[derive(Clone, PartialEq, ::prost::Message)]
             pub struct Accepted {
                 #[prost(uint32, tag = "1")]
                 pub code: u32,
+                /// missing when the media backend has no plain RTP peer
+                #[prost(message, optional, tag = "2")]
+                pub media_endpoint: ::core::option::Option<super::super::super::MediaEndpoint>,
             }
             #[derive(serde::Serialize, serde::Deserialize)]
             #[derive(Clone, Copy, PartialEq, ::prost::Message)]
@@ -218,7 +359,7 @@ pub mod outgoing_call_data {
             #[derive(Clone, Copy, PartialEq, ::prost::Message)]
             pub struct Bye {}
             #[derive(serde::Serialize, serde::Deserialize)]
-            #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
+            #[derive(Clone, PartialEq, ::prost::Oneof)]
             pub enum Event {
                 #[prost(message, tag = "1")]
                 Provisional(Provisional),
@@ -233,8 +374,15 @@ pub mod outgoing_call_data {
             }
         }
         #[derive(serde::Serialize, serde::Deserialize)]
-        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
-        pub struct Ended {}
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct Ended {
+            /// empty for normal end, node_failure when the call is cleaned up by another node
+            #[prost(string, tag = "1")]
+            pub reason: ::prost::alloc::string::String,
+            /// last media stats of the call, missing when the media backend has no stats
+            #[prost(message, optional, tag = "2")]
+            pub media_stats: ::core::option::Option<super::super::MediaStats>,
+        }
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Message)]
         pub struct Error {
@@ -250,6 +398,10 @@ pub mod outgoing_call_data {
             Sip(SipEvent),
             #[prost(message, tag = "3")]
             Ended(Ended),
+            #[prost(message, tag = "4")]
+            MediaStats(super::super::MediaStats),
+            #[prost(message, tag = "5")]
+            Recording(super::super::RecordingState),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -257,7 +409,7 @@ pub mod outgoing_call_data {
     pub struct OutgoingCallRequest {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "outgoing_call_request::Action", tags = "10")]
+        #[prost(oneof = "outgoing_call_request::Action", tags = "10, 11, 12, 13")]
         pub action: ::core::option::Option<outgoing_call_request::Action>,
     }
     /// Nested message and enum types in `OutgoingCallRequest`.
@@ -265,11 +417,27 @@ pub mod outgoing_call_data {
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct End {}
+        /// start a new recording or resume a paused one
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct StartRecording {}
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct StopRecording {}
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct PauseRecording {}
         #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
         pub enum Action {
             #[prost(message, tag = "10")]
             End(End),
+            #[prost(message, tag = "11")]
+            StartRecording(StartRecording),
+            #[prost(message, tag = "12")]
+            StopRecording(StopRecording),
+            #[prost(message, tag = "13")]
+            PauseRecording(PauseRecording),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -277,7 +445,7 @@ pub mod outgoing_call_data {
     pub struct OutgoingCallResponse {
         #[prost(uint32, tag = "1")]
         pub req_id: u32,
-        #[prost(oneof = "outgoing_call_response::Response", tags = "10, 11")]
+        #[prost(oneof = "outgoing_call_response::Response", tags = "10, 11, 12")]
         pub response: ::core::option::Option<outgoing_call_response::Response>,
     }
     /// Nested message and enum types in `OutgoingCallResponse`.
@@ -292,12 +460,20 @@ pub mod outgoing_call_data {
             pub message: ::prost::alloc::string::String,
         }
         #[derive(serde::Serialize, serde::Deserialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct Recording {
+            #[prost(string, tag = "1")]
+            pub recording_id: ::prost::alloc::string::String,
+        }
+        #[derive(serde::Serialize, serde::Deserialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Response {
             #[prost(message, tag = "10")]
             Error(Error),
             #[prost(message, tag = "11")]
             End(End),
+            #[prost(message, tag = "12")]
+            Recording(Recording),
         }
     }
     #[derive(serde::Serialize, serde::Deserialize)]
@@ -316,7 +492,7 @@ pub mod outgoing_call_data {
 pub struct IncomingCallNotify {
     #[prost(string, tag = "1")]
     pub call_id: ::prost::alloc::string::String,
-    #[prost(oneof = "incoming_call_notify::Event", tags = "10, 11, 12")]
+    #[prost(oneof = "incoming_call_notify::Event", tags = "10, 11, 12, 13")]
     pub event: ::core::option::Option<incoming_call_notify::Event>,
 }
 /// Nested message and enum types in `IncomingCallNotify`.
@@ -332,6 +508,23 @@ pub mod incoming_call_notify {
         pub call_from: ::prost::alloc::string::String,
         #[prost(string, tag = "4")]
         pub call_to: ::prost::alloc::string::String,
+        #[prost(string, optional, tag = "5")]
+        pub call_from_display: ::core::option::Option<::prost::alloc::string::String>,
+        #[prost(string, optional, tag = "6")]
+        pub call_to_display: ::core::option::Option<::prost::alloc::string::String>,
+        #[prost(string, optional, tag = "7")]
+        pub asserted_identity: ::core::option::Option<::prost::alloc::string::String>,
+        #[prost(string, optional, tag = "8")]
+        pub remote_party_id: ::core::option::Option<::prost::alloc::string::String>,
+        #[prost(string, repeated, tag = "9")]
+        pub diversions: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+        #[prost(bool, tag = "10")]
+        pub privacy: bool,
+        #[prost(map = "string, string", tag = "11")]
+        pub headers: ::std::collections::HashMap<
+            ::prost::alloc::string::String,
+            ::prost::alloc::string::String,
+        >,
     }
     #[derive(serde::Serialize, serde::Deserialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
@@ -349,6 +542,19 @@ pub mod incoming_call_notify {
         #[prost(string, tag = "4")]
         pub call_to: ::prost::alloc::string::String,
     }
+    /// the call is rejected by the gateway before it arrives, like 488 when no codec is allowed by the number policy
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct CallRejected {
+        #[prost(string, tag = "3")]
+        pub call_from: ::prost::alloc::string::String,
+        #[prost(string, tag = "4")]
+        pub call_to: ::prost::alloc::string::String,
+        #[prost(uint32, tag = "5")]
+        pub code: u32,
+        #[prost(string, tag = "6")]
+        pub reason: ::prost::alloc::string::String,
+    }
     #[derive(serde::Serialize, serde::Deserialize)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Event {
@@ -358,6 +564,8 @@ pub mod incoming_call_notify {
         Cancelled(CallCancelled),
         #[prost(message, tag = "12")]
         Accepted(CallAccepted),
+        #[prost(message, tag = "13")]
+        Rejected(CallRejected),
     }
 }
 #[derive(serde::Serialize, serde::Deserialize)]
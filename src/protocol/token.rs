@@ -0,0 +1,24 @@
+use poem_openapi::Object;
+
+use super::CallTokenScope;
+
+/// Mint an extra token for a call from an existing control token of the same call
+#[derive(Debug, Object)]
+pub struct CreateCallTokenRequest {
+    pub call_token: String,
+    pub scope: CallTokenScope,
+    /// Default and max value is the app token TTL
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Object)]
+pub struct CreateCallTokenResponse {
+    pub call_token: String,
+    pub call_ws: String,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Debug, Object)]
+pub struct RevokeCallTokenRequest {
+    pub call_token: String,
+}
@@ -0,0 +1,202 @@
+use std::{collections::HashSet, path::PathBuf, str::FromStr};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jwt_simple::{prelude::*, token::Token};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TokenKeyError {
+    #[error("IoError {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid token key {0}: {1}")]
+    Invalid(String, String),
+}
+
+/// Call token key file with its key id, a private key is needed for signing and a public key is enough for verifying
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CallTokenKeyConfig {
+    pub kid: String,
+    /// PKCS#8 ES256 or Ed25519 key in PEM
+    pub file: PathBuf,
+}
+
+impl FromStr for CallTokenKeyConfig {
+    type Err = String;
+
+    /// Parse `kid=path` from command line
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((kid, file)) if !kid.is_empty() && !file.is_empty() => Ok(Self {
+                kid: kid.to_owned(),
+                file: file.into(),
+            }),
+            _ => Err(format!("invalid token key {s}, expected kid=path")),
+        }
+    }
+}
+
+enum SigningKey {
+    Es256(ES256KeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+enum VerifyKey {
+    Es256(ES256PublicKey),
+    Ed25519(Ed25519PublicKey),
+}
+
+impl VerifyKey {
+    fn verify<T: Serialize + DeserializeOwned>(&self, token: &str, options: VerificationOptions) -> Option<JWTClaims<T>> {
+        match self {
+            Self::Es256(key) => key.verify_token::<T>(token, Some(options)).ok(),
+            Self::Ed25519(key) => key.verify_token::<T>(token, Some(options)).ok(),
+        }
+    }
+
+    fn to_jwk(&self, kid: &str) -> Jwk {
+        match self {
+            Self::Es256(key) => {
+                // uncompressed point: 0x04 | x | y
+                let point = key.to_bytes_uncompressed();
+                Jwk {
+                    kty: "EC",
+                    crv: "P-256",
+                    alg: "ES256",
+                    key_use: "sig",
+                    kid: kid.to_owned(),
+                    x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+                    y: Some(URL_SAFE_NO_PAD.encode(&point[33..65])),
+                }
+            }
+            Self::Ed25519(key) => Jwk {
+                kty: "OKP",
+                crv: "Ed25519",
+                alg: "EdDSA",
+                key_use: "sig",
+                kid: kid.to_owned(),
+                x: URL_SAFE_NO_PAD.encode(key.to_bytes()),
+                y: None,
+            },
+        }
+    }
+}
+
+/// Public key in JWK format, RFC 7517
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwk {
+    pub kty: &'static str,
+    pub crv: &'static str,
+    pub alg: &'static str,
+    #[serde(rename = "use")]
+    pub key_use: &'static str,
+    pub kid: String,
+    pub x: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// Asymmetric keys of call tokens. The first key signs new tokens, the other keys are kept for verifying
+/// tokens which are signed before a rotation.
+pub struct CallTokenKeys {
+    signer: (String, SigningKey),
+    verifiers: Vec<(String, VerifyKey)>,
+}
+
+impl CallTokenKeys {
+    pub async fn load(keys: &[CallTokenKeyConfig]) -> Result<Self, TokenKeyError> {
+        let mut pems = Vec::with_capacity(keys.len());
+        for key in keys {
+            pems.push((key.kid.clone(), tokio::fs::read_to_string(&key.file).await?));
+        }
+        let keys = Self::from_pems(&pems)?;
+        log::info!("[CallTokenKeys] loaded {} keys, signing with {}", keys.verifiers.len(), keys.signer.0);
+        Ok(keys)
+    }
+
+    pub fn from_pems(pems: &[(String, String)]) -> Result<Self, TokenKeyError> {
+        let mut signer = None;
+        let mut verifiers = Vec::with_capacity(pems.len());
+        let mut kids = HashSet::new();
+        for (kid, pem) in pems {
+            if !kids.insert(kid.as_str()) {
+                return Err(TokenKeyError::Invalid(kid.clone(), "duplicated kid".to_owned()));
+            }
+            let invalid = |e: jwt_simple::Error| TokenKeyError::Invalid(kid.clone(), e.to_string());
+            let verifier = if pem.contains("PUBLIC KEY") {
+                match ES256PublicKey::from_pem(pem) {
+                    Ok(key) => VerifyKey::Es256(key),
+                    Err(_) => VerifyKey::Ed25519(Ed25519PublicKey::from_pem(pem).map_err(invalid)?),
+                }
+            } else {
+                let key = match ES256KeyPair::from_pem(pem) {
+                    Ok(key) => SigningKey::Es256(key.with_key_id(kid)),
+                    Err(_) => SigningKey::Ed25519(Ed25519KeyPair::from_pem(pem).map_err(invalid)?.with_key_id(kid)),
+                };
+                let verifier = match &key {
+                    SigningKey::Es256(key) => VerifyKey::Es256(key.public_key()),
+                    SigningKey::Ed25519(key) => VerifyKey::Ed25519(key.public_key()),
+                };
+                if verifiers.is_empty() {
+                    signer = Some((kid.clone(), key));
+                }
+                verifier
+            };
+            verifiers.push((kid.clone(), verifier));
+        }
+        let first_kid = pems.first().map(|(kid, _)| kid.clone()).unwrap_or_default();
+        let signer = signer.ok_or_else(|| TokenKeyError::Invalid(first_kid, "first key must be a private key".to_owned()))?;
+        Ok(Self { signer, verifiers })
+    }
+
+    pub fn sign<T: Serialize + DeserializeOwned>(&self, claims: JWTClaims<T>) -> String {
+        match &self.signer.1 {
+            SigningKey::Es256(key) => key.sign(claims).expect("Should create jwt"),
+            SigningKey::Ed25519(key) => key.sign(claims).expect("Should create jwt"),
+        }
+    }
+
+    /// Verify with the key which is selected by the kid in token header
+    pub fn verify<T: Serialize + DeserializeOwned>(&self, token: &str, options: VerificationOptions) -> Option<JWTClaims<T>> {
+        let metadata = Token::decode_metadata(token).ok()?;
+        let kid = metadata.key_id()?;
+        let (_, key) = self.verifiers.iter().find(|(k, _)| k == kid)?;
+        key.verify(token, options)
+    }
+
+    pub fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: self.verifiers.iter().map(|(kid, key)| key.to_jwk(kid)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallTokenKeyConfig, CallTokenKeys};
+
+    const PUBLIC_KEY: &str =
+        "-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEAU8HZHRxZvPPiDGASE47dJhKaC7J\nY5jL5blsGT8EQlOVVjShOFc8mzaFACBm7Hk3VaX+0IoAxfSsuvBCHIQqNA==\n-----END PUBLIC KEY-----";
+
+    #[test]
+    fn test_parse_key_config() {
+        let key: CallTokenKeyConfig = "k1=/etc/gateway/k1.pem".parse().expect("should parse");
+        assert_eq!(key.kid, "k1");
+        assert_eq!(key.file.to_str(), Some("/etc/gateway/k1.pem"));
+        assert!("/etc/gateway/k1.pem".parse::<CallTokenKeyConfig>().is_err());
+        assert!("=/etc/gateway/k1.pem".parse::<CallTokenKeyConfig>().is_err());
+    }
+
+    #[test]
+    fn test_signing_key_required() {
+        // the signing key must be a private key
+        assert!(CallTokenKeys::from_pems(&[("old".to_owned(), PUBLIC_KEY.to_owned())]).is_err());
+        assert!(CallTokenKeys::from_pems(&[]).is_err());
+    }
+}
@@ -1,10 +1,24 @@
 use thiserror::Error;
 
 mod api;
+mod backend;
+mod codec_policy;
+mod engine;
+#[cfg(any(test, feature = "test-utils"))]
+mod fake;
+mod quality;
+mod relay;
 mod rtp_answer;
 mod rtp_offer;
 
 pub use api::*;
+pub use backend::*;
+pub use codec_policy::*;
+pub use engine::*;
+#[cfg(any(test, feature = "test-utils"))]
+pub use fake::*;
+pub use quality::*;
+pub use relay::*;
 pub use rtp_answer::*;
 pub use rtp_offer::*;
 
@@ -20,4 +34,18 @@ pub enum MediaEngineError {
     InvalidLocation,
     #[error("Invalid status code ({0})")]
     InvalidStatus(u16),
+    #[error("Unsupported operation {0}")]
+    Unsupported(&'static str),
+    #[error("Invalid sdp")]
+    InvalidSdp,
+    #[error("Sdp policy {0}")]
+    SdpPolicy(#[from] SdpPolicyError),
+    #[error("Unknown session")]
+    UnknownSession,
+    #[error("No free relay port")]
+    NoRelayPort,
+    #[error("Unknown media backend {0}")]
+    UnknownBackend(String),
+    #[error("Missing media secret")]
+    MissingMediaSecret,
 }
@@ -1,8 +1,11 @@
-use std::time::Duration;
+use std::sync::Arc;
 
-use serde::Deserialize;
 use thiserror::Error;
 
+use crate::protocol::{protobuf::sip_gateway::MediaEndpoint, StreamingInfo};
+
+use super::{MediaBackend, MediaEngineError, RecordingAction};
+
 #[derive(Debug, Error)]
 pub enum MediaApiError {
     #[error("HttpError ({0})")]
@@ -13,86 +16,75 @@ pub enum MediaApiError {
 
 pub type Result<T> = std::result::Result<T, MediaApiError>;
 
-#[derive(Deserialize)]
-struct TokenData {
-    token: String,
-}
-
-#[derive(Deserialize)]
-struct CreateTokenResponse {
-    // status: bool,
-    error: Option<String>,
-    data: Option<TokenData>,
-}
-
-#[derive(Debug, Clone)]
+/// Media backend of a call, credentials for the media server are held by the backend
+#[derive(Clone)]
 pub struct MediaApi {
-    gateway: String,
-    app_secret: String,
+    backend: Arc<dyn MediaBackend>,
+    backend_name: Option<String>,
 }
 
 impl MediaApi {
-    pub fn new(gateway: &str, secret: &str) -> Self {
-        Self {
-            gateway: gateway.to_string(),
-            app_secret: secret.to_string(),
-        }
+    pub fn new(backend: Arc<dyn MediaBackend>, backend_name: Option<String>) -> Self {
+        Self { backend, backend_name }
     }
 
-    pub fn gateway(&self) -> &str {
-        &self.gateway
+    pub fn backend(&self) -> &Arc<dyn MediaBackend> {
+        &self.backend
     }
 
-    pub async fn create_rtpengine_token(&self, room: &str, peer: &str, record: bool) -> Result<String> {
-        let res: CreateTokenResponse = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(3))
-            .build()
-            .expect("Should create client")
-            .post(&format!("{}/token/rtpengine", self.gateway))
-            .header("Authorization", format!("Bearer {}", self.app_secret))
-            .json(&serde_json::json!({
-                "room": room,
-                "peer": peer,
-                "ttl": 3600,
-                "record": record
-            }))
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
-
-        if let Some(data) = res.data {
-            Ok(data.token)
-        } else {
-            Err(MediaApiError::Media(res.error.unwrap_or_else(|| "Unknown".to_string())))
-        }
+    /// Name of the selected backend, None for the default atm0s backend
+    pub fn backend_name(&self) -> Option<&str> {
+        self.backend_name.as_deref()
     }
 
-    #[allow(unused)]
-    pub async fn create_webrtc_token(&self, room: &str, peer: &str, record: bool) -> Result<String> {
-        let res: CreateTokenResponse = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(3))
-            .build()
-            .expect("Should create client")
-            .post(&format!("{}/token/webrtc", self.gateway))
-            .header("Authorization", format!("Bearer {}", self.app_secret))
-            .json(&serde_json::json!({
-                "room": room,
-                "peer": peer,
-                "ttl": 3600,
-                "record": record
-            }))
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
-
-        if let Some(data) = res.data {
-            Ok(data.token)
-        } else {
-            Err(MediaApiError::Media(res.error.unwrap_or_else(|| "Unknown".to_string())))
+    /// Whether the backend reports media stats of sessions, only the RTP relay sees the packets
+    pub fn has_stats(&self) -> bool {
+        self.backend.has_stats()
+    }
+
+    /// Address where the app side peer sends RTP of a media session, None when the backend doesn't relay to a plain RTP peer
+    pub fn peer_endpoint(&self, session: &str) -> Option<MediaEndpoint> {
+        let addr = self.backend.peer_endpoint(session)?;
+        Some(MediaEndpoint {
+            ip: addr.ip().to_string(),
+            port: addr.port() as u32,
+        })
+    }
+
+    /// Control the recording of a media session of the call, return the recording id from the backend
+    pub async fn recording(&self, session: &str, action: RecordingAction) -> std::result::Result<String, MediaEngineError> {
+        self.backend.recording(session, action).await
+    }
+
+    /// Recording control of a media session which can run outside of the call
+    pub fn recorder(&self, session: &str) -> MediaRecorder {
+        MediaRecorder {
+            api: self.clone(),
+            session: session.to_owned(),
         }
     }
+
+    /// Token for the SDK which takes over the media of the call, `Unsupported` for backends without SDKs like the relay
+    pub async fn create_webrtc_token(&self, room: &str, peer: &str, record: bool) -> std::result::Result<String, MediaEngineError> {
+        let stream = StreamingInfo {
+            room: room.to_owned(),
+            peer: peer.to_owned(),
+            record,
+        };
+        self.backend.create_webrtc_token(&stream).await
+    }
+}
+
+/// Recording control of a media session, it is detached from the call so slow media server requests don't block the call loop
+#[derive(Clone)]
+pub struct MediaRecorder {
+    api: MediaApi,
+    session: String,
+}
+
+impl MediaRecorder {
+    pub async fn apply(&self, action: RecordingAction) -> std::result::Result<String, MediaEngineError> {
+        log::info!("[MediaRecorder] recording {} {}", action.as_str(), self.session);
+        self.api.recording(&self.session, action).await
+    }
 }
@@ -0,0 +1,298 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use bytes::Bytes;
+
+use crate::{
+    address_book::AddressBookStorage,
+    protocol::{protobuf::sip_gateway::MediaStats, StreamingInfo},
+};
+
+use super::{MediaApi, MediaEngineError, RtpEngine};
+
+/// Recording control of a media session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingAction {
+    /// Start a recording, or resume a paused one
+    Start,
+    Pause,
+    Stop,
+}
+
+impl RecordingAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordingAction::Start => "start",
+            RecordingAction::Pause => "pause",
+            RecordingAction::Stop => "stop",
+        }
+    }
+}
+
+/// Media sessions of calls, a session bridges the SIP side of a call with the media server which serves the stream.
+/// Sessions are identified by an opaque id which is returned on creation, credentials for the media server are held by each backend
+#[async_trait::async_trait]
+pub trait MediaBackend: Send + Sync {
+    /// Create a session which offers to the SIP side, return the session id and the offer sdp
+    async fn create_offer(&self, stream: &StreamingInfo) -> Result<(String, Bytes), MediaEngineError>;
+    /// Create a session which answers the SIP offer, return the session id and the answer sdp
+    async fn create_answer(&self, stream: &StreamingInfo, offer: Bytes) -> Result<(String, Bytes), MediaEngineError>;
+    /// Set the SIP answer of a session which is created by `create_offer`
+    async fn set_answer(&self, session: &str, answer: Bytes) -> Result<(), MediaEngineError>;
+    async fn destroy(&self, session: &str) -> Result<(), MediaEngineError>;
+    /// RTP quality of the SIP side of a session, `Unsupported` when the backend can't see the packets, like atm0s
+    async fn stats(&self, session: &str) -> Result<MediaStats, MediaEngineError>;
+    /// Whether `stats` returns the quality of sessions, calls only poll backends which have stats
+    fn has_stats(&self) -> bool {
+        false
+    }
+    /// Address where the app side peer sends its RTP, only for backends which relay to a plain RTP peer
+    fn peer_endpoint(&self, _session: &str) -> Option<SocketAddr> {
+        None
+    }
+    /// Control the recording of a session, return the id of the affected recording
+    async fn recording(&self, session: &str, action: RecordingAction) -> Result<String, MediaEngineError>;
+    /// Token for a SDK which joins the stream and takes over the media of the call, only for backends of a media server with SDKs
+    async fn create_webrtc_token(&self, _stream: &StreamingInfo) -> Result<String, MediaEngineError> {
+        Err(MediaEngineError::Unsupported("webrtc token"))
+    }
+}
+
+/// Media backend of atm0s media server, sessions are created on its rtpengine api with a token of the app media secret
+pub struct Atm0sMediaBackend {
+    engine: Arc<dyn RtpEngine>,
+    /// Secret of the app on the media server, backends without it can still update and delete sessions but can't create them
+    media_secret: Option<String>,
+}
+
+impl Atm0sMediaBackend {
+    pub fn new(engine: Arc<dyn RtpEngine>, media_secret: Option<String>) -> Self {
+        Self { engine, media_secret }
+    }
+
+    fn media_secret(&self) -> Result<&str, MediaEngineError> {
+        self.media_secret.as_deref().ok_or(MediaEngineError::MissingMediaSecret)
+    }
+}
+
+#[async_trait::async_trait]
+impl MediaBackend for Atm0sMediaBackend {
+    async fn create_offer(&self, stream: &StreamingInfo) -> Result<(String, Bytes), MediaEngineError> {
+        let token = self.engine.create_rtpengine_token(self.media_secret()?, stream).await?;
+        self.engine.create_offer(&token).await
+    }
+
+    async fn create_answer(&self, stream: &StreamingInfo, offer: Bytes) -> Result<(String, Bytes), MediaEngineError> {
+        let token = self.engine.create_rtpengine_token(self.media_secret()?, stream).await?;
+        self.engine.create_answer(&token, offer).await
+    }
+
+    async fn set_answer(&self, session: &str, answer: Bytes) -> Result<(), MediaEngineError> {
+        self.engine.set_answer(session, answer).await
+    }
+
+    async fn destroy(&self, session: &str) -> Result<(), MediaEngineError> {
+        self.engine.delete_session(session).await
+    }
+
+    async fn stats(&self, _session: &str) -> Result<MediaStats, MediaEngineError> {
+        // the rtpengine api of atm0s media server has no session stats, so calls on atm0s have no media stats.
+        // Only the built-in relay sees the packets, apps which need stats select it
+        Err(MediaEngineError::Unsupported("stats"))
+    }
+
+    async fn recording(&self, _session: &str, _action: RecordingAction) -> Result<String, MediaEngineError> {
+        // the rtpengine api of atm0s media server has no recording control, a stream is only recorded from start
+        // with `record` of the streaming info. Backends of media servers with recording apis implement it
+        Err(MediaEngineError::Unsupported("recording"))
+    }
+
+    async fn create_webrtc_token(&self, stream: &StreamingInfo) -> Result<String, MediaEngineError> {
+        Ok(self.engine.create_webrtc_token(self.media_secret()?, stream).await?)
+    }
+}
+
+/// Media backends of the gateway: atm0s is the default, apps can select another registered backend by name.
+/// The atm0s backend is created per app with the app media secret, named backends hold their own credentials
+#[derive(Clone)]
+pub struct MediaBackends {
+    engine: Arc<dyn RtpEngine>,
+    named: HashMap<String, Arc<dyn MediaBackend>>,
+    address_book: AddressBookStorage,
+}
+
+impl MediaBackends {
+    pub fn new(engine: Arc<dyn RtpEngine>, named: HashMap<String, Arc<dyn MediaBackend>>, address_book: AddressBookStorage) -> Self {
+        Self { engine, named, address_book }
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.named.contains_key(name)
+    }
+
+    /// Backend by name for existing sessions, None selects atm0s without a media secret, which can't create sessions.
+    /// Unknown names are errors, calls must not silently use another backend
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<dyn MediaBackend>, MediaEngineError> {
+        match name {
+            Some(name) => self.named.get(name).cloned().ok_or_else(|| MediaEngineError::UnknownBackend(name.to_owned())),
+            None => Ok(Arc::new(Atm0sMediaBackend::new(self.engine.clone(), None))),
+        }
+    }
+
+    /// Media api for calls of the app, with the backend which is selected in the app address book entry.
+    /// Atm0s sessions are created with the app media secret, or with the raw secret of the api caller when the app has none
+    pub fn api(&self, app_id: &str, app_secret: Option<&str>) -> Result<MediaApi, MediaEngineError> {
+        let name = self.address_book.app_media_backend(app_id);
+        let backend = match name.as_deref() {
+            Some(name) => self.get(Some(name))?,
+            None => {
+                let media_secret = self.address_book.app_media_secret(app_id).map(|s| s.0).or_else(|| app_secret.map(|s| s.to_owned()));
+                if media_secret.is_none() {
+                    return Err(MediaEngineError::MissingMediaSecret);
+                }
+                Arc::new(Atm0sMediaBackend::new(self.engine.clone(), media_secret))
+            }
+        };
+        Ok(MediaApi::new(backend, name))
+    }
+
+    /// Media api for calls of the app without an api caller secret, None if the app is unknown on this node
+    pub fn app_api(&self, app_id: &str) -> Option<Result<MediaApi, MediaEngineError>> {
+        let app = self.address_book.app(app_id)?;
+        Some(self.api(&app.app_id, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use bytes::Bytes;
+
+    use crate::{
+        address_book::AddressBookStorage,
+        protocol::{protobuf::sip_gateway::MediaStats, AppInfo, MediaSecret, SecretHash, StreamingInfo},
+        sip::media::{FakeRtpEngine, MediaEngineError, RtpEngineCall},
+    };
+
+    use super::{MediaBackend, MediaBackends, RecordingAction};
+
+    struct StaticBackend;
+
+    #[async_trait::async_trait]
+    impl MediaBackend for StaticBackend {
+        async fn create_offer(&self, _stream: &StreamingInfo) -> Result<(String, Bytes), MediaEngineError> {
+            Ok(("static-1".to_owned(), Bytes::from_static(b"static offer")))
+        }
+
+        async fn create_answer(&self, _stream: &StreamingInfo, _offer: Bytes) -> Result<(String, Bytes), MediaEngineError> {
+            Ok(("static-1".to_owned(), Bytes::from_static(b"static answer")))
+        }
+
+        async fn set_answer(&self, _session: &str, _answer: Bytes) -> Result<(), MediaEngineError> {
+            Ok(())
+        }
+
+        async fn destroy(&self, _session: &str) -> Result<(), MediaEngineError> {
+            Ok(())
+        }
+
+        async fn stats(&self, _session: &str) -> Result<MediaStats, MediaEngineError> {
+            Ok(MediaStats::default())
+        }
+
+        async fn recording(&self, _session: &str, _action: RecordingAction) -> Result<String, MediaEngineError> {
+            Ok("static-recording-1".to_owned())
+        }
+    }
+
+    fn app(app_id: &str, media_backend: Option<&str>) -> AppInfo {
+        AppInfo {
+            app_id: app_id.to_owned(),
+            secret_hash: SecretHash::of(&format!("{app_id}-secret")),
+            limits: Default::default(),
+            token_ttl_secs: None,
+            media_backend: media_backend.map(|b| b.to_owned()),
+            media_secret: Some(MediaSecret(format!("{app_id}-media-secret"))),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_is_selected_per_app() {
+        let engine = FakeRtpEngine::new();
+        let address_book = AddressBookStorage::new("root");
+        address_book.sync_apps(vec![app("app1", None), app("app2", Some("static")), app("app3", Some("missing"))]);
+        let mut named: HashMap<String, Arc<dyn MediaBackend>> = HashMap::new();
+        named.insert("static".to_owned(), Arc::new(StaticBackend));
+        let backends = MediaBackends::new(Arc::new(engine.clone()), named, address_book);
+        let stream = StreamingInfo {
+            room: "room1".to_owned(),
+            peer: "peer1".to_owned(),
+            record: false,
+        };
+
+        let api = backends.api("app2", None).expect("should select backend");
+        assert_eq!(api.backend_name(), Some("static"));
+        let (session, sdp) = api.backend().create_offer(&stream).await.expect("should create offer");
+        assert_eq!((session.as_str(), sdp.as_ref()), ("static-1", b"static offer".as_slice()));
+        assert!(engine.calls().is_empty(), "atm0s should not be called for app2");
+
+        let api = backends.api("app1", None).expect("should select default backend");
+        assert_eq!(api.backend_name(), None);
+        api.backend().create_offer(&stream).await.expect("should create offer");
+        assert!(matches!(&engine.calls()[0], RtpEngineCall::CreateToken { app_secret, .. } if app_secret == "app1-media-secret"));
+        assert!(matches!(engine.calls()[1], RtpEngineCall::CreateOffer { .. }));
+        assert_eq!(engine.sessions().len(), 1);
+
+        // unknown backend names fail instead of falling back to atm0s
+        assert!(matches!(backends.api("app3", None), Err(MediaEngineError::UnknownBackend(name)) if name == "missing"));
+        assert!(backends.get(Some("missing")).is_err());
+        assert!(!backends.has("missing"));
+    }
+
+    #[tokio::test]
+    async fn test_media_secret_per_app() {
+        let engine = FakeRtpEngine::new();
+        let address_book = AddressBookStorage::new("root");
+        let mut no_media_secret = app("app2", None);
+        no_media_secret.media_secret = None;
+        address_book.sync_apps(vec![app("app1", None), no_media_secret]);
+        let backends = MediaBackends::new(Arc::new(engine.clone()), HashMap::new(), address_book);
+        let stream = StreamingInfo {
+            room: "room1".to_owned(),
+            peer: "peer1".to_owned(),
+            record: false,
+        };
+
+        // the app media secret wins over the api caller secret, apps without it use the caller secret
+        for (app_id, caller_secret, expected) in [("app1", Some("app1-secret"), "app1-media-secret"), ("app2", Some("app2-secret"), "app2-secret"), ("", None, "root")] {
+            let api = backends.api(app_id, caller_secret).expect("should create api");
+            api.backend().create_offer(&stream).await.expect("should create offer");
+            let secret = engine.calls().into_iter().rev().find_map(|call| match call {
+                RtpEngineCall::CreateToken { app_secret, .. } => Some(app_secret),
+                _ => None,
+            });
+            assert_eq!(secret.as_deref(), Some(expected));
+        }
+        // incoming calls and calls placed on other nodes have no caller secret
+        assert!(matches!(backends.api("app2", None), Err(MediaEngineError::MissingMediaSecret)));
+        assert!(matches!(backends.app_api("app1"), Some(Ok(_))));
+        assert!(backends.app_api("unknown").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_atm0s_recording_is_unsupported() {
+        let engine = FakeRtpEngine::new();
+        let stream = StreamingInfo {
+            room: "room1".to_owned(),
+            peer: "peer1".to_owned(),
+            record: false,
+        };
+        let address_book = AddressBookStorage::new("root");
+        address_book.sync_apps(vec![app("app1", None)]);
+        let backends = MediaBackends::new(Arc::new(engine.clone()), HashMap::new(), address_book);
+        let api = backends.api("app1", None).expect("should select default backend");
+        let (session, _) = api.backend().create_offer(&stream).await.expect("should create offer");
+        assert!(matches!(api.recording(&session, RecordingAction::Start).await, Err(MediaEngineError::Unsupported("recording"))));
+        assert_eq!(engine.calls().len(), 2, "recording should not reach the media server");
+    }
+}
@@ -0,0 +1,197 @@
+use bytes::Bytes;
+use bytesstr::BytesStr;
+use ezk_sdp_types::{MediaDescription, MediaType, SessionDescription};
+use thiserror::Error;
+
+use crate::protocol::Codec;
+
+#[derive(Debug, Error)]
+pub enum SdpPolicyError {
+    #[error("Invalid sdp {0}")]
+    Invalid(String),
+    #[error("No common codec")]
+    NoCommonCodec,
+}
+
+/// Codec allow and prefer list of a number, an empty list passes sdp through untouched.
+/// With a list only audio streams are supported, other media lines are removed from offers
+#[derive(Debug, Clone, Default)]
+pub struct CodecPolicy {
+    codecs: Vec<Codec>,
+}
+
+/// Offer which passed the codec policy, removed media lines are added back to the answer as rejected ones
+/// so the answer has the same media lines as the original offer
+#[derive(Debug, Clone)]
+pub struct PolicyOffer {
+    sdp: Bytes,
+    removed: Vec<(usize, MediaDescription)>,
+    policy: CodecPolicy,
+}
+
+impl CodecPolicy {
+    pub fn new(codecs: Vec<Codec>) -> Self {
+        Self { codecs }
+    }
+
+    /// Keep audio streams with allowed codecs in preference order, fail if no stream has an allowed audio codec.
+    /// Without a list, and for late offers without sdp, the offer is forwarded as received
+    pub fn offer(&self, raw: &Bytes) -> Result<PolicyOffer, SdpPolicyError> {
+        if self.codecs.is_empty() || raw.is_empty() {
+            return Ok(PolicyOffer {
+                sdp: raw.clone(),
+                removed: vec![],
+                policy: self.clone(),
+            });
+        }
+        let mut sdp = parse_sdp(raw)?;
+        let mut removed = vec![];
+        let mut kept = vec![];
+        let mut changed = false;
+        for (index, mut media) in std::mem::take(&mut sdp.media_descriptions).into_iter().enumerate() {
+            let fmts = media.media.fmts.clone();
+            if matches!(media.media.media_type, MediaType::Audio) && media.media.port != 0 && self.filter_formats(&mut media) {
+                changed |= media.media.fmts != fmts;
+                kept.push(media);
+            } else {
+                removed.push((index, media));
+            }
+        }
+        if kept.is_empty() {
+            return Err(SdpPolicyError::NoCommonCodec);
+        }
+        // unchanged offers are forwarded as received
+        let sdp = if changed || !removed.is_empty() {
+            sdp.media_descriptions = kept;
+            sdp.to_string().into()
+        } else {
+            raw.clone()
+        };
+        Ok(PolicyOffer { sdp, removed, policy: self.clone() })
+    }
+
+    /// Keep allowed formats in preference order, return false if no audio codec is left, telephone-event alone is not enough
+    fn filter_formats(&self, media: &mut MediaDescription) -> bool {
+        let codec_of = |format: u8| match media.rtpmap.iter().find(|map| map.payload == format) {
+            Some(map) => Codec::from_encoding(&map.encoding),
+            None => Codec::from_static_payload(format),
+        };
+        let mut formats = vec![];
+        for (index, format) in media.media.fmts.iter().enumerate() {
+            let codec = codec_of(*format);
+            if self.codecs.is_empty() {
+                formats.push((index, *format, codec));
+            } else if let Some(rank) = codec.and_then(|codec| self.codecs.iter().position(|c| *c == codec)) {
+                formats.push((rank, *format, codec));
+            }
+        }
+        formats.sort_by_key(|(rank, _, _)| *rank);
+        let has_audio = formats.iter().any(|(_, _, codec)| *codec != Some(Codec::TelephoneEvent));
+
+        let fmts: Vec<u8> = formats.into_iter().map(|(_, format, _)| format).collect();
+        media.rtpmap.retain(|map| fmts.contains(&map.payload));
+        media.fmtp.retain(|fmtp| fmts.contains(&fmtp.format));
+        media.media.fmts = fmts;
+        has_audio
+    }
+}
+
+impl PolicyOffer {
+    pub fn sdp(&self) -> Bytes {
+        self.sdp.clone()
+    }
+
+    /// Filter the answer of this offer with the same policy and add the removed media lines back with port 0
+    pub fn answer(&self, raw: &Bytes) -> Result<Bytes, SdpPolicyError> {
+        if self.policy.codecs.is_empty() {
+            return Ok(raw.clone());
+        }
+        let mut sdp = parse_sdp(raw)?;
+        let mut changed = false;
+        for media in sdp.media_descriptions.iter_mut() {
+            let fmts = media.media.fmts.clone();
+            if media.media.port != 0 && !self.policy.filter_formats(media) {
+                return Err(SdpPolicyError::NoCommonCodec);
+            }
+            changed |= media.media.fmts != fmts;
+        }
+        if !changed && self.removed.is_empty() {
+            return Ok(raw.clone());
+        }
+        for (index, media) in &self.removed {
+            let mut media = media.clone();
+            media.media.port = 0;
+            let index = (*index).min(sdp.media_descriptions.len());
+            sdp.media_descriptions.insert(index, media);
+        }
+        Ok(sdp.to_string().into())
+    }
+}
+
+/// Parse an sdp, the codec policy and the built-in relay share this parser
+pub fn parse_sdp(sdp: &Bytes) -> Result<SessionDescription, SdpPolicyError> {
+    let sdp = BytesStr::from_utf8_bytes(sdp.clone()).map_err(|e| SdpPolicyError::Invalid(e.to_string()))?;
+    SessionDescription::parse(&sdp).map_err(|e| SdpPolicyError::Invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::protocol::Codec;
+
+    use super::{CodecPolicy, SdpPolicyError};
+
+    const OFFER: &str = "v=0\r\no=- 1 1 IN IP4 198.51.100.1\r\ns=-\r\nc=IN IP4 198.51.100.1\r\nt=0 0\r\nm=audio 30000 RTP/AVP 0 8 9 101\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:8 PCMA/8000\r\na=rtpmap:9 G722/8000\r\na=rtpmap:101 telephone-event/8000\r\na=fmtp:101 0-16\r\na=sendrecv\r\nm=video 30002 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\n";
+
+    fn media_lines(sdp: &Bytes) -> Vec<String> {
+        String::from_utf8_lossy(sdp).lines().filter(|line| line.starts_with("m=")).map(|line| line.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_filter_and_prefer_codecs() {
+        let policy = CodecPolicy::new(vec![Codec::Pcma, Codec::TelephoneEvent, Codec::Pcmu]);
+        let offer = policy.offer(&Bytes::from_static(OFFER.as_bytes())).expect("should pass policy");
+        let sdp = offer.sdp();
+        assert_eq!(media_lines(&sdp), vec!["m=audio 30000 RTP/AVP 8 101 0"]);
+        let text = String::from_utf8_lossy(&sdp);
+        assert!(!text.contains("G722"), "G722 is not allowed");
+        assert!(text.contains("a=fmtp:101 0-16"));
+
+        // the answer gets the removed video line back as rejected
+        let answer =
+            "v=0\r\no=- 2 1 IN IP4 203.0.113.5\r\ns=-\r\nc=IN IP4 203.0.113.5\r\nt=0 0\r\nm=audio 40000 RTP/AVP 8 101\r\na=rtpmap:8 PCMA/8000\r\na=rtpmap:101 telephone-event/8000\r\na=sendrecv\r\n";
+        let answer = offer.answer(&Bytes::from_static(answer.as_bytes())).expect("should pass policy");
+        assert_eq!(media_lines(&answer), vec!["m=audio 40000 RTP/AVP 8 101", "m=video 0 RTP/AVP 96"]);
+    }
+
+    #[test]
+    fn test_empty_policy_passes_sdp_through() {
+        // video lines are kept and the answer is not touched
+        let offer = CodecPolicy::default().offer(&Bytes::from_static(OFFER.as_bytes())).expect("should pass policy");
+        assert_eq!(offer.sdp().as_ref(), OFFER.as_bytes());
+        let answer = Bytes::from_static(b"v=0\r\nm=audio 40000 RTP/AVP 9\r\nm=video 40002 RTP/AVP 96\r\n");
+        assert_eq!(offer.answer(&answer).expect("should pass policy"), answer);
+    }
+
+    #[test]
+    fn test_late_offer_is_not_rejected() {
+        let policy = CodecPolicy::new(vec![Codec::Pcma]);
+        let offer = policy.offer(&Bytes::new()).expect("late offer should pass policy");
+        assert!(offer.sdp().is_empty());
+        assert!(CodecPolicy::default().offer(&Bytes::new()).expect("late offer should pass policy").sdp().is_empty());
+    }
+
+    #[test]
+    fn test_no_common_codec() {
+        let policy = CodecPolicy::new(vec![Codec::Opus, Codec::TelephoneEvent]);
+        assert!(matches!(policy.offer(&Bytes::from_static(OFFER.as_bytes())), Err(SdpPolicyError::NoCommonCodec)));
+
+        // the answer must not pick a codec which is not allowed
+        let policy = CodecPolicy::new(vec![Codec::Pcmu]);
+        let offer = policy.offer(&Bytes::from_static(OFFER.as_bytes())).expect("should pass policy");
+        let answer = "v=0\r\no=- 2 1 IN IP4 203.0.113.5\r\ns=-\r\nc=IN IP4 203.0.113.5\r\nt=0 0\r\nm=audio 40000 RTP/AVP 8\r\na=rtpmap:8 PCMA/8000\r\n";
+        assert!(matches!(offer.answer(&Bytes::from_static(answer.as_bytes())), Err(SdpPolicyError::NoCommonCodec)));
+        assert!(matches!(policy.offer(&Bytes::from_static(b"not sdp")), Err(SdpPolicyError::Invalid(_))));
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::protocol::StreamingInfo;
+
+use super::{MediaApiError, MediaEngineError};
+
+/// Rtpengine operations of the media server, sessions are identified by their full url
+#[async_trait::async_trait]
+pub trait RtpEngine: Send + Sync {
+    /// Create a rtpengine token for joining the room with the app secret
+    async fn create_rtpengine_token(&self, app_secret: &str, stream: &StreamingInfo) -> Result<String, MediaApiError>;
+    /// Create a webrtc token for the SDK which takes over the media of a call
+    async fn create_webrtc_token(&self, app_secret: &str, stream: &StreamingInfo) -> Result<String, MediaApiError>;
+    /// Create a session which offers, return the session url and the offer sdp
+    async fn create_offer(&self, token: &str) -> Result<(String, Bytes), MediaEngineError>;
+    /// Create a session which answers the remote offer, return the session url and the answer sdp
+    async fn create_answer(&self, token: &str, offer: Bytes) -> Result<(String, Bytes), MediaEngineError>;
+    /// Set the remote answer of a session which is created by `create_offer`
+    async fn set_answer(&self, session: &str, answer: Bytes) -> Result<(), MediaEngineError>;
+    async fn delete_session(&self, session: &str) -> Result<(), MediaEngineError>;
+}
+
+#[derive(Deserialize)]
+struct TokenData {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct CreateTokenResponse {
+    // status: bool,
+    error: Option<String>,
+    data: Option<TokenData>,
+}
+
+/// Rtpengine api of atm0s media gateway
+pub struct HttpRtpEngine {
+    gateway: String,
+    client: reqwest::Client,
+}
+
+impl HttpRtpEngine {
+    pub fn new(gateway: &str) -> Self {
+        Self {
+            gateway: gateway.to_owned(),
+            client: reqwest::ClientBuilder::new().timeout(Duration::from_secs(3)).build().expect("Should create client"),
+        }
+    }
+
+    async fn request_token(&self, kind: &str, app_secret: &str, stream: &StreamingInfo) -> Result<String, MediaApiError> {
+        let res: CreateTokenResponse = self
+            .client
+            .post(format!("{}/token/{kind}", self.gateway))
+            .header("Authorization", format!("Bearer {}", app_secret))
+            .json(&serde_json::json!({
+                "room": stream.room,
+                "peer": stream.peer,
+                "ttl": 3600,
+                "record": stream.record
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if let Some(data) = res.data {
+            Ok(data.token)
+        } else {
+            Err(MediaApiError::Media(res.error.unwrap_or_else(|| "Unknown".to_string())))
+        }
+    }
+
+    /// Read the session location and sdp of a 201 response
+    async fn created_session(&self, res: reqwest::Response) -> Result<(String, Bytes), MediaEngineError> {
+        let status = res.status().as_u16();
+        if status == 201 {
+            let endpoint = res.headers().get("Location").ok_or(MediaEngineError::MissingLocation)?;
+            let location = endpoint.to_str().map_err(|_e| MediaEngineError::InvalidLocation)?.to_string();
+            let sdp = res.bytes().await?;
+            Ok((format!("{}{}", self.gateway, location), sdp))
+        } else {
+            let response = res.text().await?;
+            log::error!("[HttpRtpEngine] create session error {status}, {response}");
+            Err(MediaEngineError::InvalidStatus(status))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RtpEngine for HttpRtpEngine {
+    async fn create_rtpengine_token(&self, app_secret: &str, stream: &StreamingInfo) -> Result<String, MediaApiError> {
+        self.request_token("rtpengine", app_secret, stream).await
+    }
+
+    async fn create_webrtc_token(&self, app_secret: &str, stream: &StreamingInfo) -> Result<String, MediaApiError> {
+        self.request_token("webrtc", app_secret, stream).await
+    }
+
+    async fn create_offer(&self, token: &str) -> Result<(String, Bytes), MediaEngineError> {
+        let res = self
+            .client
+            .post(format!("{}/rtpengine/offer", self.gateway))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?;
+        self.created_session(res).await
+    }
+
+    async fn create_answer(&self, token: &str, offer: Bytes) -> Result<(String, Bytes), MediaEngineError> {
+        let res = self
+            .client
+            .post(format!("{}/rtpengine/answer", self.gateway))
+            .header("Content-Type", "application/sdp")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(offer)
+            .send()
+            .await?;
+        self.created_session(res).await
+    }
+
+    async fn set_answer(&self, session: &str, answer: Bytes) -> Result<(), MediaEngineError> {
+        let res = self.client.patch(session).header("Content-Type", "application/sdp").body(answer).send().await?;
+        let status = res.status().as_u16();
+        if status == 200 {
+            Ok(())
+        } else {
+            Err(MediaEngineError::InvalidStatus(status))
+        }
+    }
+
+    async fn delete_session(&self, session: &str) -> Result<(), MediaEngineError> {
+        let res = self.client.delete(session).send().await?;
+        let status = res.status().as_u16();
+        if status == 200 {
+            Ok(())
+        } else {
+            Err(MediaEngineError::InvalidStatus(status))
+        }
+    }
+}
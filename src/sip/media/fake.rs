@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use spin::RwLock;
+
+use crate::protocol::StreamingInfo;
+
+use super::{MediaApiError, MediaEngineError, RtpEngine};
+
+/// Sdp which is returned by `FakeRtpEngine` for offers
+pub const FAKE_OFFER_SDP: &str = "v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\nm=audio 40000 RTP/AVP 0 8 101\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:8 PCMA/8000\r\na=rtpmap:101 telephone-event/8000\r\na=sendrecv\r\n";
+/// Sdp which is returned by `FakeRtpEngine` for answers
+pub const FAKE_ANSWER_SDP: &str =
+    "v=0\r\no=- 2 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\nm=audio 40002 RTP/AVP 0 101\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:101 telephone-event/8000\r\na=sendrecv\r\n";
+
+const FAKE_SESSION_PREFIX: &str = "fake://rtpengine/";
+
+/// Calls which are received by `FakeRtpEngine`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtpEngineCall {
+    CreateToken { app_secret: String, room: String, peer: String, record: bool },
+    CreateWebrtcToken { app_secret: String, room: String, peer: String },
+    CreateOffer { session: String },
+    CreateAnswer { session: String, offer: Bytes },
+    SetAnswer { session: String, answer: Bytes },
+    DeleteSession { session: String },
+}
+
+#[derive(Default)]
+struct FakeRtpEngineInternal {
+    calls: Vec<RtpEngineCall>,
+    sessions: Vec<String>,
+    next_session: u64,
+}
+
+/// In-process rtpengine for tests without a media server, it returns canned sdp and records all calls
+#[derive(Clone, Default)]
+pub struct FakeRtpEngine {
+    internal: Arc<RwLock<FakeRtpEngineInternal>>,
+}
+
+impl FakeRtpEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All received calls in order
+    pub fn calls(&self) -> Vec<RtpEngineCall> {
+        self.internal.read().calls.clone()
+    }
+
+    /// Sessions which are created and not deleted yet
+    pub fn sessions(&self) -> Vec<String> {
+        self.internal.read().sessions.clone()
+    }
+
+    fn create_session(&self) -> String {
+        let mut internal = self.internal.write();
+        internal.next_session += 1;
+        let session = format!("{FAKE_SESSION_PREFIX}{}", internal.next_session);
+        internal.sessions.push(session.clone());
+        session
+    }
+
+    fn record(&self, call: RtpEngineCall) {
+        self.internal.write().calls.push(call);
+    }
+}
+
+#[async_trait::async_trait]
+impl RtpEngine for FakeRtpEngine {
+    async fn create_rtpengine_token(&self, app_secret: &str, stream: &StreamingInfo) -> Result<String, MediaApiError> {
+        self.record(RtpEngineCall::CreateToken {
+            app_secret: app_secret.to_owned(),
+            room: stream.room.clone(),
+            peer: stream.peer.clone(),
+            record: stream.record,
+        });
+        Ok(format!("token-{}-{}", stream.room, stream.peer))
+    }
+
+    async fn create_webrtc_token(&self, app_secret: &str, stream: &StreamingInfo) -> Result<String, MediaApiError> {
+        self.record(RtpEngineCall::CreateWebrtcToken {
+            app_secret: app_secret.to_owned(),
+            room: stream.room.clone(),
+            peer: stream.peer.clone(),
+        });
+        Ok(format!("webrtc-token-{}-{}", stream.room, stream.peer))
+    }
+
+    async fn create_offer(&self, _token: &str) -> Result<(String, Bytes), MediaEngineError> {
+        let session = self.create_session();
+        self.record(RtpEngineCall::CreateOffer { session: session.clone() });
+        Ok((session, Bytes::from_static(FAKE_OFFER_SDP.as_bytes())))
+    }
+
+    async fn create_answer(&self, _token: &str, offer: Bytes) -> Result<(String, Bytes), MediaEngineError> {
+        let session = self.create_session();
+        self.record(RtpEngineCall::CreateAnswer { session: session.clone(), offer });
+        Ok((session, Bytes::from_static(FAKE_ANSWER_SDP.as_bytes())))
+    }
+
+    async fn set_answer(&self, session: &str, answer: Bytes) -> Result<(), MediaEngineError> {
+        self.record(RtpEngineCall::SetAnswer { session: session.to_owned(), answer });
+        if self.internal.read().sessions.iter().any(|s| s == session) {
+            Ok(())
+        } else {
+            Err(MediaEngineError::InvalidStatus(404))
+        }
+    }
+
+    async fn delete_session(&self, session: &str) -> Result<(), MediaEngineError> {
+        self.record(RtpEngineCall::DeleteSession { session: session.to_owned() });
+        let mut internal = self.internal.write();
+        let before = internal.sessions.len();
+        internal.sessions.retain(|s| s != session);
+        if internal.sessions.len() < before {
+            Ok(())
+        } else {
+            Err(MediaEngineError::InvalidStatus(404))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::protocol::StreamingInfo;
+
+    use super::{FakeRtpEngine, RtpEngine, RtpEngineCall, FAKE_ANSWER_SDP};
+
+    #[tokio::test]
+    async fn test_fake_engine_records_calls() {
+        let engine = FakeRtpEngine::new();
+        let stream = StreamingInfo {
+            room: "room1".to_owned(),
+            peer: "peer1".to_owned(),
+            record: false,
+        };
+        let token = engine.create_rtpengine_token("secret", &stream).await.expect("should create token");
+        let (session, answer) = engine.create_answer(&token, Bytes::from_static(b"offer")).await.expect("should create answer");
+        assert_eq!(answer, FAKE_ANSWER_SDP.as_bytes());
+        assert_eq!(engine.sessions(), vec![session.clone()]);
+
+        engine.delete_session(&session).await.expect("should delete session");
+        assert!(engine.delete_session(&session).await.is_err());
+        assert!(engine.sessions().is_empty());
+        assert_eq!(engine.calls().len(), 4);
+        assert_eq!(
+            engine.calls()[1],
+            RtpEngineCall::CreateAnswer {
+                session,
+                offer: Bytes::from_static(b"offer")
+            }
+        );
+    }
+}
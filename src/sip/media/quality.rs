@@ -0,0 +1,211 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::protocol::protobuf::sip_gateway::{MediaStats, MediaStreamStats};
+
+const RTP_HEADER_SIZE: usize = 12;
+/// Sequence gaps above this are treated as a restart of the sender, not as loss
+const MAX_DROPOUT: u16 = 3000;
+
+/// Receive quality of one RTP direction, loss and interarrival jitter follow RFC 3550 appendix A
+#[derive(Debug, Default)]
+pub struct RtpQuality {
+    packets: u64,
+    bytes: u64,
+    /// Clock rates of dynamic payload types from sdp, static audio types are known
+    clock_rates: HashMap<u8, u32>,
+    started: Option<Instant>,
+    ssrc: Option<u32>,
+    base_seq: u64,
+    max_seq: u64,
+    received: u64,
+    /// Loss of previous ssrcs
+    prev_lost: u64,
+    last_transit: Option<f64>,
+    jitter_ms: f64,
+}
+
+impl RtpQuality {
+    pub fn set_clock_rates(&mut self, clock_rates: HashMap<u8, u32>) {
+        self.clock_rates = clock_rates;
+    }
+
+    fn clock_rate(&self, payload_type: u8) -> Option<u32> {
+        match payload_type {
+            0 | 3 | 4 | 5 | 8 | 9 | 12 | 13 | 15 | 18 => Some(8000),
+            _ => self.clock_rates.get(&payload_type).copied(),
+        }
+    }
+
+    /// Update with a received packet, packets which are not RTP version 2 are ignored
+    pub fn on_packet(&mut self, packet: &[u8], now: Instant) {
+        if packet.len() < RTP_HEADER_SIZE || packet[0] >> 6 != 2 {
+            return;
+        }
+        let payload_type = packet[1] & 0x7f;
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        self.packets += 1;
+        self.bytes += packet.len() as u64;
+
+        if self.ssrc != Some(ssrc) {
+            self.prev_lost += self.ssrc_lost();
+            self.ssrc = Some(ssrc);
+            self.base_seq = seq as u64;
+            self.max_seq = seq as u64;
+            self.received = 0;
+            self.last_transit = None;
+        } else {
+            let delta = seq.wrapping_sub(self.max_seq as u16);
+            if delta != 0 && delta < MAX_DROPOUT {
+                // in order with a possible gap, count a cycle when the 16 bits sequence wraps
+                self.max_seq += delta as u64;
+            }
+        }
+        self.received += 1;
+
+        // telephone-event and unknown payload types have no usable timestamps
+        if let Some(clock_rate) = self.clock_rate(payload_type) {
+            let arrival_ms = now.duration_since(*self.started.get_or_insert(now)).as_secs_f64() * 1000.0;
+            let transit = arrival_ms - timestamp as f64 * 1000.0 / clock_rate as f64;
+            if let Some(last) = self.last_transit {
+                let diff = (transit - last).abs();
+                // a timestamp wrap looks like a huge transit change, skip it
+                if diff < 60_000.0 {
+                    self.jitter_ms += (diff - self.jitter_ms) / 16.0;
+                }
+            }
+            self.last_transit = Some(transit);
+        }
+    }
+
+    fn ssrc_lost(&self) -> u64 {
+        match self.ssrc {
+            Some(_) => (self.max_seq - self.base_seq + 1).saturating_sub(self.received),
+            None => 0,
+        }
+    }
+
+    pub fn stats(&self) -> MediaStreamStats {
+        MediaStreamStats {
+            packets: self.packets,
+            bytes: self.bytes,
+            lost: self.prev_lost + self.ssrc_lost(),
+            jitter_ms: self.jitter_ms as f32,
+        }
+    }
+}
+
+/// Estimated MOS of a direction with a simplified E-model, round trip time is unknown so only jitter adds latency.
+/// Returns None when no packet is received
+pub fn estimate_mos(stats: &MediaStreamStats) -> Option<f32> {
+    if stats.packets == 0 {
+        return None;
+    }
+    let loss_percent = stats.lost as f64 * 100.0 / (stats.packets + stats.lost) as f64;
+    let latency = stats.jitter_ms as f64 * 2.0 + 10.0;
+    let r = if latency < 160.0 {
+        93.2 - latency / 40.0
+    } else {
+        93.2 - (latency - 120.0) / 10.0
+    };
+    let r = (r - loss_percent * 2.5).clamp(0.0, 100.0);
+    let mos = 1.0 + 0.035 * r + 0.000007 * r * (r - 60.0) * (100.0 - r);
+    Some(mos.clamp(1.0, 4.5) as f32)
+}
+
+/// Stats of the SIP leg, the MOS is the one of the worse direction
+pub fn build_media_stats(inbound: MediaStreamStats, outbound: MediaStreamStats) -> MediaStats {
+    let mos = [estimate_mos(&inbound), estimate_mos(&outbound)].into_iter().flatten().reduce(f32::min).unwrap_or(0.0);
+    MediaStats {
+        inbound: Some(inbound),
+        outbound: Some(outbound),
+        mos,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::protocol::protobuf::sip_gateway::MediaStreamStats;
+
+    use super::{build_media_stats, estimate_mos, RtpQuality};
+
+    fn rtp(payload_type: u8, seq: u16, timestamp: u32, ssrc: u32) -> Vec<u8> {
+        let mut packet = vec![0x80, payload_type];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.extend_from_slice(&[0; 160]);
+        packet
+    }
+
+    #[test]
+    fn test_loss_with_sequence_wrap() {
+        let mut quality = RtpQuality::default();
+        let now = Instant::now();
+        for (i, seq) in [65534u16, 65535, 1, 2].into_iter().enumerate() {
+            quality.on_packet(&rtp(0, seq, i as u32 * 160, 1), now + Duration::from_millis(i as u64 * 20));
+        }
+        let stats = quality.stats();
+        assert_eq!((stats.packets, stats.bytes, stats.lost), (4, 4 * 172, 1), "seq 0 is lost");
+
+        // a new ssrc restarts the sequence, previous loss is kept
+        quality.on_packet(&rtp(0, 100, 0, 2), now);
+        quality.on_packet(&rtp(0, 103, 480, 2), now);
+        assert_eq!(quality.stats().lost, 3);
+        quality.on_packet(&[0; 4], now);
+        assert_eq!(quality.stats().packets, 6, "invalid packets are ignored");
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut quality = RtpQuality::default();
+        let now = Instant::now();
+        for i in 0..50u32 {
+            quality.on_packet(&rtp(8, i as u16, i * 160, 1), now + Duration::from_millis(i as u64 * 20));
+        }
+        assert!(quality.stats().jitter_ms < 0.1, "constant pacing should have no jitter");
+
+        // every other packet is 10ms late, dynamic payload types use the sdp clock rate
+        let mut quality = RtpQuality::default();
+        quality.set_clock_rates([(111, 48000)].into_iter().collect());
+        for i in 0..200u32 {
+            let late = if i % 2 == 1 {
+                10
+            } else {
+                0
+            };
+            quality.on_packet(&rtp(111, i as u16, i * 960, 1), now + Duration::from_millis(i as u64 * 20 + late));
+        }
+        let jitter = quality.stats().jitter_ms;
+        assert!((9.0..=10.5).contains(&jitter), "jitter {jitter}");
+
+        // telephone-event timestamps are not used
+        let mut quality = RtpQuality::default();
+        for i in 0..10u32 {
+            quality.on_packet(&rtp(101, i as u16, 0, 1), now + Duration::from_millis(i as u64 * 50));
+        }
+        assert_eq!(quality.stats().jitter_ms, 0.0);
+    }
+
+    #[test]
+    fn test_mos() {
+        let stats = |packets, lost, jitter_ms| MediaStreamStats {
+            packets,
+            bytes: packets * 172,
+            lost,
+            jitter_ms,
+        };
+        let good = estimate_mos(&stats(1000, 0, 1.0)).expect("should have mos");
+        let bad = estimate_mos(&stats(900, 100, 30.0)).expect("should have mos");
+        assert!(good > 4.3, "good mos {good}");
+        assert!(bad < 3.6, "bad mos {bad}");
+        assert_eq!(estimate_mos(&stats(0, 0, 0.0)), None);
+
+        let media = build_media_stats(stats(1000, 0, 1.0), stats(900, 100, 30.0));
+        assert_eq!(media.mos, bad, "the worse direction is used");
+        assert_eq!(build_media_stats(stats(0, 0, 0.0), stats(0, 0, 0.0)).mos, 0.0);
+    }
+}
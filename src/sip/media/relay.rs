@@ -0,0 +1,671 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use bytes::Bytes;
+use ezk_sdp_types::SessionDescription;
+use spin::RwLock;
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::protocol::{protobuf::sip_gateway::MediaStats, StreamingInfo};
+
+use super::{build_media_stats, parse_sdp, MediaBackend, MediaEngineError, RecordingAction, RtpQuality};
+
+mod sdp;
+
+/// Name of the built-in relay in the media backends
+pub const RELAY_BACKEND: &str = "relay";
+
+const MAX_PACKET_SIZE: usize = 1500;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpRelayConfig {
+    /// Local ip which relay sockets are bound to
+    pub bind_ip: IpAddr,
+    /// Ip which is written in sdp connection lines, default to the bind ip
+    pub public_ip: Option<IpAddr>,
+    /// UDP port range, each session uses two RTP/RTCP port pairs
+    pub port_min: u16,
+    pub port_max: u16,
+    /// Symmetric RTP on the SIP side: the sdp address is replaced by the source of the first packet from the SIP side,
+    /// for carriers or phones behind NAT which announce an unreachable address
+    pub sip_latching: bool,
+}
+
+impl Default for RtpRelayConfig {
+    fn default() -> Self {
+        Self {
+            bind_ip: IpAddr::from([0, 0, 0, 0]),
+            public_ip: None,
+            port_min: 40000,
+            port_max: 40999,
+            sip_latching: false,
+        }
+    }
+}
+
+/// Packet counters of one relay direction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayStreamStats {
+    pub rtp_packets: u64,
+    pub rtp_bytes: u64,
+    pub rtcp_packets: u64,
+    /// Packets which are received before the other side address is known or from unexpected sources
+    pub dropped_packets: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelaySessionInfo {
+    pub session: String,
+    pub room: String,
+    pub peer: String,
+    pub sip_local: SocketAddr,
+    pub sip_remote: Option<SocketAddr>,
+    pub peer_local: SocketAddr,
+    pub peer_remote: Option<SocketAddr>,
+    pub age_secs: u64,
+    /// Packets from the SIP side to the peer
+    pub to_peer: RelayStreamStats,
+    /// Packets from the peer to the SIP side
+    pub to_sip: RelayStreamStats,
+}
+
+#[derive(Default)]
+struct StreamCounters {
+    rtp_packets: AtomicU64,
+    rtp_bytes: AtomicU64,
+    rtcp_packets: AtomicU64,
+    dropped_packets: AtomicU64,
+    /// Loss and jitter of received RTP, before forwarding
+    quality: RwLock<RtpQuality>,
+}
+
+impl StreamCounters {
+    fn stats(&self) -> RelayStreamStats {
+        RelayStreamStats {
+            rtp_packets: self.rtp_packets.load(Ordering::Relaxed),
+            rtp_bytes: self.rtp_bytes.load(Ordering::Relaxed),
+            rtcp_packets: self.rtcp_packets.load(Ordering::Relaxed),
+            dropped_packets: self.dropped_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Remote address of one socket of a leg. An unlocked address is replaced by the source of the next received packet,
+/// then it is locked and packets from other sources are dropped
+#[derive(Debug, Clone, Copy, Default)]
+struct RelayRemote {
+    addr: Option<SocketAddr>,
+    locked: bool,
+}
+
+/// RTP/RTCP socket pair of one side, the remote addresses come from sdp or are latched from the first received packet
+#[derive(Clone)]
+struct RelayLeg {
+    port: u16,
+    rtp: Arc<UdpSocket>,
+    rtcp: Arc<UdpSocket>,
+    rtp_remote: Arc<RwLock<RelayRemote>>,
+    rtcp_remote: Arc<RwLock<RelayRemote>>,
+    /// Rtcp is sent and received on the rtp socket (RFC 5761)
+    rtcp_mux: Arc<AtomicBool>,
+}
+
+impl RelayLeg {
+    /// Set the remote addresses from sdp, `rtcp` is None when rtcp is multiplexed on the rtp port.
+    /// With `latching` the addresses are only a start and the sources of the first packets replace them
+    fn set_remote(&self, rtp: SocketAddr, rtcp: Option<SocketAddr>, latching: bool) {
+        self.rtcp_mux.store(rtcp.is_none(), Ordering::Relaxed);
+        *self.rtp_remote.write() = RelayRemote { addr: Some(rtp), locked: !latching };
+        *self.rtcp_remote.write() = RelayRemote {
+            addr: Some(rtcp.unwrap_or(rtp)),
+            locked: !latching,
+        };
+    }
+
+    fn remote(&self, rtcp: bool) -> &RwLock<RelayRemote> {
+        if rtcp && !self.rtcp_mux.load(Ordering::Relaxed) {
+            &self.rtcp_remote
+        } else {
+            &self.rtp_remote
+        }
+    }
+
+    /// Socket which sends rtp or rtcp to the remote, rtcp-mux sends rtcp from the rtp port
+    fn sender(&self, rtcp: bool) -> &UdpSocket {
+        if rtcp && !self.rtcp_mux.load(Ordering::Relaxed) {
+            &self.rtcp
+        } else {
+            &self.rtp
+        }
+    }
+}
+
+struct RelaySession {
+    room: String,
+    peer_name: String,
+    sip: RelayLeg,
+    peer: RelayLeg,
+    to_peer: Arc<StreamCounters>,
+    to_sip: Arc<StreamCounters>,
+    created: Instant,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RelaySession {
+    fn info(&self, session: &str, public_ip: IpAddr) -> RelaySessionInfo {
+        RelaySessionInfo {
+            session: session.to_owned(),
+            room: self.room.clone(),
+            peer: self.peer_name.clone(),
+            sip_local: SocketAddr::new(public_ip, self.sip.port),
+            sip_remote: self.sip.rtp_remote.read().addr,
+            peer_local: SocketAddr::new(public_ip, self.peer.port),
+            peer_remote: self.peer.rtp_remote.read().addr,
+            age_secs: self.created.elapsed().as_secs(),
+            to_peer: self.to_peer.stats(),
+            to_sip: self.to_sip.stats(),
+        }
+    }
+}
+
+impl Drop for RelaySession {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Default)]
+struct RtpRelayInternal {
+    sessions: HashMap<String, RelaySession>,
+    used_ports: HashSet<u16>,
+    next_pair: usize,
+    next_session: u64,
+}
+
+/// Built-in media backend which anchors RTP in the gateway without a media server.
+/// Each session relays between the SIP side and a plain RTP peer, the peer address is latched from its first packet.
+/// The peer sends to the endpoint which is returned in the accepted event of the call
+#[derive(Clone)]
+pub struct RtpRelay {
+    cfg: Arc<RtpRelayConfig>,
+    public_ip: IpAddr,
+    internal: Arc<RwLock<RtpRelayInternal>>,
+}
+
+impl RtpRelay {
+    pub fn new(cfg: RtpRelayConfig) -> Self {
+        let public_ip = cfg.public_ip.unwrap_or(cfg.bind_ip);
+        if public_ip.is_unspecified() {
+            log::warn!("[RtpRelay] public ip is unspecified, sdp of relay sessions will not be reachable");
+        }
+        Self {
+            cfg: Arc::new(cfg),
+            public_ip,
+            internal: Default::default(),
+        }
+    }
+
+    pub fn sessions(&self) -> Vec<RelaySessionInfo> {
+        self.internal.read().sessions.iter().map(|(id, session)| session.info(id, self.public_ip)).collect()
+    }
+
+    fn first_port(&self) -> u16 {
+        self.cfg.port_min.saturating_add(self.cfg.port_min % 2)
+    }
+
+    fn pairs(&self) -> usize {
+        (self.cfg.port_max.saturating_sub(self.first_port()) as usize + 1) / 2
+    }
+
+    /// Reserve the next free even port in a round robin way, None if all pairs are used
+    fn reserve_port(&self) -> Option<u16> {
+        let pairs = self.pairs();
+        let mut internal = self.internal.write();
+        for _ in 0..pairs {
+            let port = self.first_port() + 2 * (internal.next_pair % pairs) as u16;
+            internal.next_pair += 1;
+            if internal.used_ports.insert(port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn release_port(&self, port: u16) {
+        self.internal.write().used_ports.remove(&port);
+    }
+
+    async fn alloc_leg(&self) -> Result<RelayLeg, MediaEngineError> {
+        for _ in 0..self.pairs() {
+            let port = self.reserve_port().ok_or(MediaEngineError::NoRelayPort)?;
+            let rtp = UdpSocket::bind(SocketAddr::new(self.cfg.bind_ip, port)).await;
+            let rtcp = UdpSocket::bind(SocketAddr::new(self.cfg.bind_ip, port + 1)).await;
+            match (rtp, rtcp) {
+                (Ok(rtp), Ok(rtcp)) => {
+                    return Ok(RelayLeg {
+                        port,
+                        rtp: Arc::new(rtp),
+                        rtcp: Arc::new(rtcp),
+                        rtp_remote: Default::default(),
+                        rtcp_remote: Default::default(),
+                        rtcp_mux: Default::default(),
+                    })
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    log::warn!("[RtpRelay] bind port pair {port} error {e:?}");
+                    self.release_port(port);
+                }
+            }
+        }
+        Err(MediaEngineError::NoRelayPort)
+    }
+
+    async fn create_session(&self, stream: &StreamingInfo) -> Result<(String, SocketAddr), MediaEngineError> {
+        let sip = self.alloc_leg().await?;
+        let peer = match self.alloc_leg().await {
+            Ok(peer) => peer,
+            Err(e) => {
+                self.release_port(sip.port);
+                return Err(e);
+            }
+        };
+        let to_peer = Arc::new(StreamCounters::default());
+        let to_sip = Arc::new(StreamCounters::default());
+        let tasks = vec![
+            tokio::spawn(relay_loop(sip.clone(), peer.clone(), to_peer.clone(), false)),
+            tokio::spawn(relay_loop(sip.clone(), peer.clone(), to_peer.clone(), true)),
+            tokio::spawn(relay_loop(peer.clone(), sip.clone(), to_sip.clone(), false)),
+            tokio::spawn(relay_loop(peer.clone(), sip.clone(), to_sip.clone(), true)),
+        ];
+
+        let local = SocketAddr::new(self.public_ip, sip.port);
+        let mut internal = self.internal.write();
+        internal.next_session += 1;
+        let id = format!("{RELAY_BACKEND}-{}", internal.next_session);
+        log::info!("[RtpRelay] created session {id} with sip port {} and peer port {}", sip.port, peer.port);
+        internal.sessions.insert(
+            id.clone(),
+            RelaySession {
+                room: stream.room.clone(),
+                peer_name: stream.peer.clone(),
+                sip,
+                peer,
+                to_peer,
+                to_sip,
+                created: Instant::now(),
+                tasks,
+            },
+        );
+        Ok((id, local))
+    }
+
+    /// Apply the SIP side sdp to a session, return the local address of the SIP side
+    fn set_sip_remote(&self, session: &str, sdp: &SessionDescription) -> Result<SocketAddr, MediaEngineError> {
+        let remote = sdp::remote_addr(sdp).ok_or(MediaEngineError::InvalidSdp)?;
+        let internal = self.internal.read();
+        let session = internal.sessions.get(session).ok_or(MediaEngineError::UnknownSession)?;
+        session.sip.set_remote(remote, sdp::rtcp_addr(sdp, remote), self.cfg.sip_latching);
+        // both directions use the negotiated payload types
+        let clock_rates = sdp::clock_rates(sdp);
+        session.to_peer.quality.write().set_clock_rates(clock_rates.clone());
+        session.to_sip.quality.write().set_clock_rates(clock_rates);
+        Ok(SocketAddr::new(self.public_ip, session.sip.port))
+    }
+}
+
+#[async_trait::async_trait]
+impl MediaBackend for RtpRelay {
+    async fn create_offer(&self, stream: &StreamingInfo) -> Result<(String, Bytes), MediaEngineError> {
+        let (session, local) = self.create_session(stream).await?;
+        Ok((session, sdp::offer(local, rand::random::<u32>() as u64).into()))
+    }
+
+    async fn create_answer(&self, stream: &StreamingInfo, offer: Bytes) -> Result<(String, Bytes), MediaEngineError> {
+        let offer = parse_sdp(&offer)?;
+        sdp::remote_addr(&offer).ok_or(MediaEngineError::InvalidSdp)?;
+        let (session, local) = self.create_session(stream).await?;
+        self.set_sip_remote(&session, &offer)?;
+        Ok((session, sdp::rewrite(offer, local, true).into()))
+    }
+
+    async fn set_answer(&self, session: &str, answer: Bytes) -> Result<(), MediaEngineError> {
+        self.set_sip_remote(session, &parse_sdp(&answer)?)?;
+        Ok(())
+    }
+
+    async fn destroy(&self, session: &str) -> Result<(), MediaEngineError> {
+        let removed = {
+            let mut internal = self.internal.write();
+            let removed = internal.sessions.remove(session).ok_or(MediaEngineError::UnknownSession)?;
+            internal.used_ports.remove(&removed.sip.port);
+            internal.used_ports.remove(&removed.peer.port);
+            removed
+        };
+        let info = removed.info(session, self.public_ip);
+        log::info!("[RtpRelay] destroyed session {session} after {}s, to peer {:?}, to sip {:?}", info.age_secs, info.to_peer, info.to_sip);
+        Ok(())
+    }
+
+    fn has_stats(&self) -> bool {
+        true
+    }
+
+    async fn stats(&self, session: &str) -> Result<MediaStats, MediaEngineError> {
+        let internal = self.internal.read();
+        let session = internal.sessions.get(session).ok_or(MediaEngineError::UnknownSession)?;
+        let inbound = session.to_peer.quality.read().stats();
+        let outbound = session.to_sip.quality.read().stats();
+        Ok(build_media_stats(inbound, outbound))
+    }
+
+    fn peer_endpoint(&self, session: &str) -> Option<SocketAddr> {
+        let internal = self.internal.read();
+        let session = internal.sessions.get(session)?;
+        Some(SocketAddr::new(self.public_ip, session.peer.port))
+    }
+
+    async fn recording(&self, _session: &str, _action: RecordingAction) -> Result<String, MediaEngineError> {
+        // packets are only forwarded, recording needs a media server
+        Err(MediaEngineError::Unsupported("recording"))
+    }
+}
+
+/// Rtcp packet types are 192 to 223 in the second byte, where rtp has the marker bit and the payload type (RFC 5761)
+fn is_rtcp(packet: &[u8]) -> bool {
+    packet.len() >= 2 && (192..=223).contains(&packet[1])
+}
+
+/// Forward packets from the rtp or rtcp socket of one side to the other. A source is latched as the address of its side
+/// when the address is not locked yet, then packets from other sources are dropped. With rtcp-mux the rtp socket also carries rtcp
+async fn relay_loop(from: RelayLeg, to: RelayLeg, counters: Arc<StreamCounters>, rtcp_socket: bool) {
+    let socket = if rtcp_socket {
+        from.rtcp.clone()
+    } else {
+        from.rtp.clone()
+    };
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("[RtpRelay] recv error {e:?}");
+                break;
+            }
+        };
+        let rtcp = rtcp_socket || (from.rtcp_mux.load(Ordering::Relaxed) && is_rtcp(&buf[..len]));
+        let remote = *from.remote(rtcp).read();
+        match remote {
+            RelayRemote { locked: false, .. } => {
+                log::debug!("[RtpRelay] latched remote {src}");
+                *from.remote(rtcp).write() = RelayRemote { addr: Some(src), locked: true };
+            }
+            RelayRemote { addr: Some(remote), .. } if remote != src => {
+                log::debug!("[RtpRelay] drop packet from unexpected source {src}, remote is {remote}");
+                counters.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            _ => {}
+        }
+        if !rtcp {
+            counters.quality.write().on_packet(&buf[..len], Instant::now());
+        }
+        let dest = to.remote(rtcp).read().addr;
+        match dest {
+            Some(dest) => {
+                if let Err(e) = to.sender(rtcp).send_to(&buf[..len], dest).await {
+                    log::debug!("[RtpRelay] send to {dest} error {e:?}");
+                    counters.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                } else if rtcp {
+                    counters.rtcp_packets.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    counters.rtp_packets.fetch_add(1, Ordering::Relaxed);
+                    counters.rtp_bytes.fetch_add(len as u64, Ordering::Relaxed);
+                }
+            }
+            None => {
+                counters.dropped_packets.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use bytes::Bytes;
+    use tokio::{
+        net::UdpSocket,
+        time::{sleep, timeout},
+    };
+
+    use crate::{
+        protocol::StreamingInfo,
+        sip::media::{parse_sdp, MediaBackend, MediaEngineError},
+    };
+
+    use super::{sdp, RelaySessionInfo, RtpRelay, RtpRelayConfig};
+
+    fn relay(port_min: u16, port_max: u16) -> RtpRelay {
+        RtpRelay::new(RtpRelayConfig {
+            bind_ip: [127, 0, 0, 1].into(),
+            public_ip: None,
+            port_min,
+            port_max,
+            sip_latching: false,
+        })
+    }
+
+    fn stream() -> StreamingInfo {
+        StreamingInfo {
+            room: "room1".to_owned(),
+            peer: "peer1".to_owned(),
+            record: false,
+        }
+    }
+
+    /// Relay address of the SIP side from the sdp which the relay created
+    fn local_addr(sdp: &Bytes) -> SocketAddr {
+        sdp::remote_addr(&parse_sdp(sdp).expect("should parse sdp")).expect("sdp should have address")
+    }
+
+    fn session_info(relay: &RtpRelay, session: &str) -> RelaySessionInfo {
+        relay.sessions().into_iter().find(|s| s.session == session).expect("should have session")
+    }
+
+    async fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = [0; 1500];
+        let (len, from) = timeout(Duration::from_secs(2), socket.recv_from(&mut buf)).await.expect("should not timeout").expect("should receive");
+        (buf[..len].to_vec(), from)
+    }
+
+    #[tokio::test]
+    async fn test_relay_between_sip_and_peer() {
+        let relay = relay(41000, 41099);
+        let carrier = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        let carrier_addr = carrier.local_addr().expect("should have addr");
+        let offer = format!(
+            "v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\nm=audio {} RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n",
+            carrier_addr.port()
+        );
+
+        let (session, answer) = relay.create_answer(&stream(), Bytes::from(offer)).await.expect("should create answer");
+        let sip_local = local_addr(&answer);
+        let info = session_info(&relay, &session);
+        assert_eq!(info.sip_local, sip_local);
+        assert_eq!(info.sip_remote, Some(carrier_addr));
+
+        // SIP packets are dropped until the peer sends its first packet
+        carrier.send_to(b"early", sip_local).await.expect("should send");
+        timeout(Duration::from_secs(2), async {
+            while session_info(&relay, &session).to_peer.dropped_packets == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("early packet should be dropped");
+        let peer = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        peer.send_to(b"from peer", info.peer_local).await.expect("should send");
+        assert_eq!(recv(&carrier).await, (b"from peer".to_vec(), sip_local));
+
+        carrier.send_to(b"from carrier", sip_local).await.expect("should send");
+        assert_eq!(recv(&peer).await, (b"from carrier".to_vec(), info.peer_local));
+
+        let info = session_info(&relay, &session);
+        assert_eq!(info.peer_remote, Some(peer.local_addr().expect("should have addr")));
+        assert_eq!((info.to_peer.rtp_packets, info.to_peer.rtp_bytes, info.to_peer.dropped_packets), (1, 12, 1));
+        assert_eq!((info.to_sip.rtp_packets, info.to_sip.rtp_bytes), (1, 9));
+
+        // quality is measured on RTP packets from the SIP side, seq 2 is lost
+        for seq in [1u16, 3] {
+            let mut packet = vec![0x80, 0];
+            packet.extend_from_slice(&seq.to_be_bytes());
+            packet.extend_from_slice(&[0; 8]);
+            carrier.send_to(&packet, sip_local).await.expect("should send");
+            recv(&peer).await;
+        }
+        let stats = relay.stats(&session).await.expect("should have stats");
+        let inbound = stats.inbound.expect("should have inbound");
+        assert_eq!((inbound.packets, inbound.lost), (2, 1));
+        assert_eq!(stats.outbound.expect("should have outbound").packets, 0, "peer packets are not RTP");
+        assert!(stats.mos < 2.0, "heavy loss should have a low mos");
+
+        relay.destroy(&session).await.expect("should destroy");
+        assert!(relay.sessions().is_empty());
+        assert!(matches!(relay.destroy(&session).await, Err(MediaEngineError::UnknownSession)));
+    }
+
+    #[tokio::test]
+    async fn test_drop_packets_from_unexpected_source() {
+        let relay = relay(41100, 41199);
+        let carrier = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        let offer = format!(
+            "v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\nm=audio {} RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n",
+            carrier.local_addr().expect("should have addr").port()
+        );
+        let (session, answer) = relay.create_answer(&stream(), Bytes::from(offer)).await.expect("should create answer");
+        let sip_local = local_addr(&answer);
+        let info = session_info(&relay, &session);
+        assert_eq!(relay.peer_endpoint(&session), Some(info.peer_local));
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        peer.send_to(b"from peer", info.peer_local).await.expect("should send");
+        assert_eq!(recv(&carrier).await, (b"from peer".to_vec(), sip_local));
+
+        // the peer is latched and the SIP side comes from sdp, so a second source can't hijack either side
+        let intruder = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        intruder.send_to(b"intruder", info.peer_local).await.expect("should send");
+        intruder.send_to(b"intruder", sip_local).await.expect("should send");
+        timeout(Duration::from_secs(2), async {
+            loop {
+                let info = session_info(&relay, &session);
+                if info.to_peer.dropped_packets == 1 && info.to_sip.dropped_packets == 1 {
+                    break;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("intruder packets should be dropped");
+
+        carrier.send_to(b"from carrier", sip_local).await.expect("should send");
+        assert_eq!(recv(&peer).await, (b"from carrier".to_vec(), info.peer_local));
+        peer.send_to(b"from peer", info.peer_local).await.expect("should send");
+        assert_eq!(recv(&carrier).await, (b"from peer".to_vec(), sip_local));
+
+        let info = session_info(&relay, &session);
+        assert_eq!(info.peer_remote, Some(peer.local_addr().expect("should have addr")));
+        assert_eq!(info.sip_remote, Some(carrier.local_addr().expect("should have addr")));
+        assert_eq!((info.to_peer.rtp_packets, info.to_sip.rtp_packets), (1, 2));
+        relay.destroy(&session).await.expect("should destroy");
+    }
+
+    #[tokio::test]
+    async fn test_sip_latching() {
+        let relay = RtpRelay::new(RtpRelayConfig {
+            bind_ip: [127, 0, 0, 1].into(),
+            public_ip: None,
+            port_min: 41300,
+            port_max: 41399,
+            sip_latching: true,
+        });
+        // the carrier is behind NAT, the announced address is not where its packets come from
+        let carrier = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        let offer = "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\ns=-\r\nc=IN IP4 192.0.2.1\r\nt=0 0\r\nm=audio 30000 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n";
+        let (session, answer) = relay.create_answer(&stream(), Bytes::from_static(offer.as_bytes())).await.expect("should create answer");
+        let sip_local = local_addr(&answer);
+        let info = session_info(&relay, &session);
+        assert_eq!(info.sip_remote, Some("192.0.2.1:30000".parse().expect("should parse addr")));
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        peer.send_to(b"from peer", info.peer_local).await.expect("should send");
+        carrier.send_to(b"from carrier", sip_local).await.expect("should send");
+        assert_eq!(recv(&peer).await, (b"from carrier".to_vec(), info.peer_local));
+        assert_eq!(session_info(&relay, &session).sip_remote, Some(carrier.local_addr().expect("should have addr")));
+
+        // the latched address is locked
+        let intruder = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        intruder.send_to(b"intruder", sip_local).await.expect("should send");
+        peer.send_to(b"from peer", info.peer_local).await.expect("should send");
+        assert_eq!(recv(&carrier).await, (b"from peer".to_vec(), sip_local));
+        assert_eq!(session_info(&relay, &session).sip_remote, Some(carrier.local_addr().expect("should have addr")));
+        relay.destroy(&session).await.expect("should destroy");
+    }
+
+    #[tokio::test]
+    async fn test_sip_rtcp_mux() {
+        let relay = relay(41400, 41499);
+        let carrier = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        let offer = format!(
+            "v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\nm=audio {} RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\na=rtcp-mux\r\n",
+            carrier.local_addr().expect("should have addr").port()
+        );
+        let (session, answer) = relay.create_answer(&stream(), Bytes::from(offer)).await.expect("should create answer");
+        assert!(String::from_utf8_lossy(&answer).contains("a=rtcp-mux"), "answer should accept rtcp-mux");
+        let sip_local = local_addr(&answer);
+        let info = session_info(&relay, &session);
+        let peer_rtcp = SocketAddr::new(info.peer_local.ip(), info.peer_local.port() + 1);
+
+        // rtcp of the peer goes to the SIP side from and to the rtp port
+        let peer = UdpSocket::bind("127.0.0.1:0").await.expect("should bind");
+        let sender_report = [0x80, 200, 0, 1, 0, 0, 0, 1];
+        peer.send_to(&sender_report, peer_rtcp).await.expect("should send");
+        assert_eq!(recv(&carrier).await, (sender_report.to_vec(), sip_local));
+
+        // rtcp from the SIP side arrives on the rtp port and goes to the rtcp port of the peer
+        carrier.send_to(&sender_report, sip_local).await.expect("should send");
+        assert_eq!(recv(&peer).await, (sender_report.to_vec(), peer_rtcp));
+
+        let info = session_info(&relay, &session);
+        assert_eq!((info.to_peer.rtcp_packets, info.to_peer.rtp_packets), (1, 0));
+        assert_eq!(
+            relay.stats(&session).await.expect("should have stats").inbound.expect("should have inbound").packets,
+            0,
+            "rtcp is not rtp"
+        );
+        relay.destroy(&session).await.expect("should destroy");
+    }
+
+    #[tokio::test]
+    async fn test_port_range_exhausted() {
+        // one session needs two pairs
+        let relay = relay(41200, 41203);
+        let (session, offer) = relay.create_offer(&stream()).await.expect("should create offer");
+        assert_eq!(local_addr(&offer).port(), 41200);
+        assert!(matches!(relay.create_offer(&stream()).await, Err(MediaEngineError::NoRelayPort)));
+
+        relay.destroy(&session).await.expect("should destroy");
+        // sockets are closed when the aborted relay tasks are dropped
+        sleep(Duration::from_millis(50)).await;
+        relay.create_offer(&stream()).await.expect("ports should be released");
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use ezk_sdp_types::{Direction, MediaDescription, MediaType, SessionDescription, TaggedAddress};
+
+fn net_type(ip: IpAddr) -> &'static str {
+    if ip.is_ipv4() {
+        "IP4"
+    } else {
+        "IP6"
+    }
+}
+
+fn tagged_address(ip: IpAddr) -> TaggedAddress {
+    match ip {
+        IpAddr::V4(ip) => TaggedAddress::IP4(ip),
+        IpAddr::V6(ip) => TaggedAddress::IP6(ip),
+    }
+}
+
+fn ip_of(address: &TaggedAddress) -> Option<IpAddr> {
+    match address {
+        TaggedAddress::IP4(ip) => Some(IpAddr::V4(*ip)),
+        TaggedAddress::IP6(ip) => Some(IpAddr::V6(*ip)),
+        TaggedAddress::IP4FQDN(_) | TaggedAddress::IP6FQDN(_) => None,
+    }
+}
+
+fn audio(sdp: &SessionDescription) -> Option<&MediaDescription> {
+    sdp.media_descriptions.iter().find(|media| matches!(media.media.media_type, MediaType::Audio))
+}
+
+/// Rtp address of the first audio stream, a media level connection line overrides the session level one
+pub fn remote_addr(sdp: &SessionDescription) -> Option<SocketAddr> {
+    let media = audio(sdp)?;
+    let connection = media.connection.as_ref().or(sdp.connection.as_ref())?;
+    let ip = ip_of(&connection.address)?;
+    Some(SocketAddr::new(ip, Some(media.media.port).filter(|p| *p != 0)?))
+}
+
+/// Rtcp address of the first audio stream with the rtp address `rtp`, None when rtcp is multiplexed on the rtp port (`a=rtcp-mux`).
+/// `a=rtcp` gives the port and optionally the address, without it rtcp goes to the next port (RFC 3605)
+pub fn rtcp_addr(sdp: &SessionDescription, rtp: SocketAddr) -> Option<SocketAddr> {
+    let media = audio(sdp)?;
+    if media.rtcp_mux {
+        return None;
+    }
+    match &media.rtcp_attr {
+        Some(rtcp) => {
+            let ip = rtcp.address.as_ref().and_then(ip_of).unwrap_or(rtp.ip());
+            Some(SocketAddr::new(ip, rtcp.port))
+        }
+        None => Some(SocketAddr::new(rtp.ip(), rtp.port().wrapping_add(1))),
+    }
+}
+
+/// Clock rates of payload types from rtpmap lines, telephone-event is skipped because its timestamps don't advance per packet
+pub fn clock_rates(sdp: &SessionDescription) -> HashMap<u8, u32> {
+    sdp.media_descriptions
+        .iter()
+        .flat_map(|media| media.rtpmap.iter())
+        .filter(|map| !map.encoding.eq_ignore_ascii_case("telephone-event"))
+        .map(|map| (map.payload, map.clock_rate))
+        .collect()
+}
+
+/// Copy of the sdp with the origin, connection lines and the first audio port pointing to `addr`.
+/// Other media lines are rejected with port 0, rtcp and ice attributes are removed because they describe the old address.
+/// `a=rtcp-mux` is kept, the relay then sends and receives rtcp on the rtp port
+pub fn rewrite(mut sdp: SessionDescription, addr: SocketAddr, answer: bool) -> String {
+    let address = tagged_address(addr.ip());
+    sdp.origin.address = address.clone();
+    if let Some(connection) = sdp.connection.as_mut() {
+        connection.address = address.clone();
+    }
+    sdp.ice_ufrag = None;
+    sdp.ice_pwd = None;
+    if answer {
+        reverse(&mut sdp.direction);
+    }
+    let mut audio_found = false;
+    for media in sdp.media_descriptions.iter_mut() {
+        if !audio_found && matches!(media.media.media_type, MediaType::Audio) {
+            audio_found = true;
+            media.media.port = addr.port();
+        } else {
+            media.media.port = 0;
+        }
+        if let Some(connection) = media.connection.as_mut() {
+            connection.address = address.clone();
+        }
+        media.rtcp_attr = None;
+        media.ice_ufrag = None;
+        media.ice_pwd = None;
+        media.ice_candidates.clear();
+        media.ice_end_of_candidates = false;
+        if answer {
+            reverse(&mut media.direction);
+        }
+    }
+    sdp.to_string()
+}
+
+/// Direction of the answer is the opposite of the offer one
+fn reverse(direction: &mut Direction) {
+    match direction {
+        Direction::SendOnly => *direction = Direction::RecvOnly,
+        Direction::RecvOnly => *direction = Direction::SendOnly,
+        _ => {}
+    }
+}
+
+/// Audio offer with the codecs which every SIP endpoint is expected to support
+pub fn offer(addr: SocketAddr, session_id: u64) -> String {
+    let net = net_type(addr.ip());
+    let ip = addr.ip();
+    let port = addr.port();
+    format!(
+        "v=0\r\no=- {session_id} 1 IN {net} {ip}\r\ns=-\r\nc=IN {net} {ip}\r\nt=0 0\r\nm=audio {port} RTP/AVP 0 8 101\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:8 PCMA/8000\r\na=rtpmap:101 telephone-event/8000\r\na=fmtp:101 0-16\r\na=sendrecv\r\n"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use ezk_sdp_types::SessionDescription;
+
+    use crate::sip::media::parse_sdp;
+
+    use super::{clock_rates, offer, remote_addr, rewrite, rtcp_addr};
+
+    const OFFER: &str = "v=0\r\no=carrier 123 1 IN IP4 198.51.100.1\r\ns=-\r\nc=IN IP4 198.51.100.1\r\nt=0 0\r\nm=audio 30000 RTP/AVP 0 101\r\nc=IN IP4 198.51.100.2\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:101 telephone-event/8000\r\na=rtcp:30001\r\na=sendonly\r\nm=video 30002 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\n";
+
+    fn parse(sdp: &str) -> SessionDescription {
+        parse_sdp(&Bytes::from(sdp.to_owned())).expect("should parse sdp")
+    }
+
+    fn audio(connection: &str, port: u16) -> SessionDescription {
+        parse(&format!("v=0\r\no=- 1 1 IN IP4 198.51.100.1\r\ns=-\r\n{connection}t=0 0\r\nm=audio {port} RTP/AVP 0\r\n"))
+    }
+
+    #[test]
+    fn test_remote_addr() {
+        assert_eq!(remote_addr(&parse(OFFER)), Some("198.51.100.2:30000".parse().expect("should parse addr")));
+        assert_eq!(remote_addr(&audio("c=IN IP4 198.51.100.1\r\n", 30000)), Some("198.51.100.1:30000".parse().expect("should parse addr")));
+        assert_eq!(remote_addr(&audio("c=IN IP4 198.51.100.1\r\n", 0)), None, "rejected stream");
+        assert_eq!(remote_addr(&audio("", 30000)), None, "missing connection");
+    }
+
+    #[test]
+    fn test_rtcp_addr() {
+        let rtp = "198.51.100.2:30000".parse().expect("should parse addr");
+        assert_eq!(rtcp_addr(&parse(OFFER), rtp), Some("198.51.100.2:30001".parse().expect("should parse addr")));
+        let sdp = OFFER.replace("a=rtcp:30001\r\n", "a=rtcp:31000 IN IP4 198.51.100.9\r\n");
+        assert_eq!(rtcp_addr(&parse(&sdp), rtp), Some("198.51.100.9:31000".parse().expect("should parse addr")));
+        let sdp = OFFER.replace("a=rtcp:30001\r\n", "");
+        assert_eq!(rtcp_addr(&parse(&sdp), rtp), Some("198.51.100.2:30001".parse().expect("should parse addr")), "next port by default");
+        let sdp = OFFER.replace("a=rtcp:30001\r\n", "a=rtcp-mux\r\n");
+        assert_eq!(rtcp_addr(&parse(&sdp), rtp), None, "rtcp on the rtp port");
+        assert!(rewrite(parse(&sdp), "203.0.113.5:40000".parse().expect("should parse addr"), true).contains("a=rtcp-mux\r\n"));
+    }
+
+    #[test]
+    fn test_clock_rates() {
+        let sdp = parse("v=0\r\no=- 1 1 IN IP4 198.51.100.1\r\ns=-\r\nc=IN IP4 198.51.100.1\r\nt=0 0\r\nm=audio 30000 RTP/AVP 111 0 101\r\na=rtpmap:111 opus/48000/2\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:101 telephone-event/8000\r\n");
+        assert_eq!(clock_rates(&sdp), [(111, 48000), (0, 8000)].into_iter().collect());
+    }
+
+    #[test]
+    fn test_rewrite_answer() {
+        let answer = rewrite(parse(OFFER), "203.0.113.5:40000".parse().expect("should parse addr"), true);
+        assert!(answer.contains("o=carrier 123 1 IN IP4 203.0.113.5\r\n"));
+        assert!(answer.contains("m=audio 40000 RTP/AVP 0 101\r\n"));
+        assert!(answer.contains("m=video 0 RTP/AVP 96\r\n"));
+        assert!(answer.contains("a=recvonly\r\n"));
+        assert!(!answer.contains("a=sendonly") && !answer.contains("a=rtcp:") && !answer.contains("198.51.100."));
+        assert_eq!(remote_addr(&parse(&answer)), Some("203.0.113.5:40000".parse().expect("should parse addr")));
+    }
+
+    #[test]
+    fn test_offer() {
+        let sdp = offer("[2001:db8::1]:40002".parse().expect("should parse addr"), 7);
+        assert!(sdp.contains("c=IN IP6 2001:db8::1\r\n"));
+        assert_eq!(remote_addr(&parse(&sdp)), Some("[2001:db8::1]:40002".parse().expect("should parse addr")));
+    }
+}
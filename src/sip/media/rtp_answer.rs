@@ -1,77 +1,85 @@
-use std::time::Duration;
-
 use bytes::Bytes;
 
-use crate::protocol::StreamingInfo;
+use crate::protocol::{
+    protobuf::sip_gateway::{MediaEndpoint, MediaStats},
+    StreamingInfo,
+};
 
-use super::{MediaApi, MediaEngineError};
+use super::{MediaApi, MediaEngineError, MediaRecorder, PolicyOffer};
 
 pub struct MediaRtpEngineAnswer {
     api: MediaApi,
-    offer: Bytes,
+    offer: PolicyOffer,
     created: Option<(String, Bytes)>,
 }
 
 impl MediaRtpEngineAnswer {
-    pub fn new(api: MediaApi, offer: Bytes) -> Self {
+    /// Answer the remote offer which already passed the codec policy
+    pub fn new(api: MediaApi, offer: PolicyOffer) -> Self {
         Self { api, offer, created: None }
     }
 
+    /// Name of the media backend of the session, None for the default one
+    pub fn backend_name(&self) -> Option<String> {
+        self.api.backend_name().map(|name| name.to_owned())
+    }
+
+    /// Id of the created media session, for atm0s it is the full url of the rtpengine session
+    pub fn session_url(&self) -> Option<String> {
+        self.created.as_ref().map(|(session, _)| session.clone())
+    }
+
+    /// Quality stats of the media session, None before the session is created or when the backend has no stats
+    pub async fn stats(&self) -> Option<MediaStats> {
+        let (session, _) = self.created.as_ref()?;
+        match self.api.backend().stats(session).await {
+            Ok(stats) => Some(stats),
+            Err(MediaEngineError::Unsupported(_)) => None,
+            Err(e) => {
+                log::warn!("[MediaRtpEngineAnswer] get stats error {session} {e}");
+                None
+            }
+        }
+    }
+
+    /// Address where the app side peer sends RTP, None before the session is created or when the backend has no plain RTP peer
+    pub fn peer_endpoint(&self) -> Option<MediaEndpoint> {
+        let (session, _) = self.created.as_ref()?;
+        self.api.peer_endpoint(session)
+    }
+
+    /// Recording control of the media session, fails before the session is created
+    pub fn recorder(&self) -> Result<MediaRecorder, MediaEngineError> {
+        let (session, _) = self.created.as_ref().ok_or(MediaEngineError::UnknownSession)?;
+        Ok(self.api.recorder(session))
+    }
+
     pub async fn create_answer(&mut self, stream: &StreamingInfo) -> Result<Bytes, MediaEngineError> {
         assert!(self.created.is_none(), "should not call create_answer twice");
-        log::info!("[MediaRtpEngineAnswer] creating token");
-        let token = self.api.create_rtpengine_token(&stream.room, &stream.peer, stream.record).await?;
-        log::info!("[MediaRtpEngineAnswer] created token");
         log::info!("[MediaRtpEngineAnswer] creating answer");
-        let res = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(3))
-            .build()
-            .expect("Should create client")
-            .post(&format!("{}/rtpengine/answer", self.api.gateway()))
-            .header("Content-Type", "application/sdp")
-            .header("Authorization", format!("Bearer {}", token))
-            .body(self.offer.clone())
-            .send()
-            .await?;
-
-        let status = res.status().as_u16();
-
-        if status == 201 {
-            let endpoint = res.headers().get("Location").ok_or(MediaEngineError::MissingLocation)?;
-            let location = endpoint.to_str().map_err(|_e| MediaEngineError::InvalidLocation)?.to_string();
-            let sdp: Bytes = res.bytes().await?;
-            log::info!("[MediaRtpEngineAnswer] created answer {location}");
-            self.created = Some((location, sdp.clone()));
-            Ok(sdp)
-        } else {
-            let response = res.text().await?;
-            log::error!("[MediaRtpEngineAnswer] create answer error {status}, {response}");
-            Err(MediaEngineError::InvalidStatus(status))
+        match self.api.backend().create_answer(stream, self.offer.sdp()).await {
+            Ok((session, sdp)) => {
+                log::info!("[MediaRtpEngineAnswer] created answer {session}");
+                self.created = Some((session, sdp.clone()));
+                Ok(self.offer.answer(&sdp)?)
+            }
+            Err(e) => {
+                log::error!("[MediaRtpEngineAnswer] create answer error {e}");
+                Err(e)
+            }
         }
     }
 }
 
 impl Drop for MediaRtpEngineAnswer {
     fn drop(&mut self) {
-        if let Some((location, _)) = self.created.take() {
-            let url = format!("{}{}", self.api.gateway(), location);
+        if let Some((session, _)) = self.created.take() {
+            let backend = self.api.backend().clone();
             tokio::spawn(async move {
-                log::info!("[MediaRtpEngineAnswer] destroying {url}");
-                let res = reqwest::ClientBuilder::new()
-                    .timeout(Duration::from_secs(3))
-                    .build()
-                    .expect("Should create client")
-                    .delete(&url)
-                    .send()
-                    .await?;
-
-                let status = res.status().as_u16();
-                if status == 200 {
-                    log::info!("[MediaRtpEngineAnswer] destroyed {url}");
-                    Ok(())
-                } else {
-                    log::error!("[MediaRtpEngineAnswer] destroy error {url} {status}");
-                    Err(MediaEngineError::InvalidStatus(status))
+                log::info!("[MediaRtpEngineAnswer] destroying {session}");
+                match backend.destroy(&session).await {
+                    Ok(()) => log::info!("[MediaRtpEngineAnswer] destroyed {session}"),
+                    Err(e) => log::error!("[MediaRtpEngineAnswer] destroy error {session} {e}"),
                 }
             });
         }
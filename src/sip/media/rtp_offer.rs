@@ -1,114 +1,128 @@
-use std::time::Duration;
-
 use bytes::Bytes;
 
-use crate::protocol::StreamingInfo;
+use crate::protocol::{
+    protobuf::sip_gateway::{MediaEndpoint, MediaStats},
+    StreamingInfo,
+};
 
-use super::{MediaApi, MediaEngineError};
+use super::{CodecPolicy, MediaApi, MediaEngineError, MediaRecorder, PolicyOffer};
 
 pub struct MediaRtpEngineOffer {
     api: MediaApi,
     stream: StreamingInfo,
-    offer: Option<(String, Bytes)>,
+    policy: CodecPolicy,
+    session: Option<String>,
+    offer: Option<PolicyOffer>,
     answered: bool,
 }
 
 impl MediaRtpEngineOffer {
-    pub fn new(api: MediaApi, stream: StreamingInfo) -> Self {
+    pub fn new(api: MediaApi, stream: StreamingInfo, policy: CodecPolicy) -> Self {
         Self {
             api,
             stream,
+            policy,
+            session: None,
             offer: None,
             answered: false,
         }
     }
 
+    /// Offer sdp after applying the codec policy
     pub fn sdp(&self) -> Option<Bytes> {
-        self.offer.as_ref().map(|(_, sdp)| sdp.clone())
+        self.offer.as_ref().map(|offer| offer.sdp())
     }
 
     pub fn answered(&self) -> bool {
         self.answered
     }
 
-    pub async fn create_offer(&mut self) -> Result<Bytes, MediaEngineError> {
-        assert!(self.offer.is_none(), "should not call create_offer twice");
-        log::info!("[RtpEngineOffer] creating token");
-        let token = self.api.create_rtpengine_token(&self.stream.room, &self.stream.peer, self.stream.record).await?;
-        log::info!("[RtpEngineOffer] created token");
-        log::info!("[RtpEngineOffer] creating offer");
-        let res = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(3))
-            .build()
-            .expect("Should create client")
-            .post(&format!("{}/rtpengine/offer", self.api.gateway()))
-            .header("Authorization", format!("Bearer {}", token))
-            .send()
-            .await?;
+    /// Name of the media backend of the session, None for the default one
+    pub fn backend_name(&self) -> Option<String> {
+        self.api.backend_name().map(|name| name.to_owned())
+    }
 
-        let status = res.status().as_u16();
+    /// Id of the created media session, for atm0s it is the full url of the rtpengine session
+    pub fn session_url(&self) -> Option<String> {
+        self.session.clone()
+    }
+
+    /// Whether the media backend reports stats, calls without them don't poll `stats`
+    pub fn has_stats(&self) -> bool {
+        self.api.has_stats()
+    }
 
-        if status == 201 {
-            let endpoint = res.headers().get("Location").ok_or(MediaEngineError::MissingLocation)?;
-            let location = endpoint.to_str().map_err(|_e| MediaEngineError::InvalidLocation)?.to_string();
-            let sdp = res.bytes().await?;
-            log::info!("[RtpEngineOffer] created offer {location}");
-            self.offer = Some((location, sdp.clone()));
-            Ok(sdp)
-        } else {
-            log::error!("[RtpEngineOffer] create offer error {status}");
-            Err(MediaEngineError::InvalidStatus(status))
+    /// Quality stats of the media session, None before the session is created or when the backend has no stats
+    pub async fn stats(&self) -> Option<MediaStats> {
+        let session = self.session.as_ref()?;
+        match self.api.backend().stats(session).await {
+            Ok(stats) => Some(stats),
+            Err(MediaEngineError::Unsupported(_)) => None,
+            Err(e) => {
+                log::warn!("[RtpEngineOffer] get stats error {session} {e}");
+                None
+            }
         }
     }
 
-    pub async fn set_answer(&mut self, sdp: Bytes) -> Result<(), MediaEngineError> {
-        let (location, _) = self.offer.as_ref().expect("should call after create_offer success");
-        let url = format!("{}{}", self.api.gateway(), location);
-        log::info!("[RtpEngineOffer] sending answer {url}");
+    /// Address where the app side peer sends RTP, None before the session is created or when the backend has no plain RTP peer
+    pub fn peer_endpoint(&self) -> Option<MediaEndpoint> {
+        self.api.peer_endpoint(self.session.as_ref()?)
+    }
+
+    /// Recording control of the media session, fails before the session is created
+    pub fn recorder(&self) -> Result<MediaRecorder, MediaEngineError> {
+        let session = self.session.as_ref().ok_or(MediaEngineError::UnknownSession)?;
+        Ok(self.api.recorder(session))
+    }
 
-        let res = reqwest::ClientBuilder::new()
-            .timeout(Duration::from_secs(3))
-            .build()
-            .expect("Should create client")
-            .patch(&url)
-            .header("Content-Type", "application/sdp")
-            .body(sdp)
-            .send()
-            .await?;
+    pub async fn create_offer(&mut self) -> Result<Bytes, MediaEngineError> {
+        assert!(self.session.is_none(), "should not call create_offer twice");
+        log::info!("[RtpEngineOffer] creating offer");
+        let (session, sdp) = match self.api.backend().create_offer(&self.stream).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!("[RtpEngineOffer] create offer error {e}");
+                return Err(e);
+            }
+        };
+        log::info!("[RtpEngineOffer] created offer {session}");
+        self.session = Some(session);
+        // the session is destroyed on drop if the offer is rejected by the policy
+        let offer = self.policy.offer(&sdp)?;
+        let sdp = offer.sdp();
+        self.offer = Some(offer);
+        Ok(sdp)
+    }
 
-        let status = res.status().as_u16();
-        if status == 200 {
-            log::info!("[RtpEngineOffer] sent answer {url}");
-            self.answered = true;
-            Ok(())
-        } else {
-            log::error!("[RtpEngineOffer] send answer error {url} {status}");
-            Err(MediaEngineError::InvalidStatus(status))
+    pub async fn set_answer(&mut self, sdp: Bytes) -> Result<(), MediaEngineError> {
+        let session = self.session.as_ref().expect("should call after create_offer success");
+        let offer = self.offer.as_ref().expect("should call after create_offer success");
+        let sdp = offer.answer(&sdp)?;
+        log::info!("[RtpEngineOffer] sending answer {session}");
+        match self.api.backend().set_answer(session, sdp).await {
+            Ok(()) => {
+                log::info!("[RtpEngineOffer] sent answer {session}");
+                self.answered = true;
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("[RtpEngineOffer] send answer error {session} {e}");
+                Err(e)
+            }
         }
     }
 }
 
 impl Drop for MediaRtpEngineOffer {
     fn drop(&mut self) {
-        if let Some((location, _)) = self.offer.take() {
-            let url = format!("{}{}", self.api.gateway(), location);
+        if let Some(session) = self.session.take() {
+            let backend = self.api.backend().clone();
             tokio::spawn(async move {
-                log::info!("[RtpEngineOffer] destroying {url}");
-                let res = reqwest::ClientBuilder::new()
-                    .timeout(Duration::from_secs(3))
-                    .build()
-                    .expect("Should create client")
-                    .delete(&url)
-                    .send()
-                    .await?;
-
-                let status = res.status().as_u16();
-                if status == 200 {
-                    log::info!("[RtpEngineOffer] destroyed {url}");
-                    Ok(())
-                } else {
-                    log::error!("[RtpEngineOffer] destroy error {url} {status}");
-                    Err(MediaEngineError::InvalidStatus(status))
+                log::info!("[RtpEngineOffer] destroying {session}");
+                match backend.destroy(&session).await {
+                    Ok(()) => log::info!("[RtpEngineOffer] destroyed {session}"),
+                    Err(e) => log::error!("[RtpEngineOffer] destroy error {session} {e}"),
                 }
             });
         }
@@ -1,5 +1,13 @@
 mod media;
 mod server;
 
-pub use media::{MediaApi, MediaEngineError, MediaRtpEngineOffer};
-pub use server::{SipIncomingCall, SipIncomingCallOut, SipOutgoingCall, SipOutgoingCallOut, SipServer, SipServerError, SipServerOut};
+pub use media::{
+    Atm0sMediaBackend, CodecPolicy, HttpRtpEngine, MediaApi, MediaApiError, MediaBackend, MediaBackends, MediaEngineError, MediaRecorder, MediaRtpEngineOffer, RecordingAction, RelaySessionInfo,
+    RelayStreamStats, RtpEngine, RtpRelay, RtpRelayConfig, SdpPolicyError, RELAY_BACKEND,
+};
+#[cfg(any(test, feature = "test-utils"))]
+pub use media::{FakeRtpEngine, RtpEngineCall, FAKE_ANSWER_SDP, FAKE_OFFER_SDP};
+pub use server::{
+    caller_id_headers, to_pcap, to_text, trunk_sources, validate_custom_headers, SipCallSnapshot, SipFloodConfig, SipFloodFilter, SipIncomingCall, SipIncomingCallError, SipIncomingCallOut,
+    SipOutgoingCall, SipOutgoingCallError, SipOutgoingCallOut, SipOutgoingCallParams, SipServer, SipServerError, SipServerOut, SipTraceConfig, SipTraceDirection, SipTraceEntry, SipTracer,
+};
@@ -12,11 +12,20 @@ use tokio::sync::mpsc::{channel, Receiver};
 
 use crate::protocol::{SipAuth, StreamingInfo};
 
+mod filter;
+mod headers;
 mod incoming;
 mod outgoing;
+mod snapshot;
+mod trace;
 
-pub use incoming::{SipIncomingCall, SipIncomingCallOut};
-pub use outgoing::{SipOutgoingCall, SipOutgoingCallError, SipOutgoingCallOut};
+pub use incoming::{SipIncomingCall, SipIncomingCallError, SipIncomingCallOut};
+pub use outgoing::{SipOutgoingCall, SipOutgoingCallError, SipOutgoingCallOut, SipOutgoingCallParams};
+
+pub use filter::{trunk_sources, SipFloodConfig, SipFloodFilter};
+pub use headers::{caller_id_headers, validate_custom_headers};
+pub use snapshot::SipCallSnapshot;
+pub use trace::{to_pcap, to_text, SipTraceConfig, SipTraceDirection, SipTraceEntry, SipTracer};
 
 use super::MediaApi;
 
@@ -33,15 +42,22 @@ pub enum SipServerOut {
 pub struct SipServer {
     endpoint: Endpoint,
     contact: Contact,
+    /// Listener of the contact address
+    contact_addr: SocketAddr,
     dialog_layer: LayerKey<DialogLayer>,
     invite_layer: LayerKey<InviteLayer>,
     incoming_rx: Receiver<SipIncomingCall>,
+    tracer: SipTracer,
 }
 
 impl SipServer {
-    pub async fn new(addr: SocketAddr) -> io::Result<Self> {
+    /// Spawn an UDP transport for each listener, the first one is used as contact address
+    pub async fn new(addrs: &[SocketAddr], forward_headers: Vec<String>, filter: SipFloodFilter, tracer: SipTracer) -> io::Result<Self> {
+        let addr = addrs.first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no sip listener"))?;
         let mut builder = Endpoint::builder();
 
+        // must be the first layer, later layers take requests they handle
+        builder.add_layer(trace::SipTraceLayer::new(tracer.clone(), filter.clone()));
         let dialog_layer = builder.add_layer(DialogLayer::default());
         let invite_layer = builder.add_layer(InviteLayer::default());
 
@@ -49,9 +65,19 @@ impl SipServer {
         let contact = Contact::new(NameAddr::uri(contact));
 
         let (incoming_tx, incoming_rx) = channel(10);
-        builder.add_layer(InviteAcceptLayer::new(incoming_tx, contact.clone(), dialog_layer, invite_layer));
+        builder.add_layer(InviteAcceptLayer::new(
+            incoming_tx,
+            contact.clone(),
+            dialog_layer,
+            invite_layer,
+            forward_headers,
+            filter,
+            tracer.clone(),
+        ));
 
-        Udp::spawn(&mut builder, addr).await?;
+        for addr in addrs {
+            Udp::spawn(&mut builder, *addr).await?;
+        }
 
         // Build endpoint to start the SIP Stack
         let endpoint = builder.build();
@@ -59,14 +85,39 @@ impl SipServer {
         Ok(Self {
             endpoint,
             contact,
+            contact_addr: *addr,
             dialog_layer,
             invite_layer,
             incoming_rx,
+            tracer,
         })
     }
 
-    pub fn make_call(&self, media_api: MediaApi, from: &str, to: &str, auth: Option<SipAuth>, stream: StreamingInfo) -> Result<SipOutgoingCall, SipOutgoingCallError> {
-        SipOutgoingCall::new(media_api, self.endpoint.clone(), self.dialog_layer, self.invite_layer, from, to, self.contact.clone(), auth, stream)
+    pub fn make_call(&self, media_api: MediaApi, from: &str, to: &str, auth: Option<SipAuth>, stream: StreamingInfo, params: SipOutgoingCallParams) -> Result<SipOutgoingCall, SipOutgoingCallError> {
+        SipOutgoingCall::new(
+            media_api,
+            self.endpoint.clone(),
+            self.dialog_layer,
+            self.invite_layer,
+            from,
+            to,
+            self.contact.clone(),
+            self.contact_addr,
+            auth,
+            stream,
+            params,
+            self.tracer.clone(),
+        )
+    }
+
+    /// End a call of another node from its snapshot by sending BYE from this endpoint
+    pub fn terminate_snapshot(&self, snapshot: SipCallSnapshot) {
+        let endpoint = self.endpoint.clone();
+        tokio::spawn(async move {
+            if let Err(e) = snapshot.send_bye(&endpoint).await {
+                log::warn!("[SipServer] send BYE to {} error {e:?}", snapshot.target);
+            }
+        });
     }
 
     pub async fn recv(&mut self) -> Option<SipServerOut> {
@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use spin::RwLock;
+
+use crate::address_book::AddressBookStorage;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+/// Idle sources are cleaned when the tracked sources map grows over this size
+const MAX_TRACKED_SOURCES: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct SipFloodConfig {
+    /// Max INVITEs per second from a single source ip
+    pub invites_per_second: u32,
+    /// Number of rejected or malformed INVITEs from a source ip within a minute which triggers a ban
+    pub ban_threshold: u32,
+    pub ban_duration: Duration,
+    /// Source subnets which are always dropped
+    pub blocklist: Vec<IpNet>,
+    /// Source subnets which are never rate limited or banned, trunk servers are added to it
+    pub trusted: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SipFilterReject {
+    UnknownNumber,
+    SubnetNotAllowed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SipFilterResult {
+    Allow,
+    /// Respond a final error without creating dialog
+    Reject(SipFilterReject),
+    /// Silently ignore, used for banned or flooding sources
+    Drop,
+}
+
+struct SourceState {
+    window_started_at: Instant,
+    invites: u32,
+    violations_started_at: Instant,
+    violations: u32,
+}
+
+struct Ban {
+    until: Instant,
+    reason: &'static str,
+}
+
+#[derive(Default)]
+struct FilterInternal {
+    sources: HashMap<IpAddr, SourceState>,
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl FilterInternal {
+    fn check<F: FnOnce() -> Result<(), SipFilterReject>>(&mut self, cfg: &SipFloodConfig, ip: IpAddr, now: Instant, exempt: bool, check_number: F) -> SipFilterResult {
+        if cfg.blocklist.iter().any(|net| net.contains(&ip)) {
+            return SipFilterResult::Drop;
+        }
+
+        // trusted sources are still checked against the address book but never limited or banned
+        if exempt {
+            return match check_number() {
+                Ok(()) => SipFilterResult::Allow,
+                Err(reject) => SipFilterResult::Reject(reject),
+            };
+        }
+
+        if let Some(ban) = self.bans.get(&ip) {
+            if ban.until > now {
+                return SipFilterResult::Drop;
+            }
+            self.bans.remove(&ip);
+        }
+
+        let source = self.source(ip, now);
+        if now.duration_since(source.window_started_at) >= RATE_WINDOW {
+            source.window_started_at = now;
+            source.invites = 0;
+        }
+        source.invites += 1;
+        // rate drops are not violations, a busy but valid source only loses the exceeded INVITEs
+        if source.invites > cfg.invites_per_second {
+            return SipFilterResult::Drop;
+        }
+
+        let (reject, reason) = match check_number() {
+            Ok(()) => return SipFilterResult::Allow,
+            Err(SipFilterReject::UnknownNumber) => (SipFilterReject::UnknownNumber, "unknown number"),
+            Err(SipFilterReject::SubnetNotAllowed) => (SipFilterReject::SubnetNotAllowed, "subnet not allowed"),
+        };
+        self.add_violation(cfg, ip, now, reason);
+        SipFilterResult::Reject(reject)
+    }
+
+    /// Whether the blocklist or a ban drops packets of the source, nothing is counted
+    fn is_dropped(&self, cfg: &SipFloodConfig, ip: IpAddr, now: Instant, exempt: bool) -> bool {
+        if cfg.blocklist.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        !exempt && self.bans.get(&ip).is_some_and(|ban| ban.until > now)
+    }
+
+    fn source(&mut self, ip: IpAddr, now: Instant) -> &mut SourceState {
+        if self.sources.len() > MAX_TRACKED_SOURCES {
+            self.sources
+                .retain(|_, s| now.duration_since(s.violations_started_at) < VIOLATION_WINDOW || now.duration_since(s.window_started_at) < RATE_WINDOW);
+        }
+
+        self.sources.entry(ip).or_insert(SourceState {
+            window_started_at: now,
+            invites: 0,
+            violations_started_at: now,
+            violations: 0,
+        })
+    }
+
+    fn add_violation(&mut self, cfg: &SipFloodConfig, ip: IpAddr, now: Instant, reason: &'static str) {
+        let source = self.source(ip, now);
+        if now.duration_since(source.violations_started_at) >= VIOLATION_WINDOW {
+            source.violations_started_at = now;
+            source.violations = 0;
+        }
+        source.violations += 1;
+        if source.violations >= cfg.ban_threshold {
+            log::warn!("[SipFloodFilter] ban {ip} for {:?} because of {reason}", cfg.ban_duration);
+            self.sources.remove(&ip);
+            self.bans.insert(
+                ip,
+                Ban {
+                    until: now + cfg.ban_duration,
+                    reason,
+                },
+            );
+        }
+    }
+}
+
+/// Pre-dialog filter for incoming INVITEs, which is shared between the sip layer and admin apis
+#[derive(Clone)]
+pub struct SipFloodFilter {
+    cfg: Arc<RwLock<SipFloodConfig>>,
+    address_book: AddressBookStorage,
+    internal: Arc<RwLock<FilterInternal>>,
+}
+
+impl SipFloodFilter {
+    pub fn new(cfg: SipFloodConfig, address_book: AddressBookStorage) -> Self {
+        Self {
+            cfg: Arc::new(RwLock::new(cfg)),
+            address_book,
+            internal: Default::default(),
+        }
+    }
+
+    pub fn check(&self, remote: SocketAddr, from: &str, to: &str) -> SipFilterResult {
+        let cfg = self.cfg.read();
+        let exempt = self.is_exempt(&cfg, remote.ip());
+        self.internal.write().check(&cfg, remote.ip(), Instant::now(), exempt, || {
+            if !self.address_book.has_number(to) {
+                Err(SipFilterReject::UnknownNumber)
+            } else if self.address_book.validate_phone(remote, from, to).is_none() {
+                Err(SipFilterReject::SubnetNotAllowed)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Count an INVITE which cannot be parsed as a violation of its source
+    pub fn report_malformed(&self, remote: SocketAddr) {
+        let cfg = self.cfg.read();
+        if !self.is_exempt(&cfg, remote.ip()) {
+            self.internal.write().add_violation(&cfg, remote.ip(), Instant::now(), "malformed request");
+        }
+    }
+
+    /// Whether requests of the source are dropped by the blocklist or a ban. It doesn't count as an INVITE,
+    /// so layers before the filter can skip sources which the filter drops
+    pub fn is_dropped(&self, ip: IpAddr) -> bool {
+        let cfg = self.cfg.read();
+        let exempt = self.is_exempt(&cfg, ip);
+        self.internal.read().is_dropped(&cfg, ip, Instant::now(), exempt)
+    }
+
+    /// Trusted subnets and address book subnets are exempt from rate limit and bans
+    fn is_exempt(&self, cfg: &SipFloodConfig, ip: IpAddr) -> bool {
+        cfg.trusted.iter().any(|net| net.contains(&ip)) || self.address_book.is_known_source(ip)
+    }
+
+    /// Active bans with remaining duration and reason
+    pub fn bans(&self) -> Vec<(IpAddr, Duration, &'static str)> {
+        let now = Instant::now();
+        let internal = self.internal.read();
+        internal.bans.iter().filter(|(_, b)| b.until > now).map(|(ip, b)| (*ip, b.until - now, b.reason)).collect()
+    }
+
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.internal.write().bans.remove(&ip).is_some()
+    }
+
+    /// Replace the blocklist, it is applied to next INVITEs
+    pub fn set_blocklist(&self, blocklist: Vec<IpNet>) {
+        self.cfg.write().blocklist = blocklist;
+    }
+}
+
+/// Trunk servers as trusted source subnets, hostnames are resolved once at startup
+pub async fn trunk_sources(servers: &[String]) -> Vec<IpNet> {
+    let mut sources = vec![];
+    for server in servers {
+        if let Ok(ip) = server.parse::<IpAddr>() {
+            sources.push(IpNet::from(ip));
+            continue;
+        }
+        let target = if server.contains(':') {
+            server.clone()
+        } else {
+            format!("{server}:5060")
+        };
+        match tokio::net::lookup_host(&target).await {
+            Ok(addrs) => sources.extend(addrs.map(|addr| IpNet::from(addr.ip()))),
+            Err(e) => log::warn!("[SipFloodFilter] cannot resolve trunk server {server}: {e:?}"),
+        }
+    }
+    sources
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{FilterInternal, SipFilterReject, SipFilterResult, SipFloodConfig};
+
+    fn cfg() -> SipFloodConfig {
+        SipFloodConfig {
+            invites_per_second: 2,
+            ban_threshold: 3,
+            ban_duration: Duration::from_secs(60),
+            blocklist: vec!["192.168.0.0/16".parse().expect("should parse subnet")],
+            trusted: vec![],
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_per_ip() {
+        let mut filter = FilterInternal::default();
+        let cfg = cfg();
+        let now = Instant::now();
+        let ip = "10.0.0.1".parse().expect("should parse ip");
+        assert_eq!(filter.check(&cfg, ip, now, false, || Ok(())), SipFilterResult::Allow);
+        assert_eq!(filter.check(&cfg, ip, now, false, || Ok(())), SipFilterResult::Allow);
+        assert_eq!(filter.check(&cfg, ip, now, false, || Ok(())), SipFilterResult::Drop);
+        assert_eq!(filter.check(&cfg, "10.0.0.2".parse().expect("should parse ip"), now, false, || Ok(())), SipFilterResult::Allow);
+        assert_eq!(filter.check(&cfg, ip, now + Duration::from_secs(1), false, || Ok(())), SipFilterResult::Allow);
+    }
+
+    #[test]
+    fn test_ban_after_rejects() {
+        let mut filter = FilterInternal::default();
+        let cfg = cfg();
+        let now = Instant::now();
+        let ip = "10.0.0.1".parse().expect("should parse ip");
+        for i in 0..3 {
+            let at = now + Duration::from_secs(i);
+            assert_eq!(
+                filter.check(&cfg, ip, at, false, || Err(SipFilterReject::UnknownNumber)),
+                SipFilterResult::Reject(SipFilterReject::UnknownNumber)
+            );
+        }
+        assert_eq!(filter.check(&cfg, ip, now + Duration::from_secs(10), false, || Ok(())), SipFilterResult::Drop);
+        assert!(filter.is_dropped(&cfg, ip, now + Duration::from_secs(10), false));
+        assert!(!filter.is_dropped(&cfg, ip, now + Duration::from_secs(10), true), "exempt sources are not banned");
+        assert!(filter.is_dropped(&cfg, "192.168.1.1".parse().expect("should parse ip"), now, true), "blocklist drops all sources");
+        assert_eq!(filter.check(&cfg, ip, now + Duration::from_secs(70), false, || Ok(())), SipFilterResult::Allow);
+        assert!(!filter.is_dropped(&cfg, ip, now + Duration::from_secs(70), false));
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let mut filter = FilterInternal::default();
+        let cfg = cfg();
+        let now = Instant::now();
+        assert_eq!(filter.check(&cfg, "192.168.1.1".parse().expect("should parse ip"), now, false, || Ok(())), SipFilterResult::Drop);
+        assert_eq!(filter.check(&cfg, "10.0.0.1".parse().expect("should parse ip"), now, false, || Ok(())), SipFilterResult::Allow);
+    }
+
+    #[test]
+    fn test_rate_drops_are_not_violations() {
+        let mut filter = FilterInternal::default();
+        let cfg = cfg();
+        let now = Instant::now();
+        let ip = "10.0.0.1".parse().expect("should parse ip");
+        for _ in 0..10 {
+            filter.check(&cfg, ip, now, false, || Ok(()));
+        }
+        assert_eq!(filter.check(&cfg, ip, now + Duration::from_secs(1), false, || Ok(())), SipFilterResult::Allow);
+    }
+
+    #[test]
+    fn test_exempt_source() {
+        let mut filter = FilterInternal::default();
+        let cfg = cfg();
+        let now = Instant::now();
+        let ip = "10.0.0.1".parse().expect("should parse ip");
+        for _ in 0..10 {
+            assert_eq!(
+                filter.check(&cfg, ip, now, true, || Err(SipFilterReject::UnknownNumber)),
+                SipFilterResult::Reject(SipFilterReject::UnknownNumber)
+            );
+        }
+        assert_eq!(filter.check(&cfg, ip, now, true, || Ok(())), SipFilterResult::Allow);
+        filter.add_violation(&cfg, ip, now, "malformed request");
+        filter.add_violation(&cfg, ip, now, "malformed request");
+        filter.add_violation(&cfg, ip, now, "malformed request");
+        // bans only apply to sources which are not exempt
+        assert_eq!(filter.check(&cfg, ip, now, true, || Ok(())), SipFilterResult::Allow);
+        assert_eq!(filter.check(&cfg, ip, now, false, || Ok(())), SipFilterResult::Drop);
+    }
+}
@@ -0,0 +1,215 @@
+use bytesstr::BytesStr;
+use ezk_sip_types::{header::typed::FromTo, Headers, Name};
+
+const HEADER_PAI: &str = "P-Asserted-Identity";
+const HEADER_RPID: &str = "Remote-Party-ID";
+const HEADER_DIVERSION: &str = "Diversion";
+const HEADER_PRIVACY: &str = "Privacy";
+const HEADER_UUI: &str = "User-to-User";
+
+/// Caller identity presented by the remote side of an incoming INVITE
+#[derive(Debug, Clone, Default)]
+pub struct SipCallerInfo {
+    pub from_display: Option<String>,
+    pub to_display: Option<String>,
+    pub asserted_identity: Option<String>,
+    pub remote_party_id: Option<String>,
+    pub diversions: Vec<String>,
+    pub privacy: bool,
+}
+
+impl SipCallerInfo {
+    pub fn from_request(from: &FromTo, to: &FromTo, headers: &Headers) -> Self {
+        let rpid = header_values(headers, HEADER_RPID).into_iter().next();
+        let privacy_id = header_values(headers, HEADER_PRIVACY).iter().any(|v| v.split(';').any(|p| p.trim().eq_ignore_ascii_case("id")));
+        let privacy_rpid = rpid.as_deref().and_then(|v| header_param(v, "privacy")).map(|p| !p.eq_ignore_ascii_case("off")).unwrap_or(false);
+
+        Self {
+            from_display: from.uri.name.as_ref().map(|n| n.to_string()).filter(|n| !n.is_empty()),
+            to_display: to.uri.name.as_ref().map(|n| n.to_string()).filter(|n| !n.is_empty()),
+            asserted_identity: header_values(headers, HEADER_PAI).first().and_then(|v| uri_user(v)),
+            remote_party_id: rpid.as_deref().and_then(uri_user),
+            diversions: header_values(headers, HEADER_DIVERSION).iter().filter_map(|v| uri_user(v)).collect(),
+            privacy: privacy_id || privacy_rpid,
+        }
+    }
+}
+
+/// Build the caller id headers for an outgoing INVITE.
+/// The asserted identity can be a full uri or only the user part, in that case the sip server host is used.
+pub fn caller_id_headers(asserted_identity: Option<&str>, privacy: bool, sip_server: &str) -> Vec<(String, String)> {
+    let mut headers = vec![];
+    if let Some(identity) = asserted_identity {
+        let value = if identity.starts_with('<') || identity.contains('"') {
+            identity.to_owned()
+        } else if identity.starts_with("sip:") || identity.starts_with("sips:") || identity.starts_with("tel:") {
+            format!("<{identity}>")
+        } else {
+            format!("<sip:{identity}@{sip_server}>")
+        };
+        headers.push((HEADER_PAI.to_owned(), value));
+    }
+    if privacy {
+        headers.push((HEADER_PRIVACY.to_owned(), "id".to_owned()));
+    }
+    headers
+}
+
+pub fn insert_headers(headers: &mut Headers, values: &[(String, String)]) {
+    for (name, value) in values {
+        headers.insert(Name::from(BytesStr::from(name.as_str())), BytesStr::from(value.as_str()));
+    }
+}
+
+/// Collect all headers of a request which are in the allowlist, matching is case-insensitive
+pub fn collect_headers(headers: &Headers, allowlist: &[String]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(n, _)| allowlist.iter().any(|a| a.eq_ignore_ascii_case(n.as_print_str())))
+        .map(|(n, v)| (n.as_print_str().to_owned(), v.to_string()))
+        .collect()
+}
+
+/// Only X- headers and User-to-User are allowed to be added by application,
+/// other headers are managed by the sip stack.
+pub fn validate_custom_headers<'a>(headers: impl IntoIterator<Item = (&'a String, &'a String)>) -> Result<(), &'static str> {
+    for (name, value) in headers {
+        let allowed = name.get(..2).is_some_and(|p| p.eq_ignore_ascii_case("x-")) || name.eq_ignore_ascii_case(HEADER_UUI);
+        if !allowed {
+            return Err("only X- and User-to-User headers are allowed");
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return Err("invalid header name");
+        }
+        if value.chars().any(|c| c == '\r' || c == '\n') {
+            return Err("invalid header value");
+        }
+    }
+    Ok(())
+}
+
+/// Collect all values of a header, comma separated values are split into multiple entries
+pub fn header_values(headers: &Headers, name: &str) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(n, _)| n.as_print_str().eq_ignore_ascii_case(name))
+        .flat_map(|(_, v)| split_header_list(v))
+        .collect()
+}
+
+/// Split a header value by commas which are not inside a quoted string or an uri.
+fn split_header_list(value: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut in_quote = false;
+    let mut in_uri = false;
+    for c in value.chars() {
+        match c {
+            '"' => in_quote = !in_quote,
+            '<' if !in_quote => in_uri = true,
+            '>' if !in_quote => in_uri = false,
+            ',' if !in_quote && !in_uri => {
+                if !current.trim().is_empty() {
+                    parts.push(current.trim().to_owned());
+                }
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        parts.push(current.trim().to_owned());
+    }
+    parts
+}
+
+/// Extract the user part of the uri inside a name-addr value like `"Alice" <sip:+123@host>;party=calling`
+fn uri_user(value: &str) -> Option<String> {
+    let uri = match (value.find('<'), value.find('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value.split(';').next()?,
+    };
+    let uri = uri.trim();
+    let user = if let Some(rest) = uri.strip_prefix("tel:") {
+        rest.split([';', '?']).next()?
+    } else {
+        let rest = uri.strip_prefix("sips:").or_else(|| uri.strip_prefix("sip:"))?;
+        let (user, _host) = rest.split_once('@')?;
+        user.split(':').next()?
+    };
+    if user.is_empty() {
+        None
+    } else {
+        Some(user.to_owned())
+    }
+}
+
+/// Get a header parameter which is placed after the name-addr part
+fn header_param<'a>(value: &'a str, param: &str) -> Option<&'a str> {
+    let params = match value.rfind('>') {
+        Some(end) => &value[end + 1..],
+        None => value,
+    };
+    params.split(';').skip(1).find_map(|p| {
+        let (k, v) = p.split_once('=')?;
+        if k.trim().eq_ignore_ascii_case(param) {
+            Some(v.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uri_user() {
+        assert_eq!(uri_user("\"Alice\" <sip:+84123@carrier.com>;party=calling").as_deref(), Some("+84123"));
+        assert_eq!(uri_user("<tel:+84123;phone-context=abc>").as_deref(), Some("+84123"));
+        assert_eq!(uri_user("sip:1000@10.0.0.1:5060;user=phone").as_deref(), Some("1000"));
+        assert_eq!(uri_user("<sip:carrier.com>"), None);
+        assert_eq!(uri_user("invalid"), None);
+    }
+
+    #[test]
+    fn test_split_header_list() {
+        assert_eq!(
+            split_header_list("<sip:100@a.com>;reason=unconditional, \"B, C\" <sip:200@b.com>;counter=1"),
+            vec!["<sip:100@a.com>;reason=unconditional".to_owned(), "\"B, C\" <sip:200@b.com>;counter=1".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_header_param() {
+        assert_eq!(header_param("\"A\" <sip:100@a.com;x=y>;party=calling;privacy=full", "privacy"), Some("full"));
+        assert_eq!(header_param("<sip:100@a.com>", "privacy"), None);
+    }
+
+    #[test]
+    fn test_caller_id_headers() {
+        assert_eq!(
+            caller_id_headers(Some("+84123"), true, "carrier.com"),
+            vec![("P-Asserted-Identity".to_owned(), "<sip:+84123@carrier.com>".to_owned()), ("Privacy".to_owned(), "id".to_owned())]
+        );
+        assert_eq!(
+            caller_id_headers(Some("tel:+84123"), false, "carrier.com"),
+            vec![("P-Asserted-Identity".to_owned(), "<tel:+84123>".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_validate_custom_headers() {
+        let ok = [("X-Correlation-Id".to_owned(), "abc".to_owned()), ("user-to-user".to_owned(), "0a0b;encoding=hex".to_owned())];
+        assert_eq!(validate_custom_headers(ok.iter().map(|(k, v)| (k, v))), Ok(()));
+
+        let not_allowed = [("Contact".to_owned(), "<sip:a@b>".to_owned())];
+        assert!(validate_custom_headers(not_allowed.iter().map(|(k, v)| (k, v))).is_err());
+
+        let injection = [("X-Id".to_owned(), "abc\r\nContact: <sip:a@b>".to_owned())];
+        assert!(validate_custom_headers(injection.iter().map(|(k, v)| (k, v))).is_err());
+    }
+}
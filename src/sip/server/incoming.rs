@@ -1,11 +1,11 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::anyhow;
-use ezk_sip_core::{Endpoint, IncomingRequest, Layer, LayerKey, MayTake};
+use ezk_sip_core::{Endpoint, IncomingRequest, Layer, LayerKey, MayTake, OutgoingResponse};
 use ezk_sip_types::{
     header::typed::Contact,
     uri::sip::{SipUri, UserPart},
-    Method,
+    Code, Method,
 };
 use ezk_sip_ua::{
     dialog::{Dialog, DialogLayer},
@@ -17,8 +17,18 @@ use tokio::sync::{mpsc::Sender, Notify};
 use wait_state::WaitState;
 
 use crate::{
-    protocol::{protobuf::sip_gateway::incoming_call_data::IncomingCallEvent, InternalCallId, StreamingInfo},
-    sip::{MediaApi, MediaEngineError},
+    protocol::{
+        protobuf::sip_gateway::{incoming_call_data::IncomingCallEvent, MediaStats},
+        InternalCallId, StreamingInfo,
+    },
+    sip::{CodecPolicy, MediaApi, MediaEngineError, MediaRecorder, SdpPolicyError},
+};
+
+use super::{
+    filter::{SipFilterReject, SipFilterResult, SipFloodFilter},
+    headers::{collect_headers, validate_custom_headers, SipCallerInfo},
+    trace::{SipTraceDirection, SipTracer},
+    SipCallSnapshot,
 };
 
 mod talking_state;
@@ -30,15 +40,29 @@ pub struct InviteAcceptLayer {
     dialog_layer: LayerKey<DialogLayer>,
     invite_layer: LayerKey<InviteLayer>,
     incoming_tx: Sender<SipIncomingCall>,
+    forward_headers: Vec<String>,
+    filter: SipFloodFilter,
+    tracer: SipTracer,
 }
 
 impl InviteAcceptLayer {
-    pub fn new(incoming_tx: Sender<SipIncomingCall>, contact: Contact, dialog_layer: LayerKey<DialogLayer>, invite_layer: LayerKey<InviteLayer>) -> Self {
+    pub fn new(
+        incoming_tx: Sender<SipIncomingCall>,
+        contact: Contact,
+        dialog_layer: LayerKey<DialogLayer>,
+        invite_layer: LayerKey<InviteLayer>,
+        forward_headers: Vec<String>,
+        filter: SipFloodFilter,
+        tracer: SipTracer,
+    ) -> Self {
         Self {
             contact,
             dialog_layer,
             invite_layer,
             incoming_tx,
+            forward_headers,
+            filter,
+            tracer,
         }
     }
 
@@ -50,12 +74,39 @@ impl InviteAcceptLayer {
         };
 
         log::info!("[Incoming] {:?}", invite.base_headers.from.uri);
-        let from: &SipUri = invite.base_headers.from.uri.uri.downcast_ref().ok_or(anyhow!("parse from_uri error"))?;
-        let to: &SipUri = invite.base_headers.to.uri.uri.downcast_ref().ok_or(anyhow!("parse to_uri error"))?;
-        let from = get_user(&from.user_part).ok_or(anyhow!("missing from user"))?;
-        let to = get_user(&to.user_part).ok_or(anyhow!("missing to user"))?;
         let remote = invite.tp_info.source;
+        let local = invite.tp_info.transport.bound();
+        let Some((from, to)) = invite_users(invite) else {
+            self.filter.report_malformed(remote);
+            return Err(anyhow!("INVITE from {remote} without sip from and to users"));
+        };
+
+        // filter before creating dialog, rejects are stateless so floods don't allocate call resources
+        match self.filter.check(remote, &from, &to) {
+            SipFilterResult::Allow => {}
+            SipFilterResult::Drop => {
+                log::debug!("[InviteAcceptLayer] drop INVITE from {remote}");
+                request.take();
+                return Ok(());
+            }
+            SipFilterResult::Reject(reason) => {
+                log::info!("[InviteAcceptLayer] reject INVITE from {remote} {from} => {to}: {reason:?}");
+                let code = match reason {
+                    SipFilterReject::UnknownNumber => Code::NOT_FOUND,
+                    SipFilterReject::SubnetNotAllowed => Code::FORBIDDEN,
+                };
+                let mut response = endpoint.create_response(&request, code, None);
+                request.take();
+                endpoint.send_outgoing_response(&mut response).await?;
+                return Ok(());
+            }
+        }
+
         let offer_sdp = invite.body.clone();
+        let caller = SipCallerInfo::from_request(&invite.base_headers.from, &invite.base_headers.to, &invite.headers);
+        let headers = collect_headers(&invite.headers, &self.forward_headers);
+        let call_id = InternalCallId::random();
+        self.tracer.bind(&call_id, &invite.base_headers.call_id.0);
 
         let invite = request.take();
         let dialog = Dialog::new_server(endpoint.clone(), self.dialog_layer, &invite, self.contact.clone()).unwrap();
@@ -72,12 +123,19 @@ impl InviteAcceptLayer {
         )?;
 
         let call = SipIncomingCall {
-            call_id: InternalCallId::random(),
+            call_id: call_id.clone(),
             state: State::Wait(WaitState::new(acceptor, offer_sdp, cancelled)),
             remote,
             from,
             to,
-            ctx: Ctx {},
+            caller,
+            headers,
+            ctx: Ctx {
+                call_id,
+                local,
+                remote,
+                tracer: self.tracer.clone(),
+            },
         };
         self.incoming_tx.send(call).await.expect("should send call to main loop");
         Ok(())
@@ -107,27 +165,56 @@ pub enum SipIncomingCallError {
     RtpEngine(#[from] MediaEngineError),
     #[error("WrongState({0})")]
     WrongState(&'static str),
+    #[error("InvalidHeader({0})")]
+    InvalidHeader(&'static str),
 }
 
 pub enum SipIncomingCallOut {
     Event(IncomingCallEvent),
+    /// An in-dialog transaction changed the dialog, the call snapshot must be replicated again
+    DialogUpdated,
     Continue,
 }
 
-struct Ctx {}
+#[derive(Clone)]
+struct Ctx {
+    call_id: InternalCallId,
+    /// Listener which received the INVITE, responses are sent from it
+    local: SocketAddr,
+    remote: SocketAddr,
+    tracer: SipTracer,
+}
+
+impl Ctx {
+    /// Record a response before it is sent, the request side is recorded by the trace layer
+    fn trace_response(&self, response: &OutgoingResponse) {
+        self.tracer.record(
+            &self.call_id,
+            SipTraceDirection::Out,
+            &response.msg.line,
+            &response.msg.headers,
+            &response.msg.body,
+            self.local,
+            self.remote,
+        );
+    }
+}
 
 enum StateOut {
     Event(IncomingCallEvent),
     Switch(State, IncomingCallEvent),
+    DialogUpdated,
     Continue,
 }
 
 trait StateLogic {
     fn send_trying(&mut self, ctx: &mut Ctx) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
     fn send_ringing(&mut self, ctx: &mut Ctx) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
-    fn accept(&mut self, ctx: &mut Ctx, api: MediaApi, stream: StreamingInfo) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
-    fn end(&mut self, ctx: &mut Ctx) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
+    fn accept(&mut self, ctx: &mut Ctx, api: MediaApi, stream: StreamingInfo, headers: Vec<(String, String)>) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
+    fn end(&mut self, ctx: &mut Ctx, headers: Vec<(String, String)>) -> impl std::future::Future<Output = Result<(), SipIncomingCallError>>;
     fn kill_because_validate_failed(self, ctx: &mut Ctx);
+    fn kill_because_overloaded(self, ctx: &mut Ctx, retry_after_secs: u64);
+    fn kill_because_media_rejected(self, ctx: &mut Ctx);
     fn recv(&mut self, ctx: &mut Ctx) -> impl std::future::Future<Output = Result<Option<StateOut>, SipIncomingCallError>>;
 }
 
@@ -151,17 +238,17 @@ impl StateLogic for State {
         }
     }
 
-    async fn accept(&mut self, ctx: &mut Ctx, api: MediaApi, stream: StreamingInfo) -> Result<(), SipIncomingCallError> {
+    async fn accept(&mut self, ctx: &mut Ctx, api: MediaApi, stream: StreamingInfo, headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
         match self {
-            State::Wait(state) => state.accept(ctx, api, stream).await,
-            State::Talking(state) => state.accept(ctx, api, stream).await,
+            State::Wait(state) => state.accept(ctx, api, stream, headers).await,
+            State::Talking(state) => state.accept(ctx, api, stream, headers).await,
         }
     }
 
-    async fn end(&mut self, ctx: &mut Ctx) -> Result<(), SipIncomingCallError> {
+    async fn end(&mut self, ctx: &mut Ctx, headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
         match self {
-            State::Wait(state) => state.end(ctx).await,
-            State::Talking(state) => state.end(ctx).await,
+            State::Wait(state) => state.end(ctx, headers).await,
+            State::Talking(state) => state.end(ctx, headers).await,
         }
     }
 
@@ -172,6 +259,20 @@ impl StateLogic for State {
         }
     }
 
+    fn kill_because_overloaded(self, ctx: &mut Ctx, retry_after_secs: u64) {
+        match self {
+            State::Wait(state) => state.kill_because_overloaded(ctx, retry_after_secs),
+            State::Talking(state) => state.kill_because_overloaded(ctx, retry_after_secs),
+        }
+    }
+
+    fn kill_because_media_rejected(self, ctx: &mut Ctx) {
+        match self {
+            State::Wait(state) => state.kill_because_media_rejected(ctx),
+            State::Talking(state) => state.kill_because_media_rejected(ctx),
+        }
+    }
+
     async fn recv(&mut self, ctx: &mut Ctx) -> Result<Option<StateOut>, SipIncomingCallError> {
         match self {
             State::Wait(state) => state.recv(ctx).await,
@@ -185,6 +286,8 @@ pub struct SipIncomingCall {
     remote: SocketAddr,
     from: String,
     to: String,
+    caller: SipCallerInfo,
+    headers: Vec<(String, String)>,
     state: State,
     ctx: Ctx,
 }
@@ -206,6 +309,39 @@ impl SipIncomingCall {
         &self.to
     }
 
+    pub fn caller(&self) -> &SipCallerInfo {
+        &self.caller
+    }
+
+    /// Headers from the INVITE which are matched the forwarding allowlist
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Snapshot for ending the call from another node, only available after the call is accepted
+    pub fn snapshot(&self) -> Option<SipCallSnapshot> {
+        match &self.state {
+            State::Talking(state) => Some(state.snapshot()),
+            State::Wait(_) => None,
+        }
+    }
+
+    /// Media quality of the call, only available after the call is accepted
+    pub async fn media_stats(&self) -> Option<MediaStats> {
+        match &self.state {
+            State::Talking(state) => state.media_stats().await,
+            State::Wait(_) => None,
+        }
+    }
+
+    /// Recording control of the call media, it is only available after the call is accepted
+    pub fn recorder(&self) -> Result<MediaRecorder, SipIncomingCallError> {
+        match &self.state {
+            State::Talking(state) => Ok(state.recorder()?),
+            State::Wait(_) => Err(SipIncomingCallError::WrongState("Wait state has no media to record")),
+        }
+    }
+
     pub async fn send_trying(&mut self) -> Result<(), SipIncomingCallError> {
         self.state.send_trying(&mut self.ctx).await
     }
@@ -214,18 +350,40 @@ impl SipIncomingCall {
         self.state.send_ringing(&mut self.ctx).await
     }
 
-    pub async fn accept(&mut self, api: MediaApi, stream: StreamingInfo) -> Result<(), SipIncomingCallError> {
-        self.state.accept(&mut self.ctx, api, stream).await
+    /// Accept the call, the extra headers are added to the 200 OK response
+    pub async fn accept(&mut self, api: MediaApi, stream: StreamingInfo, headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
+        validate_custom_headers(headers.iter().map(|(k, v)| (k, v))).map_err(SipIncomingCallError::InvalidHeader)?;
+        self.state.accept(&mut self.ctx, api, stream, headers).await
     }
 
-    pub async fn end(&mut self) -> Result<(), SipIncomingCallError> {
-        self.state.end(&mut self.ctx).await
+    /// End the call, the extra headers are added to the reject response if the call is not accepted yet
+    pub async fn end(&mut self, headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
+        validate_custom_headers(headers.iter().map(|(k, v)| (k, v))).map_err(SipIncomingCallError::InvalidHeader)?;
+        self.state.end(&mut self.ctx, headers).await
     }
 
     pub fn kill_because_validate_failed(mut self) {
         self.state.kill_because_validate_failed(&mut self.ctx);
     }
 
+    /// Reject with 503 Service Unavailable and Retry-After, used when call limits are reached
+    pub fn kill_because_overloaded(mut self, retry_after_secs: u64) {
+        self.state.kill_because_overloaded(&mut self.ctx, retry_after_secs);
+    }
+
+    /// Apply the codec policy of the called number to the INVITE offer, reject the call with `kill_because_media_rejected` if it fails
+    pub fn apply_codec_policy(&mut self, policy: &CodecPolicy) -> Result<(), SdpPolicyError> {
+        match &mut self.state {
+            State::Wait(state) => state.apply_codec_policy(policy),
+            State::Talking(_) => Ok(()),
+        }
+    }
+
+    /// Reject with 488 Not Acceptable Here, used when the offer has no codec which is allowed for the number
+    pub fn kill_because_media_rejected(mut self) {
+        self.state.kill_because_media_rejected(&mut self.ctx);
+    }
+
     pub async fn recv(&mut self) -> Result<Option<SipIncomingCallOut>, SipIncomingCallError> {
         match self.state.recv(&mut self.ctx).await? {
             Some(out) => match out {
@@ -234,6 +392,7 @@ impl SipIncomingCall {
                     self.state = state;
                     Ok(Some(SipIncomingCallOut::Event(event)))
                 }
+                StateOut::DialogUpdated => Ok(Some(SipIncomingCallOut::DialogUpdated)),
                 StateOut::Continue => Ok(Some(SipIncomingCallOut::Continue)),
             },
             None => Ok(None),
@@ -241,6 +400,12 @@ impl SipIncomingCall {
     }
 }
 
+fn invite_users(invite: &IncomingRequest) -> Option<(String, String)> {
+    let from: &SipUri = invite.base_headers.from.uri.uri.downcast_ref()?;
+    let to: &SipUri = invite.base_headers.to.uri.uri.downcast_ref()?;
+    Some((get_user(&from.user_part)?, get_user(&to.user_part)?))
+}
+
 fn get_user(user_part: &UserPart) -> Option<String> {
     match user_part {
         UserPart::Empty => None,
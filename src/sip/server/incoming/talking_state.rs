@@ -2,25 +2,40 @@ use ezk_sip_ua::invite::session::Session;
 
 use crate::{
     protocol::{
-        protobuf::sip_gateway::incoming_call_data::{
-            incoming_call_event::{self, sip_event},
-            IncomingCallEvent,
+        protobuf::sip_gateway::{
+            incoming_call_data::{
+                incoming_call_event::{self, sip_event},
+                IncomingCallEvent,
+            },
+            MediaStats,
         },
         StreamingInfo,
     },
-    sip::{media::MediaRtpEngineAnswer, MediaApi},
+    sip::{media::MediaRtpEngineAnswer, server::SipCallSnapshot, MediaApi, MediaEngineError, MediaRecorder},
 };
 
 use super::{Ctx, SipIncomingCallError, StateLogic, StateOut};
 
 pub struct TalkingState {
     session: Session,
-    _rtp: MediaRtpEngineAnswer,
+    rtp: MediaRtpEngineAnswer,
 }
 
 impl TalkingState {
     pub fn new(session: Session, rtp: MediaRtpEngineAnswer) -> Self {
-        Self { session, _rtp: rtp }
+        Self { session, rtp }
+    }
+
+    pub fn snapshot(&self) -> SipCallSnapshot {
+        SipCallSnapshot::new(&self.session.dialog, self.rtp.backend_name(), self.rtp.session_url())
+    }
+
+    pub async fn media_stats(&self) -> Option<MediaStats> {
+        self.rtp.stats().await
+    }
+
+    pub fn recorder(&self) -> Result<MediaRecorder, MediaEngineError> {
+        self.rtp.recorder()
     }
 }
 
@@ -33,11 +48,11 @@ impl StateLogic for TalkingState {
         Err(SipIncomingCallError::WrongState("Talking state cannot send ringing"))
     }
 
-    async fn accept(&mut self, _ctx: &mut Ctx, _api: MediaApi, _stream: StreamingInfo) -> Result<(), SipIncomingCallError> {
+    async fn accept(&mut self, _ctx: &mut Ctx, _api: MediaApi, _stream: StreamingInfo, _headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
         Err(SipIncomingCallError::WrongState("Talking state cannot send accept"))
     }
 
-    async fn end(&mut self, _ctx: &mut Ctx) -> Result<(), SipIncomingCallError> {
+    async fn end(&mut self, _ctx: &mut Ctx, _headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
         log::info!("[TalkingState] terminate session");
         self.session.terminate().await?;
         Ok(())
@@ -47,10 +62,18 @@ impl StateLogic for TalkingState {
         panic!("should not call on talking state")
     }
 
+    fn kill_because_overloaded(self, _ctx: &mut Ctx, _retry_after_secs: u64) {
+        panic!("should not call on talking state")
+    }
+
+    fn kill_because_media_rejected(self, _ctx: &mut Ctx) {
+        panic!("should not call on talking state")
+    }
+
     async fn recv(&mut self, _ctx: &mut Ctx) -> Result<Option<StateOut>, SipIncomingCallError> {
         match self.session.drive().await? {
-            ezk_sip_ua::invite::session::Event::RefreshNeeded(_refresh_needed) => Ok(Some(StateOut::Continue)),
-            ezk_sip_ua::invite::session::Event::ReInviteReceived(_re_invite_received) => Ok(Some(StateOut::Continue)),
+            ezk_sip_ua::invite::session::Event::RefreshNeeded(_refresh_needed) => Ok(Some(StateOut::DialogUpdated)),
+            ezk_sip_ua::invite::session::Event::ReInviteReceived(_re_invite_received) => Ok(Some(StateOut::DialogUpdated)),
             ezk_sip_ua::invite::session::Event::Bye(_) => {
                 log::info!("[TalkingState] on Bye");
                 Ok(Some(StateOut::Event(IncomingCallEvent {
@@ -18,7 +18,11 @@ use crate::{
         },
         StreamingInfo,
     },
-    sip::{media::MediaRtpEngineAnswer, MediaApi},
+    sip::{
+        media::{MediaRtpEngineAnswer, PolicyOffer},
+        server::headers::insert_headers,
+        CodecPolicy, MediaApi, MediaEngineError, SdpPolicyError,
+    },
     utils::select2,
 };
 
@@ -28,6 +32,7 @@ pub struct WaitState {
     cancelled: Arc<Notify>,
     acceptor: Option<Acceptor>,
     offer_sdp: Bytes,
+    offer: Option<PolicyOffer>,
     tx: UnboundedSender<Option<StateOut>>,
     rx: UnboundedReceiver<Option<StateOut>>,
 }
@@ -39,40 +44,55 @@ impl WaitState {
             cancelled,
             acceptor: Some(acceptor),
             offer_sdp,
+            offer: None,
             tx,
             rx,
         }
     }
+
+    /// Filter the INVITE offer with the number codec policy, the filtered offer is answered on accept
+    pub fn apply_codec_policy(&mut self, policy: &CodecPolicy) -> Result<(), SdpPolicyError> {
+        self.offer = Some(policy.offer(&self.offer_sdp)?);
+        Ok(())
+    }
 }
 
 impl StateLogic for WaitState {
-    async fn send_trying(&mut self, _ctx: &mut Ctx) -> Result<(), SipIncomingCallError> {
+    async fn send_trying(&mut self, ctx: &mut Ctx) -> Result<(), SipIncomingCallError> {
         let acceptor = self.acceptor.as_mut().expect("should have acceptor when start called");
         let response = acceptor.create_response(Code::TRYING, None).await?;
+        ctx.trace_response(&response);
         acceptor.respond_provisional(response).await?;
         Ok(())
     }
 
-    async fn send_ringing(&mut self, _ctx: &mut Ctx) -> Result<(), SipIncomingCallError> {
+    async fn send_ringing(&mut self, ctx: &mut Ctx) -> Result<(), SipIncomingCallError> {
         let acceptor = self.acceptor.as_mut().expect("should have acceptor when ring called");
         let response = acceptor.create_response(Code::RINGING, None).await?;
+        ctx.trace_response(&response);
         acceptor.respond_provisional(response).await?;
         Ok(())
     }
 
-    async fn accept(&mut self, _ctx: &mut Ctx, api: MediaApi, stream: StreamingInfo) -> Result<(), SipIncomingCallError> {
+    async fn accept(&mut self, ctx: &mut Ctx, api: MediaApi, stream: StreamingInfo, headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
         log::info!("[IncomingCall/WaitState] accept");
         let mut response = self.acceptor.as_mut().expect("should have acceptor when start called").create_response(Code::OK, None).await?;
 
-        let mut rtp = MediaRtpEngineAnswer::new(api, self.offer_sdp.clone());
+        let offer = match self.offer.take() {
+            Some(offer) => offer,
+            None => CodecPolicy::default().offer(&self.offer_sdp).map_err(MediaEngineError::from)?,
+        };
+        let mut rtp = MediaRtpEngineAnswer::new(api, offer);
         let answer_sdp = rtp.create_answer(&stream).await?;
 
         response.msg.body = answer_sdp;
         response.msg.headers.insert_named(&ContentType(BytesStr::from_static("application/sdp")));
+        insert_headers(&mut response.msg.headers, &headers);
+        ctx.trace_response(&response);
 
         let (session, _) = self.acceptor.take().expect("should have acceptor").respond_success(response).await?;
         let event = IncomingCallEvent {
-            event: Some(incoming_call_event::Event::Accepted(incoming_call_event::Accepted {})),
+            event: Some(incoming_call_event::Event::Accepted(incoming_call_event::Accepted { media_endpoint: rtp.peer_endpoint() })),
         };
         self.tx
             .send(Some(StateOut::Switch(State::Talking(TalkingState::new(session, rtp)), event)))
@@ -80,19 +100,40 @@ impl StateLogic for WaitState {
         Ok(())
     }
 
-    async fn end(&mut self, _ctx: &mut Ctx) -> Result<(), SipIncomingCallError> {
+    async fn end(&mut self, ctx: &mut Ctx, headers: Vec<(String, String)>) -> Result<(), SipIncomingCallError> {
         log::info!("[IncomingCall/WaitState] end");
         let acceptor = self.acceptor.take().expect("should have acceptor when start called");
-        let response = acceptor.create_response(Code::BUSY_HERE, None).await?;
+        let mut response = acceptor.create_response(Code::BUSY_HERE, None).await?;
+        insert_headers(&mut response.msg.headers, &headers);
+        ctx.trace_response(&response);
         acceptor.respond_failure(response).await?;
         self.tx.send(None).expect("should send to parent");
         Ok(())
     }
 
-    fn kill_because_validate_failed(mut self, _ctx: &mut Ctx) {
+    fn kill_because_validate_failed(mut self, ctx: &mut Ctx) {
+        let acceptor = self.acceptor.take().expect("should have acceptor when kill called");
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            reject_call(&ctx, acceptor, Code::NOT_ACCEPTABLE, &[]).await.print_error("[SipIncoming] reject call");
+        });
+    }
+
+    fn kill_because_overloaded(mut self, ctx: &mut Ctx, retry_after_secs: u64) {
+        let acceptor = self.acceptor.take().expect("should have acceptor when kill called");
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let headers = [("Retry-After".to_owned(), retry_after_secs.to_string())];
+            reject_call(&ctx, acceptor, Code::SERVICE_UNAVAILABLE, &headers).await.print_error("[SipIncoming] reject call");
+        });
+    }
+
+    fn kill_because_media_rejected(mut self, ctx: &mut Ctx) {
         let acceptor = self.acceptor.take().expect("should have acceptor when kill called");
+        let ctx = ctx.clone();
         tokio::spawn(async move {
-            reject_call(acceptor, Code::NOT_ACCEPTABLE).await.print_error("[SipIncoming] reject call");
+            let headers = [("Warning".to_owned(), "305 gateway \"Incompatible media format\"".to_owned())];
+            reject_call(&ctx, acceptor, Code::NOT_ACCEPTABLE_HERE, &headers).await.print_error("[SipIncoming] reject call");
         });
     }
 
@@ -116,8 +157,10 @@ impl StateLogic for WaitState {
     }
 }
 
-async fn reject_call(acceptor: Acceptor, code: Code) -> anyhow::Result<()> {
-    let response = acceptor.create_response(code, None).await?;
+async fn reject_call(ctx: &Ctx, acceptor: Acceptor, code: Code, headers: &[(String, String)]) -> anyhow::Result<()> {
+    let mut response = acceptor.create_response(code, None).await?;
+    insert_headers(&mut response.msg.headers, headers);
+    ctx.trace_response(&response);
     acceptor.respond_failure(response).await?;
     Ok(())
 }
@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, net::SocketAddr};
 
 use calling_state::CallingState;
 use early_state::EarlyState;
@@ -7,7 +7,7 @@ use ezk_sip_auth::{
     CredentialStore, UacAuthSession,
 };
 use ezk_sip_core::{Endpoint, LayerKey};
-use ezk_sip_types::{header::typed::Contact, uri::NameAddr};
+use ezk_sip_types::{header::typed::Contact, print::Print, uri::NameAddr, Headers};
 use ezk_sip_ua::{
     dialog::DialogLayer,
     invite::{initiator::Initiator, InviteLayer},
@@ -17,13 +17,22 @@ use thiserror::Error;
 
 use crate::{
     protocol::{
-        protobuf::sip_gateway::outgoing_call_data::{
-            outgoing_call_event::{self, sip_event},
-            OutgoingCallEvent,
+        protobuf::sip_gateway::{
+            outgoing_call_data::{
+                outgoing_call_event::{self, sip_event},
+                OutgoingCallEvent,
+            },
+            MediaStats,
         },
-        InternalCallId, SipAuth, StreamingInfo,
+        Codec, InternalCallId, SipAuth, StreamingInfo,
     },
-    sip::{MediaApi, MediaEngineError, MediaRtpEngineOffer},
+    sip::{CodecPolicy, MediaApi, MediaEngineError, MediaRecorder, MediaRtpEngineOffer},
+};
+
+use super::{
+    headers::insert_headers,
+    trace::{header_call_id, SipTraceDirection, SipTracer},
+    SipCallSnapshot,
 };
 
 mod calling_state;
@@ -33,6 +42,7 @@ mod talking_state;
 enum StateOut {
     Event(OutgoingCallEvent),
     Switch(State, OutgoingCallEvent),
+    DialogUpdated,
     Continue,
 }
 
@@ -93,14 +103,51 @@ pub enum SipOutgoingCallError {
 
 pub enum SipOutgoingCallOut {
     Event(OutgoingCallEvent),
+    /// An in-dialog transaction changed the dialog, the call snapshot must be replicated again
+    DialogUpdated,
     Continue,
 }
 
+/// Presentation info which is attached to the outgoing INVITE
+#[derive(Debug, Default)]
+pub struct SipOutgoingCallParams {
+    pub from_display: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Codec policy of the from number, applied to the INVITE offer and the remote answer
+    pub codecs: Vec<Codec>,
+}
+
 struct Ctx {
     call_id: InternalCallId,
     initiator: Initiator,
     auth: Option<OutgoingAuth>,
     rtp: MediaRtpEngineOffer,
+    headers: Vec<(String, String)>,
+    tracer: SipTracer,
+    /// Listener of the contact address, messages of the call are traced with it
+    local: SocketAddr,
+    remote: SocketAddr,
+}
+
+impl Ctx {
+    fn create_invite(&mut self) -> ezk_sip_core::Request {
+        let mut invite = self.initiator.create_invite();
+        insert_headers(&mut invite.headers, &self.headers);
+        invite
+    }
+
+    /// Record a request before sending, its Call-ID is bound to the call so requests from the remote are traced too
+    fn trace_request(&self, request: &ezk_sip_core::Request) {
+        if let Some(sip_call_id) = header_call_id(&request.headers) {
+            self.tracer.bind(&self.call_id, &sip_call_id);
+        }
+        self.tracer
+            .record(&self.call_id, SipTraceDirection::Out, &request.line, &request.headers, &request.body, self.local, self.remote);
+    }
+
+    fn trace_response<L: Print>(&self, line: &L, headers: &Headers, body: &[u8]) {
+        self.tracer.record(&self.call_id, SipTraceDirection::In, line, headers, body, self.local, self.remote);
+    }
 }
 
 pub struct SipOutgoingCall {
@@ -117,15 +164,23 @@ impl SipOutgoingCall {
         from: &str,
         to: &str,
         contact: Contact,
+        local: SocketAddr,
         auth: Option<SipAuth>,
         stream: StreamingInfo,
+        params: SipOutgoingCallParams,
+        tracer: SipTracer,
     ) -> Result<Self, SipOutgoingCallError> {
         let call_id: InternalCallId = InternalCallId::random();
         log::info!("[SipOutgoingCall {call_id}] create with {from} => {to}");
         let local_uri = endpoint.parse_uri(from).map_err(|e| SipOutgoingCallError::Parse(e.to_string()))?;
         let target = endpoint.parse_uri(to).map_err(|e| SipOutgoingCallError::Parse(e.to_string()))?;
+        let remote = target_addr(to);
 
-        let initiator = Initiator::new(endpoint, dialog_layer, invite_layer, NameAddr::uri(local_uri.clone()), contact, target);
+        let local_addr = match params.from_display {
+            Some(display) => NameAddr::new(display, local_uri),
+            None => NameAddr::uri(local_uri),
+        };
+        let initiator = Initiator::new(endpoint, dialog_layer, invite_layer, local_addr, contact, target);
 
         let auth = auth.map(|auth| {
             let mut credentials = CredentialStore::new();
@@ -141,7 +196,11 @@ impl SipOutgoingCall {
                 initiator,
                 auth,
                 call_id,
-                rtp: MediaRtpEngineOffer::new(media_api, stream),
+                rtp: MediaRtpEngineOffer::new(media_api, stream, CodecPolicy::new(params.codecs)),
+                headers: params.headers,
+                tracer,
+                local,
+                remote,
             },
             state: State::Calling(CallingState::default()),
         })
@@ -151,6 +210,29 @@ impl SipOutgoingCall {
         self.ctx.call_id.clone()
     }
 
+    /// Snapshot for ending the call from another node, only available after the call is accepted
+    pub fn snapshot(&self) -> Option<SipCallSnapshot> {
+        match &self.state {
+            State::Talking(state) => Some(state.snapshot(self.ctx.rtp.backend_name(), self.ctx.rtp.session_url())),
+            State::Calling(_) | State::Early(_) => None,
+        }
+    }
+
+    /// Whether the media backend of the call reports media stats
+    pub fn has_media_stats(&self) -> bool {
+        self.ctx.rtp.has_stats()
+    }
+
+    /// Media quality of the call, None when the media backend has no stats
+    pub async fn media_stats(&self) -> Option<MediaStats> {
+        self.ctx.rtp.stats().await
+    }
+
+    /// Recording control of the call media, it is only available after the media session is created
+    pub fn recorder(&self) -> Result<MediaRecorder, SipOutgoingCallError> {
+        Ok(self.ctx.rtp.recorder()?)
+    }
+
     pub async fn start(&mut self) -> Result<(), SipOutgoingCallError> {
         self.state.start(&mut self.ctx).await
     }
@@ -167,6 +249,7 @@ impl SipOutgoingCall {
                     self.state = state;
                     Ok(Some(SipOutgoingCallOut::Event(event)))
                 }
+                StateOut::DialogUpdated => Ok(Some(SipOutgoingCallOut::DialogUpdated)),
                 StateOut::Continue => Ok(Some(SipOutgoingCallOut::Continue)),
             },
             None => Ok(None),
@@ -174,8 +257,32 @@ impl SipOutgoingCall {
     }
 }
 
+/// Address of the target uri, only used for labeling traced messages, unresolved hosts are unspecified
+fn target_addr(to: &str) -> SocketAddr {
+    let host = to.rsplit_once('@').map(|(_, host)| host).unwrap_or_else(|| to.trim_start_matches("sips:").trim_start_matches("sip:"));
+    let host = host.split([';', '?', '>']).next().unwrap_or_default();
+    host.parse::<SocketAddr>()
+        .or_else(|_| host.trim_start_matches('[').trim_end_matches(']').parse().map(|ip| SocketAddr::new(ip, 5060)))
+        .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)))
+}
+
 fn build_sip_event(event: sip_event::Event) -> OutgoingCallEvent {
     OutgoingCallEvent {
         event: Some(outgoing_call_event::Event::Sip(outgoing_call_event::SipEvent { event: Some(event) })),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::target_addr;
+
+    #[test]
+    fn test_target_addr() {
+        assert_eq!(target_addr("sip:2000@10.0.0.2:5080"), "10.0.0.2:5080".parse::<SocketAddr>().expect("should parse addr"));
+        assert_eq!(target_addr("sip:2000@10.0.0.2;transport=udp"), "10.0.0.2:5060".parse::<SocketAddr>().expect("should parse addr"));
+        assert_eq!(target_addr("sip:[2001:db8::2]"), "[2001:db8::2]:5060".parse::<SocketAddr>().expect("should parse addr"));
+        assert_eq!(target_addr("sip:2000@trunk.example.com"), "0.0.0.0:0".parse::<SocketAddr>().expect("should parse addr"));
+    }
+}
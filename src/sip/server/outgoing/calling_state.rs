@@ -21,13 +21,14 @@ impl StateLogic for CallingState {
         }
 
         let sdp = ctx.rtp.sdp().expect("should have sdp");
-        let mut invite = ctx.initiator.create_invite();
+        let mut invite = ctx.create_invite();
         invite.body = sdp.clone();
         invite.headers.insert_named(&ContentType(BytesStr::from_static("application/sdp")));
         if let Some(auth) = &mut ctx.auth {
             auth.session.authorize_request(&mut invite.headers);
         }
 
+        ctx.trace_request(&invite);
         ctx.initiator.send_invite(invite).await?;
         Ok(())
     }
@@ -37,6 +38,7 @@ impl StateLogic for CallingState {
         if let Some(auth) = &mut ctx.auth {
             auth.session.authorize_request(&mut cancel.headers);
         }
+        ctx.trace_request(&cancel);
         ctx.initiator.send_cancel(cancel).await?;
         Ok(())
     }
@@ -44,11 +46,13 @@ impl StateLogic for CallingState {
     async fn recv(&mut self, ctx: &mut Ctx) -> Result<Option<StateOut>, SipOutgoingCallError> {
         match ctx.initiator.receive().await? {
             Response::Provisional(response) => {
+                ctx.trace_response(&response.line, &response.headers, &response.body);
                 let code = response.line.code.into_u16();
                 log::info!("[CallingState] on Provisional {code}");
                 Ok(Some(StateOut::Event(build_sip_event(sip_event::Event::Provisional(sip_event::Provisional { code: code as u32 })))))
             }
             Response::Failure(response) => {
+                ctx.trace_response(&response.line, &response.headers, &response.body);
                 // we dont exit here, after that Finished will be called
                 let code = response.line.code.into_u16();
 
@@ -71,6 +75,8 @@ impl StateLogic for CallingState {
                         },
                     )?;
 
+                    // only one retry, a second 401 means wrong credentials
+                    self.auth_failed = true;
                     self.start(ctx).await?;
                     Ok(Some(StateOut::Continue))
                 } else {
@@ -78,6 +84,7 @@ impl StateLogic for CallingState {
                 }
             }
             Response::Early(early, response, _rseq) => {
+                ctx.trace_response(&response.line, &response.headers, &response.body);
                 let code = response.line.code.into_u16();
                 log::info!("[CallingState] switch early with code: {code}");
                 Ok(Some(StateOut::Switch(
@@ -86,8 +93,10 @@ impl StateLogic for CallingState {
                 )))
             }
             Response::Session(session, response) => {
+                ctx.trace_response(&response.line, &response.headers, &response.body);
                 let cseq_num = response.base_headers.cseq.cseq;
                 let mut ack_out = create_ack(&session.dialog, cseq_num).await.expect("should create ack");
+                ctx.trace_request(&ack_out.msg);
                 session.endpoint.send_outgoing_request(&mut ack_out).await?;
 
                 let code = response.line.code.into_u16();
@@ -98,7 +107,10 @@ impl StateLogic for CallingState {
 
                 Ok(Some(StateOut::Switch(
                     State::Talking(TalkingState::new(session)),
-                    build_sip_event(sip_event::Event::Accepted(sip_event::Accepted { code: code as u32 })),
+                    build_sip_event(sip_event::Event::Accepted(sip_event::Accepted {
+                        code: code as u32,
+                        media_endpoint: ctx.rtp.peer_endpoint(),
+                    })),
                 )))
             }
             Response::Finished => {
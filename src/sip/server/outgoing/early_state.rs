@@ -28,6 +28,7 @@ impl StateLogic for EarlyState {
         if let Some(auth) = &mut ctx.auth {
             auth.session.authorize_request(&mut cancel.headers);
         }
+        ctx.trace_request(&cancel);
         ctx.initiator.send_cancel(cancel).await?;
         Ok(())
     }
@@ -39,6 +40,7 @@ impl StateLogic for EarlyState {
                     unreachable!()
                 }
                 ezk_sip_ua::invite::initiator::Response::Failure(response) => {
+                    ctx.trace_response(&response.line, &response.headers, &response.body);
                     // we dont exit here, after that Finished will be called
                     let code = response.line.code.into_u16();
                     log::info!("[EarlyState] on Failure {code}");
@@ -57,6 +59,7 @@ impl StateLogic for EarlyState {
             },
             select2::OrOutput::Right(event) => match event? {
                 ezk_sip_ua::invite::initiator::EarlyResponse::Provisional(response, _rseq) => {
+                    ctx.trace_response(&response.line, &response.headers, &response.body);
                     let code = response.line.code.into_u16();
                     log::info!("[EarlyState] on Provisional {code}");
                     if !ctx.rtp.answered() && !response.body.is_empty() {
@@ -66,9 +69,11 @@ impl StateLogic for EarlyState {
                     Ok(Some(StateOut::Continue))
                 }
                 ezk_sip_ua::invite::initiator::EarlyResponse::Success(session, response) => {
+                    ctx.trace_response(&response.line, &response.headers, &response.body);
                     {
                         let cseq_num = response.base_headers.cseq.cseq;
                         let mut ack_out = create_ack(&session.dialog, cseq_num).await.unwrap();
+                        ctx.trace_request(&ack_out.msg);
                         session.endpoint.send_outgoing_request(&mut ack_out).await.unwrap();
                     };
 
@@ -80,7 +85,10 @@ impl StateLogic for EarlyState {
 
                     Ok(Some(StateOut::Switch(
                         State::Talking(TalkingState::new(session)),
-                        build_sip_event(sip_event::Event::Accepted(sip_event::Accepted { code: code as u32 })),
+                        build_sip_event(sip_event::Event::Accepted(sip_event::Accepted {
+                            code: code as u32,
+                            media_endpoint: ctx.rtp.peer_endpoint(),
+                        })),
                     )))
                 }
                 ezk_sip_ua::invite::initiator::EarlyResponse::Terminated => {
@@ -1,6 +1,9 @@
 use ezk_sip_ua::invite::session::Session;
 
-use crate::{protocol::protobuf::sip_gateway::outgoing_call_data::outgoing_call_event::sip_event, sip::server::outgoing::build_sip_event};
+use crate::{
+    protocol::protobuf::sip_gateway::outgoing_call_data::outgoing_call_event::sip_event,
+    sip::server::{outgoing::build_sip_event, SipCallSnapshot},
+};
 
 use super::{Ctx, SipOutgoingCallError, StateLogic, StateOut};
 
@@ -12,6 +15,10 @@ impl TalkingState {
     pub fn new(session: Session) -> Self {
         Self { session }
     }
+
+    pub fn snapshot(&self, media_backend: Option<String>, media_url: Option<String>) -> SipCallSnapshot {
+        SipCallSnapshot::new(&self.session.dialog, media_backend, media_url)
+    }
 }
 
 impl StateLogic for TalkingState {
@@ -24,8 +31,8 @@ impl StateLogic for TalkingState {
     }
     async fn recv(&mut self, _ctx: &mut Ctx) -> Result<Option<StateOut>, SipOutgoingCallError> {
         match self.session.drive().await? {
-            ezk_sip_ua::invite::session::Event::RefreshNeeded(_refresh_needed) => Ok(Some(StateOut::Continue)),
-            ezk_sip_ua::invite::session::Event::ReInviteReceived(_re_invite_received) => Ok(Some(StateOut::Continue)),
+            ezk_sip_ua::invite::session::Event::RefreshNeeded(_refresh_needed) => Ok(Some(StateOut::DialogUpdated)),
+            ezk_sip_ua::invite::session::Event::ReInviteReceived(_re_invite_received) => Ok(Some(StateOut::DialogUpdated)),
             ezk_sip_ua::invite::session::Event::Bye(_) => {
                 log::info!("[TalkingState] on Bye");
                 Ok(Some(StateOut::Event(build_sip_event(sip_event::Event::Bye(sip_event::Bye {})))))
@@ -0,0 +1,50 @@
+use ezk_sip_core::{Endpoint, Request};
+use ezk_sip_types::{print::AppendCtx, Method};
+use ezk_sip_ua::dialog::Dialog;
+use serde::{Deserialize, Serialize};
+
+use super::headers::insert_headers;
+
+/// Minimal state of an established call, which is enough for another node to end it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SipCallSnapshot {
+    /// Request uri of in-dialog requests, which is the remote contact
+    pub target: String,
+    /// Dialog headers of a BYE request: From, To, Call-ID, CSeq and Route
+    pub headers: Vec<(String, String)>,
+    /// Media session id, for the default atm0s backend it is the full url of the rtpengine session
+    pub media_url: Option<String>,
+    /// Name of the media backend which owns the session, None for the default one
+    #[serde(default)]
+    pub media_backend: Option<String>,
+}
+
+impl SipCallSnapshot {
+    pub fn new(dialog: &Dialog, media_backend: Option<String>, media_url: Option<String>) -> Self {
+        let bye = dialog.create_request(Method::BYE);
+        // Via is added by the transaction of the node which sends the request
+        let headers = bye
+            .headers
+            .iter()
+            .filter(|(n, _)| !n.as_print_str().eq_ignore_ascii_case("via"))
+            .map(|(n, v)| (n.as_print_str().to_owned(), v.to_string()))
+            .collect();
+        Self {
+            target: bye.line.uri.default_print_ctx().to_string(),
+            headers,
+            media_url,
+            media_backend,
+        }
+    }
+
+    /// Send BYE in the dialog from this endpoint, the response is only logged
+    pub async fn send_bye(&self, endpoint: &Endpoint) -> anyhow::Result<()> {
+        let target = endpoint.parse_uri(&self.target).map_err(|e| anyhow::anyhow!("parse target error {e}"))?;
+        let mut bye = Request::new(Method::BYE, target);
+        insert_headers(&mut bye.headers, &self.headers);
+        let mut tsx = endpoint.send_request(bye).await?;
+        let response = tsx.receive_final().await?;
+        log::info!("[SipCallSnapshot] BYE to {} got {:?}", self.target, response.line.code);
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ezk_sip_core::{Endpoint, IncomingRequest, Layer, MayTake};
+use ezk_sip_types::{
+    print::{AppendCtx, Print},
+    Headers,
+};
+use serde::{Deserialize, Serialize};
+use spin::RwLock;
+
+use crate::protocol::InternalCallId;
+
+use super::SipFloodFilter;
+
+mod hep;
+mod pcap;
+
+pub use hep::HepMirror;
+pub use pcap::to_pcap;
+
+/// Messages of a single call are capped, a looping re-INVITE or OPTIONS must not grow the trace without limit
+const MAX_ENTRIES_PER_CALL: usize = 500;
+/// Initial INVITEs which arrive before their call is created are kept for this many SIP Call-IDs
+const MAX_PENDING_DIALOGS: usize = 256;
+/// Total size of the kept initial INVITEs, a flood of large INVITEs with random Call-IDs must not hold much memory
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SipTraceConfig {
+    /// Number of recent calls whose messages are kept, 0 disables the trace store
+    pub max_calls: usize,
+    /// Homer compatible collector, every captured message is mirrored to it as HEP3
+    pub hep_collector: Option<SocketAddr>,
+    pub hep_capture_id: u32,
+}
+
+impl Default for SipTraceConfig {
+    fn default() -> Self {
+        Self {
+            max_calls: 1000,
+            hep_collector: None,
+            hep_capture_id: 2001,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SipTraceDirection {
+    In,
+    Out,
+}
+
+/// A captured SIP message, addresses are seen from the gateway side
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SipTraceEntry {
+    /// Unix time in microseconds
+    pub ts_us: u64,
+    pub direction: SipTraceDirection,
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub sip_call_id: String,
+    /// Request line or status line
+    pub first_line: String,
+    pub raw: String,
+}
+
+impl SipTraceEntry {
+    pub fn src(&self) -> SocketAddr {
+        match self.direction {
+            SipTraceDirection::In => self.remote,
+            SipTraceDirection::Out => self.local,
+        }
+    }
+
+    pub fn dst(&self) -> SocketAddr {
+        match self.direction {
+            SipTraceDirection::In => self.local,
+            SipTraceDirection::Out => self.remote,
+        }
+    }
+}
+
+/// Render entries as plain text, one block per message like sngrep's raw view
+pub fn to_text(entries: &[SipTraceEntry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let direction = match entry.direction {
+            SipTraceDirection::In => "IN",
+            SipTraceDirection::Out => "OUT",
+        };
+        out.push_str(&format!(
+            "[{}.{:06}] {direction} {} -> {}\r\n{}\r\n",
+            entry.ts_us / 1_000_000,
+            entry.ts_us % 1_000_000,
+            entry.src(),
+            entry.dst(),
+            entry.raw
+        ));
+    }
+    out
+}
+
+fn message_text(first_line: &str, headers: &Headers, body: &[u8]) -> String {
+    let mut raw = format!("{first_line}\r\n");
+    for (name, value) in headers.iter() {
+        raw.push_str(&format!("{}: {value}\r\n", name.as_print_str()));
+    }
+    raw.push_str("\r\n");
+    raw.push_str(&String::from_utf8_lossy(body));
+    raw
+}
+
+/// Call-ID of a message which is built by the gateway, compact form included
+pub fn header_call_id(headers: &Headers) -> Option<String> {
+    headers
+        .iter()
+        .find(|(n, _)| n.as_print_str().eq_ignore_ascii_case("call-id") || n.as_print_str().eq_ignore_ascii_case("i"))
+        .map(|(_, v)| v.to_string())
+}
+
+fn now_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+#[derive(Default)]
+struct CallTrace {
+    sip_call_ids: Vec<String>,
+    entries: Vec<SipTraceEntry>,
+}
+
+impl CallTrace {
+    fn push(&mut self, entry: SipTraceEntry) {
+        if self.entries.len() < MAX_ENTRIES_PER_CALL {
+            self.entries.push(entry);
+        }
+    }
+}
+
+#[derive(Default)]
+struct TraceStore {
+    max_calls: usize,
+    dialogs: HashMap<String, InternalCallId>,
+    calls: HashMap<InternalCallId, CallTrace>,
+    /// Calls in creation order, the oldest one is dropped when max_calls is reached
+    order: VecDeque<InternalCallId>,
+    /// Initial INVITE of SIP Call-IDs which are not bound to a call yet
+    pending: HashMap<String, SipTraceEntry>,
+    pending_order: VecDeque<String>,
+    pending_bytes: usize,
+}
+
+impl TraceStore {
+    fn bind(&mut self, call_id: &InternalCallId, sip_call_id: &str) {
+        if self.dialogs.get(sip_call_id) == Some(call_id) {
+            return;
+        }
+        if !self.calls.contains_key(call_id) {
+            while self.order.len() >= self.max_calls {
+                match self.order.pop_front() {
+                    Some(oldest) => {
+                        if let Some(trace) = self.calls.remove(&oldest) {
+                            for id in trace.sip_call_ids {
+                                self.dialogs.remove(&id);
+                            }
+                        }
+                    }
+                    None => break,
+                }
+            }
+            self.order.push_back(call_id.clone());
+        }
+        self.dialogs.insert(sip_call_id.to_owned(), call_id.clone());
+        let trace = self.calls.entry(call_id.clone()).or_default();
+        trace.sip_call_ids.push(sip_call_id.to_owned());
+        if let Some(entry) = self.pending.remove(sip_call_id) {
+            self.pending_order.retain(|id| id != sip_call_id);
+            self.pending_bytes -= entry.raw.len();
+            trace.push(entry);
+        }
+    }
+
+    fn push_call(&mut self, call_id: &InternalCallId, mut entry: SipTraceEntry) {
+        if let Some(trace) = self.calls.get_mut(call_id) {
+            if entry.sip_call_id.is_empty() {
+                entry.sip_call_id = trace.sip_call_ids.first().cloned().unwrap_or_default();
+            }
+            trace.push(entry);
+        }
+    }
+
+    fn push_dialog(&mut self, entry: SipTraceEntry) {
+        if let Some(call_id) = self.dialogs.get(&entry.sip_call_id).cloned() {
+            self.push_call(&call_id, entry);
+            return;
+        }
+        // only the initial INVITE can create a call, other requests of unbound dialogs and retransmissions are not kept
+        if !entry.first_line.starts_with("INVITE ") || self.pending.contains_key(&entry.sip_call_id) || entry.raw.len() > MAX_PENDING_BYTES {
+            return;
+        }
+        while self.pending_order.len() >= MAX_PENDING_DIALOGS || self.pending_bytes + entry.raw.len() > MAX_PENDING_BYTES {
+            match self.pending_order.pop_front() {
+                Some(oldest) => {
+                    if let Some(removed) = self.pending.remove(&oldest) {
+                        self.pending_bytes -= removed.raw.len();
+                    }
+                }
+                None => break,
+            }
+        }
+        self.pending_order.push_back(entry.sip_call_id.clone());
+        self.pending_bytes += entry.raw.len();
+        self.pending.insert(entry.sip_call_id.clone(), entry);
+    }
+}
+
+/// Per call capture of SIP messages, with optional HEP mirroring.
+/// Messages which are sent by the SIP stack itself, like ACK of failures, BYE of a terminated session
+/// and retransmissions, don't pass through the gateway code and are not captured.
+#[derive(Clone)]
+pub struct SipTracer {
+    store: Arc<RwLock<TraceStore>>,
+    hep: Option<Arc<HepMirror>>,
+}
+
+impl SipTracer {
+    pub fn new(cfg: &SipTraceConfig) -> std::io::Result<Self> {
+        let hep = match cfg.hep_collector {
+            Some(collector) => Some(Arc::new(HepMirror::new(collector, cfg.hep_capture_id)?)),
+            None => None,
+        };
+        Ok(Self {
+            store: Arc::new(RwLock::new(TraceStore {
+                max_calls: cfg.max_calls,
+                ..Default::default()
+            })),
+            hep,
+        })
+    }
+
+    fn enabled(&self) -> bool {
+        self.store.read().max_calls > 0
+    }
+
+    /// Link a SIP Call-ID to a call, requests of that dialog which are received later are stored into the call trace
+    pub fn bind(&self, call_id: &InternalCallId, sip_call_id: &str) {
+        if self.enabled() {
+            self.store.write().bind(call_id, sip_call_id);
+        }
+    }
+
+    fn entry<L: Print>(&self, direction: SipTraceDirection, sip_call_id: String, line: &L, headers: &Headers, body: &[u8], local: SocketAddr, remote: SocketAddr) -> SipTraceEntry {
+        let first_line = line.default_print_ctx().to_string();
+        SipTraceEntry {
+            ts_us: now_us(),
+            direction,
+            local,
+            remote,
+            sip_call_id,
+            raw: message_text(&first_line, headers, body),
+            first_line,
+        }
+    }
+
+    /// Record a message of a known call, which is sent or received by the call state machine on the `local` listener
+    pub fn record<L: Print>(&self, call_id: &InternalCallId, direction: SipTraceDirection, line: &L, headers: &Headers, body: &[u8], local: SocketAddr, remote: SocketAddr) {
+        if !self.enabled() && self.hep.is_none() {
+            return;
+        }
+        let entry = self.entry(direction, header_call_id(headers).unwrap_or_default(), line, headers, body, local, remote);
+        if let Some(hep) = &self.hep {
+            hep.send(&entry, call_id);
+        }
+        self.store.write().push_call(call_id, entry);
+    }
+
+    /// Record a request which is received by the SIP stack, it is matched to a call by its SIP Call-ID.
+    /// The local address is the listener which received it
+    pub fn record_request(&self, request: &IncomingRequest) {
+        if !self.enabled() && self.hep.is_none() {
+            return;
+        }
+        let sip_call_id = request.base_headers.call_id.0.to_string();
+        let local = request.tp_info.transport.bound();
+        let entry = self.entry(SipTraceDirection::In, sip_call_id, &request.line, &request.headers, &request.body, local, request.tp_info.source);
+        let mut store = self.store.write();
+        if let Some(hep) = &self.hep {
+            let call_id = store.dialogs.get(&entry.sip_call_id).cloned().unwrap_or_else(|| entry.sip_call_id.clone().into());
+            hep.send(&entry, &call_id);
+        }
+        if store.max_calls > 0 {
+            store.push_dialog(entry);
+        }
+    }
+
+    /// Captured messages of a call, None if the call is unknown or already dropped from the store
+    pub fn get(&self, call_id: &InternalCallId) -> Option<Vec<SipTraceEntry>> {
+        self.store.read().calls.get(call_id).map(|t| t.entries.clone())
+    }
+}
+
+/// First layer of the endpoint, it records every incoming request before other layers take it.
+/// It runs before the flood filter, so requests of blocked and banned sources are skipped here
+pub struct SipTraceLayer {
+    tracer: SipTracer,
+    filter: SipFloodFilter,
+}
+
+impl SipTraceLayer {
+    pub fn new(tracer: SipTracer, filter: SipFloodFilter) -> Self {
+        Self { tracer, filter }
+    }
+}
+
+#[async_trait::async_trait]
+impl Layer for SipTraceLayer {
+    fn name(&self) -> &'static str {
+        "sip-trace-layer"
+    }
+
+    async fn receive(&self, _endpoint: &Endpoint, request: MayTake<'_, IncomingRequest>) {
+        if self.filter.is_dropped(request.tp_info.source.ip()) {
+            return;
+        }
+        self.tracer.record_request(&request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::protocol::InternalCallId;
+
+    use super::{to_text, SipTraceDirection, SipTraceEntry, TraceStore, MAX_PENDING_BYTES, MAX_PENDING_DIALOGS};
+
+    fn entry(sip_call_id: &str, first_line: &str) -> SipTraceEntry {
+        SipTraceEntry {
+            ts_us: 1_700_000_000_000_123,
+            direction: SipTraceDirection::In,
+            local: "10.0.0.1:5060".parse::<SocketAddr>().expect("should parse addr"),
+            remote: "10.0.0.2:5080".parse::<SocketAddr>().expect("should parse addr"),
+            sip_call_id: sip_call_id.to_owned(),
+            first_line: first_line.to_owned(),
+            raw: format!("{first_line}\r\nCall-ID: {sip_call_id}\r\n\r\n"),
+        }
+    }
+
+    #[test]
+    fn test_pending_requests_are_moved_on_bind() {
+        let mut store = TraceStore { max_calls: 2, ..Default::default() };
+        let call1: InternalCallId = "call1".to_owned().into();
+        store.push_dialog(entry("sip-a", "INVITE sip:1000@10.0.0.1 SIP/2.0"));
+        store.bind(&call1, "sip-a");
+        store.push_dialog(entry("sip-a", "BYE sip:1000@10.0.0.1 SIP/2.0"));
+        // unknown dialogs are not stored into calls
+        store.push_dialog(entry("sip-b", "OPTIONS sip:10.0.0.1 SIP/2.0"));
+        let lines = store.calls[&call1].entries.iter().map(|e| e.first_line.as_str()).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["INVITE sip:1000@10.0.0.1 SIP/2.0", "BYE sip:1000@10.0.0.1 SIP/2.0"]);
+
+        // oldest call is dropped when the store is full
+        store.bind(&"call2".to_owned().into(), "sip-c");
+        store.bind(&"call3".to_owned().into(), "sip-d");
+        assert!(!store.calls.contains_key(&call1));
+        assert!(!store.dialogs.contains_key("sip-a"));
+        assert_eq!(store.calls.len(), 2);
+    }
+
+    #[test]
+    fn test_pending_keeps_only_initial_invites() {
+        let mut store = TraceStore { max_calls: 2, ..Default::default() };
+        store.push_dialog(entry("sip-a", "INVITE sip:1000@10.0.0.1 SIP/2.0"));
+        // retransmissions and other requests of an unbound dialog, like an OPTIONS flood, are not kept
+        store.push_dialog(entry("sip-a", "INVITE sip:1000@10.0.0.1 SIP/2.0"));
+        for i in 0..10 {
+            store.push_dialog(entry(&format!("sip-o{i}"), "OPTIONS sip:10.0.0.1 SIP/2.0"));
+        }
+        assert_eq!(store.pending.len(), 1);
+
+        let call1: InternalCallId = "call1".to_owned().into();
+        store.bind(&call1, "sip-a");
+        assert_eq!(store.calls[&call1].entries.len(), 1);
+        assert!(store.pending.is_empty());
+        assert_eq!(store.pending_bytes, 0);
+    }
+
+    #[test]
+    fn test_pending_limits() {
+        let mut store = TraceStore { max_calls: 2, ..Default::default() };
+        for i in 0..MAX_PENDING_DIALOGS + 10 {
+            store.push_dialog(entry(&format!("sip-{i}"), "INVITE sip:1000@10.0.0.1 SIP/2.0"));
+        }
+        assert_eq!(store.pending.len(), MAX_PENDING_DIALOGS);
+        assert!(!store.pending.contains_key("sip-0"), "oldest INVITE is dropped");
+
+        // large INVITEs evict older ones to stay under the byte cap
+        let mut large = entry("sip-large1", "INVITE sip:1000@10.0.0.1 SIP/2.0");
+        large.raw = "a".repeat(MAX_PENDING_BYTES / 2);
+        store.push_dialog(large.clone());
+        large.sip_call_id = "sip-large2".to_owned();
+        store.push_dialog(large.clone());
+        assert!(store.pending_bytes <= MAX_PENDING_BYTES);
+        assert!(store.pending.contains_key("sip-large1") && store.pending.contains_key("sip-large2"));
+        large.sip_call_id = "sip-huge".to_owned();
+        large.raw = "a".repeat(MAX_PENDING_BYTES + 1);
+        store.push_dialog(large);
+        assert!(!store.pending.contains_key("sip-huge"));
+    }
+
+    #[test]
+    fn test_text_render() {
+        let text = to_text(&[entry("sip-a", "INVITE sip:1000@10.0.0.1 SIP/2.0")]);
+        assert!(text.starts_with("[1700000000.000123] IN 10.0.0.2:5080 -> 10.0.0.1:5060\r\nINVITE sip:1000@10.0.0.1 SIP/2.0\r\n"));
+    }
+}
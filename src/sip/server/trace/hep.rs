@@ -0,0 +1,180 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+use crate::protocol::InternalCallId;
+
+use super::SipTraceEntry;
+
+const CHUNK_IP_FAMILY: u16 = 1;
+const CHUNK_IP_PROTO: u16 = 2;
+const CHUNK_IPV4_SRC: u16 = 3;
+const CHUNK_IPV4_DST: u16 = 4;
+const CHUNK_IPV6_SRC: u16 = 5;
+const CHUNK_IPV6_DST: u16 = 6;
+const CHUNK_SRC_PORT: u16 = 7;
+const CHUNK_DST_PORT: u16 = 8;
+const CHUNK_TS_SEC: u16 = 9;
+const CHUNK_TS_USEC: u16 = 10;
+const CHUNK_PROTO_TYPE: u16 = 11;
+const CHUNK_CAPTURE_ID: u16 = 12;
+const CHUNK_PAYLOAD: u16 = 15;
+const CHUNK_CORRELATION_ID: u16 = 17;
+
+/// Largest packet which fits the u16 length fields and an IPv4 UDP datagram, longer SIP payloads are clipped
+const MAX_PACKET_LEN: usize = 65507;
+/// Correlation ids of unbound requests are SIP Call-IDs from the network, they are clipped so the payload keeps its room
+const MAX_CORRELATION_ID_LEN: usize = 256;
+
+const FAMILY_IPV4: u8 = 2;
+const FAMILY_IPV6: u8 = 10;
+const PROTO_UDP: u8 = 17;
+const PROTO_TYPE_SIP: u8 = 1;
+
+/// Mirror captured messages to a Homer compatible collector, sending is best effort and never blocks the SIP stack
+pub struct HepMirror {
+    socket: UdpSocket,
+    collector: SocketAddr,
+    capture_id: u32,
+}
+
+impl HepMirror {
+    pub fn new(collector: SocketAddr, capture_id: u32) -> std::io::Result<Self> {
+        let bind = if collector.is_ipv4() {
+            SocketAddr::from(([0u8; 4], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, collector, capture_id })
+    }
+
+    pub fn send(&self, entry: &SipTraceEntry, call_id: &InternalCallId) {
+        let packet = encode(entry, self.capture_id, call_id);
+        if let Err(e) = self.socket.send_to(&packet, self.collector) {
+            log::debug!("[HepMirror] send to {} error {e:?}", self.collector);
+        }
+    }
+}
+
+fn chunk(out: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    let value = &value[..value.len().min(u16::MAX as usize - 6)];
+    out.extend_from_slice(&0u16.to_be_bytes());
+    out.extend_from_slice(&kind.to_be_bytes());
+    out.extend_from_slice(&(6 + value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+/// Encode an entry as HEP3 packet
+fn encode(entry: &SipTraceEntry, capture_id: u32, call_id: &InternalCallId) -> Vec<u8> {
+    let (src, dst) = (entry.src(), entry.dst());
+    let mut chunks = Vec::with_capacity(entry.raw.len() + 128);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            chunk(&mut chunks, CHUNK_IP_FAMILY, &[FAMILY_IPV4]);
+            chunk(&mut chunks, CHUNK_IPV4_SRC, &src_ip.octets());
+            chunk(&mut chunks, CHUNK_IPV4_DST, &dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            chunk(&mut chunks, CHUNK_IP_FAMILY, &[FAMILY_IPV6]);
+            chunk(&mut chunks, CHUNK_IPV6_SRC, &to_v6(src_ip).octets());
+            chunk(&mut chunks, CHUNK_IPV6_DST, &to_v6(dst_ip).octets());
+        }
+    }
+    chunk(&mut chunks, CHUNK_IP_PROTO, &[PROTO_UDP]);
+    chunk(&mut chunks, CHUNK_SRC_PORT, &src.port().to_be_bytes());
+    chunk(&mut chunks, CHUNK_DST_PORT, &dst.port().to_be_bytes());
+    chunk(&mut chunks, CHUNK_TS_SEC, &((entry.ts_us / 1_000_000) as u32).to_be_bytes());
+    chunk(&mut chunks, CHUNK_TS_USEC, &((entry.ts_us % 1_000_000) as u32).to_be_bytes());
+    chunk(&mut chunks, CHUNK_PROTO_TYPE, &[PROTO_TYPE_SIP]);
+    chunk(&mut chunks, CHUNK_CAPTURE_ID, &capture_id.to_be_bytes());
+    let call_id = call_id.as_bytes();
+    chunk(&mut chunks, CHUNK_CORRELATION_ID, &call_id[..call_id.len().min(MAX_CORRELATION_ID_LEN)]);
+    let payload = entry.raw.as_bytes();
+    let room = MAX_PACKET_LEN - 6 - chunks.len() - 6;
+    chunk(&mut chunks, CHUNK_PAYLOAD, &payload[..payload.len().min(room)]);
+
+    let mut packet = Vec::with_capacity(6 + chunks.len());
+    packet.extend_from_slice(b"HEP3");
+    packet.extend_from_slice(&(6 + chunks.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&chunks);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use crate::sip::server::trace::{SipTraceDirection, SipTraceEntry};
+
+    use super::{encode, HepMirror, MAX_PACKET_LEN};
+
+    /// Read chunks of a HEP3 packet as (type, value)
+    fn parse(packet: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        assert_eq!(&packet[0..4], b"HEP3");
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]) as usize, packet.len());
+        let mut chunks = vec![];
+        let mut pos = 6;
+        while pos < packet.len() {
+            let kind = u16::from_be_bytes([packet[pos + 2], packet[pos + 3]]);
+            let len = u16::from_be_bytes([packet[pos + 4], packet[pos + 5]]) as usize;
+            chunks.push((kind, packet[pos + 6..pos + len].to_vec()));
+            pos += len;
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_clip_large_payload() {
+        let entry = SipTraceEntry {
+            ts_us: 1_700_000_000_000_123,
+            direction: SipTraceDirection::In,
+            local: "10.0.0.1:5060".parse().expect("should parse addr"),
+            remote: "10.0.0.2:5080".parse().expect("should parse addr"),
+            sip_call_id: "abc".to_owned(),
+            first_line: "INVITE sip:10.0.0.1 SIP/2.0".to_owned(),
+            raw: "a".repeat(70_000),
+        };
+        let packet = encode(&entry, 2001, &"b".repeat(70_000).into());
+        assert_eq!(packet.len(), MAX_PACKET_LEN);
+        let chunks = parse(&packet);
+        let value = |kind: u16| chunks.iter().find(|(k, _)| *k == kind).map(|(_, v)| v.len()).expect("should have chunk");
+        assert_eq!(value(17), 256);
+        assert!(value(15) > 60_000, "payload keeps the remaining room");
+    }
+
+    #[test]
+    fn test_mirror_to_local_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").expect("should bind");
+        collector.set_read_timeout(Some(Duration::from_secs(2))).expect("should set timeout");
+        let mirror = HepMirror::new(collector.local_addr().expect("should have addr"), 2001).expect("should create mirror");
+
+        let raw = "OPTIONS sip:10.0.0.1 SIP/2.0\r\nCall-ID: abc\r\n\r\n";
+        let entry = SipTraceEntry {
+            ts_us: 1_700_000_000_000_123,
+            direction: SipTraceDirection::Out,
+            local: "10.0.0.1:5060".parse().expect("should parse addr"),
+            remote: "10.0.0.2:5080".parse().expect("should parse addr"),
+            sip_call_id: "abc".to_owned(),
+            first_line: "OPTIONS sip:10.0.0.1 SIP/2.0".to_owned(),
+            raw: raw.to_owned(),
+        };
+        mirror.send(&entry, &"call1".to_owned().into());
+
+        let mut buf = [0; 2048];
+        let len = collector.recv(&mut buf).expect("should receive packet");
+        let chunks = parse(&buf[..len]);
+        let value = |kind: u16| chunks.iter().find(|(k, _)| *k == kind).map(|(_, v)| v.clone()).expect("should have chunk");
+        assert_eq!(value(3), vec![10, 0, 0, 1]);
+        assert_eq!(value(4), vec![10, 0, 0, 2]);
+        assert_eq!(value(8), 5080u16.to_be_bytes().to_vec());
+        assert_eq!(value(9), 1_700_000_000u32.to_be_bytes().to_vec());
+        assert_eq!(value(10), 123u32.to_be_bytes().to_vec());
+        assert_eq!(value(12), 2001u32.to_be_bytes().to_vec());
+        assert_eq!(value(15), raw.as_bytes().to_vec());
+        assert_eq!(value(17), b"call1".to_vec());
+    }
+}
@@ -0,0 +1,131 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use super::SipTraceEntry;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const SNAPLEN: u32 = 65535;
+/// Packets start with the IP header, no link layer
+const LINKTYPE_RAW: u32 = 101;
+const UDP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Export entries as a pcap file with synthetic IP/UDP headers, so it can be opened in Wireshark or sngrep
+pub fn to_pcap(entries: &[SipTraceEntry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&0i32.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&SNAPLEN.to_le_bytes());
+    out.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+
+    for entry in entries {
+        let packet = ip_packet(entry);
+        out.extend_from_slice(&((entry.ts_us / 1_000_000) as u32).to_le_bytes());
+        out.extend_from_slice(&((entry.ts_us % 1_000_000) as u32).to_le_bytes());
+        out.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        out.extend_from_slice(&packet);
+    }
+    out
+}
+
+fn ip_packet(entry: &SipTraceEntry) -> Vec<u8> {
+    let (src, dst) = (entry.src(), entry.dst());
+    let payload = entry.raw.as_bytes();
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + udp_len);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let total_len = (IPV4_HEADER_LEN + udp_len) as u16;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| -> Ipv6Addr {
+                match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                }
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&to_v6(src_ip).octets());
+            packet.extend_from_slice(&to_v6(dst_ip).octets());
+        }
+    }
+    // UDP checksum is optional over IPv4 and tools don't verify it by default
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]]) as u32).sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sip::server::trace::{SipTraceDirection, SipTraceEntry};
+
+    use super::{ipv4_checksum, to_pcap};
+
+    #[test]
+    fn test_pcap_layout() {
+        let raw = "SIP/2.0 200 OK\r\nCall-ID: abc\r\n\r\n";
+        let entries = vec![
+            SipTraceEntry {
+                ts_us: 1_700_000_000_000_123,
+                direction: SipTraceDirection::In,
+                local: "10.0.0.1:5060".parse().expect("should parse addr"),
+                remote: "10.0.0.2:5080".parse().expect("should parse addr"),
+                sip_call_id: "abc".to_owned(),
+                first_line: "SIP/2.0 200 OK".to_owned(),
+                raw: raw.to_owned(),
+            },
+            SipTraceEntry {
+                ts_us: 1_700_000_001_000_000,
+                direction: SipTraceDirection::Out,
+                local: "[2001:db8::1]:5060".parse().expect("should parse addr"),
+                remote: "[2001:db8::2]:5060".parse().expect("should parse addr"),
+                sip_call_id: "abc".to_owned(),
+                first_line: "SIP/2.0 200 OK".to_owned(),
+                raw: raw.to_owned(),
+            },
+        ];
+        let pcap = to_pcap(&entries);
+        assert_eq!(&pcap[0..4], &0xa1b2c3d4u32.to_le_bytes());
+        assert_eq!(&pcap[20..24], &101u32.to_le_bytes());
+
+        let v4_len = 20 + 8 + raw.len();
+        let v6_len = 40 + 8 + raw.len();
+        assert_eq!(pcap.len(), 24 + 16 + v4_len + 16 + v6_len);
+
+        let record = &pcap[24..];
+        assert_eq!(&record[8..12], &(v4_len as u32).to_le_bytes());
+        let ip = &record[16..16 + v4_len];
+        assert_eq!(ip[0], 0x45);
+        assert_eq!(&ip[12..16], &[10, 0, 0, 2]);
+        assert_eq!(ipv4_checksum(&ip[0..20]), 0, "header checksum should verify");
+        assert_eq!(&ip[28..], raw.as_bytes());
+
+        let record = &record[16 + v4_len..];
+        assert_eq!(&record[8..12], &(v6_len as u32).to_le_bytes());
+        assert_eq!(record[16] >> 4, 6);
+    }
+}
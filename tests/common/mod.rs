@@ -0,0 +1,144 @@
+//! Harness for end-to-end tests: a gateway on loopback, stand-in hook and media servers and a scripted SIP peer
+#![allow(dead_code)]
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use atm0s_media_sip_gateway::{AddressBookStorage, CallLimitsConfig, ClusterTlsConfig, Gateway, GatewayConfig, PhoneNumber, RtpEngine, SecureContext, SipFloodConfig, SipTraceConfig};
+use tokio::time::sleep;
+
+mod servers;
+mod sip_peer;
+
+pub use servers::{HookServer, MediaServer};
+pub use sip_peer::{wait_bye, ScriptedCall, ScriptedInvite, SipPeer};
+
+pub const SECRET: &str = "test-secret";
+
+/// Free UDP address for SIP listeners, the port is released before the gateway binds it, which is fine for tests
+pub fn free_udp_addr() -> SocketAddr {
+    std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind").local_addr().expect("should have addr")
+}
+
+/// Free TCP address for the http server, a free UDP port can still be taken on TCP
+pub fn free_tcp_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").expect("should bind").local_addr().expect("should have addr")
+}
+
+pub fn gateway_config(http_addr: SocketAddr, sip_addr: SocketAddr, media_gateway: &str, media_engine: Arc<dyn RtpEngine>) -> GatewayConfig {
+    let address_book = AddressBookStorage::new(SECRET);
+    address_book.set_synced();
+    GatewayConfig {
+        http_addr,
+        sip_addrs: vec![sip_addr],
+        sip_source_ips: vec![],
+        sip_forward_headers: vec![],
+        sip_flood: SipFloodConfig {
+            invites_per_second: 100,
+            ban_threshold: 1000,
+            ban_duration: Duration::from_secs(1),
+            blocklist: vec![],
+            trusted: vec![],
+        },
+        sip_trace: SipTraceConfig::default(),
+        sip_trunks: vec![],
+        call_limits: CallLimitsConfig::default(),
+        secure_ctx: Arc::new(SecureContext::new(SECRET, address_book.clone())),
+        address_book,
+        http_hook_queues: 1,
+        media_gateway: media_gateway.to_owned(),
+        media_engine,
+        media_backends: Default::default(),
+        media_relay: None,
+        sdn_peer_id: rand::random::<u64>().into(),
+        sdn_listen_addr: "127.0.0.1:0".parse().expect("should parse addr"),
+        sdn_advertise: None,
+        sdn_seeds: vec![],
+        sdn_secret: "test".to_owned(),
+        sdn_tls: ClusterTlsConfig {
+            self_signed: true,
+            ..Default::default()
+        },
+        region: None,
+        drain_timeout: Duration::from_secs(1),
+    }
+}
+
+/// Route incoming calls of a number from loopback to the root app with the hook
+pub fn add_loopback_number(cfg: &GatewayConfig, number: &str, hook: &str) {
+    cfg.address_book.sync_numbers(vec![PhoneNumber {
+        number: number.to_owned(),
+        subnets: vec!["127.0.0.0/8".parse().expect("should parse subnet")],
+        auth: None,
+        app_id: "".to_owned(),
+        hook: hook.to_owned(),
+        limits: Default::default(),
+        codecs: vec![],
+    }]);
+}
+
+/// Drive the gateway in the same task while the test is running, the gateway is dropped with the test
+pub async fn with_gateway<F: Future<Output = ()>>(cfg: GatewayConfig, test: F) {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let mut gateway = Gateway::new(cfg).await.expect("should create gateway");
+    tokio::select! {
+        _ = async {
+            loop {
+                if let Err(e) = gateway.recv().await {
+                    log::error!("gateway error {e:?}");
+                }
+            }
+        } => {}
+        _ = test => {}
+    }
+}
+
+pub async fn wait_until<F: Fn() -> bool>(timeout: Duration, check: F) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if check() {
+            return true;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    check()
+}
+
+/// Create an outgoing call with the root secret, retry until the http server is bound. Returns the `data` of the response
+pub async fn create_outgoing_call(http_addr: SocketAddr, body: &serde_json::Value) -> serde_json::Value {
+    create_outgoing_call_as(http_addr, SECRET, body).await
+}
+
+/// Create an outgoing call with the secret of an app, like `create_outgoing_call`
+pub async fn create_outgoing_call_as(http_addr: SocketAddr, secret: &str, body: &serde_json::Value) -> serde_json::Value {
+    let client = reqwest::Client::new();
+    for _ in 0..50 {
+        match client.post(format!("http://{http_addr}/call/outgoing")).bearer_auth(secret).json(body).send().await {
+            Ok(res) => {
+                let mut res: serde_json::Value = res.error_for_status().expect("should create call").json().await.expect("should parse response");
+                return res["data"].take();
+            }
+            Err(_) => sleep(Duration::from_millis(100)).await,
+        }
+    }
+    panic!("http server should be started");
+}
+
+/// Create or update an app over the admin api with the root secret, retry until the http server is bound
+pub async fn upsert_app(http_addr: SocketAddr, app: &serde_json::Value) {
+    let client = reqwest::Client::new();
+    for _ in 0..50 {
+        match client.put(format!("http://{http_addr}/admin/address_book/apps")).bearer_auth(SECRET).json(app).send().await {
+            Ok(res) => {
+                res.error_for_status().expect("should upsert app");
+                return;
+            }
+            Err(_) => sleep(Duration::from_millis(100)).await,
+        }
+    }
+    panic!("http server should be started");
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use atm0s_media_sip_gateway::{
+    protobuf::sip_gateway::{call_event, incoming_call_notify, CallEvent},
+    FAKE_ANSWER_SDP, FAKE_OFFER_SDP,
+};
+use poem::{
+    handler,
+    http::StatusCode,
+    listener::{Acceptor, Listener, TcpListener},
+    patch, post,
+    web::{Data, Json, Path},
+    EndpointExt, IntoResponse, Response, Route, Server,
+};
+
+use super::wait_until;
+
+/// Bind on a random loopback port before returning, so the server is ready when the gateway calls it
+async fn spawn_server<E: poem::Endpoint + 'static>(app: E) -> SocketAddr {
+    let acceptor = TcpListener::bind("127.0.0.1:0").into_acceptor().await.expect("should bind server");
+    let addr = *acceptor.local_addr()[0].0.as_socket_addr().expect("should be a socket addr");
+    tokio::spawn(async move { Server::new_with_acceptor(acceptor).run(app).await });
+    addr
+}
+
+#[derive(Clone)]
+struct HookState {
+    action: serde_json::Value,
+    events: Arc<Mutex<Vec<CallEvent>>>,
+}
+
+#[handler]
+fn on_hook(Json(event): Json<CallEvent>, data: Data<&HookState>) -> Json<serde_json::Value> {
+    let arrived = matches!(
+        &event.event,
+        Some(call_event::Event::Notify(notify)) if matches!(notify.event, Some(incoming_call_notify::Event::Arrived(_)))
+    );
+    data.events.lock().expect("should lock").push(event);
+    if arrived {
+        Json(data.action.clone())
+    } else {
+        Json(serde_json::json!({}))
+    }
+}
+
+/// Stand-in of the application hook, it records all events and answers call arrivals with a fixed action
+pub struct HookServer {
+    url: String,
+    events: Arc<Mutex<Vec<CallEvent>>>,
+}
+
+impl HookServer {
+    pub async fn start(action: serde_json::Value) -> Self {
+        let events: Arc<Mutex<Vec<CallEvent>>> = Default::default();
+        let app = Route::new().at("/hook", post(on_hook)).data(HookState { action, events: events.clone() });
+        let addr = spawn_server(app).await;
+        Self {
+            url: format!("http://{addr}/hook"),
+            events,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn events(&self) -> Vec<call_event::Event> {
+        self.events.lock().expect("should lock").iter().filter_map(|e| e.event.clone()).collect()
+    }
+
+    pub async fn wait_event<F: Fn(&call_event::Event) -> bool>(&self, check: F) -> bool {
+        wait_until(Duration::from_secs(5), || self.events().iter().any(&check)).await
+    }
+}
+
+#[derive(Clone, Default)]
+struct MediaState {
+    next_id: Arc<AtomicUsize>,
+    sessions: Arc<Mutex<HashSet<String>>>,
+    answers: Arc<Mutex<Vec<String>>>,
+}
+
+#[handler]
+fn create_token(Path(kind): Path<String>, Json(body): Json<serde_json::Value>) -> Json<serde_json::Value> {
+    let room = body["room"].as_str().unwrap_or_default();
+    let peer = body["peer"].as_str().unwrap_or_default();
+    Json(serde_json::json!({
+        "status": true,
+        "data": { "token": format!("{kind}:{room}:{peer}") },
+    }))
+}
+
+#[handler]
+fn create_session(Path(kind): Path<String>, data: Data<&MediaState>) -> Response {
+    let sdp = match kind.as_str() {
+        "offer" => FAKE_OFFER_SDP,
+        "answer" => FAKE_ANSWER_SDP,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let id = data.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+    data.sessions.lock().expect("should lock").insert(id.clone());
+    Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Location", format!("/sessions/{id}"))
+        .content_type("application/sdp")
+        .body(sdp)
+}
+
+#[handler]
+fn set_answer(Path(id): Path<String>, body: String, data: Data<&MediaState>) -> StatusCode {
+    if !data.sessions.lock().expect("should lock").contains(&id) {
+        return StatusCode::NOT_FOUND;
+    }
+    data.answers.lock().expect("should lock").push(body);
+    StatusCode::OK
+}
+
+#[handler]
+fn delete_session(Path(id): Path<String>, data: Data<&MediaState>) -> StatusCode {
+    if data.sessions.lock().expect("should lock").remove(&id) {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Stand-in of the media gateway rtpengine api, it is used through `HttpRtpEngine` like the real one
+pub struct MediaServer {
+    url: String,
+    state: MediaState,
+}
+
+impl MediaServer {
+    pub async fn start() -> Self {
+        let state = MediaState::default();
+        let app = Route::new()
+            .at("/token/:kind", post(create_token))
+            .at("/rtpengine/:kind", post(create_session))
+            .at("/sessions/:id", patch(set_answer).delete(delete_session))
+            .data(state.clone());
+        let addr = spawn_server(app).await;
+        Self { url: format!("http://{addr}"), state }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Ids of sessions which are not deleted yet
+    pub fn sessions(&self) -> HashSet<String> {
+        self.state.sessions.lock().expect("should lock").clone()
+    }
+
+    /// Remote answers which are set to offer sessions
+    pub fn answers(&self) -> Vec<String> {
+        self.state.answers.lock().expect("should lock").clone()
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use bytesstr::BytesStr;
+use ezk_sip_core::{transport::udp::Udp, Endpoint, IncomingRequest, Layer, LayerKey, MayTake};
+use ezk_sip_types::{
+    header::typed::{Contact, ContentType},
+    uri::{sip::SipUri, NameAddr},
+    Code, Method, Name,
+};
+use ezk_sip_ua::{
+    dialog::{Dialog, DialogLayer},
+    invite::{
+        acceptor::Acceptor,
+        create_ack,
+        initiator::{Initiator, Response},
+        session::{Event, Session},
+        InviteLayer,
+    },
+};
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::timeout,
+};
+
+use super::free_udp_addr;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const REALM: &str = "test";
+const NONCE: &str = "5f2a3c9e";
+const CHALLENGE: &str = "Digest realm=\"test\", nonce=\"5f2a3c9e\", algorithm=MD5, qop=\"auth\"";
+
+/// Out-of-dialog INVITEs are handed to the test script, unauthorized ones are challenged when a username is required
+struct ScriptedUasLayer {
+    invite_tx: Sender<IncomingRequest>,
+    credentials: Option<(String, String)>,
+    challenges: Arc<AtomicUsize>,
+}
+
+/// Params of a Digest header value, quotes are removed
+fn digest_params(value: &str) -> Option<HashMap<String, String>> {
+    let params = value.strip_prefix("Digest ")?;
+    Some(
+        params
+            .split(',')
+            .filter_map(|p| p.split_once('='))
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().trim_matches('"').to_owned()))
+            .collect(),
+    )
+}
+
+fn md5_hex(value: String) -> String {
+    format!("{:x}", md5::compute(value))
+}
+
+impl ScriptedUasLayer {
+    /// Check the MD5 digest response (RFC 2617, qop=auth) of the Authorization header against the configured credentials
+    fn is_authorized(&self, request: &IncomingRequest) -> bool {
+        let (username, password) = match &self.credentials {
+            Some(credentials) => credentials,
+            None => return true,
+        };
+        request
+            .headers
+            .iter()
+            .filter(|(n, _)| n.as_print_str().eq_ignore_ascii_case("authorization"))
+            .filter_map(|(_, v)| digest_params(v))
+            .any(|params| {
+                let param = |name: &str| params.get(name).map(|v| v.as_str()).unwrap_or_default();
+                if param("username") != username || param("realm") != REALM || param("nonce") != NONCE {
+                    return false;
+                }
+                let ha1 = md5_hex(format!("{username}:{REALM}:{password}"));
+                let ha2 = md5_hex(format!("{}:{}", request.line.method, param("uri")));
+                let expected = match param("qop") {
+                    "auth" => md5_hex(format!("{ha1}:{NONCE}:{}:{}:auth:{ha2}", param("nc"), param("cnonce"))),
+                    _ => md5_hex(format!("{ha1}:{NONCE}:{ha2}")),
+                };
+                param("response").eq_ignore_ascii_case(&expected)
+            })
+    }
+}
+
+#[async_trait::async_trait]
+impl Layer for ScriptedUasLayer {
+    fn name(&self) -> &'static str {
+        "scripted-uas-layer"
+    }
+
+    async fn receive(&self, endpoint: &Endpoint, request: MayTake<'_, IncomingRequest>) {
+        if request.line.method != Method::INVITE {
+            return;
+        }
+        if !self.is_authorized(&request) {
+            self.challenges.fetch_add(1, Ordering::Relaxed);
+            let mut response = endpoint.create_response(&request, Code::UNAUTHORIZED, None);
+            response.msg.headers.insert(Name::from(BytesStr::from_static("WWW-Authenticate")), BytesStr::from_static(CHALLENGE));
+            request.take();
+            endpoint.send_outgoing_response(&mut response).await.expect("should send 401");
+            return;
+        }
+        self.invite_tx.send(request.take()).await.expect("should send invite to script");
+    }
+}
+
+/// SIP user agent on loopback which is driven step by step by a test
+pub struct SipPeer {
+    addr: SocketAddr,
+    endpoint: Endpoint,
+    contact: Contact,
+    dialog_layer: LayerKey<DialogLayer>,
+    invite_layer: LayerKey<InviteLayer>,
+    invite_rx: Receiver<IncomingRequest>,
+    challenges: Arc<AtomicUsize>,
+}
+
+impl SipPeer {
+    pub async fn start() -> Self {
+        Self::build(None).await
+    }
+
+    /// Incoming INVITEs without a valid digest response for the credentials are rejected with 401
+    pub async fn start_with_challenge(username: &str, password: &str) -> Self {
+        Self::build(Some((username.to_owned(), password.to_owned()))).await
+    }
+
+    async fn build(credentials: Option<(String, String)>) -> Self {
+        let addr = free_udp_addr();
+        let mut builder = Endpoint::builder();
+        let dialog_layer = builder.add_layer(DialogLayer::default());
+        let invite_layer = builder.add_layer(InviteLayer::default());
+
+        let (invite_tx, invite_rx) = channel(10);
+        let challenges = Arc::new(AtomicUsize::new(0));
+        builder.add_layer(ScriptedUasLayer {
+            invite_tx,
+            credentials,
+            challenges: challenges.clone(),
+        });
+        Udp::spawn(&mut builder, addr).await.expect("should bind sip peer");
+
+        let contact: SipUri = format!("sip:peer@{addr}").parse().expect("should parse contact");
+        Self {
+            addr,
+            endpoint: builder.build(),
+            contact: Contact::new(NameAddr::uri(contact)),
+            dialog_layer,
+            invite_layer,
+            invite_rx,
+            challenges,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Number of INVITEs which are rejected with 401
+    pub fn challenges(&self) -> usize {
+        self.challenges.load(Ordering::Relaxed)
+    }
+
+    /// Send an INVITE with an offer from `sip:{from}@peer` to `sip:{to}@target`
+    pub async fn call(&self, from: &str, to: &str, target: SocketAddr, offer: &'static str) -> ScriptedCall {
+        let local = self.endpoint.parse_uri(&format!("sip:{from}@{}", self.addr)).expect("should parse from");
+        let remote = self.endpoint.parse_uri(&format!("sip:{to}@{target}")).expect("should parse to");
+        let mut initiator = Initiator::new(self.endpoint.clone(), self.dialog_layer, self.invite_layer, NameAddr::uri(local), self.contact.clone(), remote);
+        let mut invite = initiator.create_invite();
+        invite.body = Bytes::from_static(offer.as_bytes());
+        invite.headers.insert_named(&ContentType(BytesStr::from_static("application/sdp")));
+        initiator.send_invite(invite).await.expect("should send invite");
+        ScriptedCall { initiator }
+    }
+
+    /// Wait for the next INVITE which passed the challenge
+    pub async fn recv_invite(&mut self) -> ScriptedInvite {
+        let invite = timeout(TIMEOUT, self.invite_rx.recv()).await.expect("should receive invite").expect("sip peer should be running");
+        let offer = invite.body.clone();
+        let dialog = Dialog::new_server(self.endpoint.clone(), self.dialog_layer, &invite, self.contact.clone()).expect("should create dialog");
+        let acceptor = Acceptor::new(dialog, self.invite_layer, invite, None).expect("should create acceptor");
+        ScriptedInvite { acceptor, offer }
+    }
+}
+
+/// INVITE which is received by the peer and waits for the script to answer it
+pub struct ScriptedInvite {
+    acceptor: Acceptor,
+    offer: Bytes,
+}
+
+impl ScriptedInvite {
+    pub fn offer(&self) -> &[u8] {
+        &self.offer
+    }
+
+    pub async fn ring(&mut self) {
+        let response = self.acceptor.create_response(Code::RINGING, None).await.expect("should create 180");
+        self.acceptor.respond_provisional(response).await.expect("should send 180");
+    }
+
+    pub async fn answer(mut self, answer: &'static str) -> Session {
+        let mut response = self.acceptor.create_response(Code::OK, None).await.expect("should create 200");
+        response.msg.body = Bytes::from_static(answer.as_bytes());
+        response.msg.headers.insert_named(&ContentType(BytesStr::from_static("application/sdp")));
+        let (session, _) = self.acceptor.respond_success(response).await.expect("should send 200");
+        session
+    }
+}
+
+/// INVITE which is sent by the peer
+pub struct ScriptedCall {
+    initiator: Initiator,
+}
+
+impl ScriptedCall {
+    async fn receive(&mut self) -> Response {
+        timeout(TIMEOUT, self.initiator.receive()).await.expect("should receive response").expect("should read response")
+    }
+
+    /// Skip responses until a provisional response with the code
+    pub async fn wait_provisional(&mut self, code: u16) {
+        loop {
+            match self.receive().await {
+                Response::Provisional(response) if response.line.code.into_u16() == code => return,
+                Response::Provisional(_) => {}
+                Response::Failure(response) => panic!("unexpected failure {}", response.line.code.into_u16()),
+                Response::Early(..) | Response::Session(..) | Response::Finished => panic!("unexpected response while waiting for {code}"),
+            }
+        }
+    }
+
+    /// Wait for the 2xx response and acknowledge it, return the session and the answer sdp
+    pub async fn wait_session(&mut self) -> (Session, Bytes) {
+        loop {
+            match self.receive().await {
+                Response::Provisional(_) => {}
+                Response::Session(session, response) => {
+                    let mut ack = create_ack(&session.dialog, response.base_headers.cseq.cseq).await.expect("should create ack");
+                    session.endpoint.send_outgoing_request(&mut ack).await.expect("should send ack");
+                    return (session, response.body.clone());
+                }
+                Response::Failure(response) => panic!("unexpected failure {}", response.line.code.into_u16()),
+                Response::Early(..) | Response::Finished => panic!("unexpected response while waiting for session"),
+            }
+        }
+    }
+
+    /// Wait for the final failure response, return its code
+    pub async fn wait_failure(&mut self) -> u16 {
+        loop {
+            match self.receive().await {
+                Response::Provisional(_) => {}
+                Response::Failure(response) => return response.line.code.into_u16(),
+                Response::Early(..) | Response::Session(..) | Response::Finished => panic!("unexpected response while waiting for failure"),
+            }
+        }
+    }
+
+    pub async fn cancel(&mut self) {
+        let cancel = self.initiator.create_cancel();
+        self.initiator.send_cancel(cancel).await.expect("should send cancel");
+    }
+}
+
+/// Drive an established session until the remote side sends BYE
+pub async fn wait_bye(session: &mut Session) {
+    loop {
+        match timeout(TIMEOUT, session.drive()).await.expect("should receive bye").expect("should drive session") {
+            Event::Bye(_) => return,
+            Event::Terminated => panic!("session terminated without bye"),
+            Event::RefreshNeeded(_) | Event::ReInviteReceived(_) => {}
+        }
+    }
+}
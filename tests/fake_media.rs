@@ -0,0 +1,71 @@
+use std::{sync::Arc, time::Duration};
+
+use atm0s_media_sip_gateway::{FakeRtpEngine, RtpEngineCall, FAKE_OFFER_SDP};
+use common::{create_outgoing_call, free_tcp_addr, free_udp_addr, gateway_config, wait_until, with_gateway, SECRET};
+use tokio::net::UdpSocket;
+
+mod common;
+
+/// Reply a final response to a request with the dialog headers copied from it
+fn build_response(request: &str, status: &str) -> String {
+    let mut response = format!("SIP/2.0 {status}\r\n");
+    for line in request.split("\r\n") {
+        let name = line.split(':').next().unwrap_or_default().trim().to_ascii_lowercase();
+        match name.as_str() {
+            "via" | "from" | "call-id" | "cseq" => response.push_str(&format!("{line}\r\n")),
+            "to" => response.push_str(&format!("{line};tag=fake-callee\r\n")),
+            _ => {}
+        }
+    }
+    response.push_str("Content-Length: 0\r\n\r\n");
+    response
+}
+
+#[tokio::test]
+async fn test_outgoing_call_with_fake_media() {
+    let engine = FakeRtpEngine::new();
+    let http_addr = free_tcp_addr();
+    let callee = UdpSocket::bind("127.0.0.1:0").await.expect("should bind callee");
+    let callee_addr = callee.local_addr().expect("should have addr");
+    let cfg = gateway_config(http_addr, free_udp_addr(), "http://127.0.0.1:1", Arc::new(engine.clone()));
+
+    with_gateway(cfg, async {
+        let body = serde_json::json!({
+            "sip_server": callee_addr.to_string(),
+            "from_number": "1000",
+            "to_number": "2000",
+            "hook": "http://127.0.0.1:1/hook",
+            "streaming": { "room": "room1", "peer": "peer1", "record": false },
+        });
+        let created = create_outgoing_call(http_addr, &body).await;
+        assert!(created["call_token"].is_string());
+
+        let mut buf = vec![0; 65535];
+        let (len, gateway_sip) = tokio::time::timeout(Duration::from_secs(5), callee.recv_from(&mut buf))
+            .await
+            .expect("should receive INVITE")
+            .expect("should read socket");
+        let invite = String::from_utf8_lossy(&buf[..len]).to_string();
+        assert!(invite.starts_with("INVITE sip:2000@"), "unexpected request {invite}");
+        assert!(invite.contains(FAKE_OFFER_SDP), "INVITE should carry the fake offer");
+
+        let calls = engine.calls();
+        assert_eq!(
+            calls[0],
+            RtpEngineCall::CreateToken {
+                app_secret: SECRET.to_owned(),
+                room: "room1".to_owned(),
+                peer: "peer1".to_owned(),
+                record: false,
+            }
+        );
+        assert!(matches!(calls[1], RtpEngineCall::CreateOffer { .. }));
+        assert_eq!(engine.sessions().len(), 1);
+
+        // rejected call must delete its media session
+        callee.send_to(build_response(&invite, "486 Busy Here").as_bytes(), gateway_sip).await.expect("should send response");
+        assert!(wait_until(Duration::from_secs(5), || engine.sessions().is_empty()).await, "media session should be deleted");
+        assert!(matches!(engine.calls().last(), Some(RtpEngineCall::DeleteSession { .. })));
+    })
+    .await;
+}
@@ -0,0 +1,93 @@
+use std::{sync::Arc, time::Duration};
+
+use atm0s_media_sip_gateway::{
+    protobuf::sip_gateway::{
+        call_event,
+        incoming_call_data::incoming_call_event::{self, sip_event},
+        incoming_call_notify,
+    },
+    HttpRtpEngine, FAKE_ANSWER_SDP, FAKE_OFFER_SDP,
+};
+use common::{add_loopback_number, free_tcp_addr, free_udp_addr, gateway_config, wait_until, with_gateway, HookServer, MediaServer, SipPeer};
+
+mod common;
+
+fn is_notify(event: &call_event::Event, check: fn(&incoming_call_notify::Event) -> bool) -> bool {
+    matches!(event, call_event::Event::Notify(notify) if notify.event.as_ref().is_some_and(check))
+}
+
+fn is_sip_event(event: &call_event::Event, check: fn(&sip_event::Event) -> bool) -> bool {
+    match event {
+        call_event::Event::Incoming(incoming) => match &incoming.event {
+            Some(incoming_call_event::Event::Sip(sip)) => sip.event.as_ref().is_some_and(check),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[tokio::test]
+async fn test_incoming_call_accept_then_bye() {
+    let media = MediaServer::start().await;
+    let hook = HookServer::start(serde_json::json!({
+        "action": "Accept",
+        "stream": { "room": "room1", "peer": "caller1", "record": false },
+    }))
+    .await;
+    let sip_addr = free_udp_addr();
+    let cfg = gateway_config(free_tcp_addr(), sip_addr, media.url(), Arc::new(HttpRtpEngine::new(media.url())));
+    add_loopback_number(&cfg, "2000", hook.url());
+    let peer = SipPeer::start().await;
+
+    with_gateway(cfg, async {
+        let mut call = peer.call("1000", "2000", sip_addr, FAKE_OFFER_SDP).await;
+        let (mut session, answer) = call.wait_session().await;
+        assert_eq!(answer.as_ref(), FAKE_ANSWER_SDP.as_bytes(), "200 OK should carry the media answer");
+        assert_eq!(media.sessions().len(), 1);
+
+        let arrived = hook.events().into_iter().find_map(|e| match e {
+            call_event::Event::Notify(notify) => match notify.event {
+                Some(incoming_call_notify::Event::Arrived(arrived)) => Some(arrived),
+                _ => None,
+            },
+            _ => None,
+        });
+        let arrived = arrived.expect("hook should receive the arrived notify");
+        assert_eq!(arrived.call_from, "1000");
+        assert_eq!(arrived.call_to, "2000");
+
+        session.terminate().await.expect("should send bye");
+        assert!(hook.wait_event(|e| is_sip_event(e, |s| matches!(s, sip_event::Event::Bye(_)))).await, "hook should receive bye");
+        assert!(wait_until(Duration::from_secs(5), || media.sessions().is_empty()).await, "media session should be deleted");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_incoming_call_cancel_while_ringing() {
+    let media = MediaServer::start().await;
+    let hook = HookServer::start(serde_json::json!({ "action": "Ring" })).await;
+    let sip_addr = free_udp_addr();
+    let cfg = gateway_config(free_tcp_addr(), sip_addr, media.url(), Arc::new(HttpRtpEngine::new(media.url())));
+    add_loopback_number(&cfg, "2000", hook.url());
+    let peer = SipPeer::start().await;
+
+    with_gateway(cfg, async {
+        let mut call = peer.call("1000", "2000", sip_addr, FAKE_OFFER_SDP).await;
+        call.wait_provisional(180).await;
+        call.cancel().await;
+        assert_eq!(call.wait_failure().await, 487);
+
+        assert!(
+            hook.wait_event(|e| is_notify(e, |n| matches!(n, incoming_call_notify::Event::Cancelled(_)))).await,
+            "hook should receive the cancelled notify"
+        );
+        assert!(
+            hook.wait_event(|e| is_sip_event(e, |s| matches!(s, sip_event::Event::Cancelled(_)))).await,
+            "hook should receive cancel"
+        );
+        // ringing calls don't have media
+        assert!(media.sessions().is_empty());
+    })
+    .await;
+}
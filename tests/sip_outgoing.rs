@@ -0,0 +1,257 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use atm0s_media_sip_gateway::{
+    protobuf::sip_gateway::{
+        call_event,
+        outgoing_call_data::{self, outgoing_call_event::sip_event, outgoing_call_request, outgoing_call_response, OutgoingCallRequest},
+        recording_state, MediaStats, OutgoingCallData,
+    },
+    FakeRtpEngine, HttpRtpEngine, MediaBackend, MediaEngineError, RecordingAction, StreamingInfo, FAKE_ANSWER_SDP, FAKE_OFFER_SDP,
+};
+use bytes::Bytes;
+use common::{create_outgoing_call, create_outgoing_call_as, free_tcp_addr, free_udp_addr, gateway_config, upsert_app, wait_bye, wait_until, with_gateway, HookServer, MediaServer, SipPeer};
+use futures_util::{SinkExt, StreamExt};
+use prost::Message as _;
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+mod common;
+
+fn is_sip_event(event: &call_event::Event, check: fn(&sip_event::Event) -> bool) -> bool {
+    match event {
+        call_event::Event::Outgoing(outgoing) => match &outgoing.event {
+            Some(outgoing_call_data::outgoing_call_event::Event::Sip(sip)) => sip.event.as_ref().is_some_and(check),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn recording_state(event: &call_event::Event) -> Option<recording_state::State> {
+    match event {
+        call_event::Event::Outgoing(outgoing) => match &outgoing.event {
+            Some(outgoing_call_data::outgoing_call_event::Event::Recording(recording)) => Some(recording.state()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Media backend with a recording api, sessions answer with the canned sdp
+#[derive(Clone, Default)]
+struct RecordingBackend {
+    sessions: Arc<Mutex<usize>>,
+    actions: Arc<Mutex<Vec<RecordingAction>>>,
+}
+
+impl RecordingBackend {
+    fn sessions(&self) -> usize {
+        *self.sessions.lock().expect("should lock")
+    }
+
+    fn actions(&self) -> Vec<RecordingAction> {
+        self.actions.lock().expect("should lock").clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl MediaBackend for RecordingBackend {
+    async fn create_offer(&self, _stream: &StreamingInfo) -> Result<(String, Bytes), MediaEngineError> {
+        *self.sessions.lock().expect("should lock") += 1;
+        Ok(("session1".to_owned(), Bytes::from_static(FAKE_OFFER_SDP.as_bytes())))
+    }
+
+    async fn create_answer(&self, _stream: &StreamingInfo, _offer: Bytes) -> Result<(String, Bytes), MediaEngineError> {
+        *self.sessions.lock().expect("should lock") += 1;
+        Ok(("session1".to_owned(), Bytes::from_static(FAKE_ANSWER_SDP.as_bytes())))
+    }
+
+    async fn set_answer(&self, _session: &str, _answer: Bytes) -> Result<(), MediaEngineError> {
+        Ok(())
+    }
+
+    async fn destroy(&self, _session: &str) -> Result<(), MediaEngineError> {
+        *self.sessions.lock().expect("should lock") -= 1;
+        Ok(())
+    }
+
+    async fn stats(&self, _session: &str) -> Result<MediaStats, MediaEngineError> {
+        Err(MediaEngineError::Unsupported("stats"))
+    }
+
+    async fn recording(&self, session: &str, action: RecordingAction) -> Result<String, MediaEngineError> {
+        self.actions.lock().expect("should lock").push(action);
+        Ok(format!("rec-{session}"))
+    }
+}
+
+fn call_request(peer: SocketAddr, hook: &str, auth: Option<(&str, &str)>) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "sip_server": peer.to_string(),
+        "from_number": "1000",
+        "to_number": "2000",
+        "hook": hook,
+        "streaming": { "room": "room1", "peer": "callee1", "record": false },
+    });
+    if let Some((username, password)) = auth {
+        body["sip_auth"] = serde_json::json!({ "username": username, "password": password });
+    }
+    body
+}
+
+#[tokio::test]
+async fn test_outgoing_call_with_digest_challenge() {
+    let media = MediaServer::start().await;
+    let hook = HookServer::start(serde_json::json!({})).await;
+    let http_addr = free_tcp_addr();
+    let cfg = gateway_config(http_addr, free_udp_addr(), media.url(), Arc::new(HttpRtpEngine::new(media.url())));
+    let mut peer = SipPeer::start_with_challenge("trunk-user", "trunk-pass").await;
+
+    with_gateway(cfg, async {
+        create_outgoing_call(http_addr, &call_request(peer.addr(), hook.url(), Some(("trunk-user", "trunk-pass")))).await;
+
+        let mut invite = peer.recv_invite().await;
+        assert_eq!(peer.challenges(), 1, "first INVITE should be challenged");
+        assert_eq!(invite.offer(), FAKE_OFFER_SDP.as_bytes(), "INVITE should carry the media offer");
+        invite.ring().await;
+        let mut session = invite.answer(FAKE_ANSWER_SDP).await;
+
+        assert!(
+            hook.wait_event(|e| is_sip_event(e, |s| matches!(s, sip_event::Event::Accepted(_)))).await,
+            "hook should receive accepted"
+        );
+        assert!(
+            hook.events().iter().all(|e| !is_sip_event(e, |s| matches!(s, sip_event::Event::Failure(_)))),
+            "401 should not be reported"
+        );
+        assert!(
+            wait_until(Duration::from_secs(5), || media.answers() == vec![FAKE_ANSWER_SDP.to_owned()]).await,
+            "answer should be set to media"
+        );
+
+        session.terminate().await.expect("should send bye");
+        assert!(hook.wait_event(|e| is_sip_event(e, |s| matches!(s, sip_event::Event::Bye(_)))).await, "hook should receive bye");
+        assert!(wait_until(Duration::from_secs(5), || media.sessions().is_empty()).await, "media session should be deleted");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_outgoing_call_with_wrong_digest_password() {
+    let media = MediaServer::start().await;
+    let hook = HookServer::start(serde_json::json!({})).await;
+    let http_addr = free_tcp_addr();
+    let cfg = gateway_config(http_addr, free_udp_addr(), media.url(), Arc::new(HttpRtpEngine::new(media.url())));
+    let peer = SipPeer::start_with_challenge("trunk-user", "trunk-pass").await;
+
+    with_gateway(cfg, async {
+        create_outgoing_call(http_addr, &call_request(peer.addr(), hook.url(), Some(("trunk-user", "wrong-pass")))).await;
+
+        assert!(
+            hook.wait_event(|e| is_sip_event(e, |s| matches!(s, sip_event::Event::Failure(f) if f.code == 401))).await,
+            "hook should receive the 401 failure"
+        );
+        assert_eq!(peer.challenges(), 2, "the retried INVITE should be rejected too");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_outgoing_call_end_over_websocket() {
+    let media = MediaServer::start().await;
+    let hook = HookServer::start(serde_json::json!({})).await;
+    let http_addr = free_tcp_addr();
+    let cfg = gateway_config(http_addr, free_udp_addr(), media.url(), Arc::new(HttpRtpEngine::new(media.url())));
+    let mut peer = SipPeer::start().await;
+
+    with_gateway(cfg, async {
+        let created = create_outgoing_call(http_addr, &call_request(peer.addr(), hook.url(), None)).await;
+        let call_ws = created["call_ws"].as_str().expect("should have call_ws");
+        let mut session = peer.recv_invite().await.answer(FAKE_ANSWER_SDP).await;
+        assert!(
+            hook.wait_event(|e| is_sip_event(e, |s| matches!(s, sip_event::Event::Accepted(_)))).await,
+            "hook should receive accepted"
+        );
+
+        let (mut ws, _) = connect_async(format!("ws://{http_addr}{call_ws}")).await.expect("should connect websocket");
+        let end = OutgoingCallData {
+            data: Some(outgoing_call_data::Data::Request(OutgoingCallRequest {
+                req_id: 1,
+                action: Some(outgoing_call_request::Action::End(outgoing_call_request::End {})),
+            })),
+        };
+        ws.send(Message::Binary(end.encode_to_vec())).await.expect("should send end request");
+
+        let response = timeout(Duration::from_secs(5), async {
+            while let Some(msg) = ws.next().await {
+                if let Message::Binary(data) = msg.expect("should read websocket") {
+                    if let Some(outgoing_call_data::Data::Response(res)) = OutgoingCallData::decode(data.as_slice()).expect("should decode").data {
+                        return Some(res);
+                    }
+                }
+            }
+            None
+        })
+        .await
+        .expect("should receive response")
+        .expect("websocket should stay open");
+        assert_eq!(response.req_id, 1);
+        assert!(matches!(response.response, Some(outgoing_call_response::Response::End(_))), "unexpected response {response:?}");
+
+        wait_bye(&mut session).await;
+        assert!(wait_until(Duration::from_secs(5), || media.sessions().is_empty()).await, "media session should be deleted");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_outgoing_call_recording_actions() {
+    let backend = RecordingBackend::default();
+    let hook = HookServer::start(serde_json::json!({})).await;
+    let http_addr = free_tcp_addr();
+    let mut cfg = gateway_config(http_addr, free_udp_addr(), "http://127.0.0.1:1", Arc::new(FakeRtpEngine::new()));
+    // atm0s has no recording api, the app uses a backend which has one
+    cfg.media_backends.insert("recorder".to_owned(), Arc::new(backend.clone()));
+    let mut peer = SipPeer::start().await;
+
+    with_gateway(cfg, async {
+        upsert_app(http_addr, &serde_json::json!({ "app_id": "app1", "app_secret": "app1-secret", "media_backend": "recorder" })).await;
+        let created = create_outgoing_call_as(http_addr, "app1-secret", &call_request(peer.addr(), hook.url(), None)).await;
+        let call_id = created["call_id"].as_str().expect("should have call_id");
+        let call_token = created["call_token"].as_str().expect("should have call_token");
+        let mut session = peer.recv_invite().await.answer(FAKE_ANSWER_SDP).await;
+        assert!(
+            hook.wait_event(|e| is_sip_event(e, |s| matches!(s, sip_event::Event::Accepted(_)))).await,
+            "hook should receive accepted"
+        );
+
+        let client = reqwest::Client::new();
+        for action in ["StartRecording", "PauseRecording"] {
+            let res: serde_json::Value = client
+                .post(format!("http://{http_addr}/call/outgoing/{call_id}/action?token={call_token}"))
+                .json(&serde_json::json!({ "action": action }))
+                .send()
+                .await
+                .expect("should send action")
+                .error_for_status()
+                .expect("action should succeed")
+                .json()
+                .await
+                .expect("should parse response");
+            assert!(res["data"]["recording_id"].as_str().is_some_and(|id| id.starts_with("rec-")), "unexpected response {res}");
+        }
+
+        assert_eq!(backend.actions(), vec![RecordingAction::Start, RecordingAction::Pause]);
+        assert!(hook.wait_event(|e| recording_state(e) == Some(recording_state::State::Paused)).await, "hook should receive paused");
+        let states: Vec<_> = hook.events().iter().filter_map(recording_state).collect();
+        assert_eq!(states, vec![recording_state::State::Recording, recording_state::State::Paused]);
+
+        session.terminate().await.expect("should send bye");
+        assert!(wait_until(Duration::from_secs(5), || backend.sessions() == 0).await, "media session should be destroyed");
+    })
+    .await;
+}